@@ -2,6 +2,7 @@
 
 set -e
 cargo build
+python3 tools/ksyms.py target/x86_64-unknown-uefi/debug/lazarus.efi
 qemu-system-x86_64  \
     -enable-kvm \
     -m 128 \
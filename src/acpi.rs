@@ -0,0 +1,585 @@
+//! ACPI tables
+//! The firmware hands us the RSDP through the EFI configuration table. It
+//! holds the address of the XSDT (ACPI 2.0+, 64-bit entries) or the RSDT
+//! (32-bit entries), which list every other system description table. `init()`
+//! walks the XSDT when there is a valid one and the RSDT otherwise, as tables
+//! above 4 GiB can only be listed in the XSDT. It checks the header of every
+//! table and passes the tables we understand to their parsers
+//!
+//! Every table with a valid header, plus the DSDT from the FADT, is kept in
+//! a registry afterwards, so later parsers look tables up with
+//! `find_table()` instead of walking the root table again
+//!
+//! Real firmware ships tables with broken checksums and pointers into
+//! nowhere, so nothing here panics: a bad table is skipped with a warning
+//! and only a missing RSDP or root table fails `init()`
+//!
+//! The tables live in ACPI reclaim memory, which is never handed to the
+//! allocator, so they can be read through the direct map at any time
+//! See: https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html
+use core::fmt;
+use crate::efi::{self, EFI_GUID};
+use crate::mm::{self, PhysAddr};
+use crate::sync::OnceCell;
+
+pub mod fadt;
+pub mod iommu;
+pub mod madt;
+pub mod mcfg;
+pub mod slit;
+pub mod srat;
+
+
+/// GUID of the ACPI 2.0+ RSDP in the EFI configuration table
+const ACPI_20_TABLE_GUID: EFI_GUID = EFI_GUID::new(
+    0x8868_e871, 0xe4f1, 0x11d3, [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
+);
+
+/// GUID of the ACPI 1.0 RSDP in the EFI configuration table
+const ACPI_TABLE_GUID: EFI_GUID = EFI_GUID::new(
+    0xeb9d_2d30, 0x2d88, 0x11d3, [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d],
+);
+
+/// Signature the RSDP starts with
+const RSDP_SIGNATURE: [u8; 8] = *b"RSD PTR ";
+
+/// Size of the ACPI 1.0 part of the RSDP, which its checksum covers
+const RSDP_V1_SIZE: u64 = 20;
+
+/// Size of the header every system description table starts with
+pub const HEADER_SIZE: u64 = 36;
+
+/// Largest table we read, anything bigger is taken as corrupt
+const MAX_TABLE_SIZE: u32 = 16 << 20;
+
+/// Maximum number of tables `AcpiTables` records
+const MAX_TABLES: usize = 64;
+
+/// Maximum number of broken tables `AcpiTables` records
+const MAX_SKIPPED: usize = 16;
+
+
+/// Why a table, or all of them, couldn't be used
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcpiError {
+    // There is no RSDP in the EFI configuration table
+    NoRsdp,
+
+    // The structure at this address lies outside memory we can read
+    Unreachable(PhysAddr),
+
+    // The structure at this address doesn't have the signature it should
+    BadSignature(PhysAddr),
+
+    // The table at this address claims an impossible length
+    BadLength(PhysAddr, u32),
+
+    // The structure at this address doesn't sum to 0
+    BadChecksum(PhysAddr),
+
+    // A table is too short for the fields its parser needs
+    Truncated,
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::NoRsdp => write!(f, "no RSDP"),
+            AcpiError::Unreachable(addr) => write!(f, "{:#x} is outside readable memory", addr.0),
+            AcpiError::BadSignature(addr) => write!(f, "bad signature at {:#x}", addr.0),
+            AcpiError::BadLength(addr, len) => write!(f, "bad length of {} bytes at {:#x}", len, addr.0),
+            AcpiError::BadChecksum(addr) => write!(f, "bad checksum at {:#x}", addr.0),
+            AcpiError::Truncated => write!(f, "table is truncated"),
+        }
+    }
+}
+
+
+/// The registry, what `init()` found
+static REGISTRY: OnceCell<AcpiTables> = OnceCell::new();
+
+
+/// Root System Description Pointer, ACPI 1.0 layout
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct RSDPDescriptor {
+    pub signature: [u8; 8],
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+
+    // 0 for ACPI 1.0, 2 for 2.0 and later
+    pub revision: u8,
+
+    pub rsdt_address: u32,
+}
+
+
+/// Root System Description Pointer, ACPI 2.0+ layout
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct RSDPDescriptor20 {
+    pub first: RSDPDescriptor,
+
+    // Size of the whole structure, which the extended checksum covers
+    pub length: u32,
+
+    pub xsdt_address: u64,
+    pub extended_checksum: u8,
+    pub reserved: [u8; 3],
+}
+
+
+/// Header every system description table starts with
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct SDTHeader {
+    pub signature: [u8; 4],
+
+    // Size of the table including the header
+    pub length: u32,
+
+    pub revision: u8,
+
+    // Makes all bytes of the table sum to 0
+    pub checksum: u8,
+
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+
+/// A table found through the XSDT or RSDT
+#[derive(Clone, Copy, Debug)]
+pub struct Table {
+    pub header: SDTHeader,
+    pub addr: PhysAddr,
+}
+
+impl Table {
+    /// The whole table, header included
+    pub fn bytes(&self) -> &'static [u8] {
+        // Checked by `parse_header()`, and ACPI memory is never reused
+        unsafe {
+            core::slice::from_raw_parts(mm::phys_to_virt(self.addr).0 as *const u8, self.header.length as usize)
+        }
+    }
+}
+
+
+/// Little endian fields of a table, reads past the end give `None`
+#[derive(Clone, Copy, Debug)]
+pub struct Fields<'a>(pub &'a [u8]);
+
+impl Fields<'_> {
+    pub fn u8(&self, off: usize) -> Option<u8> {
+        self.0.get(off).copied()
+    }
+
+    pub fn u16(&self, off: usize) -> Option<u16> {
+        Some(u16::from_le_bytes(self.0.get(off..off + 2)?.try_into().ok()?))
+    }
+
+    pub fn u32(&self, off: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(self.0.get(off..off + 4)?.try_into().ok()?))
+    }
+
+    pub fn u64(&self, off: usize) -> Option<u64> {
+        Some(u64::from_le_bytes(self.0.get(off..off + 8)?.try_into().ok()?))
+    }
+}
+
+
+/// A list of at most `N` entries parsed from a table
+#[derive(Clone, Copy, Debug)]
+pub struct List<T: Copy + Default, const N: usize> {
+    len: usize,
+    items: [T; N],
+}
+
+impl<T: Copy + Default, const N: usize> List<T, N> {
+    pub fn new() -> Self {
+        List { len: 0, items: [T::default(); N] }
+    }
+
+    /// Add `item`, false if the list is full
+    pub fn push(&mut self, item: T) -> bool {
+        match self.items.get_mut(self.len) {
+            Some(slot) => {
+                *slot = item;
+                self.len += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.items[..self.len]
+    }
+}
+
+
+/// What `init()` found
+#[derive(Clone, Copy, Debug)]
+pub struct AcpiTables {
+    // Revision of the RSDP, 0 for ACPI 1.0
+    pub revision: u8,
+
+    pub oem_id: [u8; 6],
+
+    // Address of the RSDP and of the XSDT or RSDT the tables came from
+    pub rsdp: PhysAddr,
+    pub root: PhysAddr,
+
+    // Whether `root` is the XSDT
+    pub xsdt: bool,
+
+    // Interrupt controllers and processors, from the MADT
+    pub madt: Option<madt::Madt>,
+
+    // Proximity domains of processors and memory, from the SRAT
+    pub srat: Option<srat::Srat>,
+
+    // Remapping units, from the DMAR or IVRS
+    pub iommu: Option<iommu::Iommu>,
+
+    count: usize,
+    tables: [Option<Table>; MAX_TABLES],
+
+    // Root table entries which were skipped, and why
+    skipped: [Option<(PhysAddr, AcpiError)>; MAX_SKIPPED],
+}
+
+impl AcpiTables {
+    /// The tables with a valid header, in the order of the root table
+    pub fn iter(&self) -> impl Iterator<Item = &Table> {
+        self.tables.iter().flatten()
+    }
+
+    /// Number of tables with a valid header
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// The first table with `signature`
+    #[allow(dead_code)]
+    pub fn find(&self, signature: &[u8; 4]) -> Option<&Table> {
+        self.iter().find(|table| table.header.signature == *signature)
+    }
+
+    /// All tables with `signature`, there may be several SSDTs
+    #[allow(dead_code)]
+    pub fn find_all<'a>(&'a self, signature: &'a [u8; 4]) -> impl Iterator<Item = &'a Table> {
+        self.iter().filter(move |table| table.header.signature == *signature)
+    }
+
+    /// Add `table`, dropping it with a warning if there is no room left
+    fn record(&mut self, table: Table) {
+        let header = table.header;
+        debug!("ACPI table {} at {:#x}, {} bytes, OEM {}", ascii(&header.signature), table.addr.0,
+            { header.length }, ascii(&header.oem_id));
+        match self.tables.get_mut(self.count) {
+            Some(slot) => {
+                *slot = Some(table);
+                self.count += 1;
+            },
+            None => warn!("Too many ACPI tables, not recording {}", ascii(&header.signature)),
+        }
+    }
+}
+
+
+/// Whether the `len` bytes at `addr` sum to 0
+/// `Unreachable` if they aren't all readable
+fn checksum(addr: PhysAddr, len: u64) -> Result<bool, AcpiError> {
+    if !mm::phys_readable(addr, len) {
+        return Err(AcpiError::Unreachable(addr));
+    }
+
+    let mut sum = 0u8;
+    let mut buf = [0u8; 256];
+    let mut done = 0;
+    while done < len {
+        let chunk = core::cmp::min(len - done, buf.len() as u64) as usize;
+        unsafe {
+            mm::read_phys_slice(PhysAddr(addr.0 + done), &mut buf[..chunk]);
+        }
+        sum = buf[..chunk].iter().fold(sum, |sum, byte| sum.wrapping_add(*byte));
+        done += chunk as u64;
+    }
+    Ok(sum == 0)
+}
+
+
+/// Read a `T` from `addr`, `Unreachable` if it isn't memory we can read
+fn read<T: Copy>(addr: PhysAddr) -> Result<T, AcpiError> {
+    if addr.0 == 0 || !mm::phys_readable(addr, core::mem::size_of::<T>() as u64) {
+        return Err(AcpiError::Unreachable(addr));
+    }
+    Ok(unsafe { mm::read_phys(addr) })
+}
+
+
+/// Signature or OEM ID as text, for log messages
+pub fn ascii(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("????").trim_end_matches([' ', '\0'])
+}
+
+
+/// Read and check the header of the table at `addr`
+/// Tables which are too small, too big, out of reach or have a bad checksum
+/// are errors for the caller to report and skip
+pub fn parse_header(addr: PhysAddr) -> Result<SDTHeader, AcpiError> {
+    let header: SDTHeader = read(addr)?;
+
+    let length = header.length;
+    if (length as u64) < HEADER_SIZE || length > MAX_TABLE_SIZE {
+        return Err(AcpiError::BadLength(addr, length));
+    }
+    if !checksum(addr, length as u64)? {
+        return Err(AcpiError::BadChecksum(addr));
+    }
+    Ok(header)
+}
+
+
+/// Find the RSDP through the EFI configuration table and check it
+/// Returns its address and the address of the XSDT if the ACPI 2.0 part of
+/// the RSDP is valid and has one
+fn find_rsdp() -> Result<(PhysAddr, Option<PhysAddr>), AcpiError> {
+    let addr = efi::config_table(&ACPI_20_TABLE_GUID)
+        .or_else(|| efi::config_table(&ACPI_TABLE_GUID))
+        .map(PhysAddr)
+        .ok_or(AcpiError::NoRsdp)?;
+
+    let rsdp: RSDPDescriptor = read(addr)?;
+    if rsdp.signature != RSDP_SIGNATURE {
+        return Err(AcpiError::BadSignature(addr));
+    }
+    if !checksum(addr, RSDP_V1_SIZE)? {
+        return Err(AcpiError::BadChecksum(addr));
+    }
+
+    if rsdp.revision < 2 {
+        return Ok((addr, None));
+    }
+
+    // A broken ACPI 2.0 part still leaves us the RSDT
+    let rsdp: RSDPDescriptor20 = match read(addr) {
+        Ok(rsdp) => rsdp,
+        Err(err) => {
+            warn!("ACPI 2.0 RSDP: {}, using the RSDT", err);
+            return Ok((addr, None));
+        },
+    };
+    let length = rsdp.length as u64;
+    let valid = length >= core::mem::size_of::<RSDPDescriptor20>() as u64 &&
+        checksum(addr, length).unwrap_or(false);
+    if !valid {
+        warn!("ACPI 2.0 RSDP at {:#x} has a bad extended checksum, using the RSDT", addr.0);
+        return Ok((addr, None));
+    }
+    let xsdt = rsdp.xsdt_address;
+    Ok((addr, (xsdt != 0).then_some(PhysAddr(xsdt))))
+}
+
+
+/// The root table to walk and the size of its entries
+/// The XSDT if there is a valid one, the RSDT otherwise
+fn root_table(desc: &RSDPDescriptor, xsdt: Option<PhysAddr>) -> Result<(PhysAddr, SDTHeader, u64), AcpiError> {
+    if let Some(xsdt) = xsdt {
+        match parse_header(xsdt) {
+            Ok(header) if header.signature == *b"XSDT" => return Ok((xsdt, header, 8)),
+            Ok(header) => warn!("ACPI XSDT at {:#x} has signature {}, using the RSDT", xsdt.0, ascii(&header.signature)),
+            Err(err) => warn!("ACPI XSDT: {}, using the RSDT", err),
+        }
+    }
+
+    let rsdt = PhysAddr(desc.rsdt_address as u64);
+    let header = parse_header(rsdt)?;
+    if header.signature != *b"RSDT" {
+        return Err(AcpiError::BadSignature(rsdt));
+    }
+    Ok((rsdt, header, 4))
+}
+
+
+/// Hand `table` to its parser, if we have one, and keep what it found in
+/// `tables`
+fn dispatch(tables: &mut AcpiTables, table: &Table) -> Result<(), AcpiError> {
+    match &table.header.signature {
+        b"FACP" => fadt::set(fadt::parse_fadt(table.bytes())?),
+        b"APIC" => tables.madt = Some(madt::parse_madt(table.bytes())?),
+        b"SRAT" => {
+            let srat = srat::parse_srat(table.bytes());
+            srat.register();
+            tables.srat = Some(srat);
+        },
+        b"SLIT" => {
+            let nodes = slit::parse_slit(table.bytes())?;
+            debug!("SLIT: distances between {} nodes", nodes);
+        },
+        b"DMAR" => tables.iommu = Some(iommu::parse_dmar(table.bytes())?),
+        b"IVRS" => tables.iommu = Some(iommu::parse_ivrs(table.bytes())?),
+        b"MCFG" => crate::pci::init(mcfg::parse_mcfg(table.bytes()).as_slice()),
+        b"NFIT" => {
+            let regions = crate::pmem::parse_nfit(table.bytes());
+            debug!("NFIT: {} persistent memory regions", regions);
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+
+/// Walk the ACPI tables, parse the ones we understand and fill the registry
+/// Tables which are broken or can't be parsed are reported and skipped, it
+/// is only an error if there is no valid RSDP or root table. Only the first
+/// call walks the tables
+pub fn init() -> Result<&'static AcpiTables, AcpiError> {
+    if let Some(tables) = registry() {
+        return Ok(tables);
+    }
+
+    let (rsdp, xsdt) = find_rsdp()?;
+    let desc: RSDPDescriptor = read(rsdp)?;
+    let (root, header, entry_size) = root_table(&desc, xsdt)?;
+
+    let mut tables = AcpiTables {
+        revision: desc.revision,
+        oem_id: desc.oem_id,
+        rsdp,
+        root,
+        xsdt: entry_size == 8,
+        madt: None,
+        srat: None,
+        iommu: None,
+        count: 0,
+        tables: [None; MAX_TABLES],
+        skipped: [None; MAX_SKIPPED],
+    };
+
+    // The root table passed `parse_header()`, so all of it is readable
+    let entries = (header.length as u64 - HEADER_SIZE) / entry_size;
+    for index in 0..entries {
+        let entry = PhysAddr(root.0 + HEADER_SIZE + index * entry_size);
+        let addr = match entry_size {
+            // XSDT entries are only 4 byte aligned
+            8 => PhysAddr(unsafe { mm::read_phys::<u64>(entry) }),
+            _ => PhysAddr(unsafe { mm::read_phys::<u32>(entry) } as u64),
+        };
+        let header = match parse_header(addr) {
+            Ok(header) => header,
+            Err(err) => {
+                warn!("Skipping ACPI table {} of the root table: {}", index, err);
+                if let Some(slot) = tables.skipped.iter_mut().find(|slot| slot.is_none()) {
+                    *slot = Some((addr, err));
+                }
+                continue;
+            },
+        };
+
+        let table = Table { header, addr };
+        tables.record(table);
+        if let Err(err) = dispatch(&mut tables, &table) {
+            warn!("ACPI table {} at {:#x}: {}", ascii(&header.signature), addr.0, err);
+        }
+    }
+
+    // The DSDT isn't in the root table, only the FADT points to it
+    if let Some(dsdt) = fadt::get().map(|fadt| fadt.dsdt) {
+        match parse_header(dsdt) {
+            Ok(header) => tables.record(Table { header, addr: dsdt }),
+            Err(err) => warn!("Skipping the DSDT: {}", err),
+        }
+    }
+
+    if REGISTRY.set(tables).is_err() {
+        warn!("ACPI tables parsed twice, keeping the first");
+    }
+    registry().ok_or(AcpiError::NoRsdp)
+}
+
+
+/// What `init()` found, `None` before it ran or if there are no tables
+pub fn registry() -> Option<&'static AcpiTables> {
+    REGISTRY.get()
+}
+
+
+/// The first table with `signature`, e.g. `find_table(b"WAET")`
+#[allow(dead_code)]
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static Table> {
+    registry()?.find(signature)
+}
+
+
+/// Every table in the registry, in the order of the root table with the DSDT
+/// last
+#[allow(dead_code)]
+pub fn iter_tables() -> impl Iterator<Item = &'static Table> {
+    registry().into_iter().flat_map(|tables| tables.iter())
+}
+
+
+/// Reset the system through the FADT reset register, for when the runtime
+/// services can't
+/// Only returns if that isn't possible or didn't work
+pub fn reboot() {
+    fadt::reboot();
+}
+
+
+/// Power the system off by entering S5, for when the runtime services can't
+/// Only returns if that isn't possible or didn't work
+#[allow(dead_code)]
+pub fn poweroff() {
+    fadt::poweroff();
+}
+
+
+/// List every table with its signature, address, length, OEM and checksum
+/// status on the console, along with the root table entries which were
+/// skipped, and hexdump the tables with signature `dump` if given
+/// For debugging firmware quirks, `--acpidump` or `acpidump=SIG` on the
+/// command line
+pub fn acpidump(dump: Option<&str>) {
+    let tables = match registry() {
+        Some(tables) => tables,
+        None => {
+            println!("No ACPI tables");
+            return;
+        },
+    };
+
+    println!("RSDP at {:#x}, revision {}, OEM {}, {} at {:#x}", tables.rsdp.0, tables.revision,
+        ascii(&tables.oem_id), if tables.xsdt {"XSDT"} else {"RSDT"}, tables.root.0);
+    println!("SIG  ADDRESS            LENGTH  REV OEM    OEM TABLE CHECKSUM");
+    for table in tables.iter() {
+        let header = table.header;
+        let status = match checksum(table.addr, header.length as u64) {
+            Ok(true) => "ok",
+            Ok(false) => "BAD",
+            Err(_) => "unreadable",
+        };
+        println!("{:<4} {:#018x} {:>7} {:>3} {:<6} {:<9} {}", ascii(&header.signature), table.addr.0,
+            { header.length }, header.revision, ascii(&header.oem_id), ascii(&header.oem_table_id), status);
+    }
+    for (addr, err) in tables.skipped.iter().flatten() {
+        println!("???? {:#018x} skipped: {}", addr.0, err);
+    }
+
+    let Some(dump) = dump else { return };
+    let mut found = false;
+    for table in tables.iter().filter(|table| ascii(&table.header.signature).eq_ignore_ascii_case(dump)) {
+        println!("{} at {:#x}:", dump, table.addr.0);
+        crate::hexdump::hexdump(table.addr.0, table.bytes());
+        found = true;
+    }
+    if !found {
+        println!("No {} table", dump);
+    }
+}
@@ -0,0 +1,353 @@
+//! FADT, the Fixed ACPI Description Table
+//! Points to the DSDT and describes the fixed hardware registers. We only
+//! use two of them: the reset register, to reboot, and the PM1 control
+//! blocks, to enter S5 (soft off). Both are fallbacks for when the runtime
+//! services `ResetSystem()` doesn't work, which happens on firmware that
+//! gets SetVirtualAddressMap wrong
+//!
+//! The sleep type values for S5 live in the `\_S5` package of the DSDT,
+//! which is AML. Without an interpreter we find the package by its name and
+//! decode it by hand, that works for the static packages all firmware uses
+use super::{AcpiError, Fields, Table, HEADER_SIZE};
+use crate::cpu::port;
+use crate::mm::{self, PhysAddr};
+use crate::pci;
+use crate::sync::OnceCell;
+
+
+// Offsets of the fields we use
+const DSDT: usize = HEADER_SIZE as usize + 4;
+const SMI_CMD: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const PM1A_CNT_BLK: usize = 64;
+const PM1B_CNT_BLK: usize = 68;
+const FLAGS: usize = 112;
+const RESET_REG: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_DSDT: usize = 140;
+const X_PM1A_CNT_BLK: usize = 172;
+const X_PM1B_CNT_BLK: usize = 184;
+
+/// Flag: the reset register is supported
+const RESET_REG_SUP: u32 = 1 << 10;
+
+/// Flag: hardware reduced ACPI, there are no PM1 blocks
+const HW_REDUCED_ACPI: u32 = 1 << 20;
+
+/// PM1 control bit: the system is in ACPI mode
+const SCI_EN: u16 = 1 << 0;
+
+/// PM1 control bits: sleep type to enter
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0x7 << SLP_TYP_SHIFT;
+
+/// PM1 control bit: enter the sleep state in SLP_TYP
+const SLP_EN: u16 = 1 << 13;
+
+// Address spaces of a generic address
+const SPACE_MEMORY: u8 = 0;
+const SPACE_IO: u8 = 1;
+const SPACE_PCI: u8 = 2;
+
+// AML opcodes found in the `\_S5` package
+const AML_NAME: u8 = 0x08;
+const AML_PACKAGE: u8 = 0x12;
+const AML_ZERO: u8 = 0x00;
+const AML_ONE: u8 = 0x01;
+const AML_BYTE: u8 = 0x0a;
+
+
+/// A register in the system memory, system IO or PCI configuration space, an
+/// ACPI generic address structure
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GenericAddress {
+    pub space: u8,
+    pub bit_width: u8,
+    #[allow(dead_code)]
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Parse the 12 byte structure at `off` in `fields`
+    /// `None` if it is beyond the end or the address is zero, meaning there
+    /// is no such register
+    pub fn parse(fields: &Fields, off: usize) -> Option<GenericAddress> {
+        let gas = GenericAddress {
+            space: fields.u8(off)?,
+            bit_width: fields.u8(off + 1)?,
+            bit_offset: fields.u8(off + 2)?,
+            access_size: fields.u8(off + 3)?,
+            address: fields.u64(off + 4)?,
+        };
+        (gas.address != 0).then_some(gas)
+    }
+
+    /// A legacy IO port block, as in the fields preceding the generic
+    /// addresses
+    fn io(port: u32, bit_width: u8) -> Option<GenericAddress> {
+        (port != 0).then_some(GenericAddress {
+            space: SPACE_IO,
+            bit_width,
+            bit_offset: 0,
+            access_size: 0,
+            address: port as u64,
+        })
+    }
+
+    /// Width of an access in bits, from the access size if given
+    fn width(&self) -> u8 {
+        match self.access_size {
+            1 => 8,
+            2 => 16,
+            3 => 32,
+            4 => 64,
+            _ => self.bit_width,
+        }
+    }
+
+    /// Read the register, `None` for address spaces and widths we don't
+    /// support
+    /// Memory registers are mapped on every access, which is fine for the
+    /// handful of accesses on the way to a reset
+    #[allow(dead_code)]
+    pub fn read(&self) -> Option<u64> {
+        match (self.space, self.width()) {
+            (SPACE_IO, 8) => Some(unsafe { port::inb(self.port()?) } as u64),
+            (SPACE_IO, 16) => Some(unsafe { port::inw(self.port()?) } as u64),
+            (SPACE_IO, 32) => Some(unsafe { port::inl(self.port()?) } as u64),
+            (SPACE_MEMORY, width @ (8 | 16 | 32 | 64)) => {
+                let virt = mm::map_mmio(PhysAddr(self.address), width as u64 / 8).ok()?;
+                Some(unsafe {
+                    match width {
+                        8 => core::ptr::read_volatile(virt.0 as *const u8) as u64,
+                        16 => core::ptr::read_volatile(virt.0 as *const u16) as u64,
+                        32 => core::ptr::read_volatile(virt.0 as *const u32) as u64,
+                        _ => core::ptr::read_volatile(virt.0 as *const u64),
+                    }
+                })
+            },
+            _ => None,
+        }
+    }
+
+    /// Write the register, `None` for address spaces and widths we don't
+    /// support
+    pub fn write(&self, val: u64) -> Option<()> {
+        match (self.space, self.width()) {
+            (SPACE_IO, 8) => unsafe { port::outb(self.port()?, val as u8) },
+            (SPACE_IO, 16) => unsafe { port::outw(self.port()?, val as u16) },
+            (SPACE_IO, 32) => unsafe { port::outl(self.port()?, val as u32) },
+            (SPACE_MEMORY, width @ (8 | 16 | 32 | 64)) => {
+                let virt = mm::map_mmio(PhysAddr(self.address), width as u64 / 8).ok()?;
+                unsafe {
+                    match width {
+                        8 => core::ptr::write_volatile(virt.0 as *mut u8, val as u8),
+                        16 => core::ptr::write_volatile(virt.0 as *mut u16, val as u16),
+                        32 => core::ptr::write_volatile(virt.0 as *mut u32, val as u32),
+                        _ => core::ptr::write_volatile(virt.0 as *mut u64, val),
+                    }
+                }
+            },
+            // Bus 0 of segment 0, device in bits 47:32, function in 31:16
+            // and the register in 15:0
+            (SPACE_PCI, 8) => {
+                let addr = pci::Address {
+                    segment: 0,
+                    bus: 0,
+                    device: (self.address >> 32) as u8,
+                    function: (self.address >> 16) as u8,
+                };
+                pci::write_u8(addr, self.address as u16, val as u8)?;
+            },
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn port(&self) -> Option<u16> {
+        u16::try_from(self.address).ok()
+    }
+}
+
+
+/// What `parse_fadt()` found
+#[derive(Clone, Copy, Debug)]
+pub struct Fadt {
+    pub flags: u32,
+
+    // The DSDT, preferring the 64-bit address
+    pub dsdt: PhysAddr,
+
+    // Reset register and the value to write to it, if supported
+    pub reset: Option<(GenericAddress, u8)>,
+
+    // PM1 control blocks, preferring the generic addresses
+    pub pm1a_cnt: Option<GenericAddress>,
+    pub pm1b_cnt: Option<GenericAddress>,
+
+    // Port and value to switch to ACPI mode if the firmware hasn't yet
+    pub smi_cmd: u32,
+    pub acpi_enable: u8,
+
+    // SLP_TYPa and SLP_TYPb of S5, from the DSDT
+    pub s5: Option<(u8, u8)>,
+}
+
+
+/// The FADT of the running system, set once by `set()`
+static FADT: OnceCell<Fadt> = OnceCell::new();
+
+
+/// Parse the FADT in `table` and the `\_S5` package of the DSDT it points to
+/// `Truncated` if it is too short to point to a DSDT
+pub fn parse_fadt(table: &[u8]) -> Result<Fadt, AcpiError> {
+    let fields = Fields(table);
+    let flags = fields.u32(FLAGS).unwrap_or(0);
+
+    let dsdt = match fields.u64(X_DSDT) {
+        Some(addr) if addr != 0 => addr,
+        _ => fields.u32(DSDT).ok_or(AcpiError::Truncated)? as u64,
+    };
+
+    let reset = match (flags & RESET_REG_SUP != 0, GenericAddress::parse(&fields, RESET_REG)) {
+        (true, Some(reg)) => fields.u8(RESET_VALUE).map(|val| (reg, val)),
+        _ => None,
+    };
+
+    let pm1a_cnt = GenericAddress::parse(&fields, X_PM1A_CNT_BLK)
+        .or_else(|| GenericAddress::io(fields.u32(PM1A_CNT_BLK)?, 16));
+    let pm1b_cnt = GenericAddress::parse(&fields, X_PM1B_CNT_BLK)
+        .or_else(|| GenericAddress::io(fields.u32(PM1B_CNT_BLK)?, 16));
+
+    let s5 = match super::parse_header(PhysAddr(dsdt)) {
+        Ok(header) => find_s5(Table { header, addr: PhysAddr(dsdt) }.bytes()),
+        Err(err) => {
+            warn!("DSDT: {}, no S5 sleep type", err);
+            None
+        },
+    };
+
+    let fadt = Fadt {
+        flags,
+        dsdt: PhysAddr(dsdt),
+        reset,
+        pm1a_cnt,
+        pm1b_cnt,
+        smi_cmd: fields.u32(SMI_CMD).unwrap_or(0),
+        acpi_enable: fields.u8(ACPI_ENABLE).unwrap_or(0),
+        s5,
+    };
+    debug!("FADT: DSDT at {:#x}, reset register {}, S5 {:?}", dsdt,
+        if reset.is_some() {"supported"} else {"unsupported"}, s5);
+    Ok(fadt)
+}
+
+
+/// Find the `\_S5` package in the AML of the DSDT `table` and return its
+/// first two elements, SLP_TYPa and SLP_TYPb
+fn find_s5(table: &[u8]) -> Option<(u8, u8)> {
+    let aml = table.get(HEADER_SIZE as usize..)?;
+    let name = aml.windows(4).position(|name| name == b"_S5_")?;
+
+    // NameOp, optionally followed by a root prefix, comes right before
+    let name_op = match aml.get(name.checked_sub(1)?)? {
+        b'\\' => name.checked_sub(2)?,
+        _ => name - 1,
+    };
+    if aml[name_op] != AML_NAME || *aml.get(name + 4)? != AML_PACKAGE {
+        return None;
+    }
+
+    // Skip the PkgLength, bits 7:6 of its lead byte give the number of bytes
+    // following it, and NumElements
+    let pkg_length = name + 5;
+    let mut off = pkg_length + 1 + (*aml.get(pkg_length)? >> 6) as usize + 1;
+
+    let mut element = || {
+        let val = match *aml.get(off)? {
+            AML_ZERO => 0,
+            AML_ONE => 1,
+            AML_BYTE => {
+                off += 1;
+                *aml.get(off)?
+            },
+            _ => return None,
+        };
+        off += 1;
+        Some(val)
+    };
+    let slp_typa = element()?;
+    let slp_typb = element()?;
+    Some((slp_typa, slp_typb))
+}
+
+
+/// Keep `fadt` for `reboot()` and `poweroff()`, only the first call counts
+pub fn set(fadt: Fadt) {
+    let _ = FADT.set(fadt);
+}
+
+
+/// The FADT given to `set()`
+pub fn get() -> Option<&'static Fadt> {
+    FADT.get()
+}
+
+
+/// Reset the system through the reset register
+/// Only returns if there is no reset register or the reset didn't happen
+pub fn reboot() {
+    let Some((reg, val)) = get().and_then(|fadt| fadt.reset) else { return };
+    if reg.write(val as u64).is_none() {
+        warn!("Unsupported reset register in address space {}", reg.space);
+        return;
+    }
+
+    // The reset may take a moment to kick in
+    crate::time::udelay(100_000);
+}
+
+
+/// Enter S5, soft off, through the PM1 control blocks
+/// Only returns if that isn't possible or didn't work
+#[allow(dead_code)]
+pub fn poweroff() {
+    let fadt = match get() {
+        Some(fadt) => fadt,
+        None => return,
+    };
+    if fadt.flags & HW_REDUCED_ACPI != 0 {
+        warn!("No PM1 control blocks on hardware reduced ACPI");
+        return;
+    }
+    let (Some(pm1a), Some((slp_typa, slp_typb))) = (fadt.pm1a_cnt, fadt.s5) else {
+        warn!("No PM1 control block or S5 sleep type");
+        return;
+    };
+
+    // SLP_EN is ignored until the firmware hands the hardware to us
+    let enabled = pm1a.read().is_some_and(|val| val as u16 & SCI_EN != 0);
+    if !enabled && fadt.smi_cmd != 0 && fadt.acpi_enable != 0 {
+        unsafe {
+            port::outb(fadt.smi_cmd as u16, fadt.acpi_enable);
+        }
+        for _ in 0..300 {
+            if pm1a.read().is_some_and(|val| val as u16 & SCI_EN != 0) {break;}
+            crate::time::udelay(10_000);
+        }
+    }
+
+    let sleep = |reg: GenericAddress, slp_typ: u8| {
+        let val = reg.read().unwrap_or(0) as u16 & !(SLP_TYP_MASK | SLP_EN);
+        reg.write((val | ((slp_typ as u16) << SLP_TYP_SHIFT & SLP_TYP_MASK) | SLP_EN) as u64)
+    };
+    let _irq = crate::cpu::irq::IrqGuard::new();
+    sleep(pm1a, slp_typa);
+    if let Some(pm1b) = fadt.pm1b_cnt {
+        sleep(pm1b, slp_typb);
+    }
+
+    crate::time::udelay(100_000);
+}
@@ -0,0 +1,215 @@
+//! IOMMU detection from the DMAR (Intel VT-d) and IVRS (AMD-Vi) tables
+//! Only enough to know which remapping units there are, which PCI segment
+//! each covers and whether the firmware left DMA translation switched on.
+//! Nothing is programmed yet, this is for the boot log and for the DMA
+//! protection to come
+//!
+//! A unit counts as active if its translation enable bit is set: TES in the
+//! VT-d global status register, IommuEn in the AMD-Vi control register
+use super::{AcpiError, Fields, List, HEADER_SIZE};
+use crate::mm::{self, PhysAddr};
+
+
+/// Offset of the DMAR flags
+const DMAR_FLAGS: usize = HEADER_SIZE as usize + 1;
+
+/// Offset of the first DMAR remapping structure
+const DMAR_ENTRIES: usize = HEADER_SIZE as usize + 12;
+
+/// DMAR flag: interrupt remapping is supported
+const DMAR_INTR_REMAP: u8 = 1 << 0;
+
+/// DMAR flag: the firmware asks us not to use x2APIC mode
+const DMAR_X2APIC_OPT_OUT: u8 = 1 << 1;
+
+/// DMAR remapping structure type of a DMA remapping hardware unit
+const DMAR_TYPE_DRHD: u16 = 0;
+
+/// DRHD flag: the unit covers every device of its segment not claimed by
+/// another unit
+const DRHD_INCLUDE_PCI_ALL: u8 = 1 << 0;
+
+/// Offset of the VT-d global status register
+const VTD_GSTS: u64 = 0x1c;
+
+/// VT-d global status: translation enable status
+const VTD_GSTS_TES: u32 = 1 << 31;
+
+/// Offset of the first IVRS definition block
+const IVRS_ENTRIES: usize = HEADER_SIZE as usize + 12;
+
+/// IVRS definition block types of an I/O virtualization hardware definition
+const IVRS_TYPE_IVHD: [u8; 3] = [0x10, 0x11, 0x40];
+
+/// Offset of the AMD-Vi control register
+const AMD_CONTROL: u64 = 0x18;
+
+/// AMD-Vi control: IOMMU enable
+const AMD_CONTROL_IOMMU_EN: u64 = 1 << 0;
+
+/// Maximum number of units we keep
+pub const MAX_UNITS: usize = 16;
+
+
+/// Who made the IOMMU, i.e. which table described it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Vendor {
+    #[default]
+    Intel,
+    Amd,
+}
+
+
+/// A remapping unit
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Unit {
+    // Physical address of its registers
+    pub base: PhysAddr,
+
+    // PCI segment it belongs to
+    pub segment: u16,
+
+    // VT-d only: the unit covers all devices of the segment not listed
+    // under another unit
+    pub include_all: bool,
+
+    // Whether DMA translation is switched on
+    pub active: bool,
+}
+
+
+/// What `parse_dmar()` or `parse_ivrs()` found
+#[derive(Clone, Copy, Debug)]
+pub struct Iommu {
+    #[allow(dead_code)]
+    pub vendor: Vendor,
+    pub units: List<Unit, MAX_UNITS>,
+
+    // VT-d only: interrupt remapping is supported
+    #[allow(dead_code)]
+    pub interrupt_remapping: bool,
+
+    // VT-d only: the firmware asks us to stay in xAPIC mode
+    #[allow(dead_code)]
+    pub x2apic_opt_out: bool,
+}
+
+impl Iommu {
+    /// Whether any unit has DMA translation switched on
+    #[allow(dead_code)]
+    pub fn active(&self) -> bool {
+        self.units.as_slice().iter().any(|unit| unit.active)
+    }
+
+    fn add(&mut self, unit: Unit) {
+        if self.units.as_slice().iter().any(|other| other.base == unit.base) {
+            return;
+        }
+        if !self.units.push(unit) {
+            warn!("More than {} IOMMUs, ignoring {:#x}", MAX_UNITS, unit.base.0);
+        }
+    }
+
+    fn log(&self, table: &str) {
+        info!("{}: {} IOMMUs, {} active", table, self.units.as_slice().len(),
+            self.units.as_slice().iter().filter(|unit| unit.active).count());
+        for unit in self.units.as_slice() {
+            debug!("  {:#x} segment {}{}{}", unit.base.0, unit.segment,
+                if unit.include_all {", all devices"} else {""},
+                if unit.active {", translating"} else {""});
+        }
+    }
+}
+
+
+/// Read the register at `offset` of the unit at `base`
+/// `None` if the registers can't be mapped
+fn read_register<T: Copy>(base: PhysAddr, offset: u64) -> Option<T> {
+    let virt = mm::map_mmio(PhysAddr(base.0 + offset), core::mem::size_of::<T>() as u64).ok()?;
+    Some(unsafe { core::ptr::read_volatile(virt.0 as *const T) })
+}
+
+
+/// Parse the DMAR in `table` and check which units are translating
+/// Malformed entries end the parsing and keep what came before
+pub fn parse_dmar(table: &[u8]) -> Result<Iommu, AcpiError> {
+    let fields = Fields(table);
+    let flags = fields.u8(DMAR_FLAGS).ok_or(AcpiError::Truncated)?;
+    let mut iommu = Iommu {
+        vendor: Vendor::Intel,
+        units: List::new(),
+        interrupt_remapping: flags & DMAR_INTR_REMAP != 0,
+        x2apic_opt_out: flags & DMAR_X2APIC_OPT_OUT != 0,
+    };
+
+    let mut off = DMAR_ENTRIES;
+    while let (Some(typ), Some(len)) = (fields.u16(off), fields.u16(off + 2)) {
+        let len = len as usize;
+        if len < 4 || off + len > table.len() {
+            warn!("Malformed DMAR structure at offset {}", off);
+            break;
+        }
+
+        let entry = Fields(&table[off..off + len]);
+        if typ == DMAR_TYPE_DRHD {
+            match (entry.u8(4), entry.u16(6), entry.u64(8)) {
+                (Some(flags), Some(segment), Some(base)) => {
+                    let base = PhysAddr(base);
+                    let active = read_register::<u32>(base, VTD_GSTS)
+                        .is_some_and(|status| status & VTD_GSTS_TES != 0);
+                    iommu.add(Unit { base, segment, include_all: flags & DRHD_INCLUDE_PCI_ALL != 0, active });
+                },
+                _ => warn!("DMAR DRHD at offset {} is too short", off),
+            }
+        }
+
+        off += len;
+    }
+
+    iommu.log("DMAR");
+    Ok(iommu)
+}
+
+
+/// Parse the IVRS in `table` and check which units are translating
+/// Firmware describes each IOMMU with one block of every IVHD type it
+/// supports, they are told apart by their register base
+pub fn parse_ivrs(table: &[u8]) -> Result<Iommu, AcpiError> {
+    let fields = Fields(table);
+    if table.len() < IVRS_ENTRIES {
+        return Err(AcpiError::Truncated);
+    }
+    let mut iommu = Iommu {
+        vendor: Vendor::Amd,
+        units: List::new(),
+        interrupt_remapping: false,
+        x2apic_opt_out: false,
+    };
+
+    let mut off = IVRS_ENTRIES;
+    while let (Some(typ), Some(len)) = (fields.u8(off), fields.u16(off + 2)) {
+        let len = len as usize;
+        if len < 4 || off + len > table.len() {
+            warn!("Malformed IVRS block at offset {}", off);
+            break;
+        }
+
+        let entry = Fields(&table[off..off + len]);
+        if IVRS_TYPE_IVHD.contains(&typ) {
+            match (entry.u64(8), entry.u16(16)) {
+                (Some(base), Some(segment)) => {
+                    let base = PhysAddr(base);
+                    let active = read_register::<u64>(base, AMD_CONTROL)
+                        .is_some_and(|control| control & AMD_CONTROL_IOMMU_EN != 0);
+                    iommu.add(Unit { base, segment, include_all: false, active });
+                },
+                _ => warn!("IVRS IVHD at offset {} is too short", off),
+            }
+        }
+
+        off += len;
+    }
+
+    iommu.log("IVRS");
+    Ok(iommu)
+}
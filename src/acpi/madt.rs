@@ -0,0 +1,336 @@
+//! MADT, the Multiple APIC Description Table
+//! Lists the processors by local APIC ID, the IO APICs and the range of
+//! global system interrupts (GSIs) each serves, the ISA IRQs which are wired
+//! to a different GSI or with different polarity or trigger mode than usual,
+//! and where the NMI pins are. SMP bring-up takes the processors from here,
+//! the IO APIC driver everything else
+//!
+//! Processors with APIC IDs above 254 only show up in x2APIC (type 9)
+//! entries, firmware may list the others in either or both
+use super::{AcpiError, Fields, List, HEADER_SIZE};
+
+
+/// Offset of the local APIC address, right after the header
+const LOCAL_APIC_ADDR: usize = HEADER_SIZE as usize;
+
+/// Offset of the flags
+const FLAGS: usize = LOCAL_APIC_ADDR + 4;
+
+/// Offset of the first interrupt controller structure
+const ENTRIES: usize = FLAGS + 4;
+
+/// Flag: the system also has dual 8259 PICs, which have to be masked
+pub const PCAT_COMPAT: u32 = 1 << 0;
+
+/// Local APIC flag: the processor is usable
+const LAPIC_ENABLED: u32 = 1 << 0;
+
+/// Local APIC flag: the processor is disabled but can be brought online
+const LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// Local APIC ID of the broadcast entry in NMI structures, meaning every
+/// processor
+pub const ALL_PROCESSORS: u32 = u32::MAX;
+
+/// Maximum number of processors we keep
+pub const MAX_CPUS: usize = 256;
+
+/// Maximum number of IO APICs we keep
+pub const MAX_IO_APICS: usize = 16;
+
+/// Maximum number of interrupt source overrides we keep
+pub const MAX_OVERRIDES: usize = 32;
+
+/// Maximum number of NMI structures we keep, of each kind
+pub const MAX_NMIS: usize = 32;
+
+// Interrupt controller structure types
+const TYPE_LOCAL_APIC: u8 = 0;
+const TYPE_IO_APIC: u8 = 1;
+const TYPE_SOURCE_OVERRIDE: u8 = 2;
+const TYPE_NMI_SOURCE: u8 = 3;
+const TYPE_LOCAL_APIC_NMI: u8 = 4;
+const TYPE_LOCAL_APIC_ADDRESS: u8 = 5;
+const TYPE_X2APIC: u8 = 9;
+const TYPE_X2APIC_NMI: u8 = 10;
+
+
+/// A processor and its local APIC
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalApic {
+    // ACPI processor UID, matches the processor objects in the namespace
+    #[allow(dead_code)]
+    pub processor_uid: u32,
+
+    // Local APIC ID, the x2APIC ID for x2APIC entries
+    pub apic_id: u32,
+
+    // Whether the processor is usable
+    pub enabled: bool,
+
+    // Whether a disabled processor can be brought online
+    #[allow(dead_code)]
+    pub online_capable: bool,
+
+    // Whether it came from an x2APIC entry
+    #[allow(dead_code)]
+    pub x2apic: bool,
+}
+
+
+/// An IO APIC
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IoApic {
+    pub id: u8,
+
+    // Physical address of its registers
+    pub addr: u32,
+
+    // First GSI it serves
+    pub gsi_base: u32,
+}
+
+
+/// Polarity and trigger mode of an interrupt, the MPS INTI flags
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IntiFlags(pub u16);
+
+impl IntiFlags {
+    /// Whether the polarity is active low, `None` if the bus default applies
+    #[allow(dead_code)]
+    pub fn active_low(&self) -> Option<bool> {
+        match self.0 & 0b11 {
+            0b01 => Some(false),
+            0b11 => Some(true),
+            _ => None,
+        }
+    }
+
+    /// Whether it is level triggered, `None` if the bus default applies
+    #[allow(dead_code)]
+    pub fn level_triggered(&self) -> Option<bool> {
+        match (self.0 >> 2) & 0b11 {
+            0b01 => Some(false),
+            0b11 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+
+/// An ISA IRQ which is not identity mapped to a GSI or doesn't use the ISA
+/// polarity and trigger mode
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SourceOverride {
+    // Always 0, ISA
+    pub bus: u8,
+
+    // ISA IRQ
+    pub source: u8,
+
+    pub gsi: u32,
+    pub flags: IntiFlags,
+}
+
+
+/// A local APIC LINT pin wired to NMI
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Nmi {
+    // Processor UID, `ALL_PROCESSORS` for every processor
+    #[allow(dead_code)]
+    pub processor_uid: u32,
+
+    // LINT0 or LINT1
+    #[allow(dead_code)]
+    pub lint: u8,
+
+    #[allow(dead_code)]
+    pub flags: IntiFlags,
+}
+
+
+/// A GSI which is an NMI and must not be used for anything else
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NmiSource {
+    pub gsi: u32,
+    #[allow(dead_code)]
+    pub flags: IntiFlags,
+}
+
+
+/// What `parse_madt()` found
+#[derive(Clone, Copy, Debug)]
+pub struct Madt {
+    // Physical address of the local APICs, after any override
+    pub local_apic_addr: u64,
+
+    // `PCAT_COMPAT`
+    pub flags: u32,
+
+    pub cpus: List<LocalApic, MAX_CPUS>,
+    pub io_apics: List<IoApic, MAX_IO_APICS>,
+    pub overrides: List<SourceOverride, MAX_OVERRIDES>,
+    pub nmis: List<Nmi, MAX_NMIS>,
+    pub nmi_sources: List<NmiSource, MAX_NMIS>,
+}
+
+impl Madt {
+    /// Whether the legacy PICs are present and must be masked
+    #[allow(dead_code)]
+    pub fn has_pics(&self) -> bool {
+        self.flags & PCAT_COMPAT != 0
+    }
+
+    /// GSI and flags an ISA IRQ is delivered on, identity mapped with the
+    /// bus default flags unless overridden
+    #[allow(dead_code)]
+    pub fn isa_irq(&self, irq: u8) -> (u32, IntiFlags) {
+        self.overrides.as_slice().iter()
+            .find(|over| over.bus == 0 && over.source == irq)
+            .map_or((irq as u32, IntiFlags(0)), |over| (over.gsi, over.flags))
+    }
+
+    /// The IO APIC serving `gsi`, the one with the highest base at or below it
+    #[allow(dead_code)]
+    pub fn io_apic_for(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics.as_slice().iter()
+            .filter(|io| io.gsi_base <= gsi)
+            .max_by_key(|io| io.gsi_base)
+    }
+
+    /// Number of usable processors
+    pub fn enabled_cpus(&self) -> usize {
+        self.cpus.as_slice().iter().filter(|cpu| cpu.enabled).count()
+    }
+
+    fn add_cpu(&mut self, cpu: LocalApic) {
+        // Firmware may describe a processor with both kinds of entry
+        if self.cpus.as_slice().iter().any(|other| other.apic_id == cpu.apic_id) {
+            return;
+        }
+        if !self.cpus.push(cpu) {
+            warn!("MADT: more than {} processors, ignoring APIC ID {}", MAX_CPUS, cpu.apic_id);
+        }
+    }
+}
+
+
+/// Parse the MADT in `table`
+/// `Truncated` if it is too short to have the fixed fields, malformed
+/// entries end the parsing and keep what came before
+pub fn parse_madt(table: &[u8]) -> Result<Madt, AcpiError> {
+    let fields = Fields(table);
+    let mut madt = Madt {
+        local_apic_addr: fields.u32(LOCAL_APIC_ADDR).ok_or(AcpiError::Truncated)? as u64,
+        flags: fields.u32(FLAGS).ok_or(AcpiError::Truncated)?,
+        cpus: List::new(),
+        io_apics: List::new(),
+        overrides: List::new(),
+        nmis: List::new(),
+        nmi_sources: List::new(),
+    };
+
+    let mut off = ENTRIES;
+    while let (Some(typ), Some(len)) = (fields.u8(off), fields.u8(off + 1)) {
+        let len = len as usize;
+        if len < 2 || off + len > table.len() {
+            warn!("Malformed MADT entry at offset {}", off);
+            break;
+        }
+        let entry = Fields(&table[off..off + len]);
+
+        if parse_entry(&mut madt, typ, entry).is_none() {
+            warn!("MADT entry of type {} at offset {} is too short", typ, off);
+        }
+
+        off += len;
+    }
+
+    debug!(
+        "MADT: {} processors ({} enabled), {} IO APICs, {} overrides, {} NMIs, local APIC at {:#x}",
+        madt.cpus.as_slice().len(),
+        madt.enabled_cpus(),
+        madt.io_apics.as_slice().len(),
+        madt.overrides.as_slice().len(),
+        madt.nmis.as_slice().len() + madt.nmi_sources.as_slice().len(),
+        madt.local_apic_addr,
+    );
+    Ok(madt)
+}
+
+
+/// Add the interrupt controller structure `entry` of type `typ` to `madt`
+/// `None` if it is too short for its type
+fn parse_entry(madt: &mut Madt, typ: u8, entry: Fields) -> Option<()> {
+    match typ {
+        TYPE_LOCAL_APIC => {
+            let flags = entry.u32(4)?;
+            madt.add_cpu(LocalApic {
+                processor_uid: entry.u8(2)? as u32,
+                apic_id: entry.u8(3)? as u32,
+                enabled: flags & LAPIC_ENABLED != 0,
+                online_capable: flags & LAPIC_ONLINE_CAPABLE != 0,
+                x2apic: false,
+            });
+        },
+        TYPE_X2APIC => {
+            let flags = entry.u32(8)?;
+            madt.add_cpu(LocalApic {
+                processor_uid: entry.u32(12)?,
+                apic_id: entry.u32(4)?,
+                enabled: flags & LAPIC_ENABLED != 0,
+                online_capable: flags & LAPIC_ONLINE_CAPABLE != 0,
+                x2apic: true,
+            });
+        },
+        TYPE_IO_APIC => {
+            let io = IoApic { id: entry.u8(2)?, addr: entry.u32(4)?, gsi_base: entry.u32(8)? };
+            if !madt.io_apics.push(io) {
+                warn!("MADT: more than {} IO APICs, ignoring ID {}", MAX_IO_APICS, io.id);
+            }
+        },
+        TYPE_SOURCE_OVERRIDE => {
+            let over = SourceOverride {
+                bus: entry.u8(2)?,
+                source: entry.u8(3)?,
+                gsi: entry.u32(4)?,
+                flags: IntiFlags(entry.u16(8)?),
+            };
+            if !madt.overrides.push(over) {
+                warn!("MADT: more than {} interrupt source overrides", MAX_OVERRIDES);
+            }
+        },
+        TYPE_NMI_SOURCE => {
+            let source = NmiSource { gsi: entry.u32(4)?, flags: IntiFlags(entry.u16(2)?) };
+            if !madt.nmi_sources.push(source) {
+                warn!("MADT: more than {} NMI sources", MAX_NMIS);
+            }
+        },
+        TYPE_LOCAL_APIC_NMI => {
+            // A UID of 0xff means every processor
+            let uid = match entry.u8(2)? {
+                0xff => ALL_PROCESSORS,
+                uid => uid as u32,
+            };
+            let nmi = Nmi { processor_uid: uid, lint: entry.u8(5)?, flags: IntiFlags(entry.u16(3)?) };
+            if !madt.nmis.push(nmi) {
+                warn!("MADT: more than {} local APIC NMIs", MAX_NMIS);
+            }
+        },
+        TYPE_X2APIC_NMI => {
+            let nmi = Nmi {
+                processor_uid: entry.u32(4)?,
+                lint: entry.u8(8)?,
+                flags: IntiFlags(entry.u16(2)?),
+            };
+            if !madt.nmis.push(nmi) {
+                warn!("MADT: more than {} local APIC NMIs", MAX_NMIS);
+            }
+        },
+        TYPE_LOCAL_APIC_ADDRESS => {
+            madt.local_apic_addr = entry.u64(4)?;
+        },
+        _ => {},
+    }
+    Some(())
+}
@@ -0,0 +1,75 @@
+//! MCFG, the PCI Express memory mapped configuration space table
+//! Gives the base of the enhanced configuration access mechanism (ECAM)
+//! window of every PCI segment group and the buses it decodes. Each function
+//! gets 4 KiB of configuration space, at
+//! `base + (bus << 20 | device << 15 | function << 12)`: the base is where
+//! bus 0 would be even if the window starts at a later bus
+use super::{Fields, List, HEADER_SIZE};
+use crate::mm::PhysAddr;
+
+
+/// Offset of the first allocation structure, after the header and 8
+/// reserved bytes
+const ENTRIES: usize = HEADER_SIZE as usize + 8;
+
+/// Size of an allocation structure
+const ENTRY_SIZE: usize = 16;
+
+/// Maximum number of ECAM windows we keep
+pub const MAX_REGIONS: usize = 16;
+
+
+/// The ECAM window of a segment group
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EcamRegion {
+    // Address of the configuration space of bus 0, which is outside the
+    // window unless `start_bus` is 0
+    pub base: PhysAddr,
+
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl EcamRegion {
+    /// Address of the configuration space of `start_bus`, where the window
+    /// starts
+    pub fn start(&self) -> PhysAddr {
+        PhysAddr(self.base.0 + ((self.start_bus as u64) << 20))
+    }
+
+    /// Size of the window in bytes, 1 MiB per bus
+    pub fn size(&self) -> u64 {
+        (self.end_bus as u64 - self.start_bus as u64 + 1) << 20
+    }
+
+    /// Whether the window decodes `bus` of `segment`
+    pub fn contains(&self, segment: u16, bus: u8) -> bool {
+        self.segment == segment && (self.start_bus..=self.end_bus).contains(&bus)
+    }
+}
+
+
+/// Parse the MCFG in `table`
+/// Entries with an inverted bus range are skipped
+pub fn parse_mcfg(table: &[u8]) -> List<EcamRegion, MAX_REGIONS> {
+    let mut regions = List::new();
+
+    for entry in table.get(ENTRIES..).unwrap_or(&[]).chunks_exact(ENTRY_SIZE) {
+        let entry = Fields(entry);
+        let (Some(base), Some(segment), Some(start_bus), Some(end_bus)) =
+            (entry.u64(0), entry.u16(8), entry.u8(10), entry.u8(11)) else { continue };
+
+        if end_bus < start_bus {
+            warn!("MCFG: segment {} has bus range {}-{}, skipping", segment, start_bus, end_bus);
+            continue;
+        }
+
+        let region = EcamRegion { base: PhysAddr(base), segment, start_bus, end_bus };
+        if !regions.push(region) {
+            warn!("MCFG: more than {} ECAM windows, ignoring segment {}", MAX_REGIONS, segment);
+        }
+    }
+
+    regions
+}
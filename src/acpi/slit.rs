@@ -0,0 +1,32 @@
+//! SLIT, the System Locality Information Table
+//! A matrix of the relative distances between the proximity domains (the
+//! NUMA nodes), row `a` column `b` being the distance from `a` to `b`. 10
+//! means local, 255 unreachable
+use super::{AcpiError, Fields, HEADER_SIZE};
+use crate::mm::numa;
+
+
+/// Offset of the number of localities
+const LOCALITIES: usize = HEADER_SIZE as usize;
+
+/// Offset of the matrix
+const MATRIX: usize = LOCALITIES + 8;
+
+
+/// Parse the SLIT in `table` and register its distances
+/// `Truncated` if the matrix doesn't fit in the table
+pub fn parse_slit(table: &[u8]) -> Result<usize, AcpiError> {
+    let localities = Fields(table).u64(LOCALITIES).ok_or(AcpiError::Truncated)?;
+    let nodes = usize::try_from(localities).map_err(|_| AcpiError::Truncated)?;
+    let size = nodes.checked_mul(nodes).ok_or(AcpiError::Truncated)?;
+    let matrix = table.get(MATRIX..).and_then(|matrix| matrix.get(..size)).ok_or(AcpiError::Truncated)?;
+
+    for a in 0..nodes {
+        if matrix[a * nodes + a] != numa::LOCAL_DISTANCE {
+            warn!("SLIT: node {} is {} from itself", a, matrix[a * nodes + a]);
+        }
+    }
+
+    numa::register_distances(nodes, matrix);
+    Ok(nodes)
+}
@@ -0,0 +1,183 @@
+//! SRAT, the System Resource Affinity Table
+//! Assigns processors (by APIC ID) and memory ranges to proximity domains,
+//! which we use as NUMA nodes as they are. `register()` hands both to the
+//! allocator so allocations can prefer memory local to the processor
+//!
+//! Processors with APIC IDs above 254 only show up in x2APIC affinity
+//! (type 2) entries
+use super::{Fields, List, HEADER_SIZE};
+use crate::mm::{numa, Range};
+
+
+/// Offset of the first static resource allocation structure, after the
+/// header and 12 reserved bytes
+const ENTRIES: usize = HEADER_SIZE as usize + 12;
+
+/// Affinity flag: the entry is valid, disabled ones are to be ignored
+const AFFINITY_ENABLED: u32 = 1 << 0;
+
+/// Memory affinity flag: the range can be hot plugged
+const MEMORY_HOT_PLUGGABLE: u32 = 1 << 1;
+
+/// Memory affinity flag: the range is non-volatile
+const MEMORY_NON_VOLATILE: u32 = 1 << 2;
+
+/// Maximum number of processors we keep
+pub const MAX_CPUS: usize = 256;
+
+/// Maximum number of memory ranges we keep
+pub const MAX_MEMORY: usize = 64;
+
+// Static resource allocation structure types
+const TYPE_LOCAL_APIC: u8 = 0;
+const TYPE_MEMORY: u8 = 1;
+const TYPE_X2APIC: u8 = 2;
+
+
+/// The proximity domain of a processor
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuAffinity {
+    // Local APIC ID, the x2APIC ID for x2APIC entries
+    pub apic_id: u32,
+
+    pub domain: u32,
+}
+
+
+/// The proximity domain of a memory range
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryAffinity {
+    pub range: Range,
+    pub domain: u32,
+
+    // Whether the range can be hot plugged, it may not be populated yet
+    #[allow(dead_code)]
+    pub hot_pluggable: bool,
+
+    // Whether the range is non-volatile memory
+    #[allow(dead_code)]
+    pub non_volatile: bool,
+}
+
+impl Default for MemoryAffinity {
+    fn default() -> Self {
+        MemoryAffinity {
+            range: Range { start: 0, end: 0 },
+            domain: 0,
+            hot_pluggable: false,
+            non_volatile: false,
+        }
+    }
+}
+
+
+/// What `parse_srat()` found, enabled entries only
+#[derive(Clone, Copy, Debug)]
+pub struct Srat {
+    pub cpus: List<CpuAffinity, MAX_CPUS>,
+    pub memory: List<MemoryAffinity, MAX_MEMORY>,
+}
+
+impl Srat {
+    /// Proximity domain of the processor with `apic_id`
+    #[allow(dead_code)]
+    pub fn domain_of(&self, apic_id: u32) -> Option<u32> {
+        self.cpus.as_slice().iter().find(|cpu| cpu.apic_id == apic_id).map(|cpu| cpu.domain)
+    }
+
+    /// Fill in the APIC ID to domain table and tag the memory ranges with
+    /// their domains, for NUMA aware allocation
+    pub fn register(&self) {
+        for cpu in self.cpus.as_slice() {
+            numa::set_cpu_node(cpu.apic_id, cpu.domain);
+        }
+
+        let memory = self.memory.as_slice();
+        let mut ranges = [(Range { start: 0, end: 0 }, 0); MAX_MEMORY];
+        for (slot, memory) in ranges.iter_mut().zip(memory) {
+            *slot = (memory.range, memory.domain);
+        }
+        numa::register_numa_nodes(&ranges[..memory.len()]);
+    }
+}
+
+
+/// Parse the SRAT in `table`
+/// Malformed entries end the parsing and keep what came before
+pub fn parse_srat(table: &[u8]) -> Srat {
+    let fields = Fields(table);
+    let mut srat = Srat { cpus: List::new(), memory: List::new() };
+
+    let mut off = ENTRIES;
+    while let (Some(typ), Some(len)) = (fields.u8(off), fields.u8(off + 1)) {
+        let len = len as usize;
+        if len < 2 || off + len > table.len() {
+            warn!("Malformed SRAT entry at offset {}", off);
+            break;
+        }
+        let entry = Fields(&table[off..off + len]);
+
+        if parse_entry(&mut srat, typ, entry).is_none() {
+            warn!("SRAT entry of type {} at offset {} is too short", typ, off);
+        }
+
+        off += len;
+    }
+
+    debug!("SRAT: {} processors and {} memory ranges with a proximity domain",
+        srat.cpus.as_slice().len(), srat.memory.as_slice().len());
+    srat
+}
+
+
+/// Add the static resource allocation structure `entry` of type `typ` to
+/// `srat` if it is enabled
+/// `None` if it is too short for its type
+fn parse_entry(srat: &mut Srat, typ: u8, entry: Fields) -> Option<()> {
+    match typ {
+        TYPE_LOCAL_APIC => {
+            if entry.u32(4)? & AFFINITY_ENABLED == 0 {
+                return Some(());
+            }
+            // The domain is split, bits 7:0 at offset 2 and 31:8 at offset 9
+            let high = [entry.u8(9)?, entry.u8(10)?, entry.u8(11)?];
+            let domain = u32::from_le_bytes([entry.u8(2)?, high[0], high[1], high[2]]);
+            add_cpu(srat, CpuAffinity { apic_id: entry.u8(3)? as u32, domain });
+        },
+        TYPE_X2APIC => {
+            if entry.u32(12)? & AFFINITY_ENABLED == 0 {
+                return Some(());
+            }
+            add_cpu(srat, CpuAffinity { apic_id: entry.u32(8)?, domain: entry.u32(4)? });
+        },
+        TYPE_MEMORY => {
+            let flags = entry.u32(28)?;
+            if flags & AFFINITY_ENABLED == 0 {
+                return Some(());
+            }
+            // Empty ranges describe nothing
+            let range = match Range::new(entry.u64(8)?, entry.u64(16)?) {
+                Some(range) => range,
+                None => return Some(()),
+            };
+            let memory = MemoryAffinity {
+                range,
+                domain: entry.u32(2)?,
+                hot_pluggable: flags & MEMORY_HOT_PLUGGABLE != 0,
+                non_volatile: flags & MEMORY_NON_VOLATILE != 0,
+            };
+            if !srat.memory.push(memory) {
+                warn!("SRAT: more than {} memory ranges, ignoring {:#x}", MAX_MEMORY, range.start);
+            }
+        },
+        _ => {},
+    }
+    Some(())
+}
+
+
+fn add_cpu(srat: &mut Srat, cpu: CpuAffinity) {
+    if !srat.cpus.push(cpu) {
+        warn!("SRAT: more than {} processors, ignoring APIC ID {}", MAX_CPUS, cpu.apic_id);
+    }
+}
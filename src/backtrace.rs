@@ -0,0 +1,106 @@
+//! Stack backtraces by walking the frame pointer chain
+//! The kernel is built with frame pointers, so every frame starts with the
+//! caller's RBP followed by the return address:
+//!
+//! ```text
+//! rbp + 8 -> return address
+//! rbp     -> caller's rbp
+//! ```
+//!
+//! Frames are only followed while they stay within the stack we were started
+//! on, so a corrupt chain ends the backtrace instead of faulting
+use core::sync::atomic::{AtomicU64, Ordering};
+
+
+/// Maximum number of frames printed
+const MAX_FRAMES: usize = 32;
+
+/// How far above RSP frames are followed before `init()` has run
+const DEFAULT_STACK_SPAN: u64 = 64 * 1024;
+
+
+/// Frame pointer of the outermost frame which is ours, `efi_main()` and
+/// then `kernel_main()` once we switched stacks
+/// 0 until `init()` has run
+static STACK_TOP: AtomicU64 = AtomicU64::new(0);
+
+
+/// Remember the current frame as the top of the kernel stack
+/// Must be called from the outermost function on the stack itself, frames
+/// above it belong to the firmware (or the stack we left) and aren't walked
+#[inline(always)]
+pub fn init() {
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    STACK_TOP.store(rbp, Ordering::Relaxed);
+}
+
+
+/// Call `f` with the return address of every frame, innermost first, starting
+/// at the frame `rbp` of a stack currently at `rsp`
+pub fn walk(rbp: u64, rsp: u64, mut f: impl FnMut(u64)) {
+    let top = match STACK_TOP.load(Ordering::Relaxed) {
+        0 => rsp.saturating_add(DEFAULT_STACK_SPAN),
+        top => top,
+    };
+
+    let mut frame = rbp;
+    for _ in 0..MAX_FRAMES {
+        // The frame must be on our stack, aligned and leave room for the
+        // return address
+        if frame < rsp || frame > top || !frame.is_multiple_of(8) {
+            break;
+        }
+
+        let (next, ret) = unsafe {
+            let frame = frame as *const u64;
+            (*frame, *frame.add(1))
+        };
+
+        if ret == 0 {
+            break;
+        }
+        f(ret);
+
+        // Frames get older going up the stack, anything else means the chain
+        // is corrupt (or we reached the top)
+        if next <= frame {
+            break;
+        }
+        frame = next;
+    }
+}
+
+
+/// Print a backtrace starting at the frame `rbp` of a stack at `rsp`
+/// Addresses are symbolized if the image has a symbol table
+pub fn print(rbp: u64, rsp: u64) {
+    let mut depth = 0;
+    walk(rbp, rsp, |addr| {
+        // Return addresses point past the call, which may be the start of the
+        // next function if the call was the last instruction
+        match crate::symbols::resolve(addr - 1) {
+            Some((name, offset)) => eprintln!("  #{:<2} {:016x} {}+{:#x}", depth, addr, name, offset + 1),
+            None => eprintln!("  #{:<2} {:016x}", depth, addr),
+        }
+        depth += 1;
+    });
+
+    if depth == 0 {
+        eprintln!("  <no frames>");
+    }
+}
+
+
+/// Print a backtrace of the caller
+#[inline(always)]
+pub fn print_current() {
+    let (rbp, rsp): (u64, u64);
+    unsafe {
+        core::arch::asm!("mov {}, rbp", "mov {}, rsp", out(reg) rbp, out(reg) rsp,
+            options(nomem, nostack, preserves_flags));
+    }
+    print(rbp, rsp);
+}
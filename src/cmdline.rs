@@ -0,0 +1,91 @@
+//! Kernel command line
+//! The command line comes from the load options of our image, which is what
+//! the UEFI shell or a boot entry passes to us. It is a list of whitespace
+//! separated `key=value` options and bare flags, e.g. `log=debug nokaslr`
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::efi::{loaded_image, EFI_HANDLE};
+
+
+/// Maximum length of the command line we keep, the rest is dropped
+const MAX_CMDLINE: usize = 1024;
+
+
+/// The command line converted to ASCII
+/// Written once by `init()` and only read afterwards
+struct Cmdline {
+    initialized: AtomicBool,
+    len: AtomicUsize,
+    buf: UnsafeCell<[u8; MAX_CMDLINE]>,
+}
+
+unsafe impl Sync for Cmdline {}
+
+static CMDLINE: Cmdline = Cmdline {
+    initialized: AtomicBool::new(false),
+    len: AtomicUsize::new(0),
+    buf: UnsafeCell::new([0u8; MAX_CMDLINE]),
+};
+
+
+/// Read the command line from the load options of `image`
+pub fn init(image: EFI_HANDLE) {
+    // Only ever fill the buffer once
+    if CMDLINE.initialized.swap(true, Ordering::SeqCst) {return;}
+
+    let options = match loaded_image::get(image) {
+        Some(loaded_image) => loaded_image.load_options,
+        None => return,
+    };
+
+    let buf = unsafe { &mut *CMDLINE.buf.get() };
+    let mut len = 0;
+
+    // Load options are UCS-2, anything outside ASCII is replaced as we
+    // only ever match against ASCII option names
+    for pair in options.chunks_exact(2) {
+        let chr = u16::from_le_bytes([pair[0], pair[1]]);
+        if chr == 0 || len == buf.len() {break;}
+
+        buf[len] = match chr {
+            0x20..=0x7e => chr as u8,
+            0x09 | 0x0a | 0x0d => b' ',
+            _ => b'?',
+        };
+        len += 1;
+    }
+
+    CMDLINE.len.store(len, Ordering::SeqCst);
+}
+
+
+/// The whole command line
+pub fn as_str() -> &'static str {
+    let len = CMDLINE.len.load(Ordering::SeqCst);
+    let buf = unsafe { &*CMDLINE.buf.get() };
+
+    // Only printable ASCII is ever stored
+    core::str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+
+/// Iterate over the options on the command line
+pub fn options() -> impl Iterator<Item = &'static str> {
+    as_str().split_ascii_whitespace()
+}
+
+
+/// Get the value of the last `key=value` option with the given key
+pub fn get(key: &str) -> Option<&'static str> {
+    options()
+        .filter_map(|option| option.split_once('='))
+        .filter(|(name, _)| *name == key)
+        .map(|(_, value)| value)
+        .last()
+}
+
+
+/// Check whether the bare flag `name` is present
+pub fn flag(name: &str) -> bool {
+    options().any(|option| option == name)
+}
@@ -0,0 +1,235 @@
+//! Console output multiplexer
+//! Output sinks (UEFI text output, serial ports, ...) are registered here and
+//! `print!`/`eprint!` output is fanned out to all of them
+//!
+//! Sinks which depend on boot services are dropped when we exit boot services
+//! and the UART takes over so output keeps working across the transition.
+//! Until the first sink is registered output goes to the `early` outputs
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::early::Early;
+use crate::print::Access;
+use crate::sync::{InIrqContext, SpinLock};
+use crate::uart::{Uart, COM1};
+
+
+/// Maximum number of sinks registered at once
+const MAX_SINKS: usize = 8;
+
+
+/// Colors output can be highlighted with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Color {
+    Red,
+    Yellow,
+    Cyan,
+    Gray,
+    DarkGray,
+}
+
+
+/// Something console output can be written to
+pub trait Sink: Sync {
+    /// Short name of the sink
+    fn name(&self) -> &str;
+
+    /// Write normal output
+    fn write_str(&self, string: &str);
+
+    /// Write error output, by default the same as normal output
+    fn write_err(&self, string: &str) {
+        self.write_str(string);
+    }
+
+    /// Whether the sink stops working once boot services are exited
+    fn needs_boot_services(&self) -> bool {
+        false
+    }
+
+    /// Whether the sink is a screen someone is looking at, as opposed to a
+    /// log stream such as a serial port
+    /// Interactive sinks can be redrawn in place, e.g. for progress bars
+    fn interactive(&self) -> bool {
+        false
+    }
+
+    /// Switch the color of further output, `None` goes back to the default
+    /// Sinks which can't do colors ignore this
+    fn set_color(&self, _color: Option<Color>) {}
+}
+
+
+/// The registered sinks
+/// Sinks are copied out before writing to them so a sink which prints (or
+/// panics) while writing can't deadlock the console
+static SINKS: SpinLock<[Option<&'static dyn Sink>; MAX_SINKS]> = SpinLock::new([None; MAX_SINKS]);
+
+/// Set when output must bypass the registered sinks, see `force_early()`
+static EARLY_ONLY: AtomicBool = AtomicBool::new(false);
+
+
+/// Add `sink` to the console
+/// Returns false if all sink slots are in use
+pub fn register(sink: &'static dyn Sink) -> bool {
+    match SINKS.lock().iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(sink);
+            true
+        },
+        None => false,
+    }
+}
+
+
+/// Remove the sink named `name` from the console
+/// Returns false if there was no such sink
+#[allow(dead_code)]
+pub fn unregister(name: &str) -> bool {
+    match SINKS.lock().iter_mut().find(|slot| matches!(slot, Some(sink) if sink.name() == name)) {
+        Some(slot) => {
+            *slot = None;
+            true
+        },
+        None => false,
+    }
+}
+
+
+/// Run `f` on every sink
+/// Without any sinks, or once they can't be trusted any more, output goes to
+/// the early outputs instead so it is never lost
+fn each_sink(mut f: impl FnMut(&'static dyn Sink)) {
+    if !EARLY_ONLY.load(Ordering::Relaxed) {
+        let sinks = *SINKS.lock();
+        if sinks.iter().any(Option::is_some) {
+            for sink in sinks.iter().flatten() {
+                f(*sink);
+            }
+            return;
+        }
+    }
+
+    f(&Early);
+}
+
+
+/// Send all further output to the early outputs only
+/// For when the sinks themselves are broken, e.g. one of them panicked
+pub fn force_early() {
+    EARLY_ONLY.store(true, Ordering::SeqCst);
+}
+
+
+/// Write normal output to every sink
+/// Taking the print lock keeps lines from different writers apart, unless we
+/// are panicking, and sinks must not be interrupted by a handler which prints
+pub fn write_str(string: &str, _access: &Access, _irq: &InIrqContext) {
+    each_sink(|sink| sink.write_str(string));
+}
+
+
+/// Write error output to every sink
+pub fn write_err(string: &str, _access: &Access, _irq: &InIrqContext) {
+    each_sink(|sink| sink.write_err(string));
+}
+
+
+/// Set the color of further output on every sink which supports it
+/// The print lock keeps anyone else's output from showing up in `color`
+pub fn set_color(color: Option<Color>, _access: &Access) {
+    each_sink(|sink| sink.set_color(color));
+}
+
+
+/// Call `f` with every registered sink
+#[allow(dead_code)]
+pub fn for_each(f: impl FnMut(&'static dyn Sink)) {
+    each_sink(f);
+}
+
+
+/// UEFI simple text output, using ConOut and StdErr
+struct EfiText;
+
+impl Sink for EfiText {
+    fn name(&self) -> &str {"efi-text"}
+
+    fn write_str(&self, string: &str) {
+        crate::efi::output_string(string);
+    }
+
+    fn write_err(&self, string: &str) {
+        crate::efi::stderr_string(string);
+    }
+
+    fn needs_boot_services(&self) -> bool {true}
+
+    fn interactive(&self) -> bool {true}
+
+    fn set_color(&self, color: Option<Color>) {
+        use crate::efi::*;
+
+        let foreground = match color {
+            Some(Color::Red) => EFI_LIGHTRED,
+            Some(Color::Yellow) => EFI_YELLOW,
+            Some(Color::Cyan) => EFI_CYAN,
+            Some(Color::Gray) | None => EFI_LIGHTGRAY,
+            Some(Color::DarkGray) => EFI_DARKGRAY,
+        };
+        set_attribute(foreground | EFI_BACKGROUND_BLACK);
+    }
+}
+
+
+/// Serial port through the UEFI Serial IO protocol
+struct EfiSerial;
+
+impl Sink for EfiSerial {
+    fn name(&self) -> &str {"efi-serial"}
+
+    fn write_str(&self, string: &str) {
+        crate::efi::serial::write_string(string);
+    }
+
+    fn needs_boot_services(&self) -> bool {true}
+}
+
+
+/// First serial port driven directly
+struct Com1(Uart);
+
+impl Sink for Com1 {
+    fn name(&self) -> &str {"com1"}
+
+    fn write_str(&self, string: &str) {
+        self.0.write_str(string);
+    }
+}
+
+static COM1_SINK: Com1 = Com1(Uart::new(COM1));
+
+
+/// Register the firmware console sinks
+/// Must be called after the system table has been registered
+pub fn init() {
+    register(&EfiText);
+
+    // Mirror console output to the firmware serial port if there is one
+    if crate::efi::serial::init() {
+        register(&EfiSerial);
+    }
+}
+
+
+/// Drop all sinks which rely on boot services and switch over to driving
+/// the serial port ourselves
+/// Must be called right after boot services have been exited
+pub fn exit_boot_services() {
+    for slot in SINKS.lock().iter_mut() {
+        if matches!(slot, Some(sink) if sink.needs_boot_services()) {
+            *slot = None;
+        }
+    }
+
+    COM1_SINK.0.init();
+    register(&COM1_SINK);
+}
@@ -0,0 +1,40 @@
+//! Helpers for talking to the processor directly
+use cr::{Cr0, Cr4};
+
+pub mod cr;
+pub mod features;
+pub mod irq;
+pub mod msr;
+pub mod port;
+pub mod regs;
+
+
+/// Switch on the processor features the kernel relies on, on every core:
+/// write protection of read-only pages in ring 0, SSE, global pages and
+/// SMEP, SMAP and UMIP where the processor has them
+/// Must come after the kernel page tables are loaded, the firmware's may
+/// have user pages the kernel touches, which SMAP would fault on
+pub fn init() {
+    let cr0 = cr::cr0().remove(Cr0::EMULATION) | Cr0::MONITOR_COPROCESSOR | Cr0::NUMERIC_ERROR |
+        Cr0::WRITE_PROTECT;
+
+    let mut cr4 = cr::cr4();
+    let optional = [
+        (features::has_fxsr(), Cr4::OSFXSR | Cr4::OSXMMEXCPT),
+        (features::has_pge(), Cr4::GLOBAL_PAGES),
+        (features::has_smep(), Cr4::SMEP),
+        (features::has_smap(), Cr4::SMAP),
+        (features::has_umip(), Cr4::UMIP),
+    ];
+    for (supported, bits) in optional {
+        if supported {
+            cr4 = cr4 | bits;
+        }
+    }
+
+    unsafe {
+        cr::set_cr0(cr0);
+        cr::set_cr4(cr4);
+    }
+    debug!("CR0={:#x} CR4={:#x}", cr0.0, cr4.0);
+}
@@ -0,0 +1,196 @@
+//! Control registers and XCR0
+//! Reads are safe, writes change how the processor translates and protects
+//! memory and are up to the caller to get right
+//! See: Intel SDM Vol. 3A, 2.5 Control Registers
+use core::ops::BitOr;
+
+
+/// CR0 flags
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cr0(pub u64);
+
+impl Cr0 {
+    #[allow(dead_code)]
+    pub const PROTECTED_MODE: Cr0 = Cr0(1 << 0);
+    pub const MONITOR_COPROCESSOR: Cr0 = Cr0(1 << 1);
+    pub const EMULATION: Cr0 = Cr0(1 << 2);
+    #[allow(dead_code)]
+    pub const TASK_SWITCHED: Cr0 = Cr0(1 << 3);
+    pub const NUMERIC_ERROR: Cr0 = Cr0(1 << 5);
+    pub const WRITE_PROTECT: Cr0 = Cr0(1 << 16);
+    #[allow(dead_code)]
+    pub const ALIGNMENT_MASK: Cr0 = Cr0(1 << 18);
+    #[allow(dead_code)]
+    pub const NOT_WRITE_THROUGH: Cr0 = Cr0(1 << 29);
+    #[allow(dead_code)]
+    pub const CACHE_DISABLE: Cr0 = Cr0(1 << 30);
+    #[allow(dead_code)]
+    pub const PAGING: Cr0 = Cr0(1 << 31);
+
+    #[allow(dead_code)]
+    pub const fn contains(&self, other: Cr0) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn remove(&self, other: Cr0) -> Cr0 {
+        Cr0(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Cr0 {
+    type Output = Cr0;
+
+    fn bitor(self, other: Cr0) -> Cr0 {
+        Cr0(self.0 | other.0)
+    }
+}
+
+
+/// CR4 flags
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cr4(pub u64);
+
+impl Cr4 {
+    #[allow(dead_code)]
+    pub const PAE: Cr4 = Cr4(1 << 5);
+    #[allow(dead_code)]
+    pub const MACHINE_CHECK: Cr4 = Cr4(1 << 6);
+    pub const GLOBAL_PAGES: Cr4 = Cr4(1 << 7);
+    pub const OSFXSR: Cr4 = Cr4(1 << 9);
+    pub const OSXMMEXCPT: Cr4 = Cr4(1 << 10);
+    pub const UMIP: Cr4 = Cr4(1 << 11);
+    #[allow(dead_code)]
+    pub const FSGSBASE: Cr4 = Cr4(1 << 16);
+    #[allow(dead_code)]
+    pub const PCIDE: Cr4 = Cr4(1 << 17);
+    pub const OSXSAVE: Cr4 = Cr4(1 << 18);
+    pub const SMEP: Cr4 = Cr4(1 << 20);
+    pub const SMAP: Cr4 = Cr4(1 << 21);
+    #[allow(dead_code)]
+    pub const PKE: Cr4 = Cr4(1 << 22);
+
+    pub const fn contains(&self, other: Cr4) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    #[allow(dead_code)]
+    pub const fn remove(&self, other: Cr4) -> Cr4 {
+        Cr4(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Cr4 {
+    type Output = Cr4;
+
+    fn bitor(self, other: Cr4) -> Cr4 {
+        Cr4(self.0 | other.0)
+    }
+}
+
+
+/// XCR0 state components
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Xcr0(pub u64);
+
+impl Xcr0 {
+    #[allow(dead_code)]
+    pub const X87: Xcr0 = Xcr0(1 << 0);
+    pub const SSE: Xcr0 = Xcr0(1 << 1);
+    pub const AVX: Xcr0 = Xcr0(1 << 2);
+
+    pub const fn contains(&self, other: Xcr0) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Xcr0 {
+    type Output = Xcr0;
+
+    fn bitor(self, other: Xcr0) -> Xcr0 {
+        Xcr0(self.0 | other.0)
+    }
+}
+
+
+pub fn cr0() -> Cr0 {
+    let cr0: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+    }
+    Cr0(cr0)
+}
+
+
+/// Safety: see the SDM for what each bit changes, clearing `PAGING` or
+/// `PROTECTED_MODE` in long mode is a #GP
+pub unsafe fn set_cr0(cr0: Cr0) {
+    core::arch::asm!("mov cr0, {}", in(reg) cr0.0, options(nostack, preserves_flags));
+}
+
+
+/// Address of the last page fault
+pub fn cr2() -> u64 {
+    let cr2: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
+    }
+    cr2
+}
+
+
+/// Physical address of the PML4 and the PCID or cache bits
+pub fn cr3() -> u64 {
+    let cr3: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+    }
+    cr3
+}
+
+
+/// Switch to the page tables at `cr3`, which flushes the non-global TLB
+/// entries
+///
+/// Safety: the tables must map the running code, its stack and everything
+/// else in use
+pub unsafe fn set_cr3(cr3: u64) {
+    core::arch::asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+}
+
+
+pub fn cr4() -> Cr4 {
+    let cr4: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+    }
+    Cr4(cr4)
+}
+
+
+/// Safety: setting bits of features the processor lacks is a #GP, see the
+/// SDM for what each bit changes
+pub unsafe fn set_cr4(cr4: Cr4) {
+    core::arch::asm!("mov cr4, {}", in(reg) cr4.0, options(nostack, preserves_flags));
+}
+
+
+/// Enabled XSAVE state components, `None` until CR4.OSXSAVE is set
+pub fn xcr0() -> Option<Xcr0> {
+    if !cr4().contains(Cr4::OSXSAVE) {
+        return None;
+    }
+    let (lo, hi): (u32, u32);
+    unsafe {
+        core::arch::asm!("xgetbv", in("ecx") 0u32, out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags));
+    }
+    Some(Xcr0((hi as u64) << 32 | lo as u64))
+}
+
+
+/// Safety: CR4.OSXSAVE must be set and the components supported, `X87` is
+/// always required and `AVX` needs `SSE`
+#[allow(dead_code)]
+pub unsafe fn set_xcr0(xcr0: Xcr0) {
+    core::arch::asm!("xsetbv", in("ecx") 0u32, in("eax") xcr0.0 as u32, in("edx") (xcr0.0 >> 32) as u32,
+        options(nostack, preserves_flags));
+}
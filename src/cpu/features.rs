@@ -0,0 +1,287 @@
+//! CPUID feature detection
+//! The leaves we branch on are read once, by whoever asks first, and kept,
+//! CPUID is slow and traps to the hypervisor in a VM. All cores are assumed
+//! to have the same features as the boot processor
+//! See: Intel SDM Vol. 2A, CPUID
+use core::fmt;
+use crate::sync::LazyLock;
+
+
+/// Registers returned by a CPUID leaf
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Leaf {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+
+/// Leaves we keep
+#[derive(Clone, Copy)]
+struct Leaves {
+    // Highest basic and extended leaf
+    max: u32,
+    max_extended: u32,
+
+    vendor: [u8; 12],
+    brand: [u8; 48],
+
+    // Leaf 1, leaf 7 subleaf 0, leaves 0x80000001 and 0x80000007
+    basic: Leaf,
+    structured: Leaf,
+    extended: Leaf,
+    power: Leaf,
+}
+
+
+/// The leaves, read on first use
+static LEAVES: LazyLock<Leaves> = LazyLock::new(read_leaves);
+
+
+/// Run CPUID leaf `leaf`, subleaf `subleaf`, uncached
+pub fn cpuid(leaf: u32, subleaf: u32) -> Leaf {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        // `rbx` is reserved by LLVM so we have to save it ourselves
+        core::arch::asm!(
+            "mov {0:r}, rbx",
+            "cpuid",
+            "xchg {0:r}, rbx",
+            out(reg) ebx,
+            inout("eax") leaf => eax,
+            inout("ecx") subleaf => ecx,
+            out("edx") edx,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    Leaf { eax, ebx, ecx, edx }
+}
+
+
+/// Read the leaves we keep
+fn read_leaves() -> Leaves {
+    let zero = Leaf::default();
+    let vendor = cpuid(0, 0);
+    let max = vendor.eax;
+    let max_extended = cpuid(0x8000_0000, 0).eax;
+
+    let mut leaves = Leaves {
+        max,
+        max_extended,
+        vendor: [0; 12],
+        brand: [0; 48],
+        basic: if max >= 1 {cpuid(1, 0)} else {zero},
+        structured: if max >= 7 {cpuid(7, 0)} else {zero},
+        extended: if max_extended >= 0x8000_0001 {cpuid(0x8000_0001, 0)} else {zero},
+        power: if max_extended >= 0x8000_0007 {cpuid(0x8000_0007, 0)} else {zero},
+    };
+
+    // The vendor string is in EBX, EDX, ECX order
+    for (i, reg) in [vendor.ebx, vendor.edx, vendor.ecx].iter().enumerate() {
+        leaves.vendor[i * 4..i * 4 + 4].copy_from_slice(&reg.to_le_bytes());
+    }
+    if max_extended >= 0x8000_0004 {
+        for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+            let leaf = cpuid(leaf, 0);
+            for (j, reg) in [leaf.eax, leaf.ebx, leaf.ecx, leaf.edx].iter().enumerate() {
+                let off = i * 16 + j * 4;
+                leaves.brand[off..off + 4].copy_from_slice(&reg.to_le_bytes());
+            }
+        }
+    }
+    leaves
+}
+
+
+/// The cached leaves, read now if nobody asked before
+fn leaves() -> &'static Leaves {
+    &LEAVES
+}
+
+
+/// Highest basic leaf
+pub fn max_leaf() -> u32 {
+    leaves().max
+}
+
+
+/// Highest extended leaf
+#[allow(dead_code)]
+pub fn max_extended_leaf() -> u32 {
+    leaves().max_extended
+}
+
+
+/// Vendor string, e.g. "GenuineIntel"
+pub fn vendor() -> &'static str {
+    core::str::from_utf8(&leaves().vendor).unwrap_or("unknown")
+}
+
+
+/// Brand string, e.g. "Intel(R) Core(TM) i7-...", empty if there is none
+pub fn brand() -> &'static str {
+    let brand = &leaves().brand;
+    let len = brand.iter().position(|&c| c == 0).unwrap_or(brand.len());
+    core::str::from_utf8(&brand[..len]).unwrap_or("").trim()
+}
+
+
+/// Global page mappings, CPUID.01H:EDX.PGE[bit 13]
+pub fn has_pge() -> bool {
+    leaves().basic.edx & (1 << 13) != 0
+}
+
+/// FXSAVE and FXRSTOR, CPUID.01H:EDX.FXSR[bit 24]
+pub fn has_fxsr() -> bool {
+    leaves().basic.edx & (1 << 24) != 0
+}
+
+/// PCID, CPUID.01H:ECX.PCID[bit 17]
+pub fn has_pcid() -> bool {
+    leaves().basic.ecx & (1 << 17) != 0
+}
+
+/// x2APIC mode, CPUID.01H:ECX.x2APIC[bit 21]
+pub fn has_x2apic() -> bool {
+    leaves().basic.ecx & (1 << 21) != 0
+}
+
+/// TSC deadline mode of the local APIC timer, CPUID.01H:ECX[bit 24]
+pub fn has_tsc_deadline() -> bool {
+    leaves().basic.ecx & (1 << 24) != 0
+}
+
+/// XSAVE and XCR0, CPUID.01H:ECX.XSAVE[bit 26]
+pub fn has_xsave() -> bool {
+    leaves().basic.ecx & (1 << 26) != 0
+}
+
+/// AVX, CPUID.01H:ECX.AVX[bit 28]
+/// The OS still has to enable its state in XCR0 before it can be used
+pub fn has_avx() -> bool {
+    leaves().basic.ecx & (1 << 28) != 0
+}
+
+/// RDRAND, CPUID.01H:ECX.RDRAND[bit 30]
+pub fn has_rdrand() -> bool {
+    leaves().basic.ecx & (1 << 30) != 0
+}
+
+/// Running under a hypervisor, CPUID.01H:ECX[bit 31]
+pub fn has_hypervisor() -> bool {
+    leaves().basic.ecx & (1 << 31) != 0
+}
+
+/// AVX2, CPUID.(EAX=07H, ECX=0H):EBX.AVX2[bit 5]
+pub fn has_avx2() -> bool {
+    leaves().structured.ebx & (1 << 5) != 0
+}
+
+/// SMEP, CPUID.(EAX=07H, ECX=0H):EBX.SMEP[bit 7]
+pub fn has_smep() -> bool {
+    leaves().structured.ebx & (1 << 7) != 0
+}
+
+/// Enhanced REP MOVSB/STOSB, CPUID.(EAX=07H, ECX=0H):EBX.ERMS[bit 9]
+pub fn has_erms() -> bool {
+    leaves().structured.ebx & (1 << 9) != 0
+}
+
+/// RDSEED, CPUID.(EAX=07H, ECX=0H):EBX.RDSEED[bit 18]
+pub fn has_rdseed() -> bool {
+    leaves().structured.ebx & (1 << 18) != 0
+}
+
+/// SMAP, CPUID.(EAX=07H, ECX=0H):EBX.SMAP[bit 20]
+pub fn has_smap() -> bool {
+    leaves().structured.ebx & (1 << 20) != 0
+}
+
+/// CLFLUSHOPT, CPUID.(EAX=07H, ECX=0H):EBX.CLFLUSHOPT[bit 23]
+pub fn has_clflushopt() -> bool {
+    leaves().structured.ebx & (1 << 23) != 0
+}
+
+/// CLWB, CPUID.(EAX=07H, ECX=0H):EBX.CLWB[bit 24]
+pub fn has_clwb() -> bool {
+    leaves().structured.ebx & (1 << 24) != 0
+}
+
+/// UMIP, CPUID.(EAX=07H, ECX=0H):ECX.UMIP[bit 2]
+pub fn has_umip() -> bool {
+    leaves().structured.ecx & (1 << 2) != 0
+}
+
+/// No-execute pages, CPUID.80000001H:EDX.NX[bit 20]
+pub fn has_nx() -> bool {
+    leaves().extended.edx & (1 << 20) != 0
+}
+
+/// 1 GiB pages, CPUID.80000001H:EDX.Page1GB[bit 26]
+pub fn has_1gb_pages() -> bool {
+    leaves().extended.edx & (1 << 26) != 0
+}
+
+/// RDTSCP, CPUID.80000001H:EDX.RDTSCP[bit 27]
+pub fn has_rdtscp() -> bool {
+    leaves().extended.edx & (1 << 27) != 0
+}
+
+/// A TSC which runs at a constant rate in all power states,
+/// CPUID.80000007H:EDX[bit 8]
+pub fn has_invariant_tsc() -> bool {
+    leaves().power.edx & (1 << 8) != 0
+}
+
+
+/// A feature query such as `has_avx()`
+type Query = fn() -> bool;
+
+/// Names of the features `log()` lists, with their queries
+const SUMMARY: [(&str, Query); 20] = [
+    ("pge", has_pge),
+    ("fxsr", has_fxsr),
+    ("pcid", has_pcid),
+    ("x2apic", has_x2apic),
+    ("tsc-deadline", has_tsc_deadline),
+    ("xsave", has_xsave),
+    ("avx", has_avx),
+    ("avx2", has_avx2),
+    ("erms", has_erms),
+    ("rdrand", has_rdrand),
+    ("rdseed", has_rdseed),
+    ("smep", has_smep),
+    ("smap", has_smap),
+    ("umip", has_umip),
+    ("clflushopt", has_clflushopt),
+    ("clwb", has_clwb),
+    ("nx", has_nx),
+    ("1g-pages", has_1gb_pages),
+    ("rdtscp", has_rdtscp),
+    ("invariant-tsc", has_invariant_tsc),
+];
+
+
+/// The names of the features the processor has, space separated
+struct Present;
+
+impl fmt::Display for Present {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut first = true;
+        for (name, query) in SUMMARY.iter() {
+            if query() {
+                write!(f, "{}{}", if first {""} else {" "}, name)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Log the processor and the features it has
+pub fn log() {
+    info!("CPU: {} {}{}", vendor(), brand(), if has_hypervisor() {" (virtualized)"} else {""});
+    info!("CPU features: {}", Present);
+}
@@ -0,0 +1,55 @@
+//! Masking of maskable interrupts on the current processor
+
+/// Interrupt flag in RFLAGS
+const RFLAGS_IF: u64 = 1 << 9;
+
+
+/// Whether interrupts are enabled on this processor
+#[inline]
+pub fn enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        core::arch::asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+    }
+    rflags & RFLAGS_IF != 0
+}
+
+
+/// Disable interrupts on this processor
+#[inline]
+pub fn disable() {
+    unsafe {
+        core::arch::asm!("cli", options(nostack));
+    }
+}
+
+
+/// Disables interrupts on this processor while alive and puts the interrupt
+/// flag back the way it was when dropped, so guards can nest
+pub struct IrqGuard {
+    // Whether interrupts were enabled when the guard was created
+    was_enabled: bool,
+}
+
+impl IrqGuard {
+    /// Disable interrupts until the returned guard is dropped
+    #[inline]
+    pub fn new() -> Self {
+        let was_enabled = enabled();
+        unsafe {
+            core::arch::asm!("cli", options(nostack));
+        }
+        IrqGuard { was_enabled }
+    }
+}
+
+impl Drop for IrqGuard {
+    #[inline]
+    fn drop(&mut self) {
+        if self.was_enabled {
+            unsafe {
+                core::arch::asm!("sti", options(nostack));
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Model specific registers
+//! See: Intel SDM Vol. 4, Model-Specific Registers
+
+/// Local APIC base address and enable bits
+#[allow(dead_code)]
+pub const IA32_APIC_BASE: u32 = 0x1b;
+
+/// Page attribute table
+pub const IA32_PAT: u32 = 0x277;
+
+/// Extended feature enables
+pub const IA32_EFER: u32 = 0xc000_0080;
+
+/// `syscall` segment selectors
+#[allow(dead_code)]
+pub const IA32_STAR: u32 = 0xc000_0081;
+
+/// `syscall` entry point in long mode
+#[allow(dead_code)]
+pub const IA32_LSTAR: u32 = 0xc000_0082;
+
+/// RFLAGS bits `syscall` clears
+#[allow(dead_code)]
+pub const IA32_FMASK: u32 = 0xc000_0084;
+
+/// Base of the FS segment
+#[allow(dead_code)]
+pub const IA32_FS_BASE: u32 = 0xc000_0100;
+
+/// Base of the GS segment
+pub const IA32_GS_BASE: u32 = 0xc000_0101;
+
+/// GS base `swapgs` exchanges with `IA32_GS_BASE`
+#[allow(dead_code)]
+pub const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// Value `rdtscp` and `rdpid` return
+#[allow(dead_code)]
+pub const IA32_TSC_AUX: u32 = 0xc000_0103;
+
+// IA32_EFER bits
+#[allow(dead_code)]
+pub const EFER_SCE: u64 = 1 << 0;
+#[allow(dead_code)]
+pub const EFER_LME: u64 = 1 << 8;
+#[allow(dead_code)]
+pub const EFER_LMA: u64 = 1 << 10;
+pub const EFER_NXE: u64 = 1 << 11;
+
+// IA32_APIC_BASE bits
+#[allow(dead_code)]
+pub const APIC_BASE_BSP: u64 = 1 << 8;
+#[allow(dead_code)]
+pub const APIC_BASE_X2APIC: u64 = 1 << 10;
+#[allow(dead_code)]
+pub const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// Mask of the physical address of the local APIC in IA32_APIC_BASE
+#[allow(dead_code)]
+pub const APIC_BASE_ADDR: u64 = 0x000f_ffff_ffff_f000;
+
+
+/// Read MSR `msr`
+///
+/// Safety: the MSR must exist, reading one which doesn't is a #GP
+#[inline]
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (lo, hi): (u32, u32);
+    core::arch::asm!("rdmsr", in("ecx") msr, out("eax") lo, out("edx") hi, options(nomem, nostack, preserves_flags));
+    (hi as u64) << 32 | lo as u64
+}
+
+
+/// Write `value` to MSR `msr`
+///
+/// Safety: the MSR must exist and `value` must be valid for it, and changing
+/// it must not break anything relying on the old value
+#[inline]
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    core::arch::asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack, preserves_flags));
+}
+
+
+/// Set the `bits` in MSR `msr`
+///
+/// Safety: as for `wrmsr()`
+#[inline]
+pub unsafe fn set_bits(msr: u32, bits: u64) {
+    wrmsr(msr, rdmsr(msr) | bits);
+}
@@ -0,0 +1,45 @@
+//! x86 IO port access
+//! See: https://wiki.osdev.org/I/O_Ports
+
+/// Write a byte to IO port `port`
+#[inline]
+pub unsafe fn outb(port: u16, val: u8) {
+    core::arch::asm!("out dx, al", in("dx") port, in("al") val, options(nomem, nostack, preserves_flags));
+}
+
+/// Read a byte from IO port `port`
+#[inline]
+pub unsafe fn inb(port: u16) -> u8 {
+    let val: u8;
+    core::arch::asm!("in al, dx", in("dx") port, out("al") val, options(nomem, nostack, preserves_flags));
+    val
+}
+
+/// Write a 16-bit value to IO port `port`
+#[inline]
+pub unsafe fn outw(port: u16, val: u16) {
+    core::arch::asm!("out dx, ax", in("dx") port, in("ax") val, options(nomem, nostack, preserves_flags));
+}
+
+/// Read a 16-bit value from IO port `port`
+#[inline]
+#[allow(dead_code)]
+pub unsafe fn inw(port: u16) -> u16 {
+    let val: u16;
+    core::arch::asm!("in ax, dx", in("dx") port, out("ax") val, options(nomem, nostack, preserves_flags));
+    val
+}
+
+/// Write a 32-bit value to IO port `port`
+#[inline]
+pub unsafe fn outl(port: u16, val: u32) {
+    core::arch::asm!("out dx, eax", in("dx") port, in("eax") val, options(nomem, nostack, preserves_flags));
+}
+
+/// Read a 32-bit value from IO port `port`
+#[inline]
+pub unsafe fn inl(port: u16) -> u32 {
+    let val: u32;
+    core::arch::asm!("in eax, dx", in("dx") port, out("eax") val, options(nomem, nostack, preserves_flags));
+    val
+}
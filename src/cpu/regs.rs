@@ -0,0 +1,90 @@
+//! Snapshot of the processor registers, for crash reports
+use core::fmt;
+
+
+/// General purpose and a few control registers
+/// The layout is relied on by `capture()`
+#[derive(Default, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+
+    // Faulting address of the last page fault
+    pub cr2: u64,
+
+    // Physical address of the top level page table
+    pub cr3: u64,
+}
+
+impl Registers {
+    /// Capture the registers at the call site
+    /// `rdi` holds the address of the snapshot and `rax` is used as scratch once
+    /// it has been saved, everything else is as the caller left it
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut regs = Registers::default();
+
+        unsafe {
+            core::arch::asm!(
+                "mov [rdi + 0x00], rax",
+                "mov [rdi + 0x08], rbx",
+                "mov [rdi + 0x10], rcx",
+                "mov [rdi + 0x18], rdx",
+                "mov [rdi + 0x20], rsi",
+                "mov [rdi + 0x28], rdi",
+                "mov [rdi + 0x30], rbp",
+                "mov [rdi + 0x38], rsp",
+                "mov [rdi + 0x40], r8",
+                "mov [rdi + 0x48], r9",
+                "mov [rdi + 0x50], r10",
+                "mov [rdi + 0x58], r11",
+                "mov [rdi + 0x60], r12",
+                "mov [rdi + 0x68], r13",
+                "mov [rdi + 0x70], r14",
+                "mov [rdi + 0x78], r15",
+                "lea rax, [rip]",
+                "mov [rdi + 0x80], rax",
+                "pushfq",
+                "pop rax",
+                "mov [rdi + 0x88], rax",
+                "mov rax, cr2",
+                "mov [rdi + 0x90], rax",
+                "mov rax, cr3",
+                "mov [rdi + 0x98], rax",
+                in("rdi") &mut regs as *mut Registers,
+                out("rax") _,
+            );
+        }
+
+        regs
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RAX={:016x} RBX={:016x} RCX={:016x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "RDX={:016x} RSI={:016x} RDI={:016x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "RBP={:016x} RSP={:016x} R8 ={:016x}", self.rbp, self.rsp, self.r8)?;
+        writeln!(f, "R9 ={:016x} R10={:016x} R11={:016x}", self.r9, self.r10, self.r11)?;
+        writeln!(f, "R12={:016x} R13={:016x} R14={:016x}", self.r12, self.r13, self.r14)?;
+        writeln!(f, "R15={:016x} RIP={:016x} RFL={:016x}", self.r15, self.rip, self.rflags)?;
+        write!(f, "CR2={:016x} CR3={:016x}", self.cr2, self.cr3)
+    }
+}
@@ -0,0 +1,114 @@
+//! Panic reports which survive a reboot
+//! When we panic, the panic message, a backtrace and the last kernel log lines
+//! are written to a non-volatile UEFI variable. The next boot prints the report
+//! and deletes the variable, so a crash on a machine without a console
+//! attached can still be looked at afterwards
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::cpu::regs::Registers;
+use crate::efi::variable::{self, LAZARUS_VARIABLE};
+
+
+/// Name of the variable holding the report
+const VARIABLE_NAME: &str = "LazarusPanicReport";
+
+/// Maximum size of a report, kept small as firmware variable storage is
+const MAX_REPORT: usize = 4096;
+
+/// Number of kernel log lines included
+const DMESG_LINES: usize = 16;
+
+
+/// Set when the next panic is on purpose, which isn't worth a report
+static EXPECTED: AtomicBool = AtomicBool::new(false);
+
+
+/// Fixed size buffer the report is formatted into
+/// Anything which doesn't fit is dropped
+struct Report {
+    buf: [u8; MAX_REPORT],
+    len: usize,
+}
+
+impl Write for Report {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        for chr in string.chars() {
+            let len = chr.len_utf8();
+            if self.len + len > self.buf.len() {break;}
+            chr.encode_utf8(&mut self.buf[self.len..]);
+            self.len += len;
+        }
+        Ok(())
+    }
+}
+
+
+/// Mark the coming panic as intentional, `save()` then leaves the report
+/// variable alone instead of wearing out firmware storage
+pub fn expect_panic() {
+    EXPECTED.store(true, Ordering::SeqCst);
+}
+
+
+/// Save a report of the panic described by `info` to the report variable
+/// Returns false if the firmware wouldn't store it or the panic was expected
+pub fn save(info: &PanicInfo, regs: &Registers) -> bool {
+    if EXPECTED.load(Ordering::SeqCst) {return false;}
+
+    let mut report = Report { buf: [0u8; MAX_REPORT], len: 0 };
+
+    let uptime = crate::time::uptime_us();
+    let _ = writeln!(report, "[{:5}.{:06}] {}", uptime / 1_000_000, uptime % 1_000_000, info);
+    let _ = writeln!(report, "{}", regs);
+
+    let _ = writeln!(report, "backtrace:");
+    crate::backtrace::walk(regs.rbp, regs.rsp, |addr| {
+        let _ = match crate::symbols::resolve(addr - 1) {
+            Some((name, offset)) => writeln!(report, "  {:016x} {}+{:#x}", addr, name, offset + 1),
+            None => writeln!(report, "  {:016x}", addr),
+        };
+    });
+
+    let _ = writeln!(report, "dmesg:");
+    crate::dmesg::tail(DMESG_LINES, |seq, line| {
+        let _ = writeln!(report, "<{:5}> {}", seq, line);
+    });
+
+    variable::set_variable(
+        VARIABLE_NAME,
+        &LAZARUS_VARIABLE,
+        variable::EFI_VARIABLE_NON_VOLATILE |
+            variable::EFI_VARIABLE_BOOTSERVICE_ACCESS |
+            variable::EFI_VARIABLE_RUNTIME_ACCESS,
+        &report.buf[..report.len],
+    ).is_ok()
+}
+
+
+/// Print and delete the report left behind by a panic in an earlier boot
+/// Returns true if there was one
+pub fn report_previous() -> bool {
+    let mut buf = [0u8; MAX_REPORT];
+
+    let len = match variable::get_variable(VARIABLE_NAME, &LAZARUS_VARIABLE, &mut buf) {
+        Ok(len) => len,
+        Err(_) => return false,
+    };
+
+    // Reports are only ever cut at character boundaries, but the variable
+    // could have been written by something else
+    let text = match core::str::from_utf8(&buf[..len]) {
+        Ok(text) => text,
+        Err(e) => core::str::from_utf8(&buf[..e.valid_up_to()]).unwrap_or(""),
+    };
+
+    warn!("The previous boot panicked, its report follows");
+    eprintln!("{}", text.trim_end());
+
+    if let Err(status) = variable::delete_variable(VARIABLE_NAME, &LAZARUS_VARIABLE) {
+        warn!("Could not delete the panic report: {:#x}", status.0);
+    }
+
+    true
+}
@@ -0,0 +1,59 @@
+//! CRC32 as used by UEFI tables, GPT and zlib/gzip
+//! This is the reflected IEEE 802.3 polynomial 0xEDB88320
+//! See: https://en.wikipedia.org/wiki/Cyclic_redundancy_check
+//! See: https://create.stephan-brumme.com/crc32/#bitwise
+
+/// Reflected polynomial
+const POLY: u32 = 0xedb8_8320;
+
+/// Lookup table with the CRC of every byte value, computed at compile time
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut ii = 0;
+    while ii < 256 {
+        let mut crc = ii as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[ii] = crc;
+        ii += 1;
+    }
+    table
+};
+
+
+/// Running CRC32 computation for data which is not available as a single slice
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    /// Start a new CRC computation
+    pub const fn new() -> Self {
+        Crc32 { state: 0xffff_ffff }
+    }
+
+    /// Feed `bytes` into the CRC
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            let index = ((self.state ^ *byte as u32) & 0xff) as usize;
+            self.state = (self.state >> 8) ^ TABLE[index];
+        }
+    }
+
+    /// Get the final CRC value
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+
+/// Compute the CRC32 of `bytes`
+#[allow(dead_code)]
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
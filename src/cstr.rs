@@ -0,0 +1,138 @@
+//! NUL-terminated strings from the firmware
+//! The firmware hands out C strings, both 8-bit (SMBIOS) and UCS-2 (the
+//! firmware vendor, variable names, device path text). Nothing guarantees
+//! they are terminated, so every helper here stops after a caller supplied
+//! maximum instead of running off into whatever follows
+use core::fmt;
+
+
+/// Length of the 8-bit string at `s`, not counting the terminating NUL, and
+/// at most `max`
+///
+/// Safety: `s` must be readable up to its NUL or for `max` bytes, whichever
+/// comes first
+#[allow(dead_code)]
+pub unsafe fn strnlen(s: *const u8, max: usize) -> usize {
+    let mut len = 0;
+    while len < max && *s.add(len) != 0 {
+        len += 1;
+    }
+    len
+}
+
+
+/// Length of the 8-bit string at `s`, not counting the terminating NUL
+///
+/// Safety: `s` must be NUL-terminated, prefer `strnlen()` for anything coming
+/// from the firmware
+#[allow(dead_code)]
+pub unsafe fn strlen(s: *const u8) -> usize {
+    strnlen(s, usize::MAX)
+}
+
+
+/// Length of the UCS-2 string at `s` in characters, not counting the
+/// terminating NUL, and at most `max`
+///
+/// Safety: as for `strnlen()`, in characters
+pub unsafe fn wcsnlen(s: *const u16, max: usize) -> usize {
+    let mut len = 0;
+    while len < max && s.add(len).read_unaligned() != 0 {
+        len += 1;
+    }
+    len
+}
+
+
+/// Length of the UCS-2 string at `s` in characters, not counting the
+/// terminating NUL
+///
+/// Safety: as for `strlen()`
+#[allow(dead_code)]
+pub unsafe fn wcslen(s: *const u16) -> usize {
+    wcsnlen(s, usize::MAX)
+}
+
+
+/// The 8-bit string at `s` as a slice without the NUL, cut off at `max`
+/// bytes, `None` for a null pointer
+///
+/// Safety: as for `strnlen()`, and the string must not change for `'a`
+#[allow(dead_code)]
+pub unsafe fn from_ptr<'a>(s: *const u8, max: usize) -> Option<&'a [u8]> {
+    if s.is_null() {
+        return None;
+    }
+    Some(core::slice::from_raw_parts(s, strnlen(s, max)))
+}
+
+
+/// The UCS-2 string at `s` as a slice without the NUL, cut off at `max`
+/// characters, `None` for a null or misaligned pointer
+///
+/// Safety: as for `wcsnlen()`, and the string must not change for `'a`
+pub unsafe fn from_ucs2_ptr<'a>(s: *const u16, max: usize) -> Option<&'a [u16]> {
+    if s.is_null() || !s.is_aligned() {
+        return None;
+    }
+    Some(core::slice::from_raw_parts(s, wcsnlen(s, max)))
+}
+
+
+/// The part of `buf` before the first NUL, all of it if there is none
+/// For strings in fixed size fields
+#[allow(dead_code)]
+pub fn until_nul(buf: &[u8]) -> &[u8] {
+    let len = buf.iter().position(|byte| *byte == 0).unwrap_or(buf.len());
+    &buf[..len]
+}
+
+
+/// Compare at most `n` bytes of two 8-bit strings like `strncmp()`, stopping
+/// at the first NUL
+#[allow(dead_code)]
+pub fn strncmp(s1: &[u8], s2: &[u8], n: usize) -> core::cmp::Ordering {
+    let s1 = until_nul(&s1[..core::cmp::min(n, s1.len())]);
+    let s2 = until_nul(&s2[..core::cmp::min(n, s2.len())]);
+    s1.cmp(s2)
+}
+
+
+/// Compare at most `n` characters of two UCS-2 strings like `wcsncmp()`,
+/// stopping at the first NUL
+#[allow(dead_code)]
+pub fn wcsncmp(s1: &[u16], s2: &[u16], n: usize) -> core::cmp::Ordering {
+    fn cut(s: &[u16], n: usize) -> &[u16] {
+        let s = &s[..core::cmp::min(n, s.len())];
+        &s[..s.iter().position(|chr| *chr == 0).unwrap_or(s.len())]
+    }
+    cut(s1, n).cmp(cut(s2, n))
+}
+
+
+/// Whether the UCS-2 string `wide` is `ascii`, ignoring ASCII case if
+/// `ignore_case` is set
+#[allow(dead_code)]
+pub fn ucs2_eq(wide: &[u16], ascii: &str, ignore_case: bool) -> bool {
+    wide.len() == ascii.len() && wide.iter().zip(ascii.bytes()).all(|(chr, byte)| {
+        match u8::try_from(*chr) {
+            Ok(chr) if ignore_case => chr.eq_ignore_ascii_case(&byte),
+            Ok(chr) => chr == byte,
+            Err(_) => false,
+        }
+    })
+}
+
+
+/// Displays a UCS-2 string, with anything that isn't valid UTF-16 replaced
+/// by U+FFFD
+pub struct Ucs2<'a>(pub &'a [u16]);
+
+impl fmt::Display for Ucs2<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chr in char::decode_utf16(self.0.iter().copied()) {
+            fmt::Write::write_char(f, chr.unwrap_or(char::REPLACEMENT_CHARACTER))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+//! Device registry
+//! Drivers register their block and character devices here once, under a
+//! major/minor device number, and everything else (VFS, shell) discovers
+//! devices through this registry instead of driver specific functions
+use crate::sync::SpinLock;
+
+
+/// Well known major numbers
+/// Numbers follow Linux where there is an equivalent
+/// See: https://www.kernel.org/doc/Documentation/admin-guide/devices.txt
+pub mod major {
+    /// Memory devices such as null, zero and random
+    pub const MEM: u16 = 1;
+
+    /// Block devices exposed by the UEFI Block IO protocol
+    /// 240-254 are reserved for local/experimental use
+    pub const EFI_BLOCK: u16 = 240;
+
+    /// Persistent memory regions
+    pub const PMEM: u16 = 241;
+}
+
+/// Maximum number of devices of each kind
+const MAX_DEVICES: usize = 64;
+
+
+/// Device number made of a major number identifying the driver and
+/// a minor number identifying the device within the driver
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DevId {
+    pub major: u16,
+    pub minor: u16,
+}
+
+
+/// Errors returned by device operations and the registry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DevError {
+    // The registry has no free slot left
+    NoSpace,
+
+    // There is no device with the given number
+    NotFound,
+
+    // The request is malformed (bad alignment, size not a multiple of the block size...)
+    InvalidArgument,
+
+    // The device has no media present
+    NoMedia,
+
+    // The device does not support the operation
+    #[allow(dead_code)]
+    Unsupported,
+
+    // The device reported an error
+    Io,
+}
+
+
+/// Operations supported by block devices
+#[allow(dead_code)]
+pub trait BlockDevOps: Sync {
+    /// Short name of the device used in listings
+    fn name(&self) -> &str;
+
+    /// Size of a single block in bytes
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device
+    fn num_blocks(&self) -> u64;
+
+    /// Read `buf.len()` bytes worth of blocks starting at `lba`
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), DevError>;
+
+    /// Write `buf.len()` bytes worth of blocks starting at `lba`
+    fn write_blocks(&self, _lba: u64, _buf: &[u8]) -> Result<(), DevError> {
+        Err(DevError::Unsupported)
+    }
+}
+
+
+/// Operations supported by character devices
+#[allow(dead_code)]
+pub trait CharDevOps: Sync {
+    /// Short name of the device used in listings
+    fn name(&self) -> &str;
+
+    /// Read up to `buf.len()` bytes, returning the number of bytes read
+    fn read(&self, buf: &mut [u8]) -> Result<usize, DevError>;
+
+    /// Write up to `buf.len()` bytes, returning the number of bytes written
+    fn write(&self, buf: &[u8]) -> Result<usize, DevError>;
+}
+
+
+/// A fixed size table of registered devices
+struct Table<T: ?Sized + 'static> {
+    slots: SpinLock<[Option<(DevId, &'static T)>; MAX_DEVICES]>,
+}
+
+impl<T: ?Sized + 'static> Table<T> {
+    const fn new() -> Self {
+        Table {
+            slots: SpinLock::new([None; MAX_DEVICES]),
+        }
+    }
+
+    /// Run `f` with exclusive access to the slots
+    fn with<R>(&self, f: impl FnOnce(&mut [Option<(DevId, &'static T)>; MAX_DEVICES]) -> R) -> R {
+        f(&mut self.slots.lock())
+    }
+
+    /// Register `dev` under `major` with the first unused minor number
+    fn register(&self, major: u16, dev: &'static T) -> Result<DevId, DevError> {
+        self.with(|slots| {
+            // Find the first minor that isn't used yet for this major
+            let minor = (0..=u16::MAX).find(|minor| {
+                !slots.iter().flatten().any(|(id, _)| id.major == major && id.minor == *minor)
+            }).ok_or(DevError::NoSpace)?;
+
+            let slot = slots.iter_mut().find(|slot| slot.is_none()).ok_or(DevError::NoSpace)?;
+            let id = DevId { major, minor };
+            *slot = Some((id, dev));
+            Ok(id)
+        })
+    }
+
+    /// Remove the device registered as `id`
+    fn unregister(&self, id: DevId) -> Result<(), DevError> {
+        self.with(|slots| {
+            let slot = slots.iter_mut()
+                .find(|slot| matches!(slot, Some((dev_id, _)) if *dev_id == id))
+                .ok_or(DevError::NotFound)?;
+            *slot = None;
+            Ok(())
+        })
+    }
+
+    /// Look up the device registered as `id`
+    fn get(&self, id: DevId) -> Option<&'static T> {
+        self.with(|slots| {
+            slots.iter().flatten()
+                .find(|(dev_id, _)| *dev_id == id)
+                .map(|(_, dev)| *dev)
+        })
+    }
+
+    /// Get the `index`th registered device
+    /// Used to iterate without holding the spin flag across the caller's code
+    fn nth(&self, index: usize) -> Option<(DevId, &'static T)> {
+        self.with(|slots| slots.iter().flatten().nth(index).copied())
+    }
+}
+
+
+/// Registered block devices
+static BLOCK_DEVICES: Table<dyn BlockDevOps> = Table::new();
+
+/// Registered character devices
+static CHAR_DEVICES: Table<dyn CharDevOps> = Table::new();
+
+
+/// Register a block device under `major`
+/// Returns the device number assigned to it
+pub fn register_block(major: u16, dev: &'static dyn BlockDevOps) -> Result<DevId, DevError> {
+    BLOCK_DEVICES.register(major, dev)
+}
+
+/// Register a character device under `major`
+/// Returns the device number assigned to it
+pub fn register_char(major: u16, dev: &'static dyn CharDevOps) -> Result<DevId, DevError> {
+    CHAR_DEVICES.register(major, dev)
+}
+
+/// Remove a block device from the registry
+#[allow(dead_code)]
+pub fn unregister_block(id: DevId) -> Result<(), DevError> {
+    BLOCK_DEVICES.unregister(id)
+}
+
+/// Remove a character device from the registry
+#[allow(dead_code)]
+pub fn unregister_char(id: DevId) -> Result<(), DevError> {
+    CHAR_DEVICES.unregister(id)
+}
+
+/// Look up a block device by number
+#[allow(dead_code)]
+pub fn block(id: DevId) -> Option<&'static dyn BlockDevOps> {
+    BLOCK_DEVICES.get(id)
+}
+
+/// Look up a character device by number
+#[allow(dead_code)]
+pub fn char(id: DevId) -> Option<&'static dyn CharDevOps> {
+    CHAR_DEVICES.get(id)
+}
+
+/// Iterate over all registered block devices
+#[allow(dead_code)]
+pub fn block_devices() -> impl Iterator<Item = (DevId, &'static dyn BlockDevOps)> {
+    (0..).map_while(|index| BLOCK_DEVICES.nth(index))
+}
+
+/// Iterate over all registered character devices
+#[allow(dead_code)]
+pub fn char_devices() -> impl Iterator<Item = (DevId, &'static dyn CharDevOps)> {
+    (0..).map_while(|index| CHAR_DEVICES.nth(index))
+}
+
+
+/// `null` device: reads return end of file and writes are discarded
+struct Null;
+
+impl CharDevOps for Null {
+    fn name(&self) -> &str {"null"}
+
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, DevError> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, DevError> {
+        Ok(buf.len())
+    }
+}
+
+
+/// `zero` device: reads return zeroes and writes are discarded
+struct Zero;
+
+impl CharDevOps for Zero {
+    fn name(&self) -> &str {"zero"}
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, DevError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, DevError> {
+        Ok(buf.len())
+    }
+}
+
+
+/// `urandom` device: reads return bytes from the kernel entropy source
+struct Urandom;
+
+impl CharDevOps for Urandom {
+    fn name(&self) -> &str {"urandom"}
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, DevError> {
+        if !crate::entropy::fill_bytes(buf) {
+            return Err(DevError::Io);
+        }
+        Ok(buf.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, DevError> {
+        Ok(buf.len())
+    }
+}
+
+
+/// Register the memory character devices provided by the kernel itself
+pub fn init() {
+    let _ = register_char(major::MEM, &Null);
+    let _ = register_char(major::MEM, &Zero);
+    let _ = register_char(major::MEM, &Urandom);
+}
@@ -0,0 +1,142 @@
+//! Kernel log ring buffer
+//! Every log line is recorded here with a sequence number so the full boot log
+//! can be dumped later, including lines logged before any console existed
+//!
+//! The buffer is a fixed array of slots indexed by sequence number. Writers
+//! claim a sequence number with a single atomic increment and then fill their
+//! slot; each slot carries the sequence number it holds, which readers check
+//! before and after copying a line out so they never see a torn line. Old lines
+//! are overwritten once the buffer wraps.
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+
+/// Number of lines kept
+const SLOTS: usize = 512;
+
+/// Maximum length of a line in bytes, longer lines are truncated
+pub const LINE_LEN: usize = 118;
+
+/// Marks a slot which is being written or has never been written
+const EMPTY: u64 = u64::MAX;
+
+
+/// A single line in the ring buffer
+struct Slot {
+    // Sequence number of the line held, `EMPTY` while being written
+    seq: AtomicU64,
+
+    // Length of the line
+    len: AtomicUsize,
+
+    // The line itself
+    data: UnsafeCell<[u8; LINE_LEN]>,
+}
+
+/// The ring buffer
+struct Ring {
+    // Sequence number the next line will get
+    next: AtomicU64,
+
+    slots: [Slot; SLOTS],
+}
+
+// Slot data is only written by the writer which claimed its sequence number
+// and readers validate what they copied against the slot's sequence number
+unsafe impl Sync for Ring {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    seq: AtomicU64::new(EMPTY),
+    len: AtomicUsize::new(0),
+    data: UnsafeCell::new([0u8; LINE_LEN]),
+};
+
+static RING: Ring = Ring {
+    next: AtomicU64::new(0),
+    slots: [EMPTY_SLOT; SLOTS],
+};
+
+
+/// Record `line` in the ring buffer
+/// Returns the sequence number assigned to the line
+pub fn record(line: &str) -> u64 {
+    let seq = RING.next.fetch_add(1, Ordering::Relaxed);
+    let slot = &RING.slots[(seq % SLOTS as u64) as usize];
+
+    // Invalidate the slot while we fill it
+    slot.seq.store(EMPTY, Ordering::Relaxed);
+    core::sync::atomic::fence(Ordering::Release);
+
+    let len = core::cmp::min(line.len(), LINE_LEN);
+    unsafe {
+        core::ptr::copy_nonoverlapping(line.as_ptr(), (*slot.data.get()).as_mut_ptr(), len);
+    }
+    slot.len.store(len, Ordering::Relaxed);
+
+    // Publish the line
+    slot.seq.store(seq, Ordering::Release);
+    seq
+}
+
+
+/// Copy the line with sequence number `seq` into `buf`
+/// Returns the length of the line, or `None` if the line has been overwritten
+/// or is still being written
+fn read(seq: u64, buf: &mut [u8; LINE_LEN]) -> Option<usize> {
+    let slot = &RING.slots[(seq % SLOTS as u64) as usize];
+
+    if slot.seq.load(Ordering::Acquire) != seq {
+        return None;
+    }
+
+    let len = core::cmp::min(slot.len.load(Ordering::Relaxed), LINE_LEN);
+    unsafe {
+        core::ptr::copy_nonoverlapping((*slot.data.get()).as_ptr(), buf.as_mut_ptr(), len);
+    }
+
+    // Make sure the line wasn't replaced while we were copying it
+    core::sync::atomic::fence(Ordering::Acquire);
+    if slot.seq.load(Ordering::Relaxed) != seq {
+        return None;
+    }
+
+    Some(len)
+}
+
+
+/// Call `f` with the sequence number and text of the last `count` lines,
+/// oldest first
+/// Lines which are overwritten while we walk the buffer are skipped
+pub fn tail(count: usize, mut f: impl FnMut(u64, &str)) {
+    let next = RING.next.load(Ordering::Acquire);
+    let oldest = next.saturating_sub(core::cmp::min(count, SLOTS) as u64);
+
+    let mut buf = [0u8; LINE_LEN];
+    for seq in oldest..next {
+        if let Some(len) = read(seq, &mut buf) {
+            // Lines may have been truncated in the middle of a character
+            let line = match core::str::from_utf8(&buf[..len]) {
+                Ok(line) => line,
+                Err(e) => core::str::from_utf8(&buf[..e.valid_up_to()]).unwrap_or(""),
+            };
+            f(seq, line);
+        }
+    }
+}
+
+
+/// Call `f` with every line still in the buffer, oldest first
+#[allow(dead_code)]
+pub fn for_each(f: impl FnMut(u64, &str)) {
+    tail(SLOTS, f);
+}
+
+
+/// Print the whole kernel log
+#[allow(dead_code)]
+pub fn dump() {
+    for_each(|seq, line| {
+        println!("<{:5}> {}", seq, line);
+    });
+}
@@ -0,0 +1,48 @@
+//! Bare minimum output for when nothing else works
+//! Writes straight to the first serial port and, when running under QEMU or
+//! Bochs with a debug console, to the 0xE9 debug port. Neither needs any
+//! setup (the firmware has programmed the serial port already) or any state,
+//! so this works from the first instruction of `efi_main` and when the
+//! console itself is what broke
+//!
+//! The console falls back to this on its own, see `console::write_str()`
+use crate::console::Sink;
+use crate::cpu::port::{inb, outb};
+use crate::uart::{Uart, COM1};
+
+
+/// Debug console port of QEMU (`-debugcon`) and Bochs
+const DEBUGCON: u16 = 0xe9;
+
+
+/// Whether the 0xE9 debug console is there
+/// It reads back as 0xE9, real hardware usually floats to 0xFF
+fn debugcon_present() -> bool {
+    unsafe { inb(DEBUGCON) == DEBUGCON as u8 }
+}
+
+
+/// Write `string` to every early output
+pub fn write_str(string: &str) {
+    Uart::new(COM1).write_str(string);
+
+    if debugcon_present() {
+        for byte in string.bytes() {
+            unsafe {
+                outb(DEBUGCON, byte);
+            }
+        }
+    }
+}
+
+
+/// The early outputs as a console sink
+pub struct Early;
+
+impl Sink for Early {
+    fn name(&self) -> &str {"early"}
+
+    fn write_str(&self, string: &str) {
+        write_str(string);
+    }
+}
@@ -4,7 +4,18 @@
 #![allow(non_upper_case_globals)]
 #![allow(dead_code)]
 #![allow(non_snake_case)]
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use crate::crc32::Crc32;
+
+pub mod block;
+pub mod loaded_image;
+pub mod reset;
+pub mod rng;
+pub mod serial;
+pub mod tcg2;
+pub mod time;
+pub mod tpl;
+pub mod variable;
 
 
 /// Struct to store EFI_HANDLE
@@ -22,6 +33,75 @@ pub struct EFI_HANDLE(usize);
 #[repr(C)]
 pub struct EFI_STATUS(pub usize);
 
+/// Common status codes
+/// Error codes have the high bit set
+/// See(Appendix D): https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+impl EFI_STATUS {
+    pub const EFI_SUCCESS: EFI_STATUS = EFI_STATUS(0);
+    pub const EFI_INVALID_PARAMETER: EFI_STATUS = EFI_STATUS(EFI_ERROR_BIT | 2);
+    pub const EFI_UNSUPPORTED: EFI_STATUS = EFI_STATUS(EFI_ERROR_BIT | 3);
+    pub const EFI_BAD_BUFFER_SIZE: EFI_STATUS = EFI_STATUS(EFI_ERROR_BIT | 4);
+    pub const EFI_BUFFER_TOO_SMALL: EFI_STATUS = EFI_STATUS(EFI_ERROR_BIT | 5);
+    pub const EFI_DEVICE_ERROR: EFI_STATUS = EFI_STATUS(EFI_ERROR_BIT | 7);
+    pub const EFI_NO_MEDIA: EFI_STATUS = EFI_STATUS(EFI_ERROR_BIT | 12);
+    pub const EFI_NOT_FOUND: EFI_STATUS = EFI_STATUS(EFI_ERROR_BIT | 14);
+    pub const EFI_ABORTED: EFI_STATUS = EFI_STATUS(EFI_ERROR_BIT | 21);
+    pub const EFI_INCOMPATIBLE_VERSION: EFI_STATUS = EFI_STATUS(EFI_ERROR_BIT | 25);
+    pub const EFI_CRC_ERROR: EFI_STATUS = EFI_STATUS(EFI_ERROR_BIT | 27);
+    pub const EFI_COMPROMISED_DATA: EFI_STATUS = EFI_STATUS(EFI_ERROR_BIT | 33);
+
+    /// Returns true if the status code represents an error
+    pub fn is_error(&self) -> bool {
+        self.0 & EFI_ERROR_BIT != 0
+    }
+}
+
+/// High bit of an `EFI_STATUS` which marks it as an error code
+const EFI_ERROR_BIT: usize = 1 << (usize::BITS - 1);
+
+
+/// 128-bit buffer containing a unique identifier value
+/// Used to identify protocols and configuration tables
+/// See: https://dox.ipxe.org/structEFI__GUID.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct EFI_GUID {
+    Data1: u32,
+    Data2: u16,
+    Data3: u16,
+    Data4: [u8; 8],
+}
+
+impl EFI_GUID {
+    /// Build a GUID from its canonical components
+    pub const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        EFI_GUID { Data1: data1, Data2: data2, Data3: data3, Data4: data4 }
+    }
+}
+
+
+/// Search type used by `LocateHandle()`
+/// See Page 202: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+enum EFI_LOCATE_SEARCH_TYPE {
+    // Retrieve all the handles in the handle database
+    AllHandles,
+
+    // Retrieve the next handle from a RegisterProtocolNotify() event
+    ByRegisterNotify,
+
+    // Retrieve the set of handles from the handle database that support a specified protocol
+    ByProtocol,
+}
+
+
+/// Task priority level
+/// See Page 142: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(C)]
+pub struct EFI_TPL(pub usize);
+
 
 /// A scan code and unicode value for an input key press
 /// See: https://dox.ipxe.org/structEFI__INPUT__KEY.html
@@ -46,7 +126,7 @@ pub struct EFI_INPUT_KEY {
 /// Boot Services vs Runtime Services
 /// See: https://www.reddit.com/r/osdev/comments/gougq6/uefi_boot_services_vs_runtime_services/
 /// See: https://forum.osdev.org/viewtopic.php?f=1&t=40937
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(C)]
 pub enum EFI_MEMORY_TYPE {
     EfiReservedMemoryType,      // Not Used
@@ -101,12 +181,15 @@ impl EFI_MEMORY_TYPE {
     // Runtime services are still accessible while the operating system is running;
     // they include services such as date, time and NVRAM access.`
 
-    fn avail_post_exit_boot_services(&self) -> bool {
+    pub fn avail_post_exit_boot_services(&self) -> bool {
         match self{
             EFI_MEMORY_TYPE::EfiBootServicesCode |
             EFI_MEMORY_TYPE::EfiBootServicesData |
-            EFI_MEMORY_TYPE::EfiConventionalMemory |
-            EFI_MEMORY_TYPE::EfiPersistentMemory => true,
+            EFI_MEMORY_TYPE::EfiConventionalMemory => true,
+
+            // Persistent memory holds data which must survive, it is handled
+            // by `pmem` and never used as general purpose RAM
+            EFI_MEMORY_TYPE::EfiPersistentMemory => false,
 
             _ => false
         }
@@ -114,6 +197,20 @@ impl EFI_MEMORY_TYPE {
 }
 
 
+/// Signature of the EFI System Table ("IBI SYST")
+const EFI_SYSTEM_TABLE_SIGNATURE: u64 = 0x5453_5953_2049_4249;
+
+/// Signature of the EFI Boot Services Table ("BOOTSERV")
+const EFI_BOOT_SERVICES_SIGNATURE: u64 = 0x5652_4553_544f_4f42;
+
+/// Signature of the EFI Runtime Services Table ("RUNTSERV")
+const EFI_RUNTIME_SERVICES_SIGNATURE: u64 = 0x5652_4553_544e_5552;
+
+/// Upper bound on the size of a table we are willing to checksum
+/// The real tables are a few hundred bytes, anything bigger is garbage
+const MAX_TABLE_SIZE: usize = 4096;
+
+
 /// Data structure that preceeds all the standard EFI Table types
 /// See: https://dox.ipxe.org/structEFI__TABLE__HEADER.html
 #[repr(C)]
@@ -155,9 +252,9 @@ struct EFI_TABLE_HEADER{
 /// See: https://github.com/tianocore/edk2/blob/91a03f78ba0b75bc4ed2c4b756cbe57c685d9c72/MdePkg/Include/Uefi/UefiSpec.h#L127
 #[derive(Clone, Copy, Default, Debug)]
 #[repr(C)]
-struct EFI_MEMORY_DESCRIPTOR{
+pub struct EFI_MEMORY_DESCRIPTOR{
     // Type of the memory region.
-    Type: u32,
+    pub Type: u32,
 
     // Physical address of the first byte in the memory region
     // It must be aligned to a 4KiB boundary and must not be above 
@@ -165,7 +262,7 @@ struct EFI_MEMORY_DESCRIPTOR{
     // See: https://www.reddit.com/r/osdev/comments/u56t5c/help_with_understanding_uefi_memory_descriptor/
     // Why 4KiB?
     // See: https://www.reddit.com/r/osdev/comments/u56t5c/comment/i50kny8/?utm_source=share&utm_medium=web2x&context=3
-    PhysicalAddress: u64, // 64 bit address
+    pub PhysicalAddress: u64, // 64 bit address
 
     // Virtual address of the first byte in the memory region
     // It must be aligned to a 4KiB boundary and must not be above 
@@ -174,18 +271,18 @@ struct EFI_MEMORY_DESCRIPTOR{
     // See: https://www.reddit.com/r/osdev/comments/u56t5c/help_with_understanding_uefi_memory_descriptor/
     // Why 4KiB?
     // See: https://www.reddit.com/r/osdev/comments/u56t5c/comment/i50kny8/?utm_source=share&utm_medium=web2x&context=3
-    VirtualAddress: u64, // 64 bit address
+    pub VirtualAddress: u64, // 64 bit address
 
     // Number of 4KiB pages in the memory region. Number of pages cannot
     // Number of Pages must not be 0, and must not be any value
     // that would represent a memory page with a start address,
     // either physical or virtual, above 0xfffffffffffff000.
-    NumberOfPages: u64,
+    pub NumberOfPages: u64,
 
     // Attributes of the memory region that describe the bit mask of capabilities
     // for that memory region, and not necessarily the current settings for that
     // memory region.
-    Attribute: u64,
+    pub Attribute: u64,
 }
 
 
@@ -203,10 +300,11 @@ struct EFI_BOOT_SERVICES {
     // TASK PRIORITY SERVICES
 
     // Raise the task priority level
-    _RaiseTPL: usize,
+    // See Page 143: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+    RaiseTPL: unsafe extern "efiapi" fn(NewTpl: EFI_TPL) -> EFI_TPL,
 
     // Restores/Lowers the task priority level
-    _RestoreTPL: usize,
+    RestoreTPL: unsafe extern "efiapi" fn(OldTpl: EFI_TPL),
 
     // MEMORY SERVICES
 
@@ -218,7 +316,7 @@ struct EFI_BOOT_SERVICES {
 
     // Returns the current boot services memory map and memory map key
     // See Page 157: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
-    GetMemoryMap: unsafe fn(
+    GetMemoryMap: unsafe extern "efiapi" fn(
         MemoryMapSize: &mut usize,
         MemoryMap: *mut u8,
         MapKey: &mut usize,
@@ -227,10 +325,14 @@ struct EFI_BOOT_SERVICES {
     ) -> EFI_STATUS,
 
     // Allocates a pool of a particular type
-    _AllocatePool: usize,
-    
+    AllocatePool: unsafe extern "efiapi" fn(
+        PoolType: EFI_MEMORY_TYPE,
+        Size: usize,
+        Buffer: &mut *mut u8,
+    ) -> EFI_STATUS,
+
     // Free Allocate pool
-    _FreePool: usize,
+    FreePool: unsafe extern "efiapi" fn(Buffer: *mut u8) -> EFI_STATUS,
 
     // EVENT & TIMER SERVICES
 
@@ -264,7 +366,12 @@ struct EFI_BOOT_SERVICES {
     _UninstallProtocolInterface: usize,
 
     // Queries a handle to check if it supports a specific protocol
-    _HandleProtocol: usize,
+    // See Page 195: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+    HandleProtocol: unsafe extern "efiapi" fn(
+        Handle: EFI_HANDLE,
+        Protocol: *const EFI_GUID,
+        Interface: *mut *mut u8,
+    ) -> EFI_STATUS,
 
     // Reserved
     _Reserved: usize,
@@ -274,7 +381,14 @@ struct EFI_BOOT_SERVICES {
     _RegisterProtocolNotify: usize,
 
     // Returns an array of handles that support a specified protocol
-    _LocateHandle: usize,
+    // See Page 202: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+    LocateHandle: unsafe extern "efiapi" fn(
+        SearchType: EFI_LOCATE_SEARCH_TYPE,
+        Protocol: *const EFI_GUID,
+        SearchKey: *const u8,
+        BufferSize: &mut usize,
+        Buffer: *mut EFI_HANDLE,
+    ) -> EFI_STATUS,
 
     // Locate all devices on a device path that support a specified protocol and 
     // returns the handle to the device that is closest to the path
@@ -292,17 +406,119 @@ struct EFI_BOOT_SERVICES {
     _StartImage: usize,
 
     // Exits an image's entry point
-    _Exit: usize,
+    // See the Image Services chapter: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+    Exit: unsafe extern "efiapi" fn(
+        ImageHandle: EFI_HANDLE,
+        ExitStatus: EFI_STATUS,
+        ExitDataSize: usize,
+        ExitData: *const u16,
+    ) -> EFI_STATUS,
 
     // Unloads an image
     _UnloadImage: usize,
 
     // Terminate boot services 
     // See Page 222: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf 
-    ExitBootServices: unsafe fn(
+    ExitBootServices: unsafe extern "efiapi" fn(
         ImageHandle: EFI_HANDLE,
         MapKey: usize
     )-> EFI_STATUS,
+
+    // MISCELLANEOUS SERVICES
+
+    // Returns a monotonically increasing count for the platform
+    _GetNextMonotonicCount: usize,
+
+    // Stalls the processor for at least the given number of microseconds
+    // See Page 229: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+    Stall: unsafe extern "efiapi" fn(Microseconds: usize) -> EFI_STATUS,
+
+    // Resets and sets a watchdog timer used during boot services time
+    _SetWatchdogTimer: usize,
+}
+
+
+/// Contains a table header and pointers to all runtime services
+/// These remain usable after `ExitBootServices()`
+/// See: https://dox.ipxe.org/structEFI__RUNTIME__SERVICES.html
+#[repr(C)]
+struct EFI_RUNTIME_SERVICES {
+    // The table header for the EFI Runtime Services Table
+    Hdr: EFI_TABLE_HEADER,
+
+    // TIME SERVICES
+
+    // Returns the current time and date, and the time-keeping capabilities of the platform
+    _GetTime: usize,
+
+    // Sets the current local time and date information
+    _SetTime: usize,
+
+    // Returns the current wakeup alarm clock setting
+    _GetWakeupTime: usize,
+
+    // Sets the system wakeup alarm clock time
+    _SetWakeupTime: usize,
+
+    // VIRTUAL MEMORY SERVICES
+
+    // Changes the runtime addressing mode of EFI firmware from physical to virtual
+    _SetVirtualAddressMap: usize,
+
+    // Determines the new virtual address that is to be used on subsequent memory accesses
+    _ConvertPointer: usize,
+
+    // VARIABLE SERVICES
+
+    // Returns the value of a variable
+    // See Page 279: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+    GetVariable: unsafe extern "efiapi" fn(
+        VariableName: *const u16,
+        VendorGuid: *const EFI_GUID,
+        Attributes: *mut u32,
+        DataSize: &mut usize,
+        Data: *mut u8,
+    ) -> EFI_STATUS,
+
+    // Enumerates the current variable names
+    _GetNextVariableName: usize,
+
+    // Sets the value of a variable
+    // A `DataSize` of zero deletes the variable
+    SetVariable: unsafe extern "efiapi" fn(
+        VariableName: *const u16,
+        VendorGuid: *const EFI_GUID,
+        Attributes: u32,
+        DataSize: usize,
+        Data: *const u8,
+    ) -> EFI_STATUS,
+
+    // MISCELLANEOUS SERVICES
+
+    // Returns the next high 32 bits of the platform's monotonic counter
+    _GetNextHighMonotonicCount: usize,
+
+    // Resets the entire platform
+    // See the Miscellaneous Runtime Services chapter: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+    ResetSystem: unsafe extern "efiapi" fn(
+        ResetType: reset::EFI_RESET_TYPE,
+        ResetStatus: EFI_STATUS,
+        DataSize: usize,
+        ResetData: *const u8,
+    ),
+
+    // UEFI 2.0 CAPSULE SERVICES
+
+    // Passes capsules to the firmware with both virtual and physical mapping
+    _UpdateCapsule: usize,
+
+    // Returns if the capsule can be supported via UpdateCapsule()
+    _QueryCapsuleCapabilities: usize,
+
+    // MISCELLANEOUS UEFI 2.0 SERVICE
+
+    // Returns information about the EFI variables
+    _QueryVariableInfo: usize,
 }
 
 
@@ -314,13 +530,13 @@ struct EFI_BOOT_SERVICES {
 struct EFI_SIMPLE_TEXT_INPUT_PROTOCOL {
     // Reset Input Device hardware
     // See: https://dox.ipxe.org/SimpleTextIn_8h.html#adf982c71dcc0af2e4495044e66201b53
-    Reset: unsafe fn(
+    Reset: unsafe extern "efiapi" fn(
         This: *const EFI_SIMPLE_TEXT_INPUT_PROTOCOL,
         ExtendedVerification: bool) -> EFI_STATUS, 
 
     // Reads the next keystroke from input device
     // See: https://dox.ipxe.org/SimpleTextIn_8h.html#a09083a7dedf5d4f8fd1d437289869d39
-    ReadKeyStroke: unsafe fn(
+    ReadKeyStroke: unsafe extern "efiapi" fn(
         This: *const EFI_SIMPLE_TEXT_INPUT_PROTOCOL,
         Key: *mut EFI_INPUT_KEY,
     )-> EFI_STATUS,
@@ -331,25 +547,49 @@ struct EFI_SIMPLE_TEXT_INPUT_PROTOCOL {
 }
 
 
+/// Current mode and cursor state of a text output device
+/// See: https://dox.ipxe.org/structEFI__SIMPLE__TEXT__OUTPUT__MODE.html
+#[repr(C)]
+struct SIMPLE_TEXT_OUTPUT_MODE {
+    // Number of modes supported by QueryMode() and SetMode()
+    MaxMode: i32,
+
+    // The text mode of the output device
+    Mode: i32,
+
+    // The current character output attribute
+    Attribute: i32,
+
+    // The cursor's column
+    CursorColumn: i32,
+
+    // The cursor's row
+    CursorRow: i32,
+
+    // The cursor is currently visible or not
+    CursorVisible: bool,
+}
+
+
 /// This protocol is used to control Text Based output devices
 /// See page 470: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
 /// See: https://edk2-docs.gitbook.io/edk-ii-uefi-driver-writer-s-guide/22_text_console_driver_design_guidelines/readme.3
 #[repr(C)]
 struct EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL {
     // Resets the text output device hardware
-    Reset: unsafe fn(
-        This: *const EFI_SIMPLE_TEXT_INPUT_PROTOCOL,
+    Reset: unsafe extern "efiapi" fn(
+        This: *const EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
         ExtendedVerification: bool) -> EFI_STATUS,  
 
     // Write String to output device
     // See: https://dox.ipxe.org/SimpleTextOut_8h.html#afcf652d19afcb35e585089c15a51b115
-    OutputString: unsafe fn(
+    OutputString: unsafe extern "efiapi" fn(
         This: *const EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
         String: *const u16,
     )->EFI_STATUS,
 
     // Verfies that all the characters in the string can be output to the target device
-    TestString: unsafe fn(
+    TestString: unsafe extern "efiapi" fn(
         This: *const EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
         String: *const u16,
     )->EFI_STATUS,
@@ -363,7 +603,10 @@ struct EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL {
 
     // Set background and foreground colors for the OutputString()
     // and ClearScreen() functions
-    _SetAttribute: usize,
+    SetAttribute: unsafe extern "efiapi" fn(
+        This: *const EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
+        Attribute: usize,
+    )->EFI_STATUS,
 
     // Clears output device to display the currently selected background color
     _ClearScreen: usize,
@@ -375,7 +618,7 @@ struct EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL {
     _EnableCursor: usize,
 
     // Pointer to SIMPLE_TEXT_OUTPUT_MODE data
-    _Mode: usize,
+    Mode: *const SIMPLE_TEXT_OUTPUT_MODE,
 }
 
 /// Contains pointers to runtime and boot time service tables
@@ -421,10 +664,31 @@ pub struct EFI_SYSTEM_TABLE {
     StdErr: *const EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL,
 
     // A pointer to the EFI Runtime Service handle
-    _RuntimeServices: usize,
+    RuntimeServices: *const EFI_RUNTIME_SERVICES,
 
     // A pointer to the EFI Boot Service handle
     BootServices: *const EFI_BOOT_SERVICES,
+
+    // The number of system configuration tables in the buffer
+    // ConfigurationTable
+    NumberOfTableEntries: usize,
+
+    // A pointer to the system configuration tables
+    ConfigurationTable: *const EFI_CONFIGURATION_TABLE,
+}
+
+/// A vendor table in the system table, e.g. the ACPI RSDP or SMBIOS entry
+/// point, identified by its GUID
+/// See: https://dox.ipxe.org/structEFI__CONFIGURATION__TABLE.html
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct EFI_CONFIGURATION_TABLE {
+    // The 128-bit GUID value that uniquely identifies the system
+    // configuration table
+    pub VendorGuid: EFI_GUID,
+
+    // A pointer to the table associated with VendorGuid
+    pub VendorTable: usize,
 }
 
 /// Pointer to the EFI System Table which is saved upon the entry of the kernel
@@ -433,85 +697,369 @@ pub struct EFI_SYSTEM_TABLE {
 /// D3eclaring it global is the only way we can get access to the system table in a print macro
 static EfiSystemTable: AtomicPtr<EFI_SYSTEM_TABLE> = AtomicPtr::new(core::ptr::null_mut());
 
+/// Handle of our own image, needed to `Exit()` back to the firmware
+static EfiImageHandle: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once boot services have been exited, after which only runtime
+/// services may be used
+static BootServicesExited: AtomicBool = AtomicBool::new(false);
 
-/// Read More about UEFI System Table: https://edk2-docs.gitbook.io/edk-ii-uefi-driver-writer-s-guide/3_foundation/33_uefi_system_table
-/// EFI System Table: https://dox.ipxe.org/structEFI__SYSTEM__TABLE.html
-/// For Detailed Reading, See Chapter 4(Page: 93): https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+
+// Read More about UEFI System Table: https://edk2-docs.gitbook.io/edk-ii-uefi-driver-writer-s-guide/3_foundation/33_uefi_system_table
+// EFI System Table: https://dox.ipxe.org/structEFI__SYSTEM__TABLE.html
+// For Detailed Reading, See Chapter 4(Page: 93): https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
 
 
 /// Register a system table pointer.
 /// Only the first non-null system table pointer will be stored in the `EfiSystemTable` global
-pub unsafe fn register_system_table(system_table: *mut EFI_SYSTEM_TABLE){
+///
+/// The system table, boot services and runtime services headers are validated
+/// first and a corrupt table is refused
+pub unsafe fn register_system_table(system_table: *mut EFI_SYSTEM_TABLE) -> Result<(), EFI_STATUS>{
+    if system_table.is_null() {
+        return Err(EFI_STATUS::EFI_INVALID_PARAMETER);
+    }
+
+    validate_table(system_table as *const EFI_TABLE_HEADER, EFI_SYSTEM_TABLE_SIGNATURE)?;
+    validate_table(
+        (*system_table).BootServices as *const EFI_TABLE_HEADER,
+        EFI_BOOT_SERVICES_SIGNATURE,
+    )?;
+    validate_table(
+        (*system_table).RuntimeServices as *const EFI_TABLE_HEADER,
+        EFI_RUNTIME_SERVICES_SIGNATURE,
+    )?;
+
     // See: https://doc.rust-lang.org/std/sync/atomic/struct.AtomicPtr.html#method.compare_exchange
-    match EfiSystemTable.compare_exchange(
+    // A table registered earlier is kept
+    let _ = EfiSystemTable.compare_exchange(
         core::ptr::null_mut(),
         system_table,
         Ordering::SeqCst,    // See: https://doc.rust-lang.org/std/sync/atomic/enum.Ordering.html#variant.SeqCst
-        Ordering::SeqCst){
-        Err(_e) => {return ;},
-        _ => (),
-    };
+        Ordering::SeqCst);
+
+    Ok(())
 }
 
 
-/// Write a `string` to UEFI output
-pub fn output_string(string: &str){
+/// Address of the configuration table with `guid`, if the firmware installed
+/// one
+/// The configuration tables stay valid after boot services have been exited
+pub fn config_table(guid: &EFI_GUID) -> Option<u64> {
+    let system_table = EfiSystemTable.load(Ordering::SeqCst);
+    if system_table.is_null() {
+        return None;
+    }
+    unsafe {
+        let tables = (*system_table).ConfigurationTable;
+        if tables.is_null() {
+            return None;
+        }
+        core::slice::from_raw_parts(tables, (*system_table).NumberOfTableEntries)
+            .iter()
+            .find(|table| table.VendorGuid == *guid)
+            .map(|table| table.VendorTable as u64)
+    }
+}
+
+
+/// Longest firmware vendor string we read, in characters
+const MAX_VENDOR: usize = 256;
+
+
+/// Vendor of the system firmware and its revision, from the system table
+pub fn firmware_vendor() -> Option<(&'static [u16], u32)> {
+    let system_table = EfiSystemTable.load(Ordering::SeqCst);
+    if system_table.is_null() {
+        return None;
+    }
+    unsafe {
+        let vendor = crate::cstr::from_ucs2_ptr((*system_table).FirmwareVendor, MAX_VENDOR)?;
+        Some((vendor, (*system_table).FirmwareRevision))
+    }
+}
+
+
+/// Remember the handle of our image as passed to `efi_main()`
+pub fn register_image_handle(image_handle: EFI_HANDLE){
+    EfiImageHandle.store(image_handle.0, Ordering::SeqCst);
+}
+
+
+/// Whether boot services can still be used
+pub fn boot_services_active() -> bool{
+    !EfiSystemTable.load(Ordering::SeqCst).is_null() && !BootServicesExited.load(Ordering::SeqCst)
+}
+
+
+/// Record that boot services are gone
+/// Must be called right after a successful `ExitBootServices()`
+pub fn boot_services_exited(){
+    BootServicesExited.store(true, Ordering::SeqCst);
+}
+
+
+/// The memory map as returned by `GetMemoryMap()`
+/// Descriptors may be bigger than `EFI_MEMORY_DESCRIPTOR`, so they are walked
+/// using the descriptor size the firmware reported
+pub struct MemoryMap<'a> {
+    buf: &'a [u8],
+    descriptor_size: usize,
+}
+
+impl<'a> MemoryMap<'a> {
+    /// Iterate over the descriptors in the map
+    pub fn iter(&self) -> impl Iterator<Item = EFI_MEMORY_DESCRIPTOR> + '_ {
+        self.buf.chunks_exact(self.descriptor_size).map(|chunk| unsafe {
+            core::ptr::read_unaligned(chunk.as_ptr() as *const EFI_MEMORY_DESCRIPTOR)
+        })
+    }
+}
+
+
+/// Read the current memory map into `buf`
+/// Returns the size of the map, the size of a descriptor and the key
+/// identifying the map
+fn get_memory_map(buf: &mut [u8]) -> Result<(usize, usize, usize), EFI_STATUS> {
     // Get the system table
     let system_table = EfiSystemTable.load(Ordering::SeqCst);
 
     // Check if pointer is null
-    if system_table.is_null(){return ;}
+    if system_table.is_null() || !boot_services_active() {
+        return Err(EFI_STATUS::EFI_UNSUPPORTED);
+    }
+
+    let mut map_size = buf.len();
+    let mut map_key = 0;
+    let mut descriptor_size = 0;
+    let mut descriptor_version = 0;
 
-    // Get the console output_pointer
-    let console_std_out = unsafe {
-        (*system_table).ConOut
+    let ret = unsafe {
+        ((*(*system_table).BootServices).GetMemoryMap)(
+            &mut map_size,
+            buf.as_mut_ptr(),
+            &mut map_key,
+            &mut descriptor_size,
+            &mut descriptor_version,
+        )
     };
 
-    // Create a temporary buffer capable of holding 31 characters and a null
-    // UEFI uses UCS-2 encoding instead of UTF-16
-    let mut tmp = [0u16; 32];
-    let mut in_use = 0;
+    if ret.is_error() {
+        return Err(ret);
+    }
 
-    // Loop through all characters
-    for chr in string.encode_utf16(){
-        // Add CRLF
-        // CRLFs are required by serial consoles at times instead
-        if chr == b'\n' as u16{
-            tmp[in_use] = b'\r' as u16;
-            in_use += 1;
-        }
+    // Don't trust a descriptor size we can't read a descriptor out of
+    if descriptor_size < core::mem::size_of::<EFI_MEMORY_DESCRIPTOR>() || map_size > buf.len() {
+        return Err(EFI_STATUS::EFI_INCOMPATIBLE_VERSION);
+    }
 
-        // Write character into buffer
-        tmp[in_use] = chr;
-        in_use += 1;
-
-        // Note the -2 instead of the usual -1
-        // This is because of `\r\n`
-        if in_use == (tmp.len()-2){
-            // Null Terminate the buffer
-            tmp[in_use] = 0;
-
-            // Write output to buffer
-            // See: https://github.com/rust-osdev/uefi-rs/blob/dfca11c419a6b2d943ef02af4c7d6c7e3732a195/src/proto/console/text/output.rs#L46
-            unsafe {
-                ((*console_std_out)
-                    .OutputString)(console_std_out, tmp.as_ptr());
-            }
+    Ok((map_size, descriptor_size, map_key))
+}
 
-            // Clear the buffer
-            in_use = 0;
+
+/// Read the current memory map into `buf`
+pub fn memory_map(buf: &mut [u8]) -> Result<MemoryMap<'_>, EFI_STATUS> {
+    let (map_size, descriptor_size, _) = get_memory_map(buf)?;
+    Ok(MemoryMap { buf: &buf[..map_size], descriptor_size })
+}
+
+
+/// Exit boot services and take over the machine
+/// The final memory map is read into `buf` and returned
+///
+/// Nothing may allocate firmware memory between reading the map and exiting,
+/// that includes printing to the firmware console. Once this returns only
+/// runtime services are left: every boot services protocol becomes unusable
+/// and the console has to be switched over with `console::exit_boot_services()`
+/// See Page 222: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+pub fn exit_boot_services(buf: &mut [u8]) -> Result<MemoryMap<'_>, EFI_STATUS> {
+    let image_handle = EFI_HANDLE(EfiImageHandle.load(Ordering::SeqCst));
+
+    // The map key goes stale if the firmware changes the map behind our back
+    // (e.g. a timer event allocating), in which case we have to read it again
+    let mut status = EFI_STATUS::EFI_INVALID_PARAMETER;
+    let mut layout = (0, 0);
+    for _ in 0..2 {
+        let (map_size, descriptor_size, map_key) = get_memory_map(buf)?;
+        layout = (map_size, descriptor_size);
+
+        status = unsafe {
+            let system_table = EfiSystemTable.load(Ordering::SeqCst);
+            ((*(*system_table).BootServices).ExitBootServices)(image_handle, map_key)
+        };
+
+        if !status.is_error() {
+            break;
         }
     }
 
-    // Write out any remaining characters
-    if in_use > 0 {
-        // Null terminate the buffer
-        tmp[in_use] = 0;
+    if status.is_error() {
+        return Err(status);
+    }
 
-        unsafe {
-            ((*console_std_out)
-                .OutputString)(console_std_out, tmp.as_ptr());
-        }
+    boot_services_exited();
+
+    let (map_size, descriptor_size) = layout;
+    Ok(MemoryMap { buf: &buf[..map_size], descriptor_size })
+}
+
+
+/// Return to the firmware with `status`
+/// Only returns if boot services are gone or the firmware refused to unload
+/// us, with the reason
+pub fn exit(status: EFI_STATUS) -> EFI_STATUS{
+    if !boot_services_active() {
+        return EFI_STATUS::EFI_UNSUPPORTED;
+    }
+
+    let image_handle = EFI_HANDLE(EfiImageHandle.load(Ordering::SeqCst));
+
+    unsafe {
+        let system_table = EfiSystemTable.load(Ordering::SeqCst);
+        ((*(*system_table).BootServices).Exit)(image_handle, status, 0, core::ptr::null())
+    }
+}
+
+
+/// Validate the header of a table passed to us by the firmware
+/// Checks the signature, the major revision, the size and the CRC32 of the table
+unsafe fn validate_table(header: *const EFI_TABLE_HEADER, signature: u64) -> Result<(), EFI_STATUS> {
+    if header.is_null() {
+        return Err(EFI_STATUS::EFI_INVALID_PARAMETER);
+    }
+
+    let hdr = &*header;
+
+    if hdr.Signature != signature {
+        return Err(EFI_STATUS::EFI_COMPROMISED_DATA);
+    }
+
+    // We only understand the 1.x and 2.x specifications
+    let major = hdr.Revision >> 16;
+    if major != 1 && major != 2 {
+        return Err(EFI_STATUS::EFI_INCOMPATIBLE_VERSION);
+    }
+
+    let size = hdr.HeaderSize as usize;
+    if size < core::mem::size_of::<EFI_TABLE_HEADER>() || size > MAX_TABLE_SIZE {
+        return Err(EFI_STATUS::EFI_BAD_BUFFER_SIZE);
+    }
+
+    // The CRC is computed over the whole table with the CRC32 field set to 0
+    // We can't modify the firmware's table so feed zeroes in place of the field
+    let bytes = core::slice::from_raw_parts(header as *const u8, size);
+    let crc_offset = core::mem::size_of::<u64>() + 2 * core::mem::size_of::<u32>();
+    let crc_end = crc_offset + core::mem::size_of::<u32>();
+
+    let mut crc = Crc32::new();
+    crc.update(&bytes[..crc_offset]);
+    crc.update(&[0u8; 4]);
+    crc.update(&bytes[crc_end..]);
+
+    if crc.finish() != hdr.CRC32 {
+        return Err(EFI_STATUS::EFI_CRC_ERROR);
+    }
+
+    Ok(())
+}
+
+
+/// Find all handles supporting the protocol identified by `guid` and call `f`
+/// with them
+/// None are missed when there are more than fit in `handles`, they are then
+/// fetched again into firmware pool memory
+fn locate_all_handles<R>(guid: &EFI_GUID, handles: &mut [EFI_HANDLE], f: impl FnOnce(&[EFI_HANDLE]) -> R) -> R {
+    let system_table = EfiSystemTable.load(Ordering::SeqCst);
+    if system_table.is_null() || !boot_services_active() {return f(&[]);}
+
+    let mut size = core::mem::size_of_val(handles);
+    let ret = unsafe { locate_handle(system_table, guid, handles.as_mut_ptr(), &mut size) };
+    if ret.0 != EFI_STATUS::EFI_BUFFER_TOO_SMALL.0 {
+        let count = if ret.is_error() {0} else {size / core::mem::size_of::<EFI_HANDLE>()};
+        return f(&handles[..count]);
+    }
+
+    // `size` is now what all of them take
+    let boot_services = unsafe { &*(*system_table).BootServices };
+    let mut pool = core::ptr::null_mut();
+    if unsafe { (boot_services.AllocatePool)(EFI_MEMORY_TYPE::EfiLoaderData, size, &mut pool) }.is_error() {
+        warn!("No pool memory for {} handles, ignoring them", size / core::mem::size_of::<EFI_HANDLE>());
+        return f(&[]);
+    }
+
+    let ret = unsafe { locate_handle(system_table, guid, pool as *mut EFI_HANDLE, &mut size) };
+    let result = if ret.is_error() {
+        f(&[])
+    } else {
+        f(unsafe { core::slice::from_raw_parts(pool as *const EFI_HANDLE, size / core::mem::size_of::<EFI_HANDLE>()) })
+    };
+
+    unsafe { (boot_services.FreePool)(pool) };
+    result
+}
+
+
+/// LocateHandle by protocol into the `size` bytes at `buffer`
+/// `size` is updated to what the handles take, even if they don't fit
+///
+/// Safety: `system_table` must be valid, boot services active and `buffer`
+/// writable for `size` bytes
+unsafe fn locate_handle(system_table: *const EFI_SYSTEM_TABLE, guid: &EFI_GUID, buffer: *mut EFI_HANDLE,
+        size: &mut usize) -> EFI_STATUS {
+    // See Page 202: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+    ((*(*system_table).BootServices).LocateHandle)(
+        EFI_LOCATE_SEARCH_TYPE::ByProtocol,
+        guid,
+        core::ptr::null(),
+        size,
+        buffer,
+    )
+}
+
+
+/// Get the interface of the protocol identified by `guid` on `handle`
+fn handle_protocol(handle: EFI_HANDLE, guid: &EFI_GUID) -> Option<*mut u8> {
+    // Get the system table
+    let system_table = EfiSystemTable.load(Ordering::SeqCst);
+
+    // Check if pointer is null
+    if system_table.is_null() || !boot_services_active() {return None;}
+
+    let mut interface: *mut u8 = core::ptr::null_mut();
+
+    // See Page 195: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+    let ret = unsafe {
+        ((*(*system_table).BootServices).HandleProtocol)(
+            handle,
+            guid,
+            &mut interface,
+        )
+    };
+
+    if ret.is_error() || interface.is_null() {return None;}
+
+    Some(interface)
+}
+
+
+/// Default number of UCS-2 characters buffered before calling `OutputString()`
+/// One slot is always kept for the null terminator
+pub const CONSOLE_CHUNK: usize = 32;
+
+/// Tab stops are placed every `TAB_WIDTH` columns
+const TAB_WIDTH: usize = 8;
+
+
+/// Write a `string` to UEFI output
+pub fn output_string(string: &str){
+    // Get the system table
+    let system_table = EfiSystemTable.load(Ordering::SeqCst);
+
+    // Check if pointer is null
+    if system_table.is_null(){return ;}
+
+    unsafe {
+        write_string::<CONSOLE_CHUNK>((*system_table).ConOut, string);
     }
 }
 
@@ -524,56 +1072,135 @@ pub fn stderr_string(string: &str){
     // Check if pointer is null
     if system_table.is_null(){return ;}
 
-    // Get the console output_pointer
-    let console_std_err = unsafe {
-        (*system_table).StdErr
-    };
+    unsafe {
+        write_string::<CONSOLE_CHUNK>((*system_table).StdErr, string);
+    }
+}
+
 
-    // Create a temporary buffer capable of holding 31 characters and a null
+/// Text attributes for `set_attribute()`, the foreground color goes in the low
+/// nibble and the background color in bits 4-6
+/// See page 481: https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+pub const EFI_CYAN: usize = 0x03;
+pub const EFI_LIGHTGRAY: usize = 0x07;
+pub const EFI_DARKGRAY: usize = 0x08;
+pub const EFI_LIGHTRED: usize = 0x0c;
+pub const EFI_YELLOW: usize = 0x0e;
+pub const EFI_BACKGROUND_BLACK: usize = 0x00;
+
+
+/// Set the text attribute used for further output on ConOut and StdErr
+pub fn set_attribute(attribute: usize){
+    // Get the system table
+    let system_table = EfiSystemTable.load(Ordering::SeqCst);
+
+    // Check if pointer is null
+    if system_table.is_null(){return ;}
+
+    unsafe {
+        let con_out = (*system_table).ConOut;
+        let std_err = (*system_table).StdErr;
+
+        if !con_out.is_null() {
+            ((*con_out).SetAttribute)(con_out, attribute);
+        }
+
+        // StdErr is usually the same device as ConOut
+        if !std_err.is_null() && std_err != con_out {
+            ((*std_err).SetAttribute)(std_err, attribute);
+        }
+    }
+}
+
+
+/// Write a `string` to a text output protocol
+///
+/// Newlines are turned into CRLF (unless they already follow a `\r`) as serial
+/// consoles require it, and tabs are expanded to spaces up to the next tab stop
+///
+/// The string is converted into UCS-2 and written in chunks of `N - 1`
+/// characters plus a null terminator
+/// See: https://github.com/rust-osdev/uefi-rs/blob/dfca11c419a6b2d943ef02af4c7d6c7e3732a195/src/proto/console/text/output.rs#L46
+unsafe fn write_string<const N: usize>(out: *const EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL, string: &str){
+    // Check if pointer is null
+    // We also need room for at least one character and the null terminator
+    if out.is_null() || N < 2 {return ;}
+
+    // Create a temporary buffer capable of holding N-1 characters and a null
     // UEFI uses UCS-2 encoding instead of UTF-16
-    let mut tmp = [0u16; 32];
+    let mut tmp = [0u16; N];
     let mut in_use = 0;
 
+    // Flush the buffer to the output device
+    let flush = |tmp: &mut [u16; N], in_use: &mut usize| {
+        // Null Terminate the buffer
+        tmp[*in_use] = 0;
+
+        // Write output to buffer
+        ((*out).OutputString)(out, tmp.as_ptr());
+
+        // Clear the buffer
+        *in_use = 0;
+    };
+
+    // Track the column so tabs line up with the tab stops on screen
+    let mut column = if (*out).Mode.is_null() {
+        0
+    } else {
+        core::cmp::max((*(*out).Mode).CursorColumn, 0) as usize
+    };
+    let mut prev = 0u16;
+
     // Loop through all characters
     for chr in string.encode_utf16(){
-        // Add CRLF
-        // CRLFs are required by serial consoles at times instead
-        if chr == b'\n' as u16{
-            tmp[in_use] = b'\r' as u16;
-            in_use += 1;
+        // Expand the character into what we actually write out
+        let mut expanded = [0u16; TAB_WIDTH];
+        let count = match chr {
+            // Add CRLF
+            // CRLFs are required by serial consoles at times instead
+            0x0a if prev != b'\r' as u16 => {
+                expanded[0] = b'\r' as u16;
+                expanded[1] = chr;
+                column = 0;
+                2
+            },
+            0x0a | 0x0d => {
+                expanded[0] = chr;
+                column = 0;
+                1
+            },
+            0x09 => {
+                let spaces = TAB_WIDTH - (column % TAB_WIDTH);
+                expanded[..spaces].fill(b' ' as u16);
+                column += spaces;
+                spaces
+            },
+            _ => {
+                expanded[0] = chr;
+                column += 1;
+                1
+            },
+        };
+        prev = chr;
+
+        // Make sure the expansion fits next to the null terminator
+        if in_use + count > N - 1 {
+            flush(&mut tmp, &mut in_use);
         }
 
-        // Write character into buffer
-        tmp[in_use] = chr;
-        in_use += 1;
-
-        // Note the -2 instead of the usual -1
-        // This is because of `\r\n`
-        if in_use == (tmp.len()-2){
-            // Null Terminate the buffer
-            tmp[in_use] = 0;
-
-            // Write output to buffer
-            // See: https://github.com/rust-osdev/uefi-rs/blob/dfca11c419a6b2d943ef02af4c7d6c7e3732a195/src/proto/console/text/output.rs#L46
-            unsafe {
-                ((*console_std_err)
-                    .OutputString)(console_std_err, tmp.as_ptr());
+        for chr in &expanded[..count] {
+            // Tiny buffers may not even fit a whole tab
+            if in_use == N - 1 {
+                flush(&mut tmp, &mut in_use);
             }
-
-            // Clear the buffer
-            in_use = 0;
+            tmp[in_use] = *chr;
+            in_use += 1;
         }
     }
 
     // Write out any remaining characters
     if in_use > 0 {
-        // Null terminate the buffer
-        tmp[in_use] = 0;
-
-        unsafe {
-            ((*console_std_err)
-                .OutputString)(console_std_err, tmp.as_ptr());
-        }
+        flush(&mut tmp, &mut in_use);
     }
 }
 
@@ -0,0 +1,270 @@
+//! Raw sector access through the UEFI Block IO protocol
+//! This lets us read disks using the firmware drivers until we have our own
+//! native storage drivers
+//! See Chapter 13.9(Page 570): https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::{handle_protocol, locate_all_handles, EFI_GUID, EFI_HANDLE, EFI_STATUS};
+use crate::dev::{self, BlockDevOps, DevError};
+
+
+/// GUID identifying `EFI_BLOCK_IO_PROTOCOL`
+/// See: https://dox.ipxe.org/BlockIo_8h.html
+const EFI_BLOCK_IO_PROTOCOL_GUID: EFI_GUID = EFI_GUID::new(
+    0x964e5b21, 0x6459, 0x11d2,
+    [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b]
+);
+
+/// Maximum number of block devices we enumerate
+/// We don't have an allocator yet so this is a fixed size buffer
+pub const MAX_BLOCK_DEVICES: usize = 64;
+
+
+/// Describes the media currently present behind a Block IO protocol instance
+/// See: https://dox.ipxe.org/structEFI__BLOCK__IO__MEDIA.html
+#[repr(C)]
+struct EFI_BLOCK_IO_MEDIA {
+    // The current media ID. If the media changes, this value is changed
+    MediaId: u32,
+
+    // TRUE if the media is removable; otherwise, FALSE
+    RemovableMedia: bool,
+
+    // TRUE if there is a media currently present in the device
+    MediaPresent: bool,
+
+    // TRUE if the Block IO was produced to abstract partition structures on the disk
+    LogicalPartition: bool,
+
+    // TRUE if the media is marked read-only
+    ReadOnly: bool,
+
+    // TRUE if the WriteBlocks() function caches write data
+    WriteCaching: bool,
+
+    // The intrinsic block size of the device
+    BlockSize: u32,
+
+    // Supplies the alignment requirement for any buffer used in a data transfer
+    // 0 and 1 both mean that there is no alignment requirement
+    IoAlign: u32,
+
+    // The last LBA on the device
+    LastBlock: u64,
+}
+
+
+/// This protocol provides control over block devices
+/// See: https://dox.ipxe.org/structEFI__BLOCK__IO__PROTOCOL.html
+#[repr(C)]
+struct EFI_BLOCK_IO_PROTOCOL {
+    // The revision to which the block IO interface adheres
+    Revision: u64,
+
+    // A pointer to the EFI_BLOCK_IO_MEDIA data for this device
+    Media: *const EFI_BLOCK_IO_MEDIA,
+
+    // Resets the block device hardware
+    _Reset: usize,
+
+    // Reads the requested number of blocks from the device
+    // See: https://dox.ipxe.org/BlockIo_8h.html
+    ReadBlocks: unsafe extern "efiapi" fn(
+        This: *const EFI_BLOCK_IO_PROTOCOL,
+        MediaId: u32,
+        Lba: u64,
+        BufferSize: usize,
+        Buffer: *mut u8,
+    ) -> EFI_STATUS,
+
+    // Writes the requested number of blocks to the device
+    _WriteBlocks: usize,
+
+    // Flushes any cached blocks
+    _FlushBlocks: usize,
+}
+
+
+/// A block device exposed by the firmware
+#[derive(Clone, Copy, Debug)]
+pub struct BlockDevice {
+    // Handle which carries the protocol
+    handle: EFI_HANDLE,
+
+    // Protocol interface for the handle
+    protocol: *const EFI_BLOCK_IO_PROTOCOL,
+}
+
+// The protocol interface is owned by the firmware and stays valid while boot
+// services are up, and we only run on the boot processor at this point
+unsafe impl Sync for BlockDevice {}
+
+impl BlockDevice {
+    /// Returns the media descriptor for this device
+    fn media(&self) -> &EFI_BLOCK_IO_MEDIA {
+        unsafe { &*(*self.protocol).Media }
+    }
+
+    /// Size of a single block in bytes
+    pub fn block_size(&self) -> usize {
+        self.media().BlockSize as usize
+    }
+
+    /// Last addressable LBA on the device
+    pub fn last_block(&self) -> u64 {
+        self.media().LastBlock
+    }
+
+    /// Whether there is media present in the device
+    pub fn media_present(&self) -> bool {
+        self.media().MediaPresent
+    }
+
+    /// Whether the device is a partition rather than a whole disk
+    pub fn is_partition(&self) -> bool {
+        self.media().LogicalPartition
+    }
+
+    /// Whether the device can be written to
+    pub fn read_only(&self) -> bool {
+        self.media().ReadOnly
+    }
+
+    /// Read `buf.len()` bytes worth of blocks starting at `lba` into `buf`
+    ///
+    /// `buf` must be a multiple of the block size and must satisfy the
+    /// alignment requirements of the device
+    pub fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), EFI_STATUS> {
+        // The protocol is gone with boot services
+        if !super::boot_services_active() {
+            return Err(EFI_STATUS::EFI_UNSUPPORTED);
+        }
+
+        let media = self.media();
+
+        if !media.MediaPresent {
+            return Err(EFI_STATUS::EFI_NO_MEDIA);
+        }
+
+        let block_size = media.BlockSize as usize;
+        if block_size == 0 || !buf.len().is_multiple_of(block_size) {
+            return Err(EFI_STATUS::EFI_BAD_BUFFER_SIZE);
+        }
+
+        // An IoAlign of 0 or 1 means no alignment requirement
+        let align = media.IoAlign as usize;
+        if align > 1 && !(buf.as_ptr() as usize).is_multiple_of(align) {
+            return Err(EFI_STATUS::EFI_INVALID_PARAMETER);
+        }
+
+        // Reading nothing is always successful
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let ret = unsafe {
+            ((*self.protocol).ReadBlocks)(
+                self.protocol,
+                media.MediaId,
+                lba,
+                buf.len(),
+                buf.as_mut_ptr(),
+            )
+        };
+
+        if ret.is_error() {
+            return Err(ret);
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Enumerate all handles which support the Block IO protocol
+/// The devices found are written into `devices` and the number of devices
+/// found is returned, devices which don't fit are left out with a warning
+pub fn enumerate(devices: &mut [Option<BlockDevice>]) -> usize {
+    let mut handles = [EFI_HANDLE(0); MAX_BLOCK_DEVICES];
+    locate_all_handles(&EFI_BLOCK_IO_PROTOCOL_GUID, &mut handles, |handles| {
+        let mut found = 0;
+
+        for handle in handles.iter() {
+            let interface = match handle_protocol(*handle, &EFI_BLOCK_IO_PROTOCOL_GUID) {
+                Some(interface) => interface,
+                None => continue,
+            };
+
+            if found == devices.len() {
+                warn!("More than {} block devices, ignoring the rest", devices.len());
+                break;
+            }
+            devices[found] = Some(BlockDevice {
+                handle: *handle,
+                protocol: interface as *const EFI_BLOCK_IO_PROTOCOL,
+            });
+            found += 1;
+        }
+
+        found
+    })
+}
+
+
+impl BlockDevOps for BlockDevice {
+    fn name(&self) -> &str {
+        if self.is_partition() {"efipart"} else {"efidisk"}
+    }
+
+    fn block_size(&self) -> usize {
+        BlockDevice::block_size(self)
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.last_block() + 1
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), DevError> {
+        BlockDevice::read_blocks(self, lba, buf).map_err(|status| {
+            match status.0 {
+                x if x == EFI_STATUS::EFI_NO_MEDIA.0 => DevError::NoMedia,
+                x if x == EFI_STATUS::EFI_BAD_BUFFER_SIZE.0 ||
+                     x == EFI_STATUS::EFI_INVALID_PARAMETER.0 => DevError::InvalidArgument,
+                _ => DevError::Io,
+            }
+        })
+    }
+}
+
+
+/// Block devices found at boot
+/// Filled in exactly once by `init()` and never modified afterwards, which is
+/// what allows handing out `'static` references to the device registry
+struct DeviceTable {
+    initialized: AtomicBool,
+    devices: UnsafeCell<[Option<BlockDevice>; MAX_BLOCK_DEVICES]>,
+}
+
+unsafe impl Sync for DeviceTable {}
+
+static Devices: DeviceTable = DeviceTable {
+    initialized: AtomicBool::new(false),
+    devices: UnsafeCell::new([None; MAX_BLOCK_DEVICES]),
+};
+
+
+/// Enumerate the firmware block devices and register them with the device registry
+/// Returns the number of devices found
+pub fn init() -> usize {
+    // Only ever fill the table once
+    if Devices.initialized.swap(true, Ordering::SeqCst) {return 0;}
+
+    let devices = unsafe { &mut *Devices.devices.get() };
+    let count = enumerate(devices);
+
+    for device in devices.iter().take(count).flatten() {
+        let _ = dev::register_block(dev::major::EFI_BLOCK, device);
+    }
+
+    count
+}
@@ -0,0 +1,95 @@
+//! Information about our own image from the UEFI Loaded Image protocol
+//! See Chapter 9.1(Page 297): https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+use super::{handle_protocol, EFI_GUID, EFI_HANDLE, EFI_SYSTEM_TABLE};
+
+
+/// GUID identifying `EFI_LOADED_IMAGE_PROTOCOL`
+/// See: https://dox.ipxe.org/LoadedImage_8h.html
+const EFI_LOADED_IMAGE_PROTOCOL_GUID: EFI_GUID = EFI_GUID::new(
+    0x5b1b31a1, 0x9562, 0x11d2,
+    [0x8e, 0x3f, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b]
+);
+
+
+/// Describes a loaded UEFI image
+/// See: https://dox.ipxe.org/structEFI__LOADED__IMAGE__PROTOCOL.html
+#[repr(C)]
+struct EFI_LOADED_IMAGE_PROTOCOL {
+    // Revision of the protocol
+    Revision: u32,
+
+    // Parent image's image handle, null if the image was loaded directly by the firmware
+    ParentHandle: EFI_HANDLE,
+
+    // The image's copy of the EFI System Table
+    SystemTable: *const EFI_SYSTEM_TABLE,
+
+    // The device handle that the image was loaded from
+    DeviceHandle: EFI_HANDLE,
+
+    // Pointer to the file path portion specific to DeviceHandle
+    _FilePath: usize,
+
+    // Reserved. DO NOT USE
+    _Reserved: usize,
+
+    // The size in bytes of LoadOptions
+    LoadOptionsSize: u32,
+
+    // A pointer to the image's binary load options
+    LoadOptions: *const u8,
+
+    // The base address at which the image was loaded
+    ImageBase: *const u8,
+
+    // The size in bytes of the loaded image
+    ImageSize: u64,
+
+    // The memory type that the code sections were loaded as
+    ImageCodeType: u32,
+
+    // The memory type that the data sections were loaded as
+    ImageDataType: u32,
+
+    // Function that unloads the image
+    _Unload: usize,
+}
+
+
+/// The parts of the loaded image information we care about
+#[derive(Clone, Copy, Debug)]
+pub struct LoadedImage {
+    /// Address the image was loaded at
+    pub image_base: u64,
+
+    /// Size of the image in memory in bytes
+    pub image_size: u64,
+
+    /// Load options (the command line) as raw bytes
+    /// When started from a boot entry or the shell this is a UCS-2 string
+    pub load_options: &'static [u8],
+}
+
+
+/// Get the loaded image information of `image`
+pub fn get(image: EFI_HANDLE) -> Option<LoadedImage> {
+    let interface = handle_protocol(image, &EFI_LOADED_IMAGE_PROTOCOL_GUID)?;
+    let loaded_image = unsafe { &*(interface as *const EFI_LOADED_IMAGE_PROTOCOL) };
+
+    let load_options = if loaded_image.LoadOptions.is_null() {
+        &[][..]
+    } else {
+        unsafe {
+            core::slice::from_raw_parts(
+                loaded_image.LoadOptions,
+                loaded_image.LoadOptionsSize as usize,
+            )
+        }
+    };
+
+    Some(LoadedImage {
+        image_base: loaded_image.ImageBase as u64,
+        image_size: loaded_image.ImageSize,
+        load_options,
+    })
+}
@@ -0,0 +1,40 @@
+//! Platform reset through the runtime services `ResetSystem()` call
+//! Runtime services stay usable after boot services are exited, so this works
+//! at any point after the system table has been registered
+use core::sync::atomic::Ordering;
+use super::{EfiSystemTable, EFI_STATUS};
+
+
+/// Kind of reset to perform
+/// See: https://dox.ipxe.org/UefiSpec_8h.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum EFI_RESET_TYPE {
+    // Power cycle the whole platform
+    Cold = 0,
+
+    // Reset the processors and devices without cycling power
+    Warm = 1,
+
+    // Power off
+    Shutdown = 2,
+
+    // A reset described by a GUID in the reset data, which `reset_system()`
+    // doesn't pass, leaving the firmware to pick one it supports
+    PlatformSpecific = 3,
+}
+
+
+/// Reset the platform
+/// Only returns if there is no system table or the firmware failed to reset
+pub fn reset_system(kind: EFI_RESET_TYPE, status: EFI_STATUS) {
+    // Get the system table
+    let system_table = EfiSystemTable.load(Ordering::SeqCst);
+
+    // Check if pointer is null
+    if system_table.is_null() {return;}
+
+    unsafe {
+        ((*(*system_table).RuntimeServices).ResetSystem)(kind, status, 0, core::ptr::null());
+    }
+}
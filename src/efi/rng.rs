@@ -0,0 +1,74 @@
+//! Random numbers from the UEFI RNG protocol
+//! See Chapter 37.5(Page 2154): https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+use core::sync::atomic::{AtomicPtr, Ordering};
+use super::{handle_protocol, locate_all_handles, EFI_GUID, EFI_HANDLE, EFI_STATUS};
+
+
+/// GUID identifying `EFI_RNG_PROTOCOL`
+/// See: https://dox.ipxe.org/Rng_8h.html
+const EFI_RNG_PROTOCOL_GUID: EFI_GUID = EFI_GUID::new(
+    0x3152bca5, 0xeade, 0x433d,
+    [0x86, 0x2e, 0xc0, 0x1c, 0xdc, 0x29, 0x1f, 0x44]
+);
+
+
+/// This protocol is used to provide random numbers for use in applications,
+/// or entropy for seeding other random number generators
+/// See: https://dox.ipxe.org/structEFI__RNG__PROTOCOL.html
+#[repr(C)]
+struct EFI_RNG_PROTOCOL {
+    // Returns information about the random number generation implementation
+    _GetInfo: usize,
+
+    // Produces and returns an RNG value using either the default or specified RNG algorithm
+    // A null `RNGAlgorithm` selects the default algorithm
+    GetRNG: unsafe extern "efiapi" fn(
+        This: *const EFI_RNG_PROTOCOL,
+        RNGAlgorithm: *const EFI_GUID,
+        RNGValueLength: usize,
+        RNGValue: *mut u8,
+    ) -> EFI_STATUS,
+}
+
+
+/// The RNG protocol instance, null if the firmware has none
+static Rng: AtomicPtr<EFI_RNG_PROTOCOL> = AtomicPtr::new(core::ptr::null_mut());
+
+
+/// Find the firmware RNG protocol
+/// Returns true if one was found
+pub fn init() -> bool {
+    let mut handles = [EFI_HANDLE(0); 4];
+    let interface = locate_all_handles(&EFI_RNG_PROTOCOL_GUID, &mut handles, |handles| {
+        handles.iter().find_map(|handle| handle_protocol(*handle, &EFI_RNG_PROTOCOL_GUID))
+    });
+
+    match interface {
+        Some(interface) => {
+            Rng.store(interface as *mut EFI_RNG_PROTOCOL, Ordering::SeqCst);
+            true
+        },
+        None => false,
+    }
+}
+
+
+/// Fill `buf` with random bytes from the firmware RNG using its default algorithm
+pub fn get_rng(buf: &mut [u8]) -> Result<(), EFI_STATUS> {
+    let rng = Rng.load(Ordering::SeqCst);
+
+    // Check if pointer is null, the protocol is gone with boot services
+    if rng.is_null() || !super::boot_services_active() {return Err(EFI_STATUS::EFI_UNSUPPORTED);}
+
+    if buf.is_empty() {return Ok(());}
+
+    let ret = unsafe {
+        ((*rng).GetRNG)(rng, core::ptr::null(), buf.len(), buf.as_mut_ptr())
+    };
+
+    if ret.is_error() {
+        return Err(ret);
+    }
+
+    Ok(())
+}
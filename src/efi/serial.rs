@@ -0,0 +1,105 @@
+//! Output through the UEFI Serial IO protocol
+//! When the firmware exposes a serial port we mirror all console output to it,
+//! so logs can be captured on headless machines and in QEMU with `-serial stdio`
+//! See Chapter 12.8(Page 524): https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+use core::sync::atomic::{AtomicPtr, Ordering};
+use super::{handle_protocol, locate_all_handles, EFI_GUID, EFI_HANDLE, EFI_STATUS};
+
+
+/// GUID identifying `EFI_SERIAL_IO_PROTOCOL`
+/// See: https://dox.ipxe.org/SerialIo_8h.html
+const EFI_SERIAL_IO_PROTOCOL_GUID: EFI_GUID = EFI_GUID::new(
+    0xbb25cf6f, 0xf1d4, 0x11d2,
+    [0x9a, 0x0c, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0xfd]
+);
+
+
+/// This protocol is used to communicate with any type of character-based IO device
+/// See: https://dox.ipxe.org/structEFI__SERIAL__IO__PROTOCOL.html
+#[repr(C)]
+struct EFI_SERIAL_IO_PROTOCOL {
+    // The revision to which the serial IO interface adheres
+    Revision: u32,
+
+    // Resets the hardware device
+    _Reset: usize,
+
+    // Sets communication parameters for a serial device
+    _SetAttributes: usize,
+
+    // Sets the control bits on a serial device
+    _SetControl: usize,
+
+    // Reads the status of the control bits on a serial device
+    _GetControl: usize,
+
+    // Sends a buffer of characters to a serial device
+    // On return `BufferSize` holds the number of bytes actually written
+    Write: unsafe extern "efiapi" fn(
+        This: *const EFI_SERIAL_IO_PROTOCOL,
+        BufferSize: &mut usize,
+        Buffer: *const u8,
+    ) -> EFI_STATUS,
+
+    // Receives a buffer of characters from a serial device
+    _Read: usize,
+
+    // Pointer to SERIAL_IO_MODE data
+    _Mode: usize,
+}
+
+
+/// The serial port we mirror output to, null if there is none
+static SerialPort: AtomicPtr<EFI_SERIAL_IO_PROTOCOL> = AtomicPtr::new(core::ptr::null_mut());
+
+
+/// Find the first serial port exposed by the firmware and use it for output
+/// Returns true if a serial port was found
+pub fn init() -> bool {
+    let mut handles = [EFI_HANDLE(0); 8];
+    let interface = locate_all_handles(&EFI_SERIAL_IO_PROTOCOL_GUID, &mut handles, |handles| {
+        handles.iter().find_map(|handle| handle_protocol(*handle, &EFI_SERIAL_IO_PROTOCOL_GUID))
+    });
+
+    match interface {
+        Some(interface) => {
+            SerialPort.store(interface as *mut EFI_SERIAL_IO_PROTOCOL, Ordering::SeqCst);
+            true
+        },
+        None => false,
+    }
+}
+
+
+/// Write raw bytes to the serial port
+fn write_bytes(port: *const EFI_SERIAL_IO_PROTOCOL, mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        let mut size = bytes.len();
+
+        let ret = unsafe {
+            ((*port).Write)(port, &mut size, bytes.as_ptr())
+        };
+
+        // Give up on errors or if the device stops accepting data
+        if ret.is_error() || size == 0 {return;}
+
+        bytes = &bytes[size..];
+    }
+}
+
+
+/// Write a `string` to the serial port if one has been found
+pub fn write_string(string: &str) {
+    let port = SerialPort.load(Ordering::SeqCst);
+
+    // Check if pointer is null, the protocol is gone with boot services
+    if port.is_null() || !super::boot_services_active() {return;}
+
+    // Serial terminals need CRLF line endings
+    for (ii, line) in string.split('\n').enumerate() {
+        if ii != 0 {
+            write_bytes(port, b"\r\n");
+        }
+        write_bytes(port, line.as_bytes());
+    }
+}
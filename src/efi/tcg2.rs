@@ -0,0 +1,155 @@
+//! Measurements into the TPM through the UEFI TCG2 protocol
+//! See: https://trustedcomputinggroup.org/resource/tcg-efi-protocol-specification/
+use core::sync::atomic::{AtomicPtr, Ordering};
+use super::{handle_protocol, locate_all_handles, EFI_GUID, EFI_HANDLE, EFI_STATUS};
+
+
+/// GUID identifying `EFI_TCG2_PROTOCOL`
+/// See: https://dox.ipxe.org/Tcg2Protocol_8h.html
+const EFI_TCG2_PROTOCOL_GUID: EFI_GUID = EFI_GUID::new(
+    0x607f766c, 0x7455, 0x42be,
+    [0x93, 0x0b, 0xe4, 0xd7, 0x6d, 0xb2, 0x72, 0x0f]
+);
+
+/// Version of `EFI_TCG2_EVENT_HEADER` we produce
+const EFI_TCG2_EVENT_HEADER_VERSION: u16 = 1;
+
+/// Maximum size of the event description logged along with a measurement
+pub const MAX_EVENT_DATA: usize = 256;
+
+
+/// Header of an event logged by `HashLogExtendEvent()`
+/// See: https://dox.ipxe.org/Tcg2Protocol_8h.html
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct EFI_TCG2_EVENT_HEADER {
+    // Size of the event header itself
+    HeaderSize: u32,
+
+    // Header version
+    HeaderVersion: u16,
+
+    // Index of the PCR that shall be extended (0 - 23)
+    PCRIndex: u32,
+
+    // Type of the event that shall be extended (and optionally logged)
+    EventType: u32,
+}
+
+
+/// An event logged by `HashLogExtendEvent()`
+/// The event data follows the header directly
+/// See: https://dox.ipxe.org/Tcg2Protocol_8h.html
+#[repr(C, packed)]
+struct EFI_TCG2_EVENT {
+    // Total size of the event including the Size component, the header and the event data
+    Size: u32,
+
+    // Event header
+    Header: EFI_TCG2_EVENT_HEADER,
+
+    // Event data
+    Event: [u8; MAX_EVENT_DATA],
+}
+
+
+/// This protocol is used to communicate with the TPM 2.0 of the platform
+/// See: https://dox.ipxe.org/Tcg2Protocol_8h.html
+#[repr(C)]
+struct EFI_TCG2_PROTOCOL {
+    // Provides protocol capability information and state information
+    _GetCapability: usize,
+
+    // Allows a caller to retrieve the address of a given event log and its last entry
+    _GetEventLog: usize,
+
+    // Extends and optionally logs an event with the digest of the given data
+    HashLogExtendEvent: unsafe extern "efiapi" fn(
+        This: *const EFI_TCG2_PROTOCOL,
+        Flags: u64,
+        DataToHash: u64,
+        DataToHashLen: u64,
+        EfiTcgEvent: *const EFI_TCG2_EVENT,
+    ) -> EFI_STATUS,
+
+    // Sends a command directly to the TPM
+    _SubmitCommand: usize,
+
+    // Returns the currently active PCR banks
+    _GetActivePcrBanks: usize,
+
+    // Sets the active PCR banks
+    _SetActivePcrBanks: usize,
+
+    // Retrieves the result of a previous SetActivePcrBanks()
+    _GetResultOfSetActivePcrBanks: usize,
+}
+
+
+/// The TCG2 protocol instance, null if the platform has no TPM 2.0
+static Tcg2: AtomicPtr<EFI_TCG2_PROTOCOL> = AtomicPtr::new(core::ptr::null_mut());
+
+
+/// Find the TCG2 protocol
+/// Returns true if the platform has a TPM 2.0
+pub fn init() -> bool {
+    let mut handles = [EFI_HANDLE(0); 4];
+    let interface = locate_all_handles(&EFI_TCG2_PROTOCOL_GUID, &mut handles, |handles| {
+        handles.iter().find_map(|handle| handle_protocol(*handle, &EFI_TCG2_PROTOCOL_GUID))
+    });
+
+    match interface {
+        Some(interface) => {
+            Tcg2.store(interface as *mut EFI_TCG2_PROTOCOL, Ordering::SeqCst);
+            true
+        },
+        None => false,
+    }
+}
+
+
+/// Hash `data`, extend PCR `pcr` with the digest and log the event with
+/// `description` as its event data
+/// `description` is truncated to `MAX_EVENT_DATA` bytes
+pub fn hash_log_extend_event(
+    pcr: u32,
+    event_type: u32,
+    data: &[u8],
+    description: &[u8],
+) -> Result<(), EFI_STATUS> {
+    let tcg2 = Tcg2.load(Ordering::SeqCst);
+
+    // Check if pointer is null, the protocol is gone with boot services
+    if tcg2.is_null() || !super::boot_services_active() {return Err(EFI_STATUS::EFI_UNSUPPORTED);}
+
+    let len = core::cmp::min(description.len(), MAX_EVENT_DATA);
+    let header_size = core::mem::size_of::<EFI_TCG2_EVENT_HEADER>();
+
+    let mut event = EFI_TCG2_EVENT {
+        Size: (core::mem::size_of::<u32>() + header_size + len) as u32,
+        Header: EFI_TCG2_EVENT_HEADER {
+            HeaderSize: header_size as u32,
+            HeaderVersion: EFI_TCG2_EVENT_HEADER_VERSION,
+            PCRIndex: pcr,
+            EventType: event_type,
+        },
+        Event: [0u8; MAX_EVENT_DATA],
+    };
+    event.Event[..len].copy_from_slice(&description[..len]);
+
+    let ret = unsafe {
+        ((*tcg2).HashLogExtendEvent)(
+            tcg2,
+            0,
+            data.as_ptr() as u64,
+            data.len() as u64,
+            &event,
+        )
+    };
+
+    if ret.is_error() {
+        return Err(ret);
+    }
+
+    Ok(())
+}
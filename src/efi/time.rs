@@ -0,0 +1,32 @@
+//! Delays using the boot services `Stall()` call
+//! This is our only delay primitive until we calibrate a timer of our own
+use core::sync::atomic::Ordering;
+use super::EfiSystemTable;
+
+
+/// Busy wait for at least `us` microseconds
+pub fn stall_us(us: u64) {
+    // Get the system table
+    let system_table = EfiSystemTable.load(Ordering::SeqCst);
+
+    // Check if pointer is null, `Stall()` is also gone with boot services
+    if system_table.is_null() || !super::boot_services_active() {return;}
+
+    // `Stall()` takes a UINTN, split up delays which don't fit into one
+    let mut remaining = us;
+    while remaining > 0 {
+        let chunk = core::cmp::min(remaining, usize::MAX as u64);
+
+        unsafe {
+            ((*(*system_table).BootServices).Stall)(chunk as usize);
+        }
+
+        remaining -= chunk;
+    }
+}
+
+
+/// Busy wait for at least `ms` milliseconds
+pub fn sleep_ms(ms: u64) {
+    stall_us(ms.saturating_mul(1000));
+}
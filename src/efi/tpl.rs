@@ -0,0 +1,74 @@
+//! Task priority level management during the boot services phase
+//! Raising the TPL masks firmware events and timer callbacks at or below the
+//! new level, which is how critical sections are made while boot services are up
+//! See Chapter 7.1(Page 142): https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+use core::sync::atomic::Ordering;
+use super::{EfiSystemTable, EFI_TPL};
+
+
+/// Level at which normal applications run
+pub const TPL_APPLICATION: EFI_TPL = EFI_TPL(4);
+
+/// Level at which most event notifications run
+pub const TPL_CALLBACK: EFI_TPL = EFI_TPL(8);
+
+/// Level at which blocking is not allowed
+pub const TPL_NOTIFY: EFI_TPL = EFI_TPL(16);
+
+/// Highest level, interrupts are disabled
+pub const TPL_HIGH_LEVEL: EFI_TPL = EFI_TPL(31);
+
+
+/// Guard which raises the task priority level and restores the previous
+/// level when dropped
+///
+/// The firmware requires that levels are restored in the reverse order they
+/// were raised, which scoping the guards gives us for free
+pub struct TplGuard {
+    // Level to restore on drop, `None` if boot services weren't available
+    old: Option<EFI_TPL>,
+}
+
+impl TplGuard {
+    /// Raise the task priority level to `tpl`
+    ///
+    /// `tpl` must be at least the current level, the firmware does not allow
+    /// lowering the level with `RaiseTPL()`
+    pub fn raise(tpl: EFI_TPL) -> Self {
+        // Get the system table
+        let system_table = EfiSystemTable.load(Ordering::SeqCst);
+
+        // Check if pointer is null
+        if system_table.is_null() {return TplGuard { old: None };}
+
+        let old = unsafe {
+            ((*(*system_table).BootServices).RaiseTPL)(tpl)
+        };
+
+        TplGuard { old: Some(old) }
+    }
+
+    /// Level that will be restored when the guard is dropped
+    pub fn previous(&self) -> Option<EFI_TPL> {
+        self.old
+    }
+}
+
+impl Drop for TplGuard {
+    fn drop(&mut self) {
+        let old = match self.old {
+            Some(old) => old,
+            None => return,
+        };
+
+        // Get the system table
+        let system_table = EfiSystemTable.load(Ordering::SeqCst);
+
+        // Check if pointer is null
+        if system_table.is_null() {return;}
+
+        unsafe {
+            ((*(*system_table).BootServices).RestoreTPL)(old);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Access to UEFI variables through the runtime services
+//! See Chapter 8.2(Page 278): https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+use core::sync::atomic::Ordering;
+use super::{EfiSystemTable, EFI_GUID, EFI_STATUS};
+
+
+/// Vendor GUID of the architecturally defined global variables
+/// such as `SecureBoot`, `SetupMode` and `BootOrder`
+/// See: https://dox.ipxe.org/GlobalVariable_8h.html
+pub const EFI_GLOBAL_VARIABLE: EFI_GUID = EFI_GUID::new(
+    0x8be4df61, 0x93ca, 0x11d2,
+    [0xaa, 0x0d, 0x00, 0xe0, 0x98, 0x03, 0x2b, 0x8c]
+);
+
+/// Vendor GUID our own variables are stored under
+pub const LAZARUS_VARIABLE: EFI_GUID = EFI_GUID::new(
+    0x6c617a61, 0x7275, 0x4f53,
+    [0x8e, 0x1d, 0x2b, 0x57, 0xc4, 0x0a, 0x93, 0x61]
+);
+
+/// Variable attributes
+pub const EFI_VARIABLE_NON_VOLATILE: u32 = 0x01;
+pub const EFI_VARIABLE_BOOTSERVICE_ACCESS: u32 = 0x02;
+pub const EFI_VARIABLE_RUNTIME_ACCESS: u32 = 0x04;
+
+/// Maximum length of a variable name including the null terminator
+const MAX_NAME_LEN: usize = 64;
+
+
+/// Convert `name` to a null terminated UCS-2 string
+fn encode_name(name: &str) -> Result<[u16; MAX_NAME_LEN], EFI_STATUS> {
+    let mut name16 = [0u16; MAX_NAME_LEN];
+    for (in_use, chr) in name.encode_utf16().enumerate() {
+        // Leave space for the null terminator
+        if in_use == name16.len() - 1 {
+            return Err(EFI_STATUS::EFI_INVALID_PARAMETER);
+        }
+        name16[in_use] = chr;
+    }
+    Ok(name16)
+}
+
+
+/// Read the variable `name` owned by `vendor` into `data`
+/// Returns the number of bytes written into `data`
+pub fn get_variable(name: &str, vendor: &EFI_GUID, data: &mut [u8]) -> Result<usize, EFI_STATUS> {
+    // Get the system table
+    let system_table = EfiSystemTable.load(Ordering::SeqCst);
+
+    // Check if pointer is null
+    if system_table.is_null() {return Err(EFI_STATUS::EFI_NOT_FOUND);}
+
+    let name16 = encode_name(name)?;
+
+    let mut size = data.len();
+
+    let ret = unsafe {
+        ((*(*system_table).RuntimeServices).GetVariable)(
+            name16.as_ptr(),
+            vendor,
+            core::ptr::null_mut(),
+            &mut size,
+            data.as_mut_ptr(),
+        )
+    };
+
+    if ret.is_error() {
+        return Err(ret);
+    }
+
+    Ok(size)
+}
+
+
+/// Write `data` to the variable `name` owned by `vendor`, creating it with
+/// `attributes` if needed
+/// Empty `data` deletes the variable
+pub fn set_variable(name: &str, vendor: &EFI_GUID, attributes: u32, data: &[u8]) -> Result<(), EFI_STATUS> {
+    // Get the system table
+    let system_table = EfiSystemTable.load(Ordering::SeqCst);
+
+    // Check if pointer is null
+    if system_table.is_null() {return Err(EFI_STATUS::EFI_NOT_FOUND);}
+
+    let name16 = encode_name(name)?;
+
+    let ret = unsafe {
+        ((*(*system_table).RuntimeServices).SetVariable)(
+            name16.as_ptr(),
+            vendor,
+            attributes,
+            data.len(),
+            data.as_ptr(),
+        )
+    };
+
+    if ret.is_error() {
+        return Err(ret);
+    }
+
+    Ok(())
+}
+
+
+/// Delete the variable `name` owned by `vendor`
+pub fn delete_variable(name: &str, vendor: &EFI_GUID) -> Result<(), EFI_STATUS> {
+    set_variable(name, vendor, 0, &[])
+}
@@ -0,0 +1,63 @@
+//! Entropy source for the kernel
+//! Random bytes come from the firmware RNG protocol when it is present,
+//! falling back to the RDRAND instruction otherwise
+//! Needed for things like KASLR and stack canaries
+use crate::cpu::features;
+
+/// Number of times to retry RDRAND before giving up
+/// Intel recommends 10 retries
+/// See: https://www.intel.com/content/www/us/en/developer/articles/guide/intel-digital-random-number-generator-drng-software-implementation-guide.html
+const RDRAND_RETRIES: usize = 10;
+
+
+/// Get a random 64-bit value using RDRAND
+/// Returns `None` if the hardware could not produce a value
+fn rdrand64() -> Option<u64> {
+    for _ in 0..RDRAND_RETRIES {
+        let val: u64;
+        let ok: u8;
+        unsafe {
+            // The carry flag is set if a random value was available
+            core::arch::asm!(
+                "rdrand {0}",
+                "setc {1}",
+                out(reg) val,
+                out(reg_byte) ok,
+            );
+        }
+
+        if ok != 0 {
+            return Some(val);
+        }
+    }
+
+    None
+}
+
+
+/// Fill `buf` with random bytes using RDRAND
+fn fill_rdrand(buf: &mut [u8]) -> bool {
+    if !features::has_rdrand() {return false;}
+
+    for chunk in buf.chunks_mut(8) {
+        let val = match rdrand64() {
+            Some(val) => val,
+            None => return false,
+        };
+        chunk.copy_from_slice(&val.to_le_bytes()[..chunk.len()]);
+    }
+
+    true
+}
+
+
+/// Fill `buf` with random bytes
+/// Uses the firmware RNG when present and falls back to RDRAND
+/// Returns false if no entropy source could provide the bytes
+pub fn fill_bytes(buf: &mut [u8]) -> bool {
+    if crate::efi::rng::get_rng(buf).is_ok() {
+        return true;
+    }
+
+    fill_rdrand(buf)
+}
@@ -0,0 +1,192 @@
+//! Global descriptor table and task state segment
+//! The firmware GDT lives in boot services memory, which the allocator hands
+//! out once we have exited boot services, so every core loads a GDT of its
+//! own before that happens. Segmentation is all but off in long mode, the
+//! GDT only holds the flat kernel and user segments and the core's TSS
+//!
+//! The TSS holds the stack the processor switches to when an interrupt
+//! arrives from user mode (RSP0) and the interrupt stacks (IST) which
+//! handlers of faults that may come with a broken stack, like a double
+//! fault, run on
+//! See: https://wiki.osdev.org/Global_Descriptor_Table
+use core::cell::UnsafeCell;
+use crate::mm::VirtAddr;
+
+
+/// Selector of the kernel code segment
+pub const KERNEL_CODE: u16 = 0x08;
+
+/// Selector of the kernel data segment
+pub const KERNEL_DATA: u16 = 0x10;
+
+/// Selector of the user data segment, RPL 3
+/// User data comes before user code as `sysret` expects
+#[allow(dead_code)]
+pub const USER_DATA: u16 = 0x18 | 3;
+
+/// Selector of the user code segment, RPL 3
+#[allow(dead_code)]
+pub const USER_CODE: u16 = 0x20 | 3;
+
+/// Selector of the TSS
+pub const TSS: u16 = 0x28;
+
+/// Number of 8 byte GDT entries, the TSS descriptor takes two
+const GDT_ENTRIES: usize = 7;
+
+/// Number of cores we have a GDT and TSS for
+const MAX_CORES: usize = 256;
+
+/// Number of interrupt stacks in a TSS
+pub const IST_ENTRIES: usize = 7;
+
+// Flat 64-bit segments: present, the DPL, code or data and for code the
+// long mode bit
+const KERNEL_CODE_DESC: u64 = 0x00af_9a00_0000_ffff;
+const KERNEL_DATA_DESC: u64 = 0x00cf_9200_0000_ffff;
+const USER_DATA_DESC: u64 = 0x00cf_f200_0000_ffff;
+const USER_CODE_DESC: u64 = 0x00af_fa00_0000_ffff;
+
+/// System descriptor type of an available 64-bit TSS, and the present bit
+const TSS_AVAILABLE: u64 = 0x89;
+
+
+/// 64-bit task state segment
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Tss {
+    _reserved0: u32,
+
+    // Stacks for switches to rings 0 to 2
+    rsp: [u64; 3],
+
+    _reserved1: u64,
+
+    // Interrupt stacks, IST1 to IST7
+    ist: [u64; IST_ENTRIES],
+
+    _reserved2: u64,
+    _reserved3: u16,
+
+    // Offset of the IO permission bitmap, past the end for none
+    iomap_base: u16,
+}
+
+impl Tss {
+    const fn new() -> Self {
+        Tss {
+            _reserved0: 0,
+            rsp: [0; 3],
+            _reserved1: 0,
+            ist: [0; IST_ENTRIES],
+            _reserved2: 0,
+            _reserved3: 0,
+            iomap_base: core::mem::size_of::<Tss>() as u16,
+        }
+    }
+}
+
+
+/// Operand of `lgdt`
+#[repr(C, packed)]
+struct Pointer {
+    limit: u16,
+    base: u64,
+}
+
+
+/// GDT and TSS of every core
+/// Each core only ever touches its own slot
+struct PerCore {
+    gdt: UnsafeCell<[[u64; GDT_ENTRIES]; MAX_CORES]>,
+    tss: UnsafeCell<[Tss; MAX_CORES]>,
+}
+
+unsafe impl Sync for PerCore {}
+
+static PER_CORE: PerCore = PerCore {
+    gdt: UnsafeCell::new([[0; GDT_ENTRIES]; MAX_CORES]),
+    tss: UnsafeCell::new([Tss::new(); MAX_CORES]),
+};
+
+
+/// The two GDT entries of an available TSS at `base`
+fn tss_descriptor(base: u64) -> [u64; 2] {
+    let limit = core::mem::size_of::<Tss>() as u64 - 1;
+    let low = (limit & 0xffff) |
+        (base & 0xff_ffff) << 16 |
+        TSS_AVAILABLE << 40 |
+        ((limit >> 16) & 0xf) << 48 |
+        ((base >> 24) & 0xff) << 56;
+    [low, base >> 32]
+}
+
+
+/// Build the GDT and TSS of `core` and load them on the calling processor
+/// Reloads every segment register, FS and GS end up null
+///
+/// Safety: must be called on `core` itself, once, before it takes interrupts
+pub unsafe fn init(core: u32) {
+    let core = core as usize;
+    if core >= MAX_CORES {
+        panic!("No GDT slot for core {}", core);
+    }
+
+    let tss = &mut (*PER_CORE.tss.get())[core];
+    *tss = Tss::new();
+    let [tss_low, tss_high] = tss_descriptor(tss as *mut Tss as u64);
+
+    let gdt = &mut (*PER_CORE.gdt.get())[core];
+    *gdt = [0, KERNEL_CODE_DESC, KERNEL_DATA_DESC, USER_DATA_DESC, USER_CODE_DESC, tss_low, tss_high];
+
+    let pointer = Pointer {
+        limit: (core::mem::size_of_val(gdt) - 1) as u16,
+        base: gdt.as_ptr() as u64,
+    };
+
+    // CS can only be reloaded with a far return
+    core::arch::asm!(
+        "lgdt [{pointer}]",
+        "push {code}",
+        "lea {tmp}, [rip + 2f]",
+        "push {tmp}",
+        "retfq",
+        "2:",
+        "mov ds, {data:x}",
+        "mov es, {data:x}",
+        "mov ss, {data:x}",
+        "mov fs, {null:x}",
+        "mov gs, {null:x}",
+        "ltr {tss:x}",
+        pointer = in(reg) &pointer,
+        code = in(reg) KERNEL_CODE as u64,
+        data = in(reg) KERNEL_DATA as u64,
+        null = in(reg) 0u64,
+        tss = in(reg) TSS as u64,
+        tmp = out(reg) _,
+        options(preserves_flags),
+    );
+
+    debug!("Loaded the GDT and TSS of core {}", core);
+}
+
+
+/// Set the stack interrupts from user mode switch to on `core`
+#[allow(dead_code)]
+pub fn set_kernel_stack(core: u32, top: VirtAddr) {
+    if let Some(tss) = unsafe { (*PER_CORE.tss.get()).get_mut(core as usize) } {
+        tss.rsp[0] = top.0;
+    }
+}
+
+
+/// Set interrupt stack `index`, 1 to 7, of `core` to the stack at `top`
+/// Interrupt gates which name the index switch to it unconditionally
+pub fn set_ist(core: u32, index: usize, top: VirtAddr) {
+    if !(1..=IST_ENTRIES).contains(&index) {
+        return;
+    }
+    if let Some(tss) = unsafe { (*PER_CORE.tss.get()).get_mut(core as usize) } {
+        tss.ist[index - 1] = top.0;
+    }
+}
@@ -0,0 +1,93 @@
+//! Canonical hex+ASCII dumps of memory, in the style of `hexdump -C`
+//!
+//! ```text
+//! 0000000000001000  52 53 44 20 50 54 52 20  4f 42 4f 43 48 53 00 00  |RSD PTR OBOCHS..|
+//! ```
+
+
+/// Number of bytes shown per line
+const BYTES_PER_LINE: usize = 16;
+
+/// Length of a formatted line:
+/// address, two spaces, 16 * 3 hex columns, an extra space in the middle,
+/// a space, the ASCII column between bars
+const LINE_LEN: usize = 16 + 2 + BYTES_PER_LINE * 3 + 1 + 1 + BYTES_PER_LINE + 2;
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+
+/// Format one line of the dump for `bytes`, which are located at `addr`
+fn format_line(addr: u64, bytes: &[u8], line: &mut [u8; LINE_LEN]) -> usize {
+    line.fill(b' ');
+    let mut pos = 0;
+
+    // Address
+    for shift in (0..16).rev() {
+        line[pos] = HEX[((addr >> (shift * 4)) & 0xf) as usize];
+        pos += 1;
+    }
+    pos += 2;
+
+    // Hex columns, with a gap after the eighth byte
+    for ii in 0..BYTES_PER_LINE {
+        if let Some(byte) = bytes.get(ii) {
+            line[pos] = HEX[(byte >> 4) as usize];
+            line[pos + 1] = HEX[(byte & 0xf) as usize];
+        }
+        pos += 3;
+        if ii == BYTES_PER_LINE / 2 - 1 {
+            pos += 1;
+        }
+    }
+    pos += 1;
+
+    // ASCII column
+    line[pos] = b'|';
+    pos += 1;
+    for byte in bytes {
+        line[pos] = if byte.is_ascii_graphic() || *byte == b' ' {*byte} else {b'.'};
+        pos += 1;
+    }
+    line[pos] = b'|';
+    pos + 1
+}
+
+
+/// Dump `bytes`, labelling them as starting at address `base`
+pub fn hexdump(base: u64, bytes: &[u8]) {
+    let mut line = [0u8; LINE_LEN];
+
+    for (ii, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        let len = format_line(base + (ii * BYTES_PER_LINE) as u64, chunk, &mut line);
+
+        // Only ASCII is ever written into the line
+        println!("{}", core::str::from_utf8(&line[..len]).unwrap_or(""));
+    }
+}
+
+
+/// Dump `len` bytes of memory at `addr`
+///
+/// Safety: the whole range must be mapped and readable. Physical memory is
+/// identity mapped while boot services are up so physical addresses work too
+pub unsafe fn hexdump_addr(addr: u64, len: usize) {
+    let bytes = core::slice::from_raw_parts(addr as *const u8, len);
+    hexdump(addr, bytes);
+}
+
+
+/// Dump memory to the console
+///
+/// `hexdump!(slice)` dumps a byte slice, labelled with its address
+/// `hexdump!(addr, len)` dumps `len` bytes at address `addr` and is unsafe
+/// in the same way `hexdump_addr()` is
+#[macro_export]
+macro_rules! hexdump {
+    ($bytes:expr) => {{
+        let bytes: &[u8] = &$bytes;
+        $crate::hexdump::hexdump(bytes.as_ptr() as u64, bytes)
+    }};
+    ($addr:expr, $len:expr) => {
+        $crate::hexdump::hexdump_addr($addr as u64, $len as usize)
+    };
+}
@@ -0,0 +1,449 @@
+//! Interrupt descriptor table and CPU exception handlers
+//! Every one of the 32 exception vectors gets a small assembly stub which
+//! pushes a dummy error code where the processor doesn't push one, the
+//! vector number and all general purpose registers, then hands the lot to
+//! `exception()` as a `Frame`. Without this any fault would go through the
+//! firmware IDT, which lives in memory we have since reused, and most likely
+//! end in a triple fault and a silent reset
+//!
+//! The handlers report the exception and halt, page faults get a closer look
+//! in `page_fault`. Double faults run on an interrupt stack of their own,
+//! they are what a kernel stack overflow turns into and the faulting stack is
+//! unusable then
+//! See: https://wiki.osdev.org/Interrupt_Descriptor_Table
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::cpu::cr;
+use crate::cpu::regs::Registers;
+use crate::gdt;
+use crate::mm::{self, VirtAddr};
+
+pub mod page_fault;
+
+
+/// Number of IDT entries
+const IDT_ENTRIES: usize = 256;
+
+/// Number of CPU exception vectors
+const EXCEPTIONS: usize = 32;
+
+/// Type and attributes of a present, ring 0 interrupt gate
+const INTERRUPT_GATE: u8 = 0x8e;
+
+/// Interrupt stack double faults run on
+const DOUBLE_FAULT_IST: u8 = 1;
+
+/// Size of the boot processor's double fault stack
+const DOUBLE_FAULT_STACK_SIZE: usize = 16 * 1024;
+
+// Exception vectors
+#[allow(dead_code)]
+pub const DIVIDE_ERROR: u8 = 0;
+#[allow(dead_code)]
+pub const DEBUG: u8 = 1;
+#[allow(dead_code)]
+pub const NMI: u8 = 2;
+#[allow(dead_code)]
+pub const BREAKPOINT: u8 = 3;
+pub const DOUBLE_FAULT: u8 = 8;
+#[allow(dead_code)]
+pub const GENERAL_PROTECTION: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+#[allow(dead_code)]
+pub const MACHINE_CHECK: u8 = 18;
+
+/// Names of the exception vectors
+const NAMES: [&str; EXCEPTIONS] = [
+    "divide error (#DE)",
+    "debug (#DB)",
+    "non-maskable interrupt",
+    "breakpoint (#BP)",
+    "overflow (#OF)",
+    "bound range exceeded (#BR)",
+    "invalid opcode (#UD)",
+    "device not available (#NM)",
+    "double fault (#DF)",
+    "coprocessor segment overrun",
+    "invalid TSS (#TS)",
+    "segment not present (#NP)",
+    "stack-segment fault (#SS)",
+    "general protection fault (#GP)",
+    "page fault (#PF)",
+    "reserved",
+    "x87 floating point error (#MF)",
+    "alignment check (#AC)",
+    "machine check (#MC)",
+    "SIMD floating point error (#XM)",
+    "virtualization exception (#VE)",
+    "control protection exception (#CP)",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "hypervisor injection exception (#HV)",
+    "VMM communication exception (#VC)",
+    "security exception (#SX)",
+    "reserved",
+];
+
+
+/// What the exception stubs and the processor saved, lowest address first
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Frame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    // Pushed by the stub
+    pub vector: u64,
+
+    // Pushed by the processor, or 0 by the stub for vectors without one
+    pub error_code: u64,
+
+    // Pushed by the processor
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl Frame {
+    /// The interrupted registers, for printing
+    pub fn registers(&self) -> Registers {
+        Registers {
+            rax: self.rax,
+            rbx: self.rbx,
+            rcx: self.rcx,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            rbp: self.rbp,
+            rsp: self.rsp,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.r11,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rip,
+            rflags: self.rflags,
+            cr2: cr::cr2(),
+            cr3: cr::cr3(),
+        }
+    }
+}
+
+
+/// An IDT entry
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Gate {
+    offset_low: u16,
+    selector: u16,
+
+    // Interrupt stack to switch to, 0 for none
+    ist: u8,
+
+    type_attr: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    _reserved: u32,
+}
+
+impl Gate {
+    const EMPTY: Gate = Gate {
+        offset_low: 0,
+        selector: 0,
+        ist: 0,
+        type_attr: 0,
+        offset_mid: 0,
+        offset_high: 0,
+        _reserved: 0,
+    };
+
+    /// An interrupt gate to `handler` in the kernel code segment
+    fn new(handler: u64, ist: u8) -> Self {
+        Gate {
+            offset_low: handler as u16,
+            selector: gdt::KERNEL_CODE,
+            ist,
+            type_attr: INTERRUPT_GATE,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            _reserved: 0,
+        }
+    }
+}
+
+
+/// Operand of `lidt`
+#[repr(C, packed)]
+struct Pointer {
+    limit: u16,
+    base: u64,
+}
+
+
+/// The IDT, shared by all cores
+/// Filled in once by the boot processor
+struct Idt {
+    ready: AtomicBool,
+    gates: UnsafeCell<[Gate; IDT_ENTRIES]>,
+}
+
+unsafe impl Sync for Idt {}
+
+static IDT: Idt = Idt {
+    ready: AtomicBool::new(false),
+    gates: UnsafeCell::new([Gate::EMPTY; IDT_ENTRIES]),
+};
+
+
+/// Double fault stack of the boot processor, there is no allocator yet when
+/// it loads the IDT
+#[repr(C, align(16))]
+struct EmergencyStack(UnsafeCell<[u8; DOUBLE_FAULT_STACK_SIZE]>);
+
+unsafe impl Sync for EmergencyStack {}
+
+static DOUBLE_FAULT_STACK: EmergencyStack = EmergencyStack(UnsafeCell::new([0; DOUBLE_FAULT_STACK_SIZE]));
+
+
+// The exception stubs and the common entry they jump to
+// The processor aligns the stack to 16 bytes before pushing its frame, the
+// frame, the two words pushed by the stub and the 15 registers make 22
+// words, so the stack is aligned again for the call
+core::arch::global_asm!(
+    ".macro EXCEPTION_NO_ERROR vector",
+    "exception_\\vector:",
+    "    push 0",
+    "    push \\vector",
+    "    jmp exception_common",
+    ".endm",
+    ".macro EXCEPTION_ERROR vector",
+    "exception_\\vector:",
+    "    push \\vector",
+    "    jmp exception_common",
+    ".endm",
+
+    "EXCEPTION_NO_ERROR 0",
+    "EXCEPTION_NO_ERROR 1",
+    "EXCEPTION_NO_ERROR 2",
+    "EXCEPTION_NO_ERROR 3",
+    "EXCEPTION_NO_ERROR 4",
+    "EXCEPTION_NO_ERROR 5",
+    "EXCEPTION_NO_ERROR 6",
+    "EXCEPTION_NO_ERROR 7",
+    "EXCEPTION_ERROR 8",
+    "EXCEPTION_NO_ERROR 9",
+    "EXCEPTION_ERROR 10",
+    "EXCEPTION_ERROR 11",
+    "EXCEPTION_ERROR 12",
+    "EXCEPTION_ERROR 13",
+    "EXCEPTION_ERROR 14",
+    "EXCEPTION_NO_ERROR 15",
+    "EXCEPTION_NO_ERROR 16",
+    "EXCEPTION_ERROR 17",
+    "EXCEPTION_NO_ERROR 18",
+    "EXCEPTION_NO_ERROR 19",
+    "EXCEPTION_NO_ERROR 20",
+    "EXCEPTION_ERROR 21",
+    "EXCEPTION_NO_ERROR 22",
+    "EXCEPTION_NO_ERROR 23",
+    "EXCEPTION_NO_ERROR 24",
+    "EXCEPTION_NO_ERROR 25",
+    "EXCEPTION_NO_ERROR 26",
+    "EXCEPTION_NO_ERROR 27",
+    "EXCEPTION_NO_ERROR 28",
+    "EXCEPTION_ERROR 29",
+    "EXCEPTION_ERROR 30",
+    "EXCEPTION_NO_ERROR 31",
+
+    "exception_common:",
+    "    push rax",
+    "    push rbx",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rbp",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov rdi, rsp",
+    "    cld",
+    "    call {handler}",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rbp",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    "    add rsp, 16",
+    "    iretq",
+
+    // Addresses of the stubs, by vector
+    ".pushsection .rodata",
+    ".balign 8",
+    ".global exception_stubs",
+    "exception_stubs:",
+    "    .quad exception_0",
+    "    .quad exception_1",
+    "    .quad exception_2",
+    "    .quad exception_3",
+    "    .quad exception_4",
+    "    .quad exception_5",
+    "    .quad exception_6",
+    "    .quad exception_7",
+    "    .quad exception_8",
+    "    .quad exception_9",
+    "    .quad exception_10",
+    "    .quad exception_11",
+    "    .quad exception_12",
+    "    .quad exception_13",
+    "    .quad exception_14",
+    "    .quad exception_15",
+    "    .quad exception_16",
+    "    .quad exception_17",
+    "    .quad exception_18",
+    "    .quad exception_19",
+    "    .quad exception_20",
+    "    .quad exception_21",
+    "    .quad exception_22",
+    "    .quad exception_23",
+    "    .quad exception_24",
+    "    .quad exception_25",
+    "    .quad exception_26",
+    "    .quad exception_27",
+    "    .quad exception_28",
+    "    .quad exception_29",
+    "    .quad exception_30",
+    "    .quad exception_31",
+    ".popsection",
+    handler = sym exception,
+);
+
+extern "C" {
+    /// Addresses of the exception stubs, by vector
+    static exception_stubs: [u64; EXCEPTIONS];
+}
+
+
+/// Halt this processor forever
+fn halt() -> ! {
+    loop {
+        unsafe {
+            core::arch::asm!("cli; hlt");
+        }
+    }
+}
+
+
+/// Common handler of all exceptions, called by the stubs with the saved
+/// `frame`
+/// Reports the exception and halts, returning would resume the interrupted
+/// code through `iretq`, which only page faults the hook resolved do
+extern "sysv64" fn exception(frame: &mut Frame) {
+    let stats = &core!().stats;
+    stats.exceptions.fetch_add(1, Ordering::Relaxed);
+    if frame.vector == PAGE_FAULT as u64 {
+        stats.page_faults.fetch_add(1, Ordering::Relaxed);
+        if page_fault::resolve(frame) {
+            return;
+        }
+    }
+
+    // Don't wait on a print lock the faulting code may hold
+    crate::print::emergency();
+
+    let name = NAMES.get(frame.vector as usize).copied().unwrap_or("unknown");
+    eprintln!("[!] EXCEPTION {}: {}", frame.vector, name);
+    eprintln!("[!] ERROR CODE: {:#x}", frame.error_code);
+    match crate::symbols::resolve(frame.rip) {
+        Some((symbol, offset)) => eprintln!("[!] AT {:#x} {}+{:#x}", frame.rip, symbol, offset),
+        None => eprintln!("[!] AT {:#x}", frame.rip),
+    }
+    eprintln!("[!] CS={:#x} SS={:#x}", frame.cs, frame.ss);
+
+    match frame.vector as u8 {
+        PAGE_FAULT => page_fault::report(frame),
+
+        // A page fault on a stack guard page can't push its frame and turns
+        // into a double fault, CR2 still holds the address
+        DOUBLE_FAULT => {
+            if let Some(core) = mm::stack::overflow_core(page_fault::fault_address()) {
+                eprintln!("[!] KERNEL STACK OVERFLOW ON CORE {}", core);
+            }
+        },
+        _ => {},
+    }
+    eprintln!("[!] REGISTERS:\n{}", frame.registers());
+
+    eprintln!("[!] BACKTRACE:");
+    crate::backtrace::print(frame.rbp, frame.rsp);
+
+    halt()
+}
+
+
+/// Load the IDT on the calling processor, building it first on the boot
+/// processor
+/// Must come after `gdt::init()`, which resets the interrupt stacks
+///
+/// Safety: must be called on `core` itself
+pub unsafe fn init(core: u32) {
+    if !IDT.ready.load(Ordering::Acquire) {
+        let gates = &mut *IDT.gates.get();
+        for (vector, stub) in exception_stubs.iter().enumerate() {
+            let ist = if vector == DOUBLE_FAULT as usize {DOUBLE_FAULT_IST} else {0};
+            gates[vector] = Gate::new(*stub, ist);
+        }
+        IDT.ready.store(true, Ordering::Release);
+    }
+
+    // Other cores bring their own double fault stack with `set_ist()`
+    if core == 0 {
+        let top = DOUBLE_FAULT_STACK.0.get() as u64 + DOUBLE_FAULT_STACK_SIZE as u64;
+        gdt::set_ist(0, DOUBLE_FAULT_IST as usize, VirtAddr(top));
+    }
+
+    let pointer = Pointer {
+        limit: (core::mem::size_of::<[Gate; IDT_ENTRIES]>() - 1) as u16,
+        base: IDT.gates.get() as u64,
+    };
+    core::arch::asm!("lidt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
+
+    debug!("Loaded the IDT on core {}", core);
+}
@@ -0,0 +1,114 @@
+//! Page fault handler
+//! Decodes the error code and tells which part of the address space the
+//! faulting address (CR2) is in, which is usually enough to tell a stack
+//! overflow from a null pointer or a stale MMIO mapping
+//!
+//! A hook can be installed to resolve faults, e.g. to map pages on demand,
+//! it runs before anything is reported and the faulting instruction is
+//! retried if it returns true
+//! See: Intel SDM Vol. 3A, 4.7 Page-Fault Exceptions
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::Frame;
+use crate::mm::{self, Region, VirtAddr};
+
+
+/// Resolves a page fault at an address, returns whether it did and the
+/// access can be retried
+pub type Hook = fn(VirtAddr, ErrorCode) -> bool;
+
+/// The installed `Hook`, 0 for none
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Error code of a page fault
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorCode(pub u64);
+
+impl ErrorCode {
+    /// The page was present, this is a protection violation
+    pub fn present(&self) -> bool {
+        self.0 & (1 << 0) != 0
+    }
+
+    /// The access was a write
+    pub fn write(&self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+
+    /// The access came from user mode
+    pub fn user(&self) -> bool {
+        self.0 & (1 << 2) != 0
+    }
+
+    /// A reserved bit is set in a paging structure
+    pub fn reserved(&self) -> bool {
+        self.0 & (1 << 3) != 0
+    }
+
+    /// The access was an instruction fetch
+    pub fn instruction_fetch(&self) -> bool {
+        self.0 & (1 << 4) != 0
+    }
+
+    /// The protection key forbade the access
+    pub fn protection_key(&self) -> bool {
+        self.0 & (1 << 5) != 0
+    }
+
+    /// The access was a shadow stack access
+    pub fn shadow_stack(&self) -> bool {
+        self.0 & (1 << 6) != 0
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = if self.instruction_fetch() {"instruction fetch"}
+            else if self.write() {"write"}
+            else {"read"};
+        write!(f, "{} {} of a {} page", if self.user() {"user"} else {"kernel"}, access,
+            if self.present() {"present"} else {"non-present"})?;
+        if self.reserved() {write!(f, ", reserved bit set")?;}
+        if self.protection_key() {write!(f, ", protection key")?;}
+        if self.shadow_stack() {write!(f, ", shadow stack")?;}
+        Ok(())
+    }
+}
+
+
+/// Install `hook` to resolve page faults, replacing any previous one
+#[allow(dead_code)]
+pub fn set_hook(hook: Hook) {
+    HOOK.store(hook as usize, Ordering::Release);
+}
+
+
+/// Address of the last page fault
+pub fn fault_address() -> VirtAddr {
+    VirtAddr(crate::cpu::cr::cr2())
+}
+
+
+/// Give the hook a go at the page fault in `frame`
+/// Returns whether it resolved the fault and the access can be retried
+pub(super) fn resolve(frame: &Frame) -> bool {
+    let hook = HOOK.load(Ordering::Acquire);
+    if hook == 0 {
+        return false;
+    }
+    let hook: Hook = unsafe { core::mem::transmute(hook) };
+    hook(fault_address(), ErrorCode(frame.error_code))
+}
+
+
+/// Print what the page fault in `frame` was and where it hit
+pub(super) fn report(frame: &Frame) {
+    let addr = fault_address();
+    let region = mm::region(addr);
+    eprintln!("[!] ADDRESS: {:#x}, {}", addr.0, region);
+    eprintln!("[!] ACCESS: {}", ErrorCode(frame.error_code));
+    if let Region::StackGuard(core) = region {
+        eprintln!("[!] KERNEL STACK OVERFLOW ON CORE {}", core);
+    }
+}
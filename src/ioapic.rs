@@ -0,0 +1,248 @@
+//! IO APIC driver
+//! The IO APICs take the interrupt lines of devices, numbered as global
+//! system interrupts (GSIs) across all of them, and deliver each to a vector
+//! on a processor as told by its redirection entry. The MADT says where they
+//! are and which ISA IRQs are wired to another GSI or with another polarity
+//! or trigger mode than the ISA default of active high, edge triggered
+//!
+//! Every entry is masked at `init()`, lines only fire once `route()` has
+//! pointed them somewhere. Destinations are physical local APIC IDs, IDs above
+//! 255 need interrupt remapping which we don't do
+//! See: https://wiki.osdev.org/IOAPIC
+use core::fmt;
+use crate::acpi::madt::{IntiFlags, Madt, MAX_IO_APICS};
+use crate::mm::{self, PhysAddr, VirtAddr};
+use crate::sync::SpinLock;
+
+
+/// Size of the register window
+const WINDOW_SIZE: u64 = 0x20;
+
+/// Offset of the register select register
+const IOREGSEL: u64 = 0x00;
+
+/// Offset of the register data window
+const IOWIN: u64 = 0x10;
+
+/// Version register, bits 16-23 hold the index of the last redirection entry
+const REG_VERSION: u32 = 0x01;
+
+/// First redirection table register, each entry takes two
+const REG_REDIRECTION: u32 = 0x10;
+
+// Redirection entry bits
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+const DESTINATION_SHIFT: u64 = 56;
+
+/// First vector devices may use, below are the CPU exceptions and the
+/// vectors of the legacy PICs
+pub const FIRST_VECTOR: u8 = crate::pic::VECTOR_BASE + 16;
+
+/// First GSI which isn't an ISA IRQ
+const FIRST_PCI_GSI: u32 = 16;
+
+
+/// Why `route()` failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoApicError {
+    // No IO APIC serves the GSI
+    NoIoApic(u32),
+
+    // The GSI is wired to NMI
+    NmiSource(u32),
+
+    // The vector is a CPU exception or belongs to the legacy PICs
+    BadVector(u8),
+
+    // The local APIC ID doesn't fit in a redirection entry
+    BadDestination(u32),
+}
+
+impl fmt::Display for IoApicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IoApicError::NoIoApic(gsi) => write!(f, "no IO APIC serves GSI {}", gsi),
+            IoApicError::NmiSource(gsi) => write!(f, "GSI {} is an NMI", gsi),
+            IoApicError::BadVector(vector) => write!(f, "vector {} is reserved", vector),
+            IoApicError::BadDestination(cpu) => write!(f, "APIC ID {} needs interrupt remapping", cpu),
+        }
+    }
+}
+
+
+/// A mapped IO APIC
+#[derive(Clone, Copy, Debug, Default)]
+struct Controller {
+    #[allow(dead_code)]
+    id: u8,
+    regs: VirtAddr,
+
+    // GSIs it serves
+    gsi_base: u32,
+    count: u32,
+}
+
+impl Controller {
+    const EMPTY: Controller = Controller { id: 0, regs: VirtAddr(0), gsi_base: 0, count: 0 };
+
+    fn serves(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.count
+    }
+
+    /// Read register `reg`, with `IO_APICS` held
+    unsafe fn read(&self, reg: u32) -> u32 {
+        core::ptr::write_volatile((self.regs.0 + IOREGSEL) as *mut u32, reg);
+        core::ptr::read_volatile((self.regs.0 + IOWIN) as *const u32)
+    }
+
+    /// Write register `reg`, with `IO_APICS` held
+    unsafe fn write(&self, reg: u32, value: u32) {
+        core::ptr::write_volatile((self.regs.0 + IOREGSEL) as *mut u32, reg);
+        core::ptr::write_volatile((self.regs.0 + IOWIN) as *mut u32, value);
+    }
+
+    unsafe fn read_entry(&self, index: u32) -> u64 {
+        let reg = REG_REDIRECTION + index * 2;
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    /// Write the redirection entry at `index`, the high half with the
+    /// destination first so the line is never live with a stale one
+    unsafe fn write_entry(&self, index: u32, entry: u64) {
+        let reg = REG_REDIRECTION + index * 2;
+        self.write(reg, MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+
+/// The IO APICs and how many there are
+/// Held while their registers are in use, the select and data registers only
+/// work as a pair
+static IO_APICS: SpinLock<([Controller; MAX_IO_APICS], usize)> =
+    SpinLock::new(([Controller::EMPTY; MAX_IO_APICS], 0));
+
+
+/// Map the IO APICs in `madt` and mask all their lines
+pub fn init(madt: &Madt) {
+    let mut io_apics = IO_APICS.lock();
+    let (controllers, count) = &mut *io_apics;
+    for io in madt.io_apics.as_slice() {
+        let regs = match mm::map_mmio(PhysAddr(io.addr as u64), WINDOW_SIZE) {
+            Ok(regs) => regs,
+            Err(err) => {
+                warn!("Could not map IO APIC {} at {:#x}: {:?}", io.id, io.addr, err);
+                continue;
+            },
+        };
+
+        let mut controller = Controller { id: io.id, regs, gsi_base: io.gsi_base, count: 0 };
+        controller.count = unsafe { (controller.read(REG_VERSION) >> 16 & 0xff) + 1 };
+        for index in 0..controller.count {
+            unsafe { controller.write_entry(index, MASKED) };
+        }
+
+        if *count == MAX_IO_APICS {
+            warn!("More than {} IO APICs, ignoring {}", MAX_IO_APICS, io.id);
+            break;
+        }
+        controllers[*count] = controller;
+        *count += 1;
+        debug!("IO APIC {} at {:#x}: GSIs {}-{}", io.id, io.addr, io.gsi_base,
+            io.gsi_base + controller.count - 1);
+    }
+}
+
+
+/// The IO APIC serving `gsi`, out of the `count` first `controllers`
+fn controller_for(controllers: &[Controller], count: usize, gsi: u32) -> Result<&Controller, IoApicError> {
+    controllers[..count].iter().find(|controller| controller.serves(gsi)).ok_or(IoApicError::NoIoApic(gsi))
+}
+
+
+/// Polarity and trigger mode bits of the redirection entry of `gsi`
+/// Source overrides win, otherwise ISA IRQs are active high and edge
+/// triggered and everything above, PCI, active low and level triggered
+fn entry_flags(madt: Option<&Madt>, gsi: u32) -> u64 {
+    let over = madt.and_then(|madt| madt.overrides.as_slice().iter().find(|over| over.gsi == gsi));
+    let flags = over.map_or(IntiFlags(0), |over| over.flags);
+
+    // Overrides are for ISA IRQs, whose defaults apply to them
+    let pci = over.is_none() && gsi >= FIRST_PCI_GSI;
+
+    let mut bits = 0;
+    if flags.active_low().unwrap_or(pci) {
+        bits |= ACTIVE_LOW;
+    }
+    if flags.level_triggered().unwrap_or(pci) {
+        bits |= LEVEL_TRIGGERED;
+    }
+    bits
+}
+
+
+/// Deliver `gsi` as `vector` to the processor with local APIC ID `cpu` and
+/// unmask it
+#[allow(dead_code)]
+pub fn route(gsi: u32, vector: u8, cpu: u32) -> Result<(), IoApicError> {
+    if vector < FIRST_VECTOR {
+        return Err(IoApicError::BadVector(vector));
+    }
+    if cpu > 0xff {
+        return Err(IoApicError::BadDestination(cpu));
+    }
+
+    let madt = crate::acpi::registry().and_then(|tables| tables.madt.as_ref());
+    if madt.is_some_and(|madt| madt.nmi_sources.as_slice().iter().any(|nmi| nmi.gsi == gsi)) {
+        return Err(IoApicError::NmiSource(gsi));
+    }
+
+    let entry = vector as u64 | entry_flags(madt, gsi) | (cpu as u64) << DESTINATION_SHIFT;
+    let io_apics = IO_APICS.lock();
+    let controller = controller_for(&io_apics.0, io_apics.1, gsi)?;
+    unsafe { controller.write_entry(gsi - controller.gsi_base, entry) };
+    debug!("GSI {} routed to vector {} on APIC ID {}", gsi, vector, cpu);
+    Ok(())
+}
+
+
+/// Deliver ISA `irq` as `vector` to the processor with local APIC ID `cpu`,
+/// on whatever GSI it is wired to
+/// Returns the GSI
+#[allow(dead_code)]
+pub fn route_isa(irq: u8, vector: u8, cpu: u32) -> Result<u32, IoApicError> {
+    let gsi = crate::acpi::registry().and_then(|tables| tables.madt.as_ref())
+        .map_or(irq as u32, |madt| madt.isa_irq(irq).0);
+    route(gsi, vector, cpu)?;
+    Ok(gsi)
+}
+
+
+/// Mask or unmask `gsi`, keeping the rest of its redirection entry
+fn set_masked(gsi: u32, masked: bool) -> Result<(), IoApicError> {
+    let io_apics = IO_APICS.lock();
+    let controller = controller_for(&io_apics.0, io_apics.1, gsi)?;
+    let index = gsi - controller.gsi_base;
+    unsafe {
+        let entry = controller.read_entry(index);
+        controller.write_entry(index, if masked {entry | MASKED} else {entry & !MASKED});
+    }
+    Ok(())
+}
+
+
+/// Stop delivering `gsi`
+#[allow(dead_code)]
+pub fn mask(gsi: u32) -> Result<(), IoApicError> {
+    set_masked(gsi, true)
+}
+
+
+/// Resume delivering `gsi`, once `route()` has set it up
+#[allow(dead_code)]
+pub fn unmask(gsi: u32) -> Result<(), IoApicError> {
+    set_masked(gsi, false)
+}
@@ -0,0 +1,295 @@
+//! Kernel logging
+//! Provides the `error!`, `warn!`, `info!`, `debug!` and `trace!` macros
+//!
+//! Every message is tagged with the module it came from (its target). The
+//! level is set at runtime with the `log=` command line option, which takes a
+//! default level optionally followed by per-target overrides:
+//!
+//! ```text
+//! log=info,efi::block:trace,dev:debug
+//! ```
+//!
+//! Levels above `STATIC_MAX_LEVEL` are compiled out entirely
+//!
+//! Consoles which support it show messages colored by level, `--no-color` on
+//! the command line turns that off
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use crate::console::Color;
+
+
+/// Log levels, from the most to the least important
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// Parse a level name as given on the command line
+    pub fn parse(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    /// Color messages of this level are shown in on consoles with colors
+    fn color(&self) -> Color {
+        match self {
+            Level::Error => Color::Red,
+            Level::Warn => Color::Yellow,
+            Level::Info => Color::Cyan,
+            Level::Debug => Color::Gray,
+            Level::Trace => Color::DarkGray,
+        }
+    }
+
+    /// Prefix printed in front of messages of this level
+    /// Kept on colored consoles too, it is what serial logs and dmesg go by
+    fn prefix(&self) -> &'static str {
+        match self {
+            Level::Error => "[!]",
+            Level::Warn => "[w]",
+            Level::Info => "[i]",
+            Level::Debug => "[d]",
+            Level::Trace => "[t]",
+        }
+    }
+
+    fn from_u8(val: u8) -> Level {
+        match val {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+}
+
+
+/// Most verbose level compiled into the kernel
+/// Release builds drop debug and trace messages at compile time
+pub const STATIC_MAX_LEVEL: Level = if cfg!(debug_assertions) {
+    Level::Trace
+} else {
+    Level::Info
+};
+
+/// Level used when the command line doesn't set one
+const DEFAULT_LEVEL: Level = Level::Info;
+
+/// Maximum number of per-target overrides
+const MAX_FILTERS: usize = 8;
+
+
+/// Default runtime level
+static LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LEVEL as u8);
+
+/// Whether messages are colored by level
+static COLOR: AtomicBool = AtomicBool::new(true);
+
+
+/// Per-target level overrides from the command line
+/// Written once by `init()` and only read afterwards
+struct Filters {
+    initialized: AtomicBool,
+    filters: UnsafeCell<[Option<(&'static str, Level)>; MAX_FILTERS]>,
+}
+
+unsafe impl Sync for Filters {}
+
+static FILTERS: Filters = Filters {
+    initialized: AtomicBool::new(false),
+    filters: UnsafeCell::new([None; MAX_FILTERS]),
+};
+
+
+/// Set up the log levels from the `log=` command line option and colors from
+/// `--no-color`
+pub fn init() {
+    // Only ever fill the filters once
+    if FILTERS.initialized.swap(true, Ordering::SeqCst) {return;}
+
+    if crate::cmdline::flag("--no-color") {
+        COLOR.store(false, Ordering::Relaxed);
+    }
+
+    let spec = match crate::cmdline::get("log") {
+        Some(spec) => spec,
+        None => return,
+    };
+
+    let filters = unsafe { &mut *FILTERS.filters.get() };
+    let mut count = 0;
+
+    for directive in spec.split(',') {
+        match directive.rsplit_once(':') {
+            // `target:level` override
+            Some((target, level)) => {
+                let level = match Level::parse(level) {
+                    Some(level) => level,
+                    None => continue,
+                };
+                if count < filters.len() {
+                    filters[count] = Some((target, level));
+                    count += 1;
+                }
+            },
+
+            // Plain default level
+            None => {
+                if let Some(level) = Level::parse(directive) {
+                    set_level(level);
+                }
+            },
+        }
+    }
+}
+
+
+/// Set the default runtime level
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+
+/// Get the default runtime level
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+
+/// Strip our crate name from a module path to get the target name
+fn target(module_path: &'static str) -> &'static str {
+    match module_path.split_once("::") {
+        Some((_, rest)) => rest,
+        None => module_path,
+    }
+}
+
+
+/// Level that applies to `target`
+/// The most specific matching override wins, falling back to the default level
+fn level_for(target: &str) -> Level {
+    let filters = unsafe { &*FILTERS.filters.get() };
+
+    filters.iter().flatten()
+        .filter(|(filter, _)| {
+            target == *filter ||
+                (target.starts_with(filter) && target[filter.len()..].starts_with("::"))
+        })
+        .max_by_key(|(filter, _)| filter.len())
+        .map(|(_, level)| *level)
+        .unwrap_or_else(level)
+}
+
+
+/// Check whether a message of `level` from `module_path` would be logged
+#[inline]
+pub fn enabled(level: Level, module_path: &'static str) -> bool {
+    level <= STATIC_MAX_LEVEL && level <= level_for(target(module_path))
+}
+
+
+/// Fixed size buffer a log line is formatted into for the kernel log
+/// Anything which doesn't fit is dropped
+struct LineBuf {
+    buf: [u8; crate::dmesg::LINE_LEN],
+    len: usize,
+}
+
+impl LineBuf {
+    fn as_str(&self) -> &str {
+        // Only whole characters are ever copied in
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for LineBuf {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        for chr in string.chars() {
+            let len = chr.len_utf8();
+            if self.len + len > self.buf.len() {break;}
+            chr.encode_utf8(&mut self.buf[self.len..]);
+            self.len += len;
+        }
+        Ok(())
+    }
+}
+
+
+/// Write a log message
+/// Use the logging macros instead of calling this directly
+pub fn log(level: Level, module_path: &'static str, args: fmt::Arguments) {
+    // Timestamp every line with the time since boot
+    let uptime = crate::time::uptime_us();
+    let (secs, micros) = (uptime / 1_000_000, uptime % 1_000_000);
+
+    // Keep a copy in the kernel log, this works even before we have a console
+    let mut line = LineBuf { buf: [0u8; crate::dmesg::LINE_LEN], len: 0 };
+    let _ = fmt::Write::write_fmt(
+        &mut line,
+        format_args!("[{:5}.{:06}] {} {}: {}",
+            secs, micros, level.prefix(), target(module_path), args),
+    );
+    crate::dmesg::record(line.as_str());
+
+    // Errors and warnings go to stderr
+    let err = matches!(level, Level::Error | Level::Warn);
+    let color = if COLOR.load(Ordering::Relaxed) {Some(level.color())} else {None};
+
+    crate::print::print_colored(color, err, format_args!("[{:5}.{:06}] {} {}: {}\n",
+        secs, micros, level.prefix(), target(module_path), args));
+}
+
+
+/// Log a message at the given level
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level, module_path!()) {
+            $crate::log::log($level, module_path!(), format_args!($($arg)*));
+        }
+    }
+}
+
+/// Log an error
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log!($crate::log::Level::Error, $($arg)*) }
+}
+
+/// Log a warning
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log!($crate::log::Level::Warn, $($arg)*) }
+}
+
+/// Log an informational message
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log!($crate::log::Level::Info, $($arg)*) }
+}
+
+/// Log a debugging message
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log!($crate::log::Level::Debug, $($arg)*) }
+}
+
+/// Log a tracing message
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::log!($crate::log::Level::Trace, $($arg)*) }
+}
@@ -2,18 +2,179 @@
 #![no_std]
 #![no_main]
 
+extern crate alloc;
+
 #[macro_use] mod print;
+#[macro_use] mod log;
+#[macro_use] mod hexdump;
+#[macro_use] mod time;
+#[macro_use] mod percpu;
 mod panic_handler;
+mod backtrace;
+mod symbols;
+mod crashlog;
 mod mem;
+mod mm;
 mod efi;
+mod console;
+mod early;
+mod cmdline;
+mod dmesg;
+mod progress;
+mod uart;
+mod crc32;
+mod cstr;
+mod cpu;
+mod entropy;
+mod dev;
+mod security;
+mod tpm;
+mod pmem;
+mod sync;
+mod acpi;
+mod gdt;
+mod interrupts;
+mod ioapic;
+mod pci;
+mod pic;
 
 use crate::efi::{EFI_HANDLE, EFI_SYSTEM_TABLE, EFI_STATUS};
 
+
+/// Space for the memory map read when exiting boot services
+const MEMORY_MAP_SIZE: usize = 16 * 1024;
+
+
 #[no_mangle]
-extern fn efi_main(_image_handle: EFI_HANDLE, system_table: *mut EFI_SYSTEM_TABLE) -> EFI_STATUS{
+extern "efiapi" fn efi_main(image_handle: EFI_HANDLE, system_table: *mut EFI_SYSTEM_TABLE) -> EFI_STATUS{
+    // Mark the top of our stack so backtraces know where to stop
+    backtrace::init();
+
     // First, register the system table in a global so we can use it in other places such as the `print!` macro
+    // A corrupt system table is handed straight back to the firmware as we can't
+    // even print an error without it
+    if let Err(status) = unsafe { efi::register_system_table(system_table) } {
+        return status;
+    }
+    efi::register_image_handle(image_handle);
+
+    // Start the clock so log lines get timestamps
+    time::init(true);
+
+    // Bring up the console sinks backing `print!`
+    console::init();
+
+    // Parse the command line early, it controls how verbose we are
+    cmdline::init(image_handle);
+    log::init();
+    cpu::features::log();
+    time::log_clock();
+    mm::heap::init();
+
+    // Show what went wrong last time, if anything
+    crashlog::report_previous();
+
+    // Find the firmware entropy source
+    efi::rng::init();
+
+    // Find the TPM so payloads can be measured before we run them
+    efi::tcg2::init();
+
+    // Register the devices we know about
+    dev::init();
+    efi::block::init();
+
+    if let Some((vendor, revision)) = efi::firmware_vendor() {
+        info!("Firmware: {} revision {:#x}", cstr::Ucs2(vendor), revision);
+    }
+    info!("Secure Boot: {}, unsigned payloads {}", security::secure_boot_state(),
+        if security::unsigned_payloads_allowed() {"allowed"} else {"refused"});
+
+    // Remember where we live, loaded image info is a boot service
+    let kernel = efi::loaded_image::get(image_handle)
+        .and_then(|image| mm::Range::new(image.image_base, image.image_size))
+        .unwrap_or(mm::Range { start: 0, end: 0 });
+
+    // Take over the machine
+    let mut map_buf = [0u8; MEMORY_MAP_SIZE];
+    let map = match efi::exit_boot_services(&mut map_buf) {
+        Ok(map) => map,
+        Err(status) => panic!("ExitBootServices failed: {:#x}", status.0),
+    };
+    console::exit_boot_services();
+
+    // Keep the legacy PICs from raising IRQs on exception vectors, nothing
+    // enables interrupts before the IO APICs are set up
+    cpu::irq::disable();
     unsafe {
-        efi::register_system_table(system_table);
+        pic::init();
     }
+
+    // The firmware GDT and IDT are in boot services memory, which
+    // `mm::init()` frees
+    unsafe {
+        gdt::init(0);
+        percpu::init(0);
+        interrupts::init(0);
+    }
+
+    mm::init(&map, kernel);
+    pmem::init(&map);
+    if let Err(err) = mm::paging::init(&map, kernel) {
+        panic!("Could not set up paging: {:?}", err);
+    }
+    cpu::init();
+    mem::init();
+    info!("{} MiB of free memory", mm::free_bytes() >> 20);
+
+    // Find the firmware tables describing the machine
+    match bench!("ACPI parsing", acpi::init()) {
+        Ok(tables) => {
+            info!("ACPI revision {}, {} tables from {}", tables.revision, tables.len(),
+                acpi::ascii(&tables.oem_id));
+            if tables.iommu.is_none() {
+                info!("No IOMMU, DMA is not remapped");
+            }
+            if let Some(madt) = &tables.madt {
+                ioapic::init(madt);
+            }
+        },
+        Err(err) => warn!("No usable ACPI tables: {}", err),
+    }
+    mm::numa::log_distances();
+
+    // Leave the firmware stack for one with a guard page below it, and the
+    // identity mapped image for the slid one
+    match mm::stack::alloc(0) {
+        Ok(stack) => unsafe { stack.switch_to(mm::slid(kernel_main)) },
+        Err(err) => {
+            warn!("Could not allocate a guarded stack: {:?}", err);
+            mm::slid(kernel_main)()
+        },
+    }
+}
+
+
+/// Rest of the boot, on the kernel stack of the boot core
+extern "C" fn kernel_main() -> ! {
+    // Backtraces stop here now
+    backtrace::init();
+
+    // Boot is done, anything still using firmware memory would have faulted
+    mm::bsguard::release();
+
+    if cmdline::flag("--mem-bench") {
+        mem::bench();
+    }
+
+    if cmdline::flag("--acpidump") || cmdline::get("acpidump").is_some() {
+        acpi::acpidump(cmdline::get("acpidump"));
+    }
+
+    // Anything still allocated here is a leak or meant to stay
+    mm::heap::dump_live();
+
+    // Nothing went wrong, there just is nothing left to do
+    crashlog::expect_panic();
     panic!("LazarusOS Is Live!\n");
 }
@@ -19,15 +19,149 @@
 /// dest: Pointer to memory to copy to
 #[no_mangle]
 #[cfg(target_arch = "x86_64")]
-pub unsafe extern fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8{
+pub unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8{
+    if n >= LARGE {
+        copy_with(copy_method(), dest, src, n);
+    } else {
+        copy_bytes(dest, src, n);
+    }
+    dest
+}
+
+
+/// Copies and fills at least this big use the fastest method the processor
+/// has, below this `rep movsb`/`rep stosb` is as good as anything
+#[cfg(target_arch = "x86_64")]
+const LARGE: usize = 512;
+
+
+/// Ways of copying memory, slowest first
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CopyMethod {
+    // `rep movsb`, one byte at a time on processors without ERMS
+    Bytes = 1,
+
+    // `rep movsq`, then the tail with `rep movsb`
+    Words = 2,
+
+    // `rep movsb` with Enhanced REP MOVSB/STOSB, which moves whole cache
+    // lines internally
+    Erms = 3,
+
+    // 32 bytes at a time through an AVX register
+    Avx = 4,
+}
+
+
+/// Copy method picked for large copies by `init()`, 0 until then
+#[cfg(target_arch = "x86_64")]
+static COPY_METHOD: core::sync::atomic::AtomicU8 = core::sync::atomic::AtomicU8::new(0);
+
+
+/// Check if AVX is there and enabled
+/// The processor having it is not enough, XCR0 has to enable the SSE and AVX
+/// state as well
+#[cfg(target_arch = "x86_64")]
+fn avx_enabled() -> bool {
+    use crate::cpu::cr::{self, Xcr0};
+    crate::cpu::features::has_avx() && cr::xcr0().is_some_and(|xcr0| xcr0.contains(Xcr0::SSE | Xcr0::AVX))
+}
+
+
+/// Pick the copy method for large copies
+/// ERMS `rep movsb` is the fastest where it exists, AVX copies are next
+/// Must come after `cpu::init()`, copies before use `rep movsq`
+#[cfg(target_arch = "x86_64")]
+pub fn init() {
+    let method = if crate::cpu::features::has_erms() {
+        CopyMethod::Erms
+    } else if avx_enabled() {
+        CopyMethod::Avx
+    } else {
+        CopyMethod::Words
+    };
+    COPY_METHOD.store(method as u8, core::sync::atomic::Ordering::Relaxed);
+    debug!("memcpy uses {:?} for copies of {} bytes and up", method, LARGE);
+}
+
+
+/// Copy method for large copies on this processor
+#[cfg(target_arch = "x86_64")]
+pub fn copy_method() -> CopyMethod {
+    match COPY_METHOD.load(core::sync::atomic::Ordering::Relaxed) {
+        1 => CopyMethod::Bytes,
+        3 => CopyMethod::Erms,
+        4 => CopyMethod::Avx,
+        _ => CopyMethod::Words,
+    }
+}
+
+
+/// Copy `n` bytes with `rep movsb`
+#[cfg(target_arch = "x86_64")]
+unsafe fn copy_bytes(dest: *mut u8, src: *const u8, n: usize) {
     core::arch::asm!("rep movsb",   // Move string block `rcx` number of times
             inout("rcx") n => _,        // move  value of n to rcx to repeat instruction n times
             inout("rdi") dest => _,
-            inout("rsi") src => _
+            inout("rsi") src => _,
+            options(nostack, preserves_flags)
         );
-    // Architecture independent code
-    // See: https://docs.rs/rlibc/1.0.0/src/rlibc/lib.rs.html#41-57
-    dest
+}
+
+
+/// Copy `blocks` 32 byte blocks through `ymm0`
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx")]
+unsafe fn copy_avx(dest: *mut u8, src: *const u8, blocks: usize) {
+    if blocks == 0 {
+        return;
+    }
+    // Clear the upper halves afterwards, leaving them dirty slows down any
+    // SSE code which runs next
+    core::arch::asm!(
+        "2:",
+        "vmovdqu ymm0, [{src}]",
+        "vmovdqu [{dest}], ymm0",
+        "add {src}, 32",
+        "add {dest}, 32",
+        "dec {blocks}",
+        "jnz 2b",
+        "vzeroupper",
+        src = inout(reg) src => _,
+        dest = inout(reg) dest => _,
+        blocks = inout(reg) blocks => _,
+        out("ymm0") _,
+        options(nostack),
+    );
+}
+
+
+/// Copy `n` bytes from `src` to `dest` with `method`
+/// The buffers must not overlap
+///
+/// Safety: as for `memcpy()`, and `method` must be supported by the processor
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn copy_with(method: CopyMethod, dest: *mut u8, src: *const u8, n: usize) {
+    match method {
+        CopyMethod::Bytes | CopyMethod::Erms => copy_bytes(dest, src, n),
+        CopyMethod::Words => {
+            core::arch::asm!("rep movsq",
+                    inout("rcx") n / 8 => _,
+                    inout("rdi") dest => _,
+                    inout("rsi") src => _,
+                    options(nostack, preserves_flags)
+                );
+            let done = n & !7;
+            copy_bytes(dest.add(done), src.add(done), n - done);
+        },
+        CopyMethod::Avx => {
+            copy_avx(dest, src, n / 32);
+            let done = n & !31;
+            copy_bytes(dest.add(done), src.add(done), n - done);
+        },
+    }
 }
 
 
@@ -44,7 +178,24 @@ pub unsafe extern fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8{
 /// s - Pointer to memory to set
 #[no_mangle]
 #[cfg(target_arch = "x86_64")]
-pub unsafe fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8{
+pub unsafe extern "C" fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8{
+    // Without ERMS large fills go 8 bytes at a time
+    if n >= LARGE && copy_method() != CopyMethod::Erms {
+        let pattern = (c as u8 as u64) * 0x0101_0101_0101_0101;
+        core::arch::asm!("rep stosq",
+                inout("rcx") n / 8 => _,
+                inout("rdi") s => _,
+                in("rax") pattern
+            );
+        let done = n & !7;
+        core::arch::asm!("rep stosb",
+                inout("rcx") n - done => _,
+                inout("rdi") s.add(done) => _,
+                in("eax") c as u32
+            );
+        return s;
+    }
+
     core::arch::asm!("rep stosb",
             inout("rcx") n => _,
             inout("rdi") s => _,
@@ -53,6 +204,72 @@ pub unsafe fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8{
     s
 }
 
+/// Portable `memcpy`, for targets without the assembly version
+#[no_mangle]
+#[cfg(not(target_arch = "x86_64"))]
+pub unsafe extern "C" fn memcpy(dest: *mut u8, src: *const u8, n: usize) -> *mut u8{
+    copy_forward(dest, src, n);
+    dest
+}
+
+
+/// Portable `memset`, for targets without the assembly version
+/// Fills a word at a time once `s` is aligned
+#[no_mangle]
+#[cfg(not(target_arch = "x86_64"))]
+pub unsafe extern "C" fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8{
+    const WORD: usize = core::mem::size_of::<usize>();
+    let pattern = (c as u8 as usize).wrapping_mul(usize::MAX / 0xff);
+
+    let mut i = 0;
+    while i < n && (s as usize).wrapping_add(i) % WORD != 0 {
+        core::ptr::write_volatile(s.add(i), c as u8);
+        i += 1;
+    }
+    while n - i >= WORD {
+        core::ptr::write_volatile(s.add(i) as *mut usize, pattern);
+        i += WORD;
+    }
+    while i < n {
+        core::ptr::write_volatile(s.add(i), c as u8);
+        i += 1;
+    }
+    s
+}
+
+
+/// Copy `n` bytes from `src` to `dest` front to back, for `memmove()`
+#[cfg(target_arch = "x86_64")]
+unsafe fn copy_forward(dest: *mut u8, src: *const u8, n: usize) {
+    memcpy(dest, src, n);
+}
+
+
+/// Copy `n` bytes from `src` to `dest` front to back, a word at a time once
+/// `dest` is aligned
+/// Stores are volatile so LLVM can't recognize the loops and turn them back
+/// into calls to `memcpy()`, which would be us
+#[cfg(not(target_arch = "x86_64"))]
+unsafe fn copy_forward(dest: *mut u8, src: *const u8, n: usize) {
+    const WORD: usize = core::mem::size_of::<usize>();
+
+    let mut i = 0;
+    while i < n && (dest as usize).wrapping_add(i) % WORD != 0 {
+        core::ptr::write_volatile(dest.add(i), *src.add(i));
+        i += 1;
+    }
+    while n - i >= WORD {
+        let val = core::ptr::read_unaligned(src.add(i) as *const usize);
+        core::ptr::write_volatile(dest.add(i) as *mut usize, val);
+        i += WORD;
+    }
+    while i < n {
+        core::ptr::write_volatile(dest.add(i), *src.add(i));
+        i += 1;
+    }
+}
+
+
 /// libc `memcmp` implementation in Rust
 /// Note that this is in accoradance with man memcmp(3)
 /// 
@@ -66,15 +283,15 @@ pub unsafe fn memset(s: *mut u8, c: i32, n: usize) -> *mut u8{
 /// The function returns an integer less than, equal to, or greater than zero if the first n bytes of s1 is found, respectively, to be less than, to match, or be greater than the first n bytes of s2.
 /// For  a  nonzero  return value, the sign is determined by the sign of the difference between the first pair of bytes that differ in s1 and s2.
 #[no_mangle]
-pub unsafe fn memcmp(s1: *const u8, s2: *const u8, n: usize)-> i32{
+pub unsafe extern "C" fn memcmp(s1: *const u8, s2: *const u8, n: usize)-> i32{
     if n==0 {
         return 0;
     }
 
     let mut i = 0;
     while i < n{
-        let a = *s1.offset(i as isize);
-        let b = *s2.offset(i as isize);
+        let a = *s1.add(i);
+        let b = *s2.add(i);
         if a != b {
             return (a as i32).wrapping_sub(b as i32);
         }
@@ -94,9 +311,8 @@ pub unsafe fn memcmp(s1: *const u8, s2: *const u8, n: usize)-> i32{
 ///
 /// Returns:
 /// dest: Pointer to memory to copy to
-
 #[no_mangle]
-pub unsafe extern fn memmove(dest: *mut u8, src: *const u8, mut n: usize) -> *mut u8{
+pub unsafe extern "C" fn memmove(dest: *mut u8, src: *const u8, mut n: usize) -> *mut u8{
     // Check if there is an overlap with the source coming prior to the dest
     // Even if there is an overlap, if the destination is earlier in memory than
     // the source, we can copy forwards
@@ -117,7 +333,7 @@ pub unsafe extern fn memmove(dest: *mut u8, src: *const u8, mut n: usize) -> *mu
             // See: https://stackoverflow.com/a/54307719
             while n != 0 && ((dest as usize).wrapping_add(n) & 0x7) != 0 {
                 n = n.wrapping_sub(1);
-                *dest.offset(n as isize) = *src.offset(n as isize);
+                *dest.add(n) = *src.add(n);
             }
 
             //  Do a reverse copy 8-bytes at a time
@@ -126,12 +342,12 @@ pub unsafe extern fn memmove(dest: *mut u8, src: *const u8, mut n: usize) -> *mu
                 
                 // Read value to copy
                 let val = core::ptr::read_unaligned(
-                    src.offset(n as isize) as *const u64
+                    src.add(n) as *const u64
                 );
 
                 // Write value to destination
                 core::ptr::write(
-                    dest.offset(n as isize) as *mut u64, val
+                    dest.add(n) as *mut u64, val
                 );
 
             }
@@ -139,7 +355,7 @@ pub unsafe extern fn memmove(dest: *mut u8, src: *const u8, mut n: usize) -> *mu
             // Copy the remainder
             while n != 0 {
                 n = n.wrapping_sub(1);
-                *dest.offset(n as isize) = *src.offset(n as isize);
+                *dest.add(n) = *src.add(n);
             }
 
             return dest;
@@ -151,9 +367,9 @@ pub unsafe extern fn memmove(dest: *mut u8, src: *const u8, mut n: usize) -> *mu
             n = n.wrapping_sub(delta);
 
             // Copy the remaining parts
-            let src = src.offset(n as isize);
-            let dest = dest.offset(n as isize);
-            memcpy(dest,src, delta);
+            let src = src.add(n);
+            let dest = dest.add(n);
+            copy_forward(dest,src, delta);
         }
 
         if n == 0 {
@@ -162,6 +378,58 @@ pub unsafe extern fn memmove(dest: *mut u8, src: *const u8, mut n: usize) -> *mu
     }
 
     // Just copy forward
-    memcpy(dest, src, n);
+    copy_forward(dest, src, n);
     dest
-}
\ No newline at end of file
+}
+
+/// Buffer sizes `bench()` copies
+#[cfg(target_arch = "x86_64")]
+const BENCH_SIZES: [usize; 4] = [4 << 10, 64 << 10, 1 << 20, 8 << 20];
+
+/// Timed runs of every method and size, after one untimed warm up run
+#[cfg(target_arch = "x86_64")]
+const BENCH_SAMPLES: usize = 15;
+
+
+/// Microbenchmark of the copy methods the processor supports
+/// Every method copies every size `BENCH_SAMPLES` times, the fastest and the
+/// median run are logged together with the throughput of the median, so the
+/// method `memcpy()` picks can be checked against the others
+#[cfg(target_arch = "x86_64")]
+pub fn bench() {
+    let hz = match crate::time::tsc_hz() {
+        Some(hz) => hz,
+        None => {
+            warn!("Memory benchmark needs a calibrated TSC");
+            return;
+        },
+    };
+
+    let max = BENCH_SIZES[BENCH_SIZES.len() - 1];
+    let src = alloc::vec![0x5au8; max];
+    let mut dest = alloc::vec![0u8; max];
+
+    let mut methods = alloc::vec![("rep movsb", CopyMethod::Bytes), ("rep movsq", CopyMethod::Words)];
+    if avx_enabled() {
+        methods.push(("avx", CopyMethod::Avx));
+    }
+    info!("memcpy uses {:?} for copies of {} bytes and up", copy_method(), LARGE);
+
+    for size in BENCH_SIZES {
+        for (name, method) in methods.iter() {
+            let mut samples = [0u64; BENCH_SAMPLES];
+            unsafe { copy_with(*method, dest.as_mut_ptr(), src.as_ptr(), size); }
+            for sample in samples.iter_mut() {
+                let start = crate::time::tsc::rdtsc();
+                unsafe { copy_with(*method, dest.as_mut_ptr(), src.as_ptr(), size); }
+                *sample = crate::time::tsc::rdtsc() - start;
+            }
+            samples.sort_unstable();
+
+            let (min, median) = (samples[0], samples[BENCH_SAMPLES / 2]);
+            let mib_per_s = ((size as u64 * hz) / core::cmp::max(median, 1)) >> 20;
+            info!("{:>7} KiB {:<9} min {:>6} ns median {:>6} ns {:>6} MiB/s",
+                size >> 10, name, min * 1_000_000_000 / hz, median * 1_000_000_000 / hz, mib_per_s);
+        }
+    }
+}
@@ -0,0 +1,994 @@
+//! Physical memory management
+//! Once boot services are exited the memory map tells us which physical memory
+//! is ours. Free memory is first collected as a set of ranges and then handed
+//! over to the buddy allocator, which serves all allocations from then on
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::efi::{MemoryMap, EFI_MEMORY_TYPE};
+use crate::sync::{OnceCell, SpinLock};
+
+pub mod bsguard;
+pub mod buddy;
+pub mod dma;
+pub mod heap;
+pub mod mmio;
+pub mod numa;
+pub mod paging;
+pub mod stack;
+pub mod virt;
+
+
+/// Size of a page
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Maximum number of disjoint ranges a `RangeSet` can hold
+const MAX_RANGES: usize = 256;
+
+/// Lowest start of the direct map, all physical memory is mapped at its
+/// physical address plus `direct_map_base()`, which KASLR slides up from here
+pub const DIRECT_MAP_BASE: u64 = 0xffff_8880_0000_0000;
+
+/// Size of the direct map window, physical memory above this isn't reachable
+/// through it
+pub const DIRECT_MAP_SIZE: u64 = 32 << 40;
+
+/// Granularity of the direct map slide, keeps 1 GiB pages usable
+const KASLR_ALIGN: u64 = 1 << 30;
+
+/// Lowest virtual base of the kernel image, which KASLR slides up from here
+pub const KERNEL_BASE: u64 = 0xffff_ffff_8000_0000;
+
+/// Size of the window the kernel image is slid around in
+const KERNEL_WINDOW: u64 = 1 << 30;
+
+/// Granularity of the kernel image slide
+const KERNEL_ALIGN: u64 = 2 << 20;
+
+/// Whether the direct map is installed, before that the firmware identity
+/// map is all there is
+static DIRECT_MAP: AtomicBool = AtomicBool::new(false);
+
+/// Start of the direct map for this boot
+static DIRECT_MAP_START: AtomicU64 = AtomicU64::new(DIRECT_MAP_BASE);
+
+/// Where the kernel image is mapped this boot
+static KERNEL_START: AtomicU64 = AtomicU64::new(KERNEL_BASE);
+
+/// Distance from the identity mapped kernel image to the slid one, 0 until
+/// the image has been relocated
+static KERNEL_SLIDE: AtomicU64 = AtomicU64::new(0);
+
+
+/// A physical address
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct PhysAddr(pub u64);
+
+impl PhysAddr {
+    #[allow(dead_code)]
+    pub const fn new(addr: u64) -> Self {
+        PhysAddr(addr)
+    }
+
+    #[allow(dead_code)]
+    pub const fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::LowerHex for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+
+/// A virtual address
+/// x86_64 only has 48 address bits, the upper 16 bits must be copies of bit
+/// 47 for an address to be canonical, i.e. usable
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct VirtAddr(pub u64);
+
+impl VirtAddr {
+    #[allow(dead_code)]
+    pub const fn new(addr: u64) -> Self {
+        VirtAddr(addr)
+    }
+
+    /// `addr` if it is canonical
+    pub const fn try_new(addr: u64) -> Option<Self> {
+        let addr = VirtAddr(addr);
+        if addr.is_canonical() {Some(addr)} else {None}
+    }
+
+    /// `addr` made canonical by sign extending bit 47
+    pub const fn new_truncate(addr: u64) -> Self {
+        VirtAddr((((addr << 16) as i64) >> 16) as u64)
+    }
+
+    #[allow(dead_code)]
+    pub const fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    /// Whether the address can be used
+    pub const fn is_canonical(&self) -> bool {
+        Self::new_truncate(self.0).0 == self.0
+    }
+
+    /// Whether the address is a multiple of `align`, a power of two
+    pub const fn is_aligned(&self, align: u64) -> bool {
+        self.0 & (align - 1) == 0
+    }
+
+    /// Round down to a multiple of `align`, a power of two
+    #[allow(dead_code)]
+    pub const fn align_down(&self, align: u64) -> Self {
+        VirtAddr(self.0 & !(align - 1))
+    }
+
+    /// Round up to a multiple of `align`, a power of two
+    /// `None` if that wraps
+    #[allow(dead_code)]
+    pub fn align_up(&self, align: u64) -> Option<Self> {
+        Some(VirtAddr(self.0.checked_add(align - 1)? & !(align - 1)))
+    }
+
+    /// `bytes` after the address, `None` if that wraps or isn't canonical
+    pub fn checked_add(&self, bytes: u64) -> Option<Self> {
+        Self::try_new(self.0.checked_add(bytes)?)
+    }
+
+    /// `bytes` before the address, `None` if that wraps or isn't canonical
+    #[allow(dead_code)]
+    pub fn checked_sub(&self, bytes: u64) -> Option<Self> {
+        Self::try_new(self.0.checked_sub(bytes)?)
+    }
+
+    /// Start of the page the address is in
+    #[allow(dead_code)]
+    pub const fn page(&self) -> Self {
+        self.align_down(PAGE_SIZE)
+    }
+
+    /// Offset of the address within its page
+    #[allow(dead_code)]
+    pub const fn page_offset(&self) -> u64 {
+        self.0 & (PAGE_SIZE - 1)
+    }
+
+    /// Index into the page table at `level` (4 is the PML4, 1 the page
+    /// table) used to translate the address
+    pub const fn table_index(&self, level: usize) -> usize {
+        ((self.0 >> (12 + 9 * (level - 1))) & 0x1ff) as usize
+    }
+}
+
+impl fmt::LowerHex for VirtAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+
+/// Pick where the direct map and the kernel image `kernel` go this boot
+/// The direct map slides up from `DIRECT_MAP_BASE` by a random number of GiB,
+/// up to where the MMIO window starts, and the kernel image up from
+/// `KERNEL_BASE` in 2 MiB steps within `KERNEL_WINDOW`, unless `nokaslr` is on
+/// the command line
+/// Called by `paging::init()` before the tables are built
+fn randomize_layout(kernel: Range) {
+    if DIRECT_MAP.load(Ordering::SeqCst) {return;}
+    if crate::cmdline::flag("nokaslr") {
+        info!("KASLR disabled, direct map at {:#x}, kernel at {:#x}", DIRECT_MAP_BASE, KERNEL_BASE);
+        return;
+    }
+
+    let mut seed = [0u8; 16];
+    if !crate::entropy::fill_bytes(&mut seed) {
+        warn!("No entropy for KASLR, direct map at {:#x}, kernel at {:#x}", DIRECT_MAP_BASE, KERNEL_BASE);
+        return;
+    }
+    let (direct, image) = seed.split_at(8);
+    let random = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap_or_default());
+
+    let slots = (mmio::MMIO_BASE - DIRECT_MAP_BASE - DIRECT_MAP_SIZE) / KASLR_ALIGN;
+    let slide = random(direct) % (slots + 1) * KASLR_ALIGN;
+    DIRECT_MAP_START.store(DIRECT_MAP_BASE + slide, Ordering::SeqCst);
+
+    // The image is mapped from the start of its first page
+    let size = (kernel.end - (kernel.start & !(PAGE_SIZE - 1))).next_multiple_of(KERNEL_ALIGN);
+    let slots = KERNEL_WINDOW.saturating_sub(size) / KERNEL_ALIGN;
+    let slide = random(image) % (slots + 1) * KERNEL_ALIGN;
+    KERNEL_START.store(KERNEL_BASE + slide, Ordering::SeqCst);
+
+    debug!("Direct map at {:#x}, kernel at {:#x}", direct_map_base(), kernel_base());
+}
+
+
+/// Start of the direct map for this boot
+pub fn direct_map_base() -> u64 {
+    DIRECT_MAP_START.load(Ordering::Relaxed)
+}
+
+
+/// Mark the direct map as installed
+/// Called by `paging::init()` once its tables are loaded
+fn enable_direct_map() {
+    DIRECT_MAP.store(true, Ordering::SeqCst);
+}
+
+
+/// Where the kernel image is mapped this boot
+pub fn kernel_base() -> u64 {
+    KERNEL_START.load(Ordering::Relaxed)
+}
+
+
+/// Distance from the identity mapped kernel image to the slid one, 0 while
+/// there only is the identity mapped one
+pub fn kernel_slide() -> u64 {
+    KERNEL_SLIDE.load(Ordering::Relaxed)
+}
+
+
+/// Mark the kernel image as relocated `slide` bytes up
+/// Called by `paging::init()` once its relocations have been applied
+fn enable_kernel_slide(slide: u64) {
+    KERNEL_SLIDE.store(slide, Ordering::SeqCst);
+}
+
+
+/// `f` in the slid kernel image, for leaving the identity mapped one
+/// Code from there on runs at the random base, `f` itself if the image
+/// couldn't be relocated
+pub fn slid(f: extern "C" fn() -> !) -> extern "C" fn() -> ! {
+    let addr = (f as usize).wrapping_add(kernel_slide() as usize);
+    unsafe { core::mem::transmute::<usize, extern "C" fn() -> !>(addr) }
+}
+
+
+/// Virtual address physical address `addr` can be accessed at
+/// In the direct map once it is installed, identity mapped before
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    if DIRECT_MAP.load(Ordering::Relaxed) && addr.0 < DIRECT_MAP_SIZE {
+        VirtAddr(direct_map_base() + addr.0)
+    } else {
+        VirtAddr(addr.0)
+    }
+}
+
+
+/// Physical address backing virtual address `virt`
+/// Direct map addresses are converted directly, anything else is looked up in
+/// the page tables, or taken as identity mapped if we don't have our own yet
+pub fn virt_to_phys(virt: VirtAddr) -> Option<PhysAddr> {
+    let base = direct_map_base();
+    if (base..base + DIRECT_MAP_SIZE).contains(&virt.0) {
+        return Some(PhysAddr(virt.0 - base));
+    }
+    match paging::kernel_space() {
+        Some(space) => space.translate(virt),
+        None => Some(PhysAddr(virt.0)),
+    }
+}
+
+
+/// Part of the kernel address space a virtual address belongs to, for fault
+/// reports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Region {
+    // The first page, a null pointer dereference
+    Null,
+
+    // The guard page below the stack of a core
+    StackGuard(u32),
+
+    // The stack of a core
+    Stack(u32),
+
+    // The MMIO window
+    Mmio,
+
+    // The direct map, which the heap and page tables live in
+    DirectMap,
+
+    // The slid kernel image
+    Kernel,
+
+    // Anything else: the identity mapped kernel image and firmware memory,
+    // or nothing at all
+    Other,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Region::Null => write!(f, "null page"),
+            Region::StackGuard(core) => write!(f, "stack guard page of core {}", core),
+            Region::Stack(core) => write!(f, "stack of core {}", core),
+            Region::Mmio => write!(f, "MMIO window"),
+            Region::DirectMap => write!(f, "direct map (heap)"),
+            Region::Kernel => write!(f, "kernel image"),
+            Region::Other => write!(f, "no known region"),
+        }
+    }
+}
+
+
+/// Region of the address space `addr` is in
+pub fn region(addr: VirtAddr) -> Region {
+    let base = direct_map_base();
+    if addr.0 < PAGE_SIZE {
+        Region::Null
+    } else if let Some(core) = stack::overflow_core(addr) {
+        Region::StackGuard(core)
+    } else if let Some(core) = stack::owner_core(addr) {
+        Region::Stack(core)
+    } else if mmio::contains(addr) {
+        Region::Mmio
+    } else if DIRECT_MAP.load(Ordering::Relaxed) && (base..base + DIRECT_MAP_SIZE).contains(&addr.0) {
+        Region::DirectMap
+    } else if kernel_slide() != 0 && (KERNEL_BASE..KERNEL_BASE + KERNEL_WINDOW).contains(&addr.0) {
+        Region::Kernel
+    } else {
+        Region::Other
+    }
+}
+
+
+/// Map `size` bytes of device registers at `phys` uncached
+/// Returns the virtual address `phys` is accessible at
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, paging::MapError> {
+    mmio::map(phys, size, virt::CacheType::Uncached)
+}
+
+
+/// Map `size` bytes of device memory at `phys` write-combining, for frame
+/// buffers and other memory which is only written in bulk
+#[allow(dead_code)]
+pub fn map_mmio_wc(phys: PhysAddr, size: u64) -> Result<VirtAddr, paging::MapError> {
+    mmio::map(phys, size, virt::CacheType::WriteCombining)
+}
+
+
+/// Pointer to physical address `addr`
+fn phys_ptr<T>(addr: PhysAddr) -> *mut T {
+    phys_to_virt(addr).0 as *mut T
+}
+
+
+/// Why a physical range can't be accessed, from `phys_problem()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PhysProblem {
+    // The range runs past the end of the address space
+    Wraps,
+
+    // It touches memory the firmware marked unusable
+    Unusable,
+
+    // It is outside the memory map and the low 4 GiB
+    Unknown,
+
+    // This page of it isn't mapped
+    Unmapped(u64),
+}
+
+
+/// What is wrong with the `len` bytes at `addr`, if anything, for a table
+/// parser or driver which is about to access them
+/// Before the memory map is known nothing is
+fn phys_problem(addr: PhysAddr, len: u64) -> Option<PhysProblem> {
+    let memory = match KNOWN_MEMORY.get() {
+        Some(memory) if len != 0 => memory,
+        _ => return None,
+    };
+
+    let range = match Range::new(addr.0, len) {
+        Some(range) => range,
+        None => return Some(PhysProblem::Wraps),
+    };
+    let (known, unusable) = (&memory.known, &memory.unusable);
+    if unusable.overlaps(range) {
+        return Some(PhysProblem::Unusable);
+    }
+    if !known.contains(range) {
+        return Some(PhysProblem::Unknown);
+    }
+
+    if let Some(space) = paging::kernel_space() {
+        let mut page = range.start & !(PAGE_SIZE - 1);
+        while page <= range.end {
+            if space.translate(phys_to_virt(PhysAddr(page))).is_none() {
+                return Some(PhysProblem::Unmapped(page));
+            }
+            page = match page.checked_add(PAGE_SIZE) {
+                Some(page) => page,
+                None => break,
+            };
+        }
+    }
+    None
+}
+
+
+/// Whether the `len` bytes at `addr` can be read with `read_phys()`, for
+/// parsers of firmware tables which must not trust the addresses in them
+pub fn phys_readable(addr: PhysAddr, len: u64) -> bool {
+    phys_problem(addr, len).is_none()
+}
+
+
+/// Panic if the `len` bytes at `addr` a table parser or driver is about to
+/// `access` aren't memory we know and can reach: outside the memory map and
+/// the low 4 GiB, in memory the firmware marked unusable or not mapped
+/// Debug builds only, and only once the memory map is known
+#[track_caller]
+#[allow(unused_variables)]
+fn check_phys(addr: PhysAddr, len: u64, access: &str) {
+    #[cfg(debug_assertions)]
+    {
+        let end = addr.0.wrapping_add(len).wrapping_sub(1);
+        match phys_problem(addr, len) {
+            None => (),
+            Some(PhysProblem::Wraps) => panic!("{} of {} bytes at {:#x} wraps around", access, len, addr.0),
+            Some(PhysProblem::Unusable) => {
+                panic!("{} of {:#x}-{:#x} touches memory the firmware marked unusable", access, addr.0, end)
+            },
+            Some(PhysProblem::Unknown) => panic!("{} of {:#x}-{:#x} is outside the memory map", access, addr.0, end),
+            Some(PhysProblem::Unmapped(page)) => {
+                panic!("{} of {:#x}-{:#x} hits unmapped page {:#x}", access, addr.0, end, page)
+            },
+        }
+    }
+}
+
+
+/// Read a `T` from physical address `addr`
+/// Debug builds check the address against the memory map
+///
+/// Safety: the range must be backed by memory which is safe to read as a `T`
+#[track_caller]
+pub unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
+    check_phys(addr, core::mem::size_of::<T>() as u64, "read_phys");
+    core::ptr::read_unaligned(phys_ptr::<T>(addr))
+}
+
+
+/// Write `val` to physical address `addr`
+/// Debug builds check the address against the memory map
+///
+/// Safety: the range must be backed by memory nothing else relies on staying
+/// the same
+#[track_caller]
+#[allow(dead_code)]
+pub unsafe fn write_phys<T: Copy>(addr: PhysAddr, val: T) {
+    check_phys(addr, core::mem::size_of::<T>() as u64, "write_phys");
+    core::ptr::write_unaligned(phys_ptr::<T>(addr), val)
+}
+
+
+/// Copy `buf.len()` bytes starting at physical address `addr` into `buf`
+///
+/// Safety: as for `read_phys()`
+#[track_caller]
+pub unsafe fn read_phys_slice(addr: PhysAddr, buf: &mut [u8]) {
+    check_phys(addr, buf.len() as u64, "read_phys_slice");
+    core::ptr::copy_nonoverlapping(phys_ptr::<u8>(addr), buf.as_mut_ptr(), buf.len());
+}
+
+
+/// Copy `buf` to physical address `addr`
+///
+/// Safety: as for `write_phys()`
+#[track_caller]
+#[allow(dead_code)]
+pub unsafe fn write_phys_slice(addr: PhysAddr, buf: &[u8]) {
+    check_phys(addr, buf.len() as u64, "write_phys_slice");
+    core::ptr::copy_nonoverlapping(buf.as_ptr(), phys_ptr::<u8>(addr), buf.len());
+}
+
+
+/// A bounded window of physical memory read front to back, e.g. a firmware
+/// table
+/// Every access is checked against the bounds and fails instead of running
+/// off the end, and the position can't over- or underflow
+#[derive(Clone, Copy, Debug)]
+pub struct PhysSlice {
+    // First byte of the window
+    base: PhysAddr,
+
+    // Size of the window in bytes
+    len: u64,
+
+    // Offset of the next read
+    pos: u64,
+}
+
+impl PhysSlice {
+    /// Window of `len` bytes at `base`
+    ///
+    /// Safety: as for `read_phys()`, for all `len` bytes
+    #[allow(dead_code)]
+    pub unsafe fn new(base: PhysAddr, len: u64) -> Option<PhysSlice> {
+        base.0.checked_add(len)?;
+        Some(PhysSlice { base, len, pos: 0 })
+    }
+
+    /// Size of the window in bytes
+    #[allow(dead_code)]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Offset of the next read
+    #[allow(dead_code)]
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    /// Bytes left after the position
+    #[allow(dead_code)]
+    pub fn remaining(&self) -> u64 {
+        self.len - self.pos
+    }
+
+    /// Move the position to `pos`, which may be the end of the window
+    #[allow(dead_code)]
+    pub fn seek(&mut self, pos: u64) -> Option<()> {
+        if pos > self.len {
+            return None;
+        }
+        self.pos = pos;
+        Some(())
+    }
+
+    /// Move the position forward by `count` bytes
+    #[allow(dead_code)]
+    pub fn skip(&mut self, count: u64) -> Option<()> {
+        self.seek(self.pos.checked_add(count)?)
+    }
+
+    /// Move the position back by `count` bytes
+    #[allow(dead_code)]
+    pub fn rewind(&mut self, count: u64) -> Option<()> {
+        self.seek(self.pos.checked_sub(count)?)
+    }
+
+    /// Address of `size` bytes at `offset`, if they are in the window
+    fn addr(&self, offset: u64, size: u64) -> Option<PhysAddr> {
+        if offset.checked_add(size)? > self.len {
+            return None;
+        }
+        Some(PhysAddr(self.base.0 + offset))
+    }
+
+    /// Read a `T` at `offset` without moving the position
+    #[allow(dead_code)]
+    pub fn read_at<T: Copy>(&self, offset: u64) -> Option<T> {
+        let addr = self.addr(offset, core::mem::size_of::<T>() as u64)?;
+        Some(unsafe { read_phys(addr) })
+    }
+
+    /// Read a `T` at the position without moving it
+    #[allow(dead_code)]
+    pub fn peek<T: Copy>(&self) -> Option<T> {
+        self.read_at(self.pos)
+    }
+
+    /// Read a `T` at the position and move past it
+    #[allow(dead_code)]
+    pub fn read<T: Copy>(&mut self) -> Option<T> {
+        let val = self.peek()?;
+        self.pos += core::mem::size_of::<T>() as u64;
+        Some(val)
+    }
+
+    /// Fill `buf` from the position and move past it
+    #[allow(dead_code)]
+    pub fn read_bytes(&mut self, buf: &mut [u8]) -> Option<()> {
+        let addr = self.addr(self.pos, buf.len() as u64)?;
+        unsafe {
+            read_phys_slice(addr, buf);
+        }
+        self.pos += buf.len() as u64;
+        Some(())
+    }
+
+    /// Window of `len` bytes at the position, moving past it
+    /// Useful to walk nested structures which carry their own length
+    #[allow(dead_code)]
+    pub fn split(&mut self, len: u64) -> Option<PhysSlice> {
+        let addr = self.addr(self.pos, len)?;
+        self.pos += len;
+        Some(PhysSlice { base: addr, len, pos: 0 })
+    }
+}
+
+
+/// An inclusive range of addresses
+/// Inclusive so a range can reach the very top of the address space
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Range {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Range {
+    /// Range of `size` bytes starting at `start`, `None` if `size` is 0 or the
+    /// range would wrap
+    pub fn new(start: u64, size: u64) -> Option<Range> {
+        let end = start.checked_add(size.checked_sub(1)?)?;
+        Some(Range { start, end })
+    }
+
+    /// Number of bytes in the range
+    /// Saturates for the full 64-bit address space
+    pub fn size(&self) -> u64 {
+        (self.end - self.start).saturating_add(1)
+    }
+
+    /// Whether the ranges share at least one address
+    pub fn overlaps(&self, other: &Range) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    /// Whether the ranges overlap or touch, i.e. can be merged into one
+    fn mergeable(&self, other: &Range) -> bool {
+        self.overlaps(other) ||
+            self.end.checked_add(1) == Some(other.start) ||
+            other.end.checked_add(1) == Some(self.start)
+    }
+}
+
+
+/// A set of non-overlapping ranges, kept sorted and merged
+#[derive(Clone, Copy)]
+pub struct RangeSet {
+    ranges: [Range; MAX_RANGES],
+    in_use: usize,
+}
+
+impl RangeSet {
+    pub const fn new() -> Self {
+        RangeSet {
+            ranges: [Range { start: 0, end: 0 }; MAX_RANGES],
+            in_use: 0,
+        }
+    }
+
+    /// The ranges in the set, sorted by address
+    pub fn entries(&self) -> &[Range] {
+        &self.ranges[..self.in_use]
+    }
+
+    /// Total number of bytes in the set
+    pub fn sum(&self) -> u64 {
+        self.entries().iter().fold(0u64, |sum, range| sum.saturating_add(range.size()))
+    }
+
+    /// Whether all of `range` is in the set
+    pub fn contains(&self, range: Range) -> bool {
+        self.entries().iter().any(|ent| ent.start <= range.start && range.end <= ent.end)
+    }
+
+    /// Whether any part of `range` is in the set
+    pub fn overlaps(&self, range: Range) -> bool {
+        self.entries().iter().any(|ent| ent.overlaps(&range))
+    }
+
+    /// Remove the entry at `index`
+    fn delete(&mut self, index: usize) {
+        self.ranges.copy_within(index + 1..self.in_use, index);
+        self.in_use -= 1;
+    }
+
+    /// Insert `range` at `index`, which must keep the set sorted
+    fn insert_at(&mut self, index: usize, range: Range) -> bool {
+        if self.in_use == MAX_RANGES {
+            return false;
+        }
+        self.ranges.copy_within(index..self.in_use, index + 1);
+        self.ranges[index] = range;
+        self.in_use += 1;
+        true
+    }
+
+    /// Add `range` to the set, merging it with the ranges it overlaps or touches
+    /// Returns false if the set is full
+    pub fn insert(&mut self, mut range: Range) -> bool {
+        // Swallow every entry we can merge with
+        let mut ii = 0;
+        while ii < self.in_use {
+            let ent = self.ranges[ii];
+            if ent.mergeable(&range) {
+                range.start = core::cmp::min(range.start, ent.start);
+                range.end = core::cmp::max(range.end, ent.end);
+                self.delete(ii);
+            } else {
+                ii += 1;
+            }
+        }
+
+        let index = self.entries().partition_point(|ent| ent.start < range.start);
+        self.insert_at(index, range)
+    }
+
+    /// Take `range` out of the set, splitting entries as needed
+    /// Returns false if the set is full and an entry could not be split, in
+    /// which case the part of `range` past the failed split stays in the set
+    pub fn remove(&mut self, range: Range) -> bool {
+        let mut ii = 0;
+        while ii < self.in_use {
+            let ent = self.ranges[ii];
+            if !ent.overlaps(&range) {
+                ii += 1;
+                continue;
+            }
+
+            // What is left of the entry below and above the removed range
+            let below = (ent.start < range.start).then(|| Range { start: ent.start, end: range.start - 1 });
+            let above = (range.end < ent.end).then(|| Range { start: range.end + 1, end: ent.end });
+
+            match (below, above) {
+                (Some(below), Some(above)) => {
+                    self.ranges[ii] = below;
+                    if !self.insert_at(ii + 1, above) {
+                        return false;
+                    }
+                    ii += 2;
+                },
+                (Some(part), None) | (None, Some(part)) => {
+                    self.ranges[ii] = part;
+                    ii += 1;
+                },
+                (None, None) => self.delete(ii),
+            }
+        }
+
+        true
+    }
+
+    /// Carve `size` bytes aligned to `align` out of the set
+    /// Uses the smallest range the allocation fits in to keep big ranges intact
+    /// `align` must be a power of two
+    pub fn allocate(&mut self, size: u64, align: u64) -> Option<u64> {
+        if size == 0 || !align.is_power_of_two() {
+            return None;
+        }
+
+        let mut best: Option<(Range, u64)> = None;
+        for ent in self.entries() {
+            let start = match ent.start.checked_add(align - 1) {
+                Some(addr) => addr & !(align - 1),
+                None => continue,
+            };
+            let fits = Range::new(start, size).is_some_and(|range| range.end <= ent.end);
+
+            if fits && best.is_none_or(|(best, _)| ent.size() < best.size()) {
+                best = Some((*ent, start));
+            }
+        }
+
+        let (_, start) = best?;
+        let range = Range::new(start, size)?;
+        if !self.remove(range) {
+            // Couldn't split, give back what was taken
+            self.insert(range);
+            return None;
+        }
+
+        Some(start)
+    }
+}
+
+
+/// Free physical memory, until the buddy allocator takes it over
+static PHYS_MEMORY: SpinLock<RangeSet> = SpinLock::new(RangeSet::new());
+
+/// Set by the one `init()` which populates `PHYS_MEMORY`
+static PHYS_MEMORY_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Pages of the firmware page tables, held back from the allocator until
+/// they are no longer loaded
+static FIRMWARE_TABLES: SpinLock<RangeSet> = SpinLock::new(RangeSet::new());
+
+
+/// Physical memory the memory map knows about, which debug builds check
+/// every `read_phys()` and `write_phys()` against
+struct KnownMemory {
+    // Everything in the map and all of the low 4 GiB, which has MMIO the map
+    // doesn't list
+    known: RangeSet,
+
+    // Memory the firmware found errors in
+    unusable: RangeSet,
+}
+
+/// Set once by `init()`
+static KNOWN_MEMORY: OnceCell<KnownMemory> = OnceCell::new();
+
+
+/// Whether memory of this type must never be handed out, even if the map
+/// claims it overlaps free memory
+fn reserved(typ: EFI_MEMORY_TYPE) -> bool {
+    matches!(typ,
+        EFI_MEMORY_TYPE::EfiACPIReclaimMemory |
+        EFI_MEMORY_TYPE::EfiACPIMemoryNVS |
+        EFI_MEMORY_TYPE::EfiMemoryMappedIO |
+        EFI_MEMORY_TYPE::EfiMemoryMappedIOPortSpace |
+        EFI_MEMORY_TYPE::EfiRuntimeServiceCode |
+        EFI_MEMORY_TYPE::EfiRuntimeServicesData |
+        EFI_MEMORY_TYPE::EfiUnusableMemory)
+}
+
+
+/// Populate the free memory from the final memory `map`
+/// `kernel` is the memory our image occupies, which is kept out of the free
+/// memory along with the stack we are running on
+/// Must be called once, right after boot services have been exited
+pub fn init(map: &MemoryMap, kernel: Range) {
+    // Only ever populate once
+    if PHYS_MEMORY_INITIALIZED.swap(true, Ordering::SeqCst) {return;}
+
+    // The stack the firmware gave us is boot services data, which would
+    // otherwise look free
+    let rsp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+
+    {
+        let mut free = PHYS_MEMORY.lock();
+        let free = &mut *free;
+        for desc in map.iter() {
+            let typ: EFI_MEMORY_TYPE = desc.Type.into();
+            if !typ.avail_post_exit_boot_services() {continue;}
+
+            if let Some(range) = Range::new(desc.PhysicalAddress, desc.NumberOfPages * PAGE_SIZE) {
+                if !free.insert(range) {
+                    warn!("Out of free ranges, dropping {:#x}-{:#x}", range.start, range.end);
+                }
+            }
+        }
+
+        // Firmware maps have been seen with overlapping entries, make sure
+        // nothing reserved ends up free
+        for desc in map.iter() {
+            let typ: EFI_MEMORY_TYPE = desc.Type.into();
+            let range = match Range::new(desc.PhysicalAddress, desc.NumberOfPages * PAGE_SIZE) {
+                Some(range) => range,
+                None => continue,
+            };
+
+            if reserved(typ) || (range.start <= rsp && rsp <= range.end) {
+                free.remove(range);
+            }
+        }
+
+        free.remove(kernel);
+
+        // We still run on the firmware's page tables, which are in boot
+        // services memory, until `paging::init()` has loaded ours. Only what
+        // would be free is given back later
+        let tables = match paging::current_table_pages() {
+            Some(tables) => tables,
+            None => panic!("Too many firmware page table pages to keep track of"),
+        };
+        let mut held = RangeSet::new();
+        for range in tables.entries() {
+            for part in free.entries().iter().filter(|part| part.overlaps(range)) {
+                let part = Range {
+                    start: core::cmp::max(part.start, range.start),
+                    end: core::cmp::min(part.end, range.end),
+                };
+                if !held.insert(part) {
+                    panic!("Too many firmware page table pages to keep track of");
+                }
+            }
+        }
+        for range in held.entries() {
+            if !free.remove(*range) {
+                panic!("Out of free ranges holding back the firmware page tables");
+            }
+        }
+        *FIRMWARE_TABLES.lock() = held;
+
+        // Never hand out the null page
+        free.remove(Range { start: 0, end: PAGE_SIZE - 1 });
+
+        bsguard::hold(map, free);
+
+        if !buddy::init(free) {
+            error!("Could not set up the page frame allocator");
+        }
+    }
+
+    let mut memory = KnownMemory { known: RangeSet::new(), unusable: RangeSet::new() };
+    let (known, unusable) = (&mut memory.known, &mut memory.unusable);
+    known.insert(Range { start: 0, end: (4 << 30) - 1 });
+    for desc in map.iter() {
+        if let Some(range) = Range::new(desc.PhysicalAddress, desc.NumberOfPages * PAGE_SIZE) {
+            known.insert(range);
+            if EFI_MEMORY_TYPE::from(desc.Type) == EFI_MEMORY_TYPE::EfiUnusableMemory {
+                unusable.insert(range);
+            }
+        }
+    }
+    let _ = KNOWN_MEMORY.set(memory);
+}
+
+
+/// Give the firmware page tables to the allocator
+/// Called by `paging::init()` once our own tables are loaded
+fn release_firmware_tables() {
+    let tables = core::mem::replace(&mut *FIRMWARE_TABLES.lock(), RangeSet::new());
+    for range in tables.entries() {
+        free_phys(*range);
+    }
+    debug!("Released {} KiB of firmware page tables", tables.sum() >> 10);
+}
+
+
+/// Allocate `size` bytes of physically contiguous memory aligned to `align`
+/// Sizes are rounded up to whole pages and allocations are at least page
+/// aligned, `align` must be a power of two
+/// Once the buddy allocator is up allocations are limited to its largest block
+pub fn alloc_phys(size: u64, align: u64) -> Option<PhysAddr> {
+    let size = size.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+    let align = core::cmp::max(align, PAGE_SIZE);
+
+    if buddy::initialized() {
+        // Prefer memory close to us
+        return alloc_block(size, align, |order| numa::alloc_local(order).or_else(|| buddy::alloc(order)));
+    }
+
+    PHYS_MEMORY.lock().allocate(size, align).map(PhysAddr)
+}
+
+
+/// Allocate `size` bytes of physically contiguous, page aligned memory on
+/// NUMA `node`
+/// Fails if the node has no memory left rather than using another node
+#[allow(dead_code)]
+pub fn alloc_phys_on_node(node: u32, size: u64) -> Option<PhysAddr> {
+    let size = size.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+    alloc_block(size, PAGE_SIZE, |order| numa::alloc_on(node, order))
+}
+
+
+/// Allocate `size` bytes aligned to `align` as a buddy block from `alloc`
+/// Buddy blocks are aligned to their size, so this rounds up to a block big
+/// enough for both and gives back the pages we don't need
+fn alloc_block(size: u64, align: u64, alloc: impl FnOnce(usize) -> Option<PhysAddr>) -> Option<PhysAddr> {
+    let order = buddy::order_for(core::cmp::max(size, align))?;
+    let addr = alloc(order)?;
+    if let Some(tail) = Range::new(addr.0 + size, buddy::block_size(order) - size) {
+        buddy::free_range(tail);
+    }
+    Some(addr)
+}
+
+
+/// Give memory back to the free memory
+/// Returns false if the range was (partly) free already, which is a double
+/// free and leaves the free memory untouched, or if there was no room to
+/// track it
+pub fn free_phys(range: Range) -> bool {
+    if buddy::initialized() {
+        return buddy::free_range(range);
+    }
+
+    let mut free = PHYS_MEMORY.lock();
+    if free.overlaps(range) {
+        return false;
+    }
+    free.insert(range)
+}
+
+
+/// Number of free bytes of physical memory
+pub fn free_bytes() -> u64 {
+    buddy::stats().free_bytes() + PHYS_MEMORY.lock().sum()
+}
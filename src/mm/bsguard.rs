@@ -0,0 +1,125 @@
+//! Boot services memory guard
+//! Debug aid enabled with `--bs-guard` on the command line. Memory typed
+//! EfiBootServicesCode/Data is ours after ExitBootServices, but anything still
+//! pointing into firmware structures (a cached protocol, ConOut...) would then
+//! silently corrupt whatever the allocator put there. With the guard on that
+//! memory is held back from the allocator and left unmapped until `release()`,
+//! so such accesses fault instead
+//!
+//! The firmware GDT and IDT live in boot services memory, but `gdt::init()`
+//! and `interrupts::init()` have replaced them by the time we get here, so
+//! the faults end up in the kernel IDT and get reported as page faults. The
+//! pages of whichever tables are loaded are still kept mapped and never
+//! recycled
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::{paging, Range, RangeSet, PAGE_SIZE};
+use crate::efi::{MemoryMap, EFI_MEMORY_TYPE};
+use crate::sync::SpinLock;
+
+
+/// Boot services memory held back
+static GUARDED: SpinLock<RangeSet> = SpinLock::new(RangeSet::new());
+
+/// Whether `GUARDED` is in use, until `release()`
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+
+/// Pages holding the descriptor table whose base and limit `sgdt`/`sidt`
+/// stored at `desc`
+fn table_pages(desc: &[u8; 10]) -> Option<Range> {
+    let limit = u16::from_le_bytes([desc[0], desc[1]]) as u64;
+    let base = u64::from_le_bytes(desc[2..10].try_into().ok()?);
+    let start = base & !(PAGE_SIZE - 1);
+    let end = base.checked_add(limit)? | (PAGE_SIZE - 1);
+    Some(Range { start, end })
+}
+
+
+/// Move the boot services memory in `free` over to the guard if `--bs-guard`
+/// is on the command line
+/// Called by `mm::init()` before the free memory is handed to the allocator
+pub(super) fn hold(map: &MemoryMap, free: &mut RangeSet) {
+    if !crate::cmdline::flag("--bs-guard") {return;}
+
+    let (mut gdt, mut idt) = ([0u8; 10], [0u8; 10]);
+    unsafe {
+        core::arch::asm!("sgdt [{}]", in(reg) gdt.as_mut_ptr(), options(nostack, preserves_flags));
+        core::arch::asm!("sidt [{}]", in(reg) idt.as_mut_ptr(), options(nostack, preserves_flags));
+    }
+
+    let held = {
+        let mut guarded = GUARDED.lock();
+        for desc in map.iter() {
+            let typ: EFI_MEMORY_TYPE = desc.Type.into();
+            if !matches!(typ, EFI_MEMORY_TYPE::EfiBootServicesCode | EFI_MEMORY_TYPE::EfiBootServicesData) {
+                continue;
+            }
+
+            // Only what is actually free, which leaves out our stack
+            let range = match Range::new(desc.PhysicalAddress, desc.NumberOfPages * PAGE_SIZE) {
+                Some(range) => range,
+                None => continue,
+            };
+            for part in free.entries().iter().filter(|part| part.overlaps(&range)) {
+                guarded.insert(Range {
+                    start: core::cmp::max(part.start, range.start),
+                    end: core::cmp::min(part.end, range.end),
+                });
+            }
+        }
+
+        // Still in use by the processor
+        for table in [table_pages(&gdt), table_pages(&idt)].into_iter().flatten() {
+            free.remove(table);
+            guarded.remove(table);
+        }
+
+        for range in guarded.entries() {
+            free.remove(*range);
+        }
+        guarded.sum()
+    };
+
+    ENABLED.store(true, Ordering::SeqCst);
+    info!("Holding back {} KiB of boot services memory", held >> 10);
+}
+
+
+/// Whether boot services memory is currently held back
+#[allow(dead_code)]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+
+/// Boot services memory currently held back, which must stay unmapped
+pub fn held() -> RangeSet {
+    *GUARDED.lock()
+}
+
+
+/// End the verification period: map the held back memory and give it to
+/// the allocator
+pub fn release() {
+    if !ENABLED.swap(false, Ordering::SeqCst) {return;}
+
+    let held = core::mem::replace(&mut *GUARDED.lock(), RangeSet::new());
+    let space = paging::kernel_space();
+
+    for range in held.entries() {
+        if let Some(space) = space {
+            let flags = paging::Flags::WRITABLE | paging::Flags::NO_EXECUTE;
+            let mapped = paging::lock_tables(|lock, irq| {
+                space.identity_map(*range, flags, lock, irq)?;
+                space.direct_map(*range, flags, lock, irq)
+            });
+            if let Err(err) = mapped {
+                error!("Could not map {:#x}-{:#x}: {:?}", range.start, range.end, err);
+                continue;
+            }
+        }
+        super::free_phys(*range);
+    }
+
+    info!("Released {} KiB of boot services memory", held.sum() >> 10);
+}
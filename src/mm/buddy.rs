@@ -0,0 +1,402 @@
+//! Buddy page frame allocator
+//! Free memory is kept as naturally aligned blocks of 2^order pages, from 4 KiB
+//! (order 0) up to 1 GiB (`MAX_ORDER`), on one free list per order. Allocating
+//! splits a bigger block in halves until one of the right order is left, and
+//! freeing merges a block with its buddy (the other half of the block they were
+//! split from) for as long as the buddy is free as well
+//!
+//! The free lists are threaded through the free blocks themselves. One bitmap
+//! per order records which blocks are on a free list, which is what tells us
+//! whether a buddy can be merged and catches double frees
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::{phys_to_virt, PhysAddr, Range, RangeSet, PAGE_SIZE};
+use crate::sync::{InIrqContext, LockHeld, SpinLock};
+
+
+/// Largest block order, 2^18 pages is 1 GiB
+pub const MAX_ORDER: usize = 18;
+
+/// Number of orders
+const ORDERS: usize = MAX_ORDER + 1;
+
+/// Marks the end of a free list, the null page is never handed out so it can
+/// never be on a free list itself
+const NONE: u64 = 0;
+
+
+/// Size of a block of `order` in bytes
+pub const fn block_size(order: usize) -> u64 {
+    PAGE_SIZE << order
+}
+
+
+/// Smallest order whose blocks hold `size` bytes
+pub fn order_for(size: u64) -> Option<usize> {
+    (0..ORDERS).find(|order| block_size(*order) >= size)
+}
+
+
+/// Allocator statistics
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    // Number of free blocks of each order
+    pub free_blocks: [u64; ORDERS],
+
+    // Successful allocations and frees
+    pub allocs: u64,
+    pub frees: u64,
+
+    // Allocations which could not be satisfied
+    pub failed: u64,
+
+    // Frees of memory which was free already, these are refused
+    pub double_frees: u64,
+}
+
+impl Stats {
+    /// Number of free bytes
+    pub fn free_bytes(&self) -> u64 {
+        self.free_blocks.iter().enumerate()
+            .map(|(order, count)| count * block_size(order))
+            .sum()
+    }
+}
+
+
+/// Links of a free block, stored at the start of the block
+#[repr(C)]
+struct FreeBlock {
+    next: u64,
+    prev: u64,
+}
+
+
+/// The allocator state
+struct Buddy {
+    // First free block of each order
+    heads: [u64; ORDERS],
+
+    // Free bitmap of each order, one bit per block
+    bitmaps: [*mut u64; ORDERS],
+
+    // End of the memory covered by the bitmaps
+    end: u64,
+
+    stats: Stats,
+}
+
+// The bitmaps are only touched with the allocator lock held
+unsafe impl Send for Buddy {}
+
+impl Buddy {
+    /// Access the links of the free block at `addr`
+    fn block(&self, addr: u64) -> *mut FreeBlock {
+        phys_to_virt(PhysAddr(addr)).0 as *mut FreeBlock
+    }
+
+    /// Bitmap word and bit for the block at `addr`
+    fn bit(&self, addr: u64, order: usize) -> (*mut u64, u64) {
+        let index = addr / block_size(order);
+        (unsafe { self.bitmaps[order].add((index / 64) as usize) }, 1 << (index % 64))
+    }
+
+    /// Whether the block at `addr` is on the free list of `order`
+    fn is_free(&self, addr: u64, order: usize) -> bool {
+        if addr.saturating_add(block_size(order)) > self.end {
+            return false;
+        }
+        let (word, mask) = self.bit(addr, order);
+        unsafe { *word & mask != 0 }
+    }
+
+    /// Whether any free block contains the block at `addr`
+    fn covered(&self, addr: u64, order: usize) -> bool {
+        (order..ORDERS).any(|order| self.is_free(addr & !(block_size(order) - 1), order))
+    }
+
+    /// Put the block at `addr` on the free list of `order`
+    fn push(&mut self, addr: u64, order: usize) {
+        let head = self.heads[order];
+        unsafe {
+            *self.block(addr) = FreeBlock { next: head, prev: NONE };
+            if head != NONE {
+                (*self.block(head)).prev = addr;
+            }
+
+            let (word, mask) = self.bit(addr, order);
+            *word |= mask;
+        }
+        self.heads[order] = addr;
+        self.stats.free_blocks[order] += 1;
+    }
+
+    /// Take the block at `addr` off the free list of `order`
+    fn unlink(&mut self, addr: u64, order: usize) {
+        unsafe {
+            let FreeBlock { next, prev } = core::ptr::read(self.block(addr));
+            if prev == NONE {
+                self.heads[order] = next;
+            } else {
+                (*self.block(prev)).next = next;
+            }
+            if next != NONE {
+                (*self.block(next)).prev = prev;
+            }
+
+            let (word, mask) = self.bit(addr, order);
+            *word &= !mask;
+        }
+        self.stats.free_blocks[order] -= 1;
+    }
+
+    /// Take the free block of `found` at `block` off its free list and split
+    /// it down to the block of `order` at `target`, freeing the other halves
+    fn take(&mut self, block: u64, found: usize, target: u64, order: usize) {
+        self.unlink(block, found);
+
+        let mut addr = block;
+        for split in (order..found).rev() {
+            let half = block_size(split);
+            if target >= addr + half {
+                self.push(addr, split);
+                addr += half;
+            } else {
+                self.push(addr + half, split);
+            }
+        }
+    }
+
+    /// Allocate a block of `order`
+    fn alloc(&mut self, order: usize, _lock: &LockHeld<BuddyLock>, _irq: &InIrqContext) -> Option<u64> {
+        // Smallest free block which is big enough
+        let found = match (order..ORDERS).find(|order| self.heads[*order] != NONE) {
+            Some(found) => found,
+            None => {
+                self.stats.failed += 1;
+                return None;
+            },
+        };
+
+        let addr = self.heads[found];
+        self.take(addr, found, addr, order);
+
+        self.stats.allocs += 1;
+        Some(addr)
+    }
+
+    /// Allocate a block of `order` which lies within `range`
+    /// Free lists are searched from the smallest fitting order up, which is
+    /// slower than `alloc()` as blocks outside `range` have to be skipped
+    fn alloc_in(&mut self, order: usize, range: Range, _lock: &LockHeld<BuddyLock>, _irq: &InIrqContext)
+        -> Option<u64> {
+        let size = block_size(order);
+
+        for found in order..ORDERS {
+            let mut block = self.heads[found];
+            while block != NONE {
+                // First block of `order` in both the free block and the range
+                let end = core::cmp::min(block + (block_size(found) - 1), range.end);
+                let start = core::cmp::max(block, range.start).checked_add(size - 1).map(|addr| addr & !(size - 1));
+
+                if let Some(start) = start.filter(|start| start.checked_add(size - 1).is_some_and(|last| last <= end)) {
+                    self.take(block, found, start, order);
+                    self.stats.allocs += 1;
+                    return Some(start);
+                }
+
+                block = unsafe { (*self.block(block)).next };
+            }
+        }
+
+        // Not counted as failed, callers fall back to other memory
+        None
+    }
+
+    /// Free the block of `order` at `addr`, merging it with its buddies
+    /// Returns false if the block is misaligned, out of range or (partly)
+    /// free already
+    fn free(&mut self, mut addr: u64, mut order: usize, _lock: &LockHeld<BuddyLock>, _irq: &InIrqContext) -> bool {
+        if !addr.is_multiple_of(block_size(order)) || addr.saturating_add(block_size(order)) > self.end || addr == NONE {
+            return false;
+        }
+
+        if self.covered(addr, order) {
+            self.stats.double_frees += 1;
+            return false;
+        }
+
+        while order < MAX_ORDER {
+            let buddy = addr ^ block_size(order);
+            if !self.is_free(buddy, order) {
+                break;
+            }
+
+            self.unlink(buddy, order);
+            addr = core::cmp::min(addr, buddy);
+            order += 1;
+        }
+
+        self.push(addr, order);
+        self.stats.frees += 1;
+        true
+    }
+
+    /// Free `range` as the largest aligned blocks it can be split into
+    /// Partial pages at either end are ignored
+    fn free_range(&mut self, range: Range, lock: &LockHeld<BuddyLock>, irq: &InIrqContext) -> bool {
+        let mut addr = (range.start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let end = range.end.saturating_add(1) & !(PAGE_SIZE - 1);
+        let mut ok = true;
+
+        while addr < end {
+            let order = (0..ORDERS).rev()
+                .find(|order| addr.is_multiple_of(block_size(*order)) && addr + block_size(*order) <= end)
+                .unwrap_or(0);
+            ok &= self.free(addr, order, lock, irq);
+            addr += block_size(order);
+        }
+
+        ok
+    }
+}
+
+
+/// The lock guarding the allocator state
+type BuddyLock = SpinLock<Buddy>;
+
+/// The allocator
+struct Allocator {
+    initialized: AtomicBool,
+    buddy: BuddyLock,
+}
+
+impl Allocator {
+    /// Run `f` with exclusive access to the allocator
+    fn with<R>(&self, f: impl FnOnce(&mut Buddy, &LockHeld<BuddyLock>, &InIrqContext) -> R) -> R {
+        let mut guard = self.buddy.lock();
+        let (buddy, lock, irq) = guard.split();
+        f(buddy, &lock, &irq)
+    }
+}
+
+static ALLOCATOR: Allocator = Allocator {
+    initialized: AtomicBool::new(false),
+    buddy: SpinLock::new(Buddy {
+        heads: [NONE; ORDERS],
+        bitmaps: [core::ptr::null_mut(); ORDERS],
+        end: 0,
+        stats: Stats {
+            free_blocks: [0; ORDERS],
+            allocs: 0,
+            frees: 0,
+            failed: 0,
+            double_frees: 0,
+        },
+    }),
+};
+
+
+/// Take over all memory in `free`
+/// The bitmaps are carved out of `free` first, everything left ends up on the
+/// free lists and `free` is emptied
+pub(super) fn init(free: &mut RangeSet) -> bool {
+    // Only ever initialize once
+    if ALLOCATOR.initialized.load(Ordering::SeqCst) {return false;}
+
+    let end = match free.entries().last() {
+        Some(last) => last.end.saturating_add(1) & !(PAGE_SIZE - 1),
+        None => return false,
+    };
+
+    // Words needed for the bitmap of every order
+    let words = |order: usize| end.div_ceil(block_size(order)).div_ceil(64);
+    let total: u64 = (0..ORDERS).map(words).sum();
+
+    let base = match free.allocate(total * 8, PAGE_SIZE) {
+        Some(base) => base,
+        None => return false,
+    };
+
+    ALLOCATOR.with(|buddy, lock, irq| {
+        let mut word = base as *mut u64;
+        for order in 0..ORDERS {
+            buddy.bitmaps[order] = word;
+            word = unsafe { word.add(words(order) as usize) };
+        }
+        unsafe {
+            core::ptr::write_bytes(base as *mut u64, 0, total as usize);
+        }
+        buddy.end = end;
+
+        for range in free.entries() {
+            buddy.free_range(*range, lock, irq);
+        }
+
+        // Handing the memory over isn't an allocator operation
+        buddy.stats.frees = 0;
+    });
+
+    *free = RangeSet::new();
+    ALLOCATOR.initialized.store(true, Ordering::SeqCst);
+    true
+}
+
+
+/// Whether the allocator has taken over
+pub fn initialized() -> bool {
+    ALLOCATOR.initialized.load(Ordering::SeqCst)
+}
+
+
+/// Allocate a naturally aligned block of 2^`order` pages
+pub fn alloc(order: usize) -> Option<PhysAddr> {
+    if order > MAX_ORDER || !initialized() {
+        return None;
+    }
+    ALLOCATOR.with(|buddy, lock, irq| buddy.alloc(order, lock, irq)).map(PhysAddr)
+}
+
+
+/// Allocate a naturally aligned block of 2^`order` pages within `range`
+pub fn alloc_in(order: usize, range: Range) -> Option<PhysAddr> {
+    if order > MAX_ORDER || !initialized() {
+        return None;
+    }
+    ALLOCATOR.with(|buddy, lock, irq| buddy.alloc_in(order, range, lock, irq)).map(PhysAddr)
+}
+
+
+/// Free a block allocated with `alloc()` or `alloc_in()`
+/// Misaligned blocks and double frees are reported and refused
+#[allow(dead_code)]
+pub fn free(addr: PhysAddr, order: usize) -> bool {
+    if order > MAX_ORDER || !initialized() {
+        return false;
+    }
+
+    let ok = ALLOCATOR.with(|buddy, lock, irq| buddy.free(addr.0, order, lock, irq));
+    if !ok {
+        error!("Bad free of order {} block at {:#x}", order, addr);
+    }
+    ok
+}
+
+
+/// Free an arbitrary page aligned range, e.g. the unused tail of a block
+pub fn free_range(range: Range) -> bool {
+    if !initialized() {
+        return false;
+    }
+
+    let ok = ALLOCATOR.with(|buddy, lock, irq| buddy.free_range(range, lock, irq));
+    if !ok {
+        error!("Bad free of {:#x}-{:#x}", range.start, range.end);
+    }
+    ok
+}
+
+
+/// Current allocator statistics
+pub fn stats() -> Stats {
+    ALLOCATOR.with(|buddy, _, _| buddy.stats)
+}
@@ -0,0 +1,90 @@
+//! DMA buffers
+//! Memory handed to devices has to be physically contiguous, often has to be
+//! below some address the device can reach (4 GiB for 32-bit DMA) and must be
+//! known by both its physical address, for the device, and a virtual one, for
+//! us. x86 DMA is cache coherent, so buffers are plain write-back memory
+//! accessed through the direct map
+//!
+//! Buffers are zeroed when allocated and freed when dropped
+use super::{buddy, phys_to_virt, PhysAddr, Range, VirtAddr, PAGE_SIZE};
+
+
+/// Highest address of devices which can only do 32-bit DMA
+#[allow(dead_code)]
+pub const BELOW_4G: u64 = 0xffff_ffff;
+
+/// Highest address of devices which can reach all memory
+#[allow(dead_code)]
+pub const ANY: u64 = u64::MAX;
+
+
+/// A physically contiguous buffer for DMA
+#[derive(Debug)]
+pub struct Buffer {
+    // First byte of the buffer
+    phys: PhysAddr,
+
+    // Size in bytes, a multiple of the page size
+    size: u64,
+}
+
+impl Buffer {
+    /// Physical address of the buffer, for the device
+    #[allow(dead_code)]
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Virtual address of the buffer, for us
+    #[allow(dead_code)]
+    pub fn virt(&self) -> VirtAddr {
+        phys_to_virt(self.phys)
+    }
+
+    /// Size of the buffer in bytes, the requested size rounded up to pages
+    #[allow(dead_code)]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The buffer contents
+    /// The device may change them at any time while it owns the buffer
+    #[allow(dead_code)]
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt().0 as *const u8, self.size as usize) }
+    }
+
+    /// The buffer contents, mutable
+    #[allow(dead_code)]
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt().0 as *mut u8, self.size as usize) }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(range) = Range::new(self.phys.0, self.size) {
+            super::free_phys(range);
+        }
+    }
+}
+
+
+/// Allocate a zeroed buffer of `size` bytes aligned to `align` which lies
+/// entirely at or below `max_addr`
+/// `None` if there is no such memory or the allocator isn't up yet
+#[allow(dead_code)]
+pub fn alloc(size: u64, max_addr: u64, align: u64) -> Option<Buffer> {
+    if size == 0 || !buddy::initialized() {
+        return None;
+    }
+    let size = size.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+    let align = core::cmp::max(align, PAGE_SIZE);
+
+    let reachable = Range { start: 0, end: max_addr };
+    let phys = super::alloc_block(size, align, |order| buddy::alloc_in(order, reachable))?;
+
+    let mut buffer = Buffer { phys, size };
+    buffer.as_mut_slice().fill(0);
+    Some(buffer)
+}
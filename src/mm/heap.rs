@@ -0,0 +1,464 @@
+//! Kernel heap
+//! Backs `alloc` (`Vec`, `BTreeMap`, ...) once the physical memory manager is
+//! up. Small allocations come from slabs: pages carved into equally sized
+//! objects, one size class per power of two, with the free objects of each
+//! class kept on a linked list threaded through them. Anything bigger than the
+//! largest class gets whole pages straight from the frame allocator
+//!
+//! Slab pages are never given back, the free lists keep them for reuse
+//!
+//! `--heap-debug` turns on a debug mode catching the usual heap bugs: every
+//! allocation gets a header recording its size and call site and a redzone on
+//! either side which is checked when it is freed, new memory is filled with
+//! `ALLOC_POISON` and freed memory with `FREE_POISON`, and live allocations
+//! are kept on a list `dump_live()` prints to find leaks
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::{Range, VirtAddr, PAGE_SIZE};
+use crate::sync::{InIrqContext, LockHeld, SpinLock};
+
+
+/// Size classes of the slabs, objects are aligned to their size
+const CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Fill of newly allocated memory in debug mode
+const ALLOC_POISON: u8 = 0xcd;
+
+/// Fill of freed memory in debug mode
+const FREE_POISON: u8 = 0xdd;
+
+/// Fill of the redzones in debug mode
+const REDZONE_FILL: u8 = 0xfd;
+
+/// Size of the redzone on either side of an allocation in debug mode
+const REDZONE: usize = 16;
+
+/// Return addresses recorded for every allocation in debug mode
+const CALLERS: usize = 6;
+
+/// Magic of live and freed allocations in debug mode
+const LIVE_MAGIC: u64 = 0x4c49_5645_4845_4150;
+const FREED_MAGIC: u64 = 0x4652_4545_4845_4150;
+
+/// Most live allocations `dump_live()` prints
+const MAX_DUMP: usize = 64;
+
+
+/// Heap statistics
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    // Bytes handed out, as requested
+    pub allocated: u64,
+
+    // Pages taken for slabs
+    pub slab_pages: u64,
+
+    // Pages currently handed out for big allocations
+    pub big_pages: u64,
+
+    // Allocations which could not be satisfied
+    pub failed: u64,
+
+    // Corrupt redzones, bad and double frees found in debug mode
+    pub corrupted: u64,
+}
+
+
+/// Link of a free slab object, stored in the object itself
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+
+/// Bookkeeping in front of every allocation in debug mode
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DebugHeader {
+    // Left alone for the free lists, which link freed memory through its
+    // first words
+    _links: [u64; 2],
+
+    magic: u64,
+
+    // Size the allocation was made with
+    size: usize,
+
+    // Offset of the memory handed out from the header
+    front: usize,
+
+    // Innermost return addresses at the time of the allocation
+    callers: [u64; CALLERS],
+
+    // Neighbours on the list of live allocations
+    next: *mut DebugHeader,
+    prev: *mut DebugHeader,
+}
+
+
+/// The heap state
+struct HeapState {
+    // First free object of each size class
+    free: [*mut FreeObject; CLASSES.len()],
+
+    // Most recent live allocation in debug mode
+    live: *mut DebugHeader,
+
+    stats: Stats,
+}
+
+// The pointers are only followed with the heap lock held
+unsafe impl Send for HeapState {}
+
+impl HeapState {
+    /// Carve a fresh page into objects of `class` and put them on its free list
+    fn refill(&mut self, class: usize, _lock: &LockHeld<HeapLock>, _irq: &InIrqContext) -> bool {
+        let page = match super::alloc_phys(PAGE_SIZE, PAGE_SIZE) {
+            Some(page) => super::phys_to_virt(page).0,
+            None => return false,
+        };
+
+        let size = CLASSES[class] as u64;
+        for obj in (0..PAGE_SIZE / size).rev() {
+            let obj = (page + obj * size) as *mut FreeObject;
+            unsafe {
+                (*obj).next = self.free[class];
+            }
+            self.free[class] = obj;
+        }
+        self.stats.slab_pages += 1;
+        true
+    }
+
+    /// Take an object of `class` off its free list
+    fn alloc_small(&mut self, class: usize, lock: &LockHeld<HeapLock>, irq: &InIrqContext) -> *mut u8 {
+        if self.free[class].is_null() && !self.refill(class, lock, irq) {
+            return core::ptr::null_mut();
+        }
+
+        let obj = self.free[class];
+        self.free[class] = unsafe { (*obj).next };
+        obj as *mut u8
+    }
+
+    /// Put an object of `class` back on its free list
+    fn free_small(&mut self, ptr: *mut u8, class: usize, _lock: &LockHeld<HeapLock>, _irq: &InIrqContext) {
+        let obj = ptr as *mut FreeObject;
+        unsafe {
+            (*obj).next = self.free[class];
+        }
+        self.free[class] = obj;
+    }
+}
+
+
+/// Size class for `layout`, `None` if it needs whole pages
+fn class_for(layout: &Layout) -> Option<usize> {
+    let size = core::cmp::max(layout.size(), layout.align());
+    CLASSES.iter().position(|class| *class >= size)
+}
+
+
+/// Number of bytes of pages needed for a big allocation of `size` bytes
+fn big_size(size: usize) -> u64 {
+    (size as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+
+/// Layout of the allocation backing `layout` in debug mode, and the offset of
+/// the memory handed out in it
+/// The header and front redzone come first, the rear redzone after the data
+fn debug_layout(layout: &Layout) -> Option<(Layout, usize)> {
+    let align = core::cmp::max(layout.align(), core::mem::align_of::<DebugHeader>());
+    let front = (core::mem::size_of::<DebugHeader>() + REDZONE).checked_next_multiple_of(align)?;
+    let size = front.checked_add(layout.size())?.checked_add(REDZONE)?;
+    Some((Layout::from_size_align(size, align).ok()?, front))
+}
+
+
+/// Whether all of `len` bytes at `ptr` are `fill`
+unsafe fn filled(ptr: *const u8, len: usize, fill: u8) -> bool {
+    core::slice::from_raw_parts(ptr, len).iter().all(|byte| *byte == fill)
+}
+
+
+/// Print where the allocation with `header` was made
+fn print_callers(header: &DebugHeader) {
+    for addr in header.callers.iter().take_while(|addr| **addr != 0) {
+        match crate::symbols::resolve(addr - 1) {
+            Some((name, offset)) => eprintln!("    {:016x} {}+{:#x}", addr, name, offset + 1),
+            None => eprintln!("    {:016x}", addr),
+        }
+    }
+}
+
+
+/// The lock guarding the heap state
+type HeapLock = SpinLock<HeapState>;
+
+/// The global allocator
+pub struct Heap {
+    state: HeapLock,
+}
+
+impl Heap {
+    /// Run `f` with exclusive access to the heap state
+    fn with<R>(&self, f: impl FnOnce(&mut HeapState, &LockHeld<HeapLock>, &InIrqContext) -> R) -> R {
+        let mut guard = self.state.lock();
+        let (heap, lock, irq) = guard.split();
+        f(heap, &lock, &irq)
+    }
+
+    /// Allocate in debug mode, see the module documentation
+    unsafe fn alloc_debug(&self, layout: Layout) -> *mut u8 {
+        let (outer, front) = match debug_layout(&layout) {
+            Some(outer) => outer,
+            None => return core::ptr::null_mut(),
+        };
+        let base = self.alloc_raw(outer);
+        if base.is_null() {
+            return base;
+        }
+
+        let mut callers = [0u64; CALLERS];
+        let (rbp, rsp): (u64, u64);
+        core::arch::asm!("mov {}, rbp", "mov {}, rsp", out(reg) rbp, out(reg) rsp,
+            options(nomem, nostack, preserves_flags));
+        let mut depth = 0;
+        crate::backtrace::walk(rbp, rsp, |addr| {
+            if let Some(slot) = callers.get_mut(depth) {
+                *slot = addr;
+            }
+            depth += 1;
+        });
+
+        let header = base as *mut DebugHeader;
+        header.write(DebugHeader {
+            _links: [0; 2],
+            magic: LIVE_MAGIC,
+            size: layout.size(),
+            front,
+            callers,
+            next: core::ptr::null_mut(),
+            prev: core::ptr::null_mut(),
+        });
+
+        let data = base.add(front);
+        let header_size = core::mem::size_of::<DebugHeader>();
+        core::ptr::write_bytes(base.add(header_size), REDZONE_FILL, front - header_size);
+        core::ptr::write_bytes(data, ALLOC_POISON, layout.size());
+        core::ptr::write_bytes(data.add(layout.size()), REDZONE_FILL, REDZONE);
+
+        self.with(|heap, _, _| {
+            (*header).next = heap.live;
+            if !heap.live.is_null() {
+                (*heap.live).prev = header;
+            }
+            heap.live = header;
+        });
+        data
+    }
+
+    /// Free in debug mode, checking the header and redzones first
+    /// Anything wrong is reported, bad and double frees are then leaked
+    /// rather than corrupting the free lists
+    unsafe fn dealloc_debug(&self, ptr: *mut u8, layout: Layout) {
+        let (outer, front) = match debug_layout(&layout) {
+            Some(outer) => outer,
+            None => return,
+        };
+        let base = ptr.sub(front);
+        let header = base as *mut DebugHeader;
+
+        let magic = (*header).magic;
+        if magic != LIVE_MAGIC {
+            self.with(|heap, _, _| heap.stats.corrupted += 1);
+            if magic == FREED_MAGIC {
+                error!("Heap: double free of {:p} ({} bytes)", ptr, layout.size());
+            } else {
+                error!("Heap: free of {:p} ({} bytes) which isn't allocated or has a corrupt header", ptr, layout.size());
+            }
+            crate::backtrace::print_current();
+            return;
+        }
+
+        let header_size = core::mem::size_of::<DebugHeader>();
+        let size = (*header).size;
+        let before = filled(base.add(header_size), front - header_size, REDZONE_FILL);
+        let after = filled(ptr.add(layout.size()), REDZONE, REDZONE_FILL);
+        if size != layout.size() || !before || !after {
+            self.with(|heap, _, _| heap.stats.corrupted += 1);
+            if size != layout.size() {
+                error!("Heap: {:p} freed as {} bytes but allocated as {}", ptr, layout.size(), size);
+            }
+            if !before {
+                error!("Heap: write before the start of {:p} ({} bytes)", ptr, size);
+            }
+            if !after {
+                error!("Heap: write past the end of {:p} ({} bytes)", ptr, size);
+            }
+            eprintln!("  allocated at:");
+            print_callers(&*header);
+            eprintln!("  freed at:");
+            crate::backtrace::print_current();
+        }
+
+        self.with(|heap, _, _| {
+            let (next, prev) = ((*header).next, (*header).prev);
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+            if prev.is_null() {
+                heap.live = next;
+            } else {
+                (*prev).next = next;
+            }
+        });
+
+        core::ptr::write_bytes(base.add(header_size), FREE_POISON, outer.size() - header_size);
+        (*header).magic = FREED_MAGIC;
+        self.dealloc_raw(base, outer);
+    }
+
+    /// Allocate from the slabs or the frame allocator
+    unsafe fn alloc_raw(&self, layout: Layout) -> *mut u8 {
+        let ptr = match class_for(&layout) {
+            Some(class) => self.with(|heap, lock, irq| heap.alloc_small(class, lock, irq)),
+            None => {
+                let size = big_size(layout.size());
+                match super::alloc_phys(size, layout.align() as u64) {
+                    Some(addr) => {
+                        self.with(|heap, _, _| heap.stats.big_pages += size / PAGE_SIZE);
+                        super::phys_to_virt(addr).0 as *mut u8
+                    },
+                    None => core::ptr::null_mut(),
+                }
+            },
+        };
+
+        self.with(|heap, _, _| {
+            if ptr.is_null() {
+                heap.stats.failed += 1;
+            } else {
+                heap.stats.allocated += layout.size() as u64;
+            }
+        });
+        ptr
+    }
+
+    /// Give an allocation of `alloc_raw()` back
+    unsafe fn dealloc_raw(&self, ptr: *mut u8, layout: Layout) {
+        match class_for(&layout) {
+            Some(class) => self.with(|heap, lock, irq| heap.free_small(ptr, class, lock, irq)),
+            None => {
+                let size = big_size(layout.size());
+                let phys = super::virt_to_phys(VirtAddr(ptr as u64));
+                if let Some(range) = phys.and_then(|phys| Range::new(phys.0, size)) {
+                    super::free_phys(range);
+                }
+                self.with(|heap, _, _| heap.stats.big_pages -= size / PAGE_SIZE);
+            },
+        }
+
+        self.with(|heap, _, _| heap.stats.allocated -= layout.size() as u64);
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if DEBUG.load(Ordering::Relaxed) {
+            self.alloc_debug(layout)
+        } else {
+            self.alloc_raw(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if DEBUG.load(Ordering::Relaxed) {
+            self.dealloc_debug(ptr, layout)
+        } else {
+            self.dealloc_raw(ptr, layout)
+        }
+    }
+}
+
+
+#[global_allocator]
+static HEAP: Heap = Heap {
+    state: SpinLock::new(HeapState {
+        free: [core::ptr::null_mut(); CLASSES.len()],
+        live: core::ptr::null_mut(),
+        stats: Stats {
+            allocated: 0,
+            slab_pages: 0,
+            big_pages: 0,
+            failed: 0,
+            corrupted: 0,
+        },
+    }),
+};
+
+/// Whether the heap is in debug mode
+/// Only ever switched on before the first allocation, so every allocation
+/// freed in debug mode was made in it
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+
+/// Turn on debug mode if `--heap-debug` is on the command line
+/// Must be called before anything is allocated
+pub fn init() {
+    if !crate::cmdline::flag("--heap-debug") {return;}
+
+    let used = HEAP.with(|heap, _, _| heap.stats.slab_pages != 0 || heap.stats.big_pages != 0);
+    if used {
+        warn!("Heap already in use, not turning on heap debugging");
+        return;
+    }
+    DEBUG.store(true, Ordering::SeqCst);
+    info!("Heap debugging on");
+}
+
+
+/// Whether the heap is in debug mode
+pub fn debug_enabled() -> bool {
+    DEBUG.load(Ordering::Relaxed)
+}
+
+
+/// Print the live allocations with where they were made, newest first
+/// Only available in debug mode
+pub fn dump_live() {
+    if !debug_enabled() {return;}
+
+    // Copied out first, printing may allocate
+    let mut found = [None; MAX_DUMP];
+    let (count, bytes) = HEAP.with(|heap, _, _| {
+        let (mut count, mut bytes) = (0, 0);
+        let mut header = heap.live;
+        while !header.is_null() {
+            let entry = unsafe { *header };
+            if let Some(slot) = found.get_mut(count) {
+                *slot = Some((header as usize, entry));
+            }
+            count += 1;
+            bytes += entry.size;
+            header = entry.next;
+        }
+        (count, bytes)
+    });
+
+    info!("{} live allocations, {} bytes", count, bytes);
+    for (addr, header) in found.iter().flatten() {
+        eprintln!("  {} bytes at {:#x}", header.size, addr + header.front);
+        print_callers(header);
+    }
+    if count > MAX_DUMP {
+        eprintln!("  ... and {} more", count - MAX_DUMP);
+    }
+}
+
+
+/// Current heap statistics
+#[allow(dead_code)]
+pub fn stats() -> Stats {
+    HEAP.with(|heap, _, _| heap.stats)
+}
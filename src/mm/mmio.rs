@@ -0,0 +1,127 @@
+//! Memory mapped I/O
+//! Device registers are mapped uncached (or write-combining for frame buffers
+//! and the like) into a window of their own, away from the identity and
+//! direct maps which are write-back. Drivers describe their registers as a
+//! `#[repr(C)]` struct of `Volatile`, `ReadOnly` and `WriteOnly` fields and
+//! get a reference to it with `registers()`, so every access is volatile
+//! without any pointer math
+//!
+//! The window is handed out front to back and never reused, devices are
+//! mapped once and stay mapped
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::paging::MapError;
+use super::virt::{self, CacheType, MapFlags};
+use super::{PhysAddr, VirtAddr, PAGE_SIZE};
+
+
+/// Start of the MMIO window, the direct map ends below it
+pub(super) const MMIO_BASE: u64 = 0xffff_c900_0000_0000;
+
+/// Size of the MMIO window
+const MMIO_SIZE: u64 = 1 << 40;
+
+
+/// Offset of the next free address in the window
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+
+/// Whether `addr` is in the MMIO window
+pub fn contains(addr: VirtAddr) -> bool {
+    (MMIO_BASE..MMIO_BASE + MMIO_SIZE).contains(&addr.0)
+}
+
+
+/// Map `size` bytes of device memory at `phys` with `cache`
+/// Returns the virtual address of `phys`, which needn't be page aligned
+pub(super) fn map(phys: PhysAddr, size: u64, cache: CacheType) -> Result<VirtAddr, MapError> {
+    if size == 0 {
+        return Err(MapError::Misaligned);
+    }
+    let offset = phys.0 % PAGE_SIZE;
+    let start = phys.0 - offset;
+    let pages = offset.checked_add(size).and_then(|size| size.checked_next_multiple_of(PAGE_SIZE))
+        .ok_or(MapError::Misaligned)?;
+
+    // Space isn't given back when mapping fails, there is plenty
+    let base = NEXT.fetch_add(pages, Ordering::SeqCst);
+    if base.checked_add(pages).is_none_or(|end| end > MMIO_SIZE) {
+        return Err(MapError::OutOfMemory);
+    }
+
+    let virt = VirtAddr(MMIO_BASE + base);
+    let flags = MapFlags { writable: true, executable: false, user: false, cache };
+    virt::map(virt, PhysAddr(start), pages, flags)?;
+    Ok(VirtAddr(virt.0 + offset))
+}
+
+
+/// Access the registers `T` mapped at `virt`
+///
+/// Safety: `virt` must come from `mm::map_mmio()` and the mapping must cover a `T`
+#[allow(dead_code)]
+pub unsafe fn registers<'a, T>(virt: VirtAddr) -> &'a T {
+    &*(virt.0 as *const T)
+}
+
+
+/// A device register which can be read and written
+/// Every access is a single volatile access of `T`
+#[repr(transparent)]
+pub struct Volatile<T: Copy> {
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for Volatile<T> {}
+
+impl<T: Copy> Volatile<T> {
+    #[allow(dead_code)]
+    pub fn read(&self) -> T {
+        unsafe { core::ptr::read_volatile(self.value.get()) }
+    }
+
+    #[allow(dead_code)]
+    pub fn write(&self, value: T) {
+        unsafe { core::ptr::write_volatile(self.value.get(), value) }
+    }
+
+    /// Read, change with `f` and write back
+    /// Not atomic, the device may change the register in between
+    #[allow(dead_code)]
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+}
+
+
+/// A device register which must only be read
+#[repr(transparent)]
+pub struct ReadOnly<T: Copy> {
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for ReadOnly<T> {}
+
+impl<T: Copy> ReadOnly<T> {
+    #[allow(dead_code)]
+    pub fn read(&self) -> T {
+        unsafe { core::ptr::read_volatile(self.value.get()) }
+    }
+}
+
+
+/// A device register which must only be written, reading it may have side
+/// effects or return garbage
+#[repr(transparent)]
+pub struct WriteOnly<T: Copy> {
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for WriteOnly<T> {}
+
+impl<T: Copy> WriteOnly<T> {
+    #[allow(dead_code)]
+    pub fn write(&self, value: T) {
+        unsafe { core::ptr::write_volatile(self.value.get(), value) }
+    }
+}
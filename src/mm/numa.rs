@@ -0,0 +1,211 @@
+//! NUMA memory nodes
+//! The firmware (the ACPI SRAT) tells us which memory ranges belong to which
+//! node and which node every processor is on. With that allocations can come
+//! from the memory closest to the processor asking for it, by default they
+//! prefer the local node and fall back to any memory
+//!
+//! The SLIT adds how far apart the nodes are, as relative distances where
+//! 10 is local memory. Without it a node is 10 from itself and 20 from the
+//! others
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use super::{buddy, PhysAddr, Range};
+use crate::sync::SpinLock;
+
+
+/// Maximum number of memory ranges tagged with a node
+const MAX_NODE_RANGES: usize = 64;
+
+/// Number of APIC IDs we can map to a node
+const MAX_APIC_IDS: usize = 256;
+
+/// Node of processors we know nothing about
+const NO_NODE: u32 = u32::MAX;
+
+/// Number of nodes we keep distances between
+const MAX_NODES: usize = 64;
+
+/// Distance of a node to itself
+pub const LOCAL_DISTANCE: u8 = 10;
+
+/// Distance between different nodes when there is no SLIT
+pub const REMOTE_DISTANCE: u8 = 20;
+
+/// Distance between nodes which can't reach each other
+#[allow(dead_code)]
+pub const UNREACHABLE: u8 = 0xff;
+
+
+/// Memory ranges and the node they belong to
+struct Nodes {
+    registered: AtomicBool,
+    ranges: SpinLock<[Option<(Range, u32)>; MAX_NODE_RANGES]>,
+}
+
+impl Nodes {
+    /// Run `f` with exclusive access to the ranges
+    fn with<R>(&self, f: impl FnOnce(&mut [Option<(Range, u32)>; MAX_NODE_RANGES]) -> R) -> R {
+        f(&mut self.ranges.lock())
+    }
+}
+
+static NODES: Nodes = Nodes {
+    registered: AtomicBool::new(false),
+    ranges: SpinLock::new([None; MAX_NODE_RANGES]),
+};
+
+/// Distances between the first `DISTANCE_NODES` nodes, from the SLIT
+static DISTANCES: [[AtomicU8; MAX_NODES]; MAX_NODES] =
+    [const { [const { AtomicU8::new(0) }; MAX_NODES] }; MAX_NODES];
+
+/// Number of nodes in `DISTANCES`, 0 without a SLIT
+static DISTANCE_NODES: AtomicUsize = AtomicUsize::new(0);
+
+/// Node, the proximity domain from the SRAT, of each processor, indexed by
+/// APIC ID
+static APIC_TO_DOMAIN: [AtomicU32; MAX_APIC_IDS] = [const { AtomicU32::new(NO_NODE) }; MAX_APIC_IDS];
+
+
+/// Tag the memory `ranges` with the node they belong to
+/// Ranges which don't fit any more are dropped with a warning
+pub fn register_numa_nodes(ranges: &[(Range, u32)]) {
+    let dropped = NODES.with(|slots| {
+        let mut dropped = 0;
+        for range in ranges {
+            match slots.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => *slot = Some(*range),
+                None => dropped += 1,
+            }
+        }
+        dropped
+    });
+
+    if dropped != 0 {
+        warn!("Out of node range slots, dropped {} ranges", dropped);
+    }
+    NODES.registered.store(true, Ordering::SeqCst);
+}
+
+
+/// Record that the processor with `apic_id` is on `node`
+pub fn set_cpu_node(apic_id: u32, node: u32) {
+    if let Some(slot) = APIC_TO_DOMAIN.get(apic_id as usize) {
+        slot.store(node, Ordering::Relaxed);
+    }
+}
+
+
+/// Record the distances between `nodes` nodes, `matrix` holds the distance
+/// from node `a` to node `b` at `a * nodes + b`
+/// Nodes beyond the ones we have room for keep the default distances
+pub fn register_distances(nodes: usize, matrix: &[u8]) {
+    if matrix.len() < nodes.saturating_mul(nodes) {
+        warn!("Distance matrix of {} nodes has only {} entries", nodes, matrix.len());
+        return;
+    }
+    if nodes > MAX_NODES {
+        warn!("Only keeping distances between the first {} of {} nodes", MAX_NODES, nodes);
+    }
+
+    let kept = core::cmp::min(nodes, MAX_NODES);
+    for (a, row) in DISTANCES.iter().enumerate().take(kept) {
+        for (b, distance) in row.iter().enumerate().take(kept) {
+            distance.store(matrix[a * nodes + b], Ordering::Relaxed);
+        }
+    }
+    DISTANCE_NODES.store(kept, Ordering::Release);
+}
+
+
+/// Relative distance from node `a` to node `b`, `LOCAL_DISTANCE` being the
+/// distance of a node to itself
+pub fn distance(a: u32, b: u32) -> u8 {
+    let nodes = DISTANCE_NODES.load(Ordering::Acquire);
+    let (a, b) = (a as usize, b as usize);
+    if a < nodes && b < nodes {
+        return DISTANCES[a][b].load(Ordering::Relaxed);
+    }
+    if a == b {LOCAL_DISTANCE} else {REMOTE_DISTANCE}
+}
+
+
+/// Log the distance matrix, if there is one
+pub fn log_distances() {
+    let nodes = DISTANCE_NODES.load(Ordering::Acquire);
+    if nodes == 0 {return;}
+
+    info!("NUMA distances between {} nodes:", nodes);
+    let mut line = alloc::string::String::new();
+    let _ = write!(line, "{:>5}", "");
+    for b in 0..nodes {
+        let _ = write!(line, "{:>4}", b);
+    }
+    info!("{}", line);
+
+    for a in 0..nodes {
+        line.clear();
+        let _ = write!(line, "{:>4}:", a);
+        for b in 0..nodes {
+            let _ = write!(line, "{:>4}", distance(a as u32, b as u32));
+        }
+        info!("{}", line);
+    }
+}
+
+
+/// Whether any node information has been registered
+pub fn registered() -> bool {
+    NODES.registered.load(Ordering::SeqCst)
+}
+
+
+/// Node of the memory at `addr`, if known
+#[allow(dead_code)]
+pub fn node_of(addr: PhysAddr) -> Option<u32> {
+    NODES.with(|slots| {
+        slots.iter().flatten()
+            .find(|(range, _)| range.start <= addr.0 && addr.0 <= range.end)
+            .map(|(_, node)| *node)
+    })
+}
+
+
+/// Initial APIC ID of the calling processor
+/// CPUID.01H:EBX[31:24]
+fn apic_id() -> u32 {
+    crate::cpu::features::cpuid(1, 0).ebx >> 24
+}
+
+
+/// Node of the calling processor, if known
+pub fn current_node() -> Option<u32> {
+    let node = APIC_TO_DOMAIN.get(apic_id() as usize)?.load(Ordering::Relaxed);
+    (node != NO_NODE).then_some(node)
+}
+
+
+/// Allocate a buddy block of `order` from the memory of `node`
+pub(super) fn alloc_on(node: u32, order: usize) -> Option<PhysAddr> {
+    for index in 0..MAX_NODE_RANGES {
+        // Don't hold the spin flag across the allocator
+        let range = match NODES.with(|slots| slots[index]) {
+            Some((range, owner)) if owner == node => range,
+            _ => continue,
+        };
+
+        if let Some(addr) = buddy::alloc_in(order, range) {
+            return Some(addr);
+        }
+    }
+    None
+}
+
+
+/// Allocate a buddy block of `order` from the node of the calling processor
+/// `None` if that node is unknown or out of memory
+pub(super) fn alloc_local(order: usize) -> Option<PhysAddr> {
+    if !registered() {
+        return None;
+    }
+    alloc_on(current_node()?, order)
+}
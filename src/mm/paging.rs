@@ -0,0 +1,775 @@
+//! 4-level page tables
+//! The firmware leaves us running on its own page tables, which live in boot
+//! services memory the allocator only gets once ours are loaded. `init()`
+//! builds our own: all physical memory but the null page is identity mapped
+//! without execute permission and the kernel image is mapped section by
+//! section with the permissions from its PE headers, then the tables are
+//! loaded into CR3
+//! Nothing is meant to be writable and executable at once, `init()` walks the
+//! finished tables and warns about any page which is
+//!
+//! All physical memory is mapped a second time in the direct map, which is
+//! where `phys_to_virt()` points once the tables are loaded. Physical memory is
+//! only ever accessed through there, the identity map stays for the kernel
+//! image, our stack and the pointers handed out before the switch. The direct
+//! map starts at a random GiB above `DIRECT_MAP_BASE` unless `nokaslr` is set
+//!
+//! The kernel image is mapped a second time as well, at a random 2 MiB above
+//! `KERNEL_BASE`. Once the tables are loaded its base relocations are applied
+//! again for that address and the boot continues there, see `mm::slid()`
+//!
+//! Memory is mapped with 2 MiB and 1 GiB pages wherever alignment allows,
+//! large pages are split when part of them is unmapped or changes permissions
+//!
+//! The identity and direct maps are write-back, the MTRRs set up by the
+//! firmware still make MMIO in them uncached. The PAT is reprogrammed so PCD
+//! alone selects write-combining, see `PAT_VALUE`
+//! See: https://wiki.osdev.org/Paging
+use core::ops::BitOr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use super::{phys_to_virt, PhysAddr, Range, RangeSet, VirtAddr, DIRECT_MAP_SIZE, PAGE_SIZE};
+use crate::cpu::cr::{self, Cr0};
+use crate::cpu::{features, msr};
+use crate::efi::{MemoryMap, EFI_MEMORY_TYPE};
+use crate::sync::{InIrqContext, LockHeld, SpinLock};
+
+
+/// Number of entries in a page table
+const ENTRIES: usize = 512;
+
+/// Bits of an entry holding the physical address of the next table or page
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// Memory below this is always identity mapped, whatever the memory map says,
+/// it holds the local APIC, IOAPIC and other MMIO the map doesn't list
+const LOW_MEMORY: u64 = 4 << 30;
+
+/// Our PAT: the power-on default except entry 2 (PCD) is write-combining
+/// instead of UC-, so the PWT/PCD bits select WB, WT, WC and UC
+/// Entries 4 to 7 are left as they are, we never set the PAT bit
+const PAT_VALUE: u64 = 0x0007_0406_0001_0406;
+
+
+/// Page table entry flags
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Flags(pub u64);
+
+impl Flags {
+    pub const PRESENT: Flags = Flags(1 << 0);
+    pub const WRITABLE: Flags = Flags(1 << 1);
+    pub const USER: Flags = Flags(1 << 2);
+    pub const WRITE_THROUGH: Flags = Flags(1 << 3);
+    pub const NO_CACHE: Flags = Flags(1 << 4);
+    #[allow(dead_code)]
+    pub const ACCESSED: Flags = Flags(1 << 5);
+    #[allow(dead_code)]
+    pub const DIRTY: Flags = Flags(1 << 6);
+    pub const HUGE: Flags = Flags(1 << 7);
+    #[allow(dead_code)]
+    pub const GLOBAL: Flags = Flags(1 << 8);
+    pub const NO_EXECUTE: Flags = Flags(1 << 63);
+
+    pub const fn empty() -> Flags {
+        Flags(0)
+    }
+
+    pub const fn contains(&self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn remove(&self, other: Flags) -> Flags {
+        Flags(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, other: Flags) -> Flags {
+        Flags(self.0 | other.0)
+    }
+}
+
+
+/// Errors returned when changing mappings
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapError {
+    // No memory left for a page table
+    OutOfMemory,
+
+    // An address is not page aligned
+    Misaligned,
+
+    // The page is mapped already
+    AlreadyMapped,
+
+    // The page is not mapped
+    NotMapped,
+
+    // An address is not canonical or the range crosses the non-canonical hole
+    NonCanonical,
+
+    // The mapping would be both writable and executable
+    WritableExecutable,
+}
+
+
+/// What `TABLE_LOCK` guards, the tables themselves are reached through
+/// their physical addresses
+pub struct Tables;
+
+/// The lock serializing changes to page tables
+/// Changing mappings takes proof that it is held
+pub type TableLock = SpinLock<Tables>;
+
+static TABLE_LOCK: TableLock = SpinLock::new(Tables);
+
+
+/// Run `f` with the page table lock held, and so interrupts disabled
+pub fn lock_tables<R>(f: impl FnOnce(&LockHeld<TableLock>, &InIrqContext) -> R) -> R {
+    let guard = TABLE_LOCK.lock();
+    f(&guard.held(), &guard.irq_context())
+}
+
+
+/// A page table at any level
+#[repr(C, align(4096))]
+pub struct PageTable {
+    entries: [u64; ENTRIES],
+}
+
+
+/// Size of the memory mapped by an entry at `level`
+const fn page_size(level: usize) -> u64 {
+    PAGE_SIZE << (9 * (level - 1))
+}
+
+
+/// Check `size` bytes at `virt` are page aligned and canonical throughout
+fn check_range(virt: VirtAddr, size: u64) -> Result<(), MapError> {
+    if !virt.is_aligned(PAGE_SIZE) || !size.is_multiple_of(PAGE_SIZE) {
+        return Err(MapError::Misaligned);
+    }
+    if size == 0 {
+        return Ok(());
+    }
+
+    // Both ends canonical and on the same side of the hole
+    let last = virt.checked_add(size - 1).ok_or(MapError::NonCanonical)?;
+    if !virt.is_canonical() || (virt.0 >> 47) != (last.0 >> 47) {
+        return Err(MapError::NonCanonical);
+    }
+    Ok(())
+}
+
+
+/// Access the table at physical address `addr`
+unsafe fn table<'a>(addr: u64) -> &'a mut PageTable {
+    &mut *(phys_to_virt(PhysAddr(addr)).0 as *mut PageTable)
+}
+
+
+/// Pages holding the page tables currently loaded in CR3
+/// `None` if there are too many ranges of them to keep track of
+/// Must run while physical memory is still identity mapped
+pub(super) fn current_table_pages() -> Option<RangeSet> {
+    fn walk(addr: u64, level: usize, pages: &mut RangeSet) -> bool {
+        if !pages.insert(Range { start: addr, end: addr + PAGE_SIZE - 1 }) {
+            return false;
+        }
+        if level == 1 {
+            return true;
+        }
+        unsafe { table(addr) }.entries.iter()
+            .filter(|entry| **entry & Flags::PRESENT.0 != 0 && (level == 4 || **entry & Flags::HUGE.0 == 0))
+            .all(|entry| walk(*entry & ADDR_MASK, level - 1, pages))
+    }
+
+    let mut pages = RangeSet::new();
+    walk(AddressSpace::current().pml4.0, 4, &mut pages).then_some(pages)
+}
+
+
+/// Bits of a page entry mapping a page of `level` with `flags`
+/// No-execute is dropped on processors without it, where the bit is reserved
+fn leaf_bits(flags: Flags, level: usize) -> u64 {
+    let huge = if level > 1 {Flags::HUGE} else {Flags::empty()};
+    let flags = flags | huge | Flags::PRESENT;
+    if NO_EXECUTE.load(Ordering::Relaxed) {
+        flags.0
+    } else {
+        flags.remove(Flags::NO_EXECUTE).0
+    }
+}
+
+
+/// Allocate a zeroed page table
+fn alloc_table() -> Result<u64, MapError> {
+    let addr = super::alloc_phys(PAGE_SIZE, PAGE_SIZE).ok_or(MapError::OutOfMemory)?;
+    unsafe {
+        core::ptr::write_bytes(phys_to_virt(addr).0 as *mut u8, 0, PAGE_SIZE as usize);
+    }
+    Ok(addr.0)
+}
+
+
+/// A set of page tables, identified by its PML4
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddressSpace {
+    pml4: PhysAddr,
+}
+
+impl AddressSpace {
+    /// An empty address space
+    pub fn new() -> Result<AddressSpace, MapError> {
+        Ok(AddressSpace { pml4: PhysAddr(alloc_table()?) })
+    }
+
+    /// The address space currently loaded in CR3
+    pub fn current() -> AddressSpace {
+        AddressSpace { pml4: PhysAddr(cr::cr3() & ADDR_MASK) }
+    }
+
+    /// Physical address of the PML4
+    #[allow(dead_code)]
+    pub fn pml4(&self) -> PhysAddr {
+        self.pml4
+    }
+
+    /// The entry at `level` for `virt`, creating the tables above it as needed
+    /// Intermediate tables get the most permissive flags, the final entry
+    /// decides what is allowed
+    /// Only dereference the pointer with the table lock held
+    fn entry(&self, virt: VirtAddr, level: usize) -> Result<*mut u64, MapError> {
+        let mut addr = self.pml4.0;
+        for upper in (level + 1..=4).rev() {
+            let entry = &mut unsafe { table(addr) }.entries[virt.table_index(upper)];
+            if *entry & Flags::PRESENT.0 == 0 {
+                let next = alloc_table()?;
+                *entry = next | (Flags::PRESENT | Flags::WRITABLE | Flags::USER).0;
+            } else if *entry & Flags::HUGE.0 != 0 {
+                return Err(MapError::AlreadyMapped);
+            }
+            addr = *entry & ADDR_MASK;
+        }
+        Ok(&mut unsafe { table(addr) }.entries[virt.table_index(level)] as *mut u64)
+    }
+
+    /// The entry mapping `virt` and its level, which is above 1 for large pages
+    /// Never creates tables, only dereference the pointer with the table lock
+    /// held or on tables nobody else changes
+    fn find(&self, virt: VirtAddr) -> Option<(*mut u64, usize)> {
+        let mut addr = self.pml4.0;
+        for level in (1..=4).rev() {
+            let entry = &mut unsafe { table(addr) }.entries[virt.table_index(level)];
+            if *entry & Flags::PRESENT.0 == 0 {
+                return None;
+            }
+            if level == 1 || (level <= 3 && *entry & Flags::HUGE.0 != 0) {
+                return Some((entry as *mut u64, level));
+            }
+            addr = *entry & ADDR_MASK;
+        }
+        None
+    }
+
+    /// Whether the entry at `level` for `virt` points to a lower table, so a
+    /// large page can't be put there
+    fn has_table(&self, virt: VirtAddr, level: usize) -> bool {
+        let mut addr = self.pml4.0;
+        for level in (level..=4).rev() {
+            let entry = unsafe { table(addr) }.entries[virt.table_index(level)];
+            if entry & Flags::PRESENT.0 == 0 || entry & Flags::HUGE.0 != 0 {
+                return false;
+            }
+            addr = entry & ADDR_MASK;
+        }
+        true
+    }
+
+    /// Map one page of `level` (2 MiB at 2, 1 GiB at 3) at `virt` to `phys`
+    fn map_at(&self, virt: VirtAddr, phys: PhysAddr, level: usize, flags: Flags) -> Result<(), MapError> {
+        let entry = unsafe { &mut *self.entry(virt, level)? };
+        if *entry & Flags::PRESENT.0 != 0 {
+            return Err(MapError::AlreadyMapped);
+        }
+        *entry = phys.0 | leaf_bits(flags, level);
+        Ok(())
+    }
+
+    /// Map `size` bytes at `virt` to `phys` with the largest pages that fit
+    /// With `skip_mapped` pages which are mapped already are left alone,
+    /// otherwise they stop the mapping and the bytes mapped so far are
+    /// returned with the error
+    fn map_pages(&self, virt: VirtAddr, phys: PhysAddr, size: u64, flags: Flags, skip_mapped: bool)
+            -> Result<(), (MapError, u64)> {
+        check_range(virt, size).map_err(|err| (err, 0))?;
+        if !phys.0.is_multiple_of(PAGE_SIZE) {
+            return Err((MapError::Misaligned, 0));
+        }
+
+        let max_level = if GIGANTIC_PAGES.load(Ordering::Relaxed) {3} else {2};
+        let mut done = 0;
+        while done < size {
+            let (virt, phys, left) = (VirtAddr(virt.0 + done), PhysAddr(phys.0 + done), size - done);
+            let level = (2..=max_level).rev()
+                .find(|level| {
+                    let size = page_size(*level);
+                    virt.is_aligned(size) && phys.0.is_multiple_of(size) && left >= size && !self.has_table(virt, *level)
+                })
+                .unwrap_or(1);
+
+            match self.map_at(virt, phys, level, flags) {
+                Ok(()) => {},
+                Err(MapError::AlreadyMapped) if skip_mapped => {},
+                Err(err) => return Err((err, done)),
+            }
+            done += page_size(level);
+        }
+
+        Ok(())
+    }
+
+    /// Map the page at `virt` to `phys`
+    pub fn map_page(&self, virt: VirtAddr, phys: PhysAddr, flags: Flags,
+            _lock: &LockHeld<TableLock>, _irq: &InIrqContext) -> Result<(), MapError> {
+        check_range(virt, PAGE_SIZE)?;
+        if !phys.0.is_multiple_of(PAGE_SIZE) {
+            return Err(MapError::Misaligned);
+        }
+        self.map_at(virt, phys, 1, flags)
+    }
+
+    /// Map `size` bytes at `virt` to `phys`, using large pages where the
+    /// addresses and size allow
+    /// Nothing is mapped if any page fails, e.g. because it is mapped already
+    pub fn map_range(&self, virt: VirtAddr, phys: PhysAddr, size: u64, flags: Flags,
+            lock: &LockHeld<TableLock>, irq: &InIrqContext) -> Result<(), MapError> {
+        self.map_pages(virt, phys, size, flags, false).map_err(|(err, done)| {
+            // Those pages were not mapped before so they can't be in any TLB
+            if done > 0 {
+                let _ = self.unmap_range(virt, done, lock, irq);
+            }
+            err
+        })
+    }
+
+    /// Map `range` at the same virtual addresses, partial pages at either
+    /// end included
+    /// Pages which are mapped already are left alone
+    pub fn identity_map(&self, range: Range, flags: Flags, _lock: &LockHeld<TableLock>, _irq: &InIrqContext)
+            -> Result<(), MapError> {
+        let start = range.start & !(PAGE_SIZE - 1);
+        let end = range.end | (PAGE_SIZE - 1);
+        let size = (end - start).checked_add(1).ok_or(MapError::Misaligned)?;
+        self.map_pages(VirtAddr(start), PhysAddr(start), size, flags, true).map_err(|(err, _)| err)
+    }
+
+    /// Map `range` into the direct map, partial pages at either end included
+    /// Memory above the end of the window is left out
+    /// Pages which are mapped already are left alone
+    pub fn direct_map(&self, range: Range, flags: Flags, _lock: &LockHeld<TableLock>, _irq: &InIrqContext)
+            -> Result<(), MapError> {
+        if range.start >= DIRECT_MAP_SIZE {
+            return Ok(());
+        }
+        let start = range.start & !(PAGE_SIZE - 1);
+        let end = core::cmp::min(range.end, DIRECT_MAP_SIZE - 1) | (PAGE_SIZE - 1);
+        let size = end - start + 1;
+        self.map_pages(VirtAddr(super::direct_map_base() + start), PhysAddr(start), size, flags, true).map_err(|(err, _)| err)
+    }
+
+    /// Split the large page of `level` behind `entry` into a table of pages
+    /// one level down with the same flags, which maps the same memory
+    fn split(entry: &mut u64, level: usize) -> Result<(), MapError> {
+        let next = alloc_table()?;
+        let base = *entry & ADDR_MASK & !(page_size(level) - 1);
+        let mut flags = *entry & !ADDR_MASK;
+        if level == 2 {
+            flags &= !Flags::HUGE.0;
+        }
+
+        let entries = &mut unsafe { table(next) }.entries;
+        for (ii, small) in entries.iter_mut().enumerate() {
+            *small = (base + ii as u64 * page_size(level - 1)) | flags;
+        }
+        *entry = next | (Flags::PRESENT | Flags::WRITABLE | Flags::USER).0;
+        Ok(())
+    }
+
+    /// Run `f` on the entry of every page in `size` bytes at `virt`
+    /// Large pages only partly in the range are split first, whole ones are
+    /// handed to `f` as they are
+    /// Pages which are not mapped are skipped, the error is returned once
+    /// the rest has been handled
+    fn update_pages(&self, virt: VirtAddr, size: u64, mut f: impl FnMut(&mut u64, usize))
+            -> Result<(), MapError> {
+        check_range(virt, size)?;
+
+        let mut ret = Ok(());
+        let mut done = 0;
+        while done < size {
+            let (virt, left) = (VirtAddr(virt.0 + done), size - done);
+            let (entry, level) = match self.find(virt) {
+                Some((entry, level)) => (unsafe { &mut *entry }, level),
+                None => {
+                    ret = Err(MapError::NotMapped);
+                    done += PAGE_SIZE;
+                    continue;
+                },
+            };
+
+            if level > 1 && (!virt.is_aligned(page_size(level)) || left < page_size(level)) {
+                Self::split(entry, level)?;
+                continue;
+            }
+
+            f(entry, level);
+            done += page_size(level);
+        }
+
+        ret
+    }
+
+    /// Remove the mappings of `size` bytes at `virt`
+    /// The TLB is left to the caller
+    pub fn unmap_range(&self, virt: VirtAddr, size: u64, _lock: &LockHeld<TableLock>, _irq: &InIrqContext)
+            -> Result<(), MapError> {
+        self.update_pages(virt, size, |entry, _| *entry = 0)
+    }
+
+    /// Replace the flags of `size` bytes at `virt`, keeping where they are
+    /// mapped to
+    /// The TLB is left to the caller
+    #[allow(dead_code)]
+    pub fn set_flags(&self, virt: VirtAddr, size: u64, flags: Flags, _lock: &LockHeld<TableLock>, _irq: &InIrqContext)
+            -> Result<(), MapError> {
+        self.update_pages(virt, size, |entry, level| {
+            *entry = (*entry & ADDR_MASK) | leaf_bits(flags, level);
+        })
+    }
+
+    /// Call `f` with the address and size of every page which is both
+    /// writable and executable
+    /// The tables above a page restrict it as well, so their flags count too
+    pub fn writable_executable(&self, mut f: impl FnMut(VirtAddr, u64)) {
+        fn walk(addr: u64, level: usize, base: u64, f: &mut impl FnMut(VirtAddr, u64)) {
+            for (index, entry) in unsafe { table(addr) }.entries.iter().enumerate() {
+                let entry = Flags(*entry);
+                if !entry.contains(Flags::PRESENT | Flags::WRITABLE) || entry.contains(Flags::NO_EXECUTE) {
+                    continue;
+                }
+                let virt = base + index as u64 * page_size(level);
+                if level == 1 || entry.contains(Flags::HUGE) {
+                    f(VirtAddr::new_truncate(virt), page_size(level));
+                } else {
+                    walk(entry.0 & ADDR_MASK, level - 1, virt, f);
+                }
+            }
+        }
+        walk(self.pml4.0, 4, 0, &mut f);
+    }
+
+    /// Physical address `virt` is mapped to, if it is mapped
+    /// Understands large pages, so it works on the firmware tables as well
+    pub fn translate(&self, virt: VirtAddr) -> Option<PhysAddr> {
+        if !virt.is_canonical() {
+            return None;
+        }
+        let (entry, level) = self.find(virt)?;
+        let entry = unsafe { *entry };
+        let page_mask = page_size(level) - 1;
+        Some(PhysAddr((entry & ADDR_MASK & !page_mask) | (virt.0 & page_mask)))
+    }
+
+    /// Load the address space into CR3
+    ///
+    /// Safety: the code, stack and data in use must be mapped
+    pub unsafe fn activate(&self) {
+        cr::set_cr3(self.pml4.0);
+    }
+}
+
+
+/// PML4 of the kernel address space, 0 until `init()` has run
+static KERNEL_PML4: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the processor supports 1 GiB pages, checked by `init()`
+static GIGANTIC_PAGES: AtomicBool = AtomicBool::new(false);
+
+/// Whether the processor supports no-execute pages, checked by `init()`
+static NO_EXECUTE: AtomicBool = AtomicBool::new(false);
+
+
+/// The kernel address space, `None` until `init()` has run
+pub fn kernel_space() -> Option<AddressSpace> {
+    match KERNEL_PML4.load(Ordering::SeqCst) {
+        0 => None,
+        pml4 => Some(AddressSpace { pml4: PhysAddr(pml4) }),
+    }
+}
+
+
+/// Physical address `virt` is mapped to in the current address space
+#[allow(dead_code)]
+pub fn translate(virt: VirtAddr) -> Option<PhysAddr> {
+    AddressSpace::current().translate(virt)
+}
+
+
+/// Flags for a kernel page from the characteristics of the PE sections
+/// covering it, sections sharing a page get the union of their permissions
+fn section_flags(characteristics: impl Iterator<Item = u32>) -> Flags {
+    const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+    const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+    let mut flags = Flags::NO_EXECUTE;
+    for chars in characteristics {
+        if chars & IMAGE_SCN_MEM_EXECUTE != 0 {
+            flags = flags.remove(Flags::NO_EXECUTE);
+        }
+        if chars & IMAGE_SCN_MEM_WRITE != 0 {
+            flags = flags | Flags::WRITABLE;
+        }
+    }
+    flags
+}
+
+
+/// Read `len` bytes at offset `off` of the identity mapped image at `base`
+fn read_image(base: u64, off: u64, len: usize) -> u64 {
+    let mut val = [0u8; 8];
+    unsafe {
+        core::ptr::copy_nonoverlapping((base + off) as *const u8, val.as_mut_ptr(), len);
+    }
+    u64::from_le_bytes(val)
+}
+
+
+/// Map the kernel image at `kernel`, page by page with the permissions of the
+/// PE sections it belongs to, both where it is and at `virt`
+/// Pages outside of any section (the headers) are read-only
+fn map_kernel(space: &AddressSpace, kernel: Range, virt: u64, lock: &LockHeld<TableLock>, irq: &InIrqContext)
+        -> Result<(), MapError> {
+    let base = kernel.start;
+    let read = |off: u64, len: usize| read_image(base, off, len);
+
+    // DOS header points to the PE header, which is followed by the COFF
+    // header, the optional header and the section table
+    let pe = read(0x3c, 4);
+    let valid = read(0, 2) == 0x5a4d && pe < kernel.size() && read(pe, 4) == 0x4550;
+    let (sections, table) = if valid {
+        (read(pe + 6, 2), pe + 24 + read(pe + 20, 2))
+    } else {
+        warn!("Kernel image has no PE header, mapping it all writable");
+        (0, 0)
+    };
+
+    // (start, end, characteristics) of section `ii`
+    let section = |ii: u64| {
+        let entry = table + ii * 40;
+        let start = base + read(entry + 12, 4);
+        (start, start + read(entry + 8, 4), read(entry + 36, 4) as u32)
+    };
+
+    let mut page = base & !(PAGE_SIZE - 1);
+    while page <= kernel.end {
+        let flags = if valid {
+            section_flags((0..sections).map(section)
+                .filter(|(start, end, _)| *start < page + PAGE_SIZE && page < *end)
+                .map(|(_, _, chars)| chars))
+        } else {
+            Flags::WRITABLE
+        };
+        space.map_page(VirtAddr(page), PhysAddr(page), flags, lock, irq)?;
+        space.map_page(VirtAddr(virt + (page - (base & !(PAGE_SIZE - 1)))), PhysAddr(page), flags, lock, irq)?;
+        page += PAGE_SIZE;
+    }
+
+    Ok(())
+}
+
+
+/// Apply the base relocations of the kernel image at `kernel` once more, for
+/// running it `slide` bytes above where it is
+/// The firmware applied them when loading us, so only slots which still
+/// point into the image are moved, anything else was stored at runtime.
+/// Returns false if the image has no usable relocations, which may leave
+/// part of them applied: both mappings are valid, so that is harmless
+fn relocate(kernel: Range, slide: u64) -> bool {
+    const IMAGE_DIRECTORY_ENTRY_BASERELOC: u64 = 5;
+    const IMAGE_REL_BASED_ABSOLUTE: u64 = 0;
+    const IMAGE_REL_BASED_DIR64: u64 = 10;
+
+    let base = kernel.start;
+    let read = |off: u64, len: usize| read_image(base, off, len);
+
+    // The PE32+ optional header ends in the data directories, the relocations
+    // are a list of blocks of 16-bit entries for one 4 KiB page each
+    let pe = read(0x3c, 4);
+    if read(0, 2) != 0x5a4d || pe >= kernel.size() || read(pe, 4) != 0x4550 {
+        return false;
+    }
+    let opt = pe + 24;
+    if read(opt, 2) != 0x20b || read(opt + 108, 4) <= IMAGE_DIRECTORY_ENTRY_BASERELOC {
+        return false;
+    }
+    let dir = opt + 112 + IMAGE_DIRECTORY_ENTRY_BASERELOC * 8;
+    let (mut block, end) = (read(dir, 4), read(dir, 4) + read(dir + 4, 4));
+    if block == 0 || end > kernel.size() {
+        return false;
+    }
+
+    while block + 8 <= end {
+        let (page, size) = (read(block, 4), read(block + 4, 4));
+        if size < 8 || block + size > end {
+            return false;
+        }
+
+        for entry in (block + 8..block + size).step_by(2) {
+            let entry = read(entry, 2);
+            match entry >> 12 {
+                IMAGE_REL_BASED_ABSOLUTE => {},
+                IMAGE_REL_BASED_DIR64 => {
+                    let slot = page + (entry & 0xfff);
+                    if slot + 8 > kernel.size() {
+                        return false;
+                    }
+
+                    // Read-only sections have relocations too, they are
+                    // written through the direct map
+                    let slot = phys_to_virt(PhysAddr(base + slot)).0 as *mut u64;
+                    unsafe {
+                        let val = slot.read_unaligned();
+                        if (kernel.start..=kernel.end).contains(&val) {
+                            slot.write_unaligned(val + slide);
+                        }
+                    }
+                },
+                _ => return false,
+            }
+        }
+        block += size;
+    }
+
+    true
+}
+
+
+/// Fill the new kernel address space `space`
+fn build(space: &AddressSpace, map: &MemoryMap, kernel: Range, lock: &LockHeld<TableLock>, irq: &InIrqContext)
+        -> Result<(), MapError> {
+    // The kernel goes first so the rest of the identity map leaves its
+    // pages alone
+    if kernel.size() > 1 {
+        map_kernel(space, kernel, super::kernel_base(), lock, irq)?;
+    }
+
+    let mut memory = RangeSet::new();
+    let mut runtime_code = RangeSet::new();
+    memory.insert(Range { start: 0, end: LOW_MEMORY - 1 });
+    for desc in map.iter() {
+        if let Some(range) = Range::new(desc.PhysicalAddress, desc.NumberOfPages * PAGE_SIZE) {
+            if EFI_MEMORY_TYPE::from(desc.Type) == EFI_MEMORY_TYPE::EfiRuntimeServiceCode {
+                runtime_code.insert(range);
+            } else {
+                memory.insert(range);
+            }
+        }
+    }
+
+    // The null page stays unmapped so null pointer dereferences fault, and
+    // so does boot services memory while it is being guarded
+    memory.remove(Range { start: 0, end: PAGE_SIZE - 1 });
+    for range in super::bsguard::held().entries() {
+        memory.remove(*range);
+    }
+
+    // Runtime services keep running at their physical addresses, so their
+    // code is identity mapped executable, but not writable
+    for range in runtime_code.entries() {
+        memory.remove(*range);
+        space.identity_map(*range, Flags::empty(), lock, irq)?;
+        space.direct_map(*range, Flags::NO_EXECUTE, lock, irq)?;
+    }
+
+    // Data only, nothing but the kernel and runtime services may be executed
+    for range in memory.entries() {
+        space.identity_map(*range, Flags::WRITABLE | Flags::NO_EXECUTE, lock, irq)?;
+        space.direct_map(*range, Flags::WRITABLE | Flags::NO_EXECUTE, lock, irq)?;
+    }
+
+    Ok(())
+}
+
+
+/// Build the kernel address space from the final memory `map` and switch to it
+/// `kernel` is the memory our image occupies
+/// Must be called once, after the physical memory manager is up
+pub fn init(map: &MemoryMap, kernel: Range) -> Result<(), MapError> {
+    if kernel_space().is_some() {return Ok(());}
+
+    GIGANTIC_PAGES.store(features::has_1gb_pages(), Ordering::Relaxed);
+    NO_EXECUTE.store(features::has_nx(), Ordering::Relaxed);
+    if !NO_EXECUTE.load(Ordering::Relaxed) {
+        warn!("No-execute pages are not supported, all memory is executable");
+    }
+
+    super::randomize_layout(kernel);
+    let space = AddressSpace::new()?;
+    lock_tables(|lock, irq| build(&space, map, kernel, lock, irq))?;
+
+    unsafe {
+        // No-execute and write protection have to be on before the tables
+        // relying on them are
+        if NO_EXECUTE.load(Ordering::Relaxed) {
+            msr::set_bits(msr::IA32_EFER, msr::EFER_NXE);
+        }
+
+        // Makes read-only pages read-only for the kernel too
+        cr::set_cr0(cr::cr0() | Cr0::WRITE_PROTECT);
+
+        // Nothing uses PCD alone yet, but whatever the firmware left in the
+        // caches has to go before its meaning changes. Loading the tables
+        // flushes the TLB
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+        msr::wrmsr(msr::IA32_PAT, PAT_VALUE);
+
+        space.activate();
+    }
+    super::release_firmware_tables();
+
+    KERNEL_PML4.store(space.pml4.0, Ordering::SeqCst);
+    super::enable_direct_map();
+    check_writable_executable(&space);
+
+    if kernel.size() > 1 {
+        let slide = super::kernel_base().wrapping_sub(kernel.start & !(PAGE_SIZE - 1));
+        if relocate(kernel, slide) {
+            super::enable_kernel_slide(slide);
+        } else {
+            warn!("Kernel image can't be relocated, running at {:#x}", kernel.start);
+        }
+    }
+    Ok(())
+}
+
+
+/// Warn about every writable and executable mapping in `space`
+/// Nothing should be both, W^X is broken if this finds anything
+fn check_writable_executable(space: &AddressSpace) {
+    if !NO_EXECUTE.load(Ordering::Relaxed) {return;}
+
+    // Adjacent pages merge into one range
+    let mut found = RangeSet::new();
+    space.writable_executable(|virt, size| {
+        found.insert(Range { start: virt.0, end: virt.0 + (size - 1) });
+    });
+
+    for range in found.entries() {
+        warn!("Writable and executable mapping at {:#x}-{:#x}", range.start, range.end);
+    }
+    if found.entries().is_empty() {
+        info!("W^X: no writable and executable mappings");
+    }
+}
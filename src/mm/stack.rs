@@ -0,0 +1,104 @@
+//! Kernel stacks with guard pages
+//! Every core gets its stack in a slot of its own in the stack window, with
+//! the page below the stack left unmapped. A stack overflow then faults on
+//! that guard page instead of silently running into whatever memory is below,
+//! and the page fault handler can tell it apart from any other fault with
+//! `overflow_core()`
+//!
+//! ```text
+//! slot N:  | guard page | STACK_SIZE of stack -> top |
+//! ```
+use super::paging::MapError;
+use super::virt::{self, MapFlags};
+use super::{VirtAddr, PAGE_SIZE};
+
+
+/// Start of the stack window
+const STACKS_BASE: u64 = 0xffff_d000_0000_0000;
+
+/// Size of a kernel stack, without its guard page
+pub const STACK_SIZE: u64 = 64 * 1024;
+
+/// Size of a slot, the stack and its guard page
+const SLOT_SIZE: u64 = STACK_SIZE + PAGE_SIZE;
+
+/// Number of cores we have slots for
+const MAX_CORES: u32 = 256;
+
+
+/// The kernel stack of a core
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stack {
+    core: u32,
+}
+
+impl Stack {
+    /// Core the stack belongs to
+    #[allow(dead_code)]
+    pub fn core(&self) -> u32 {
+        self.core
+    }
+
+    /// Lowest address of the stack, right above the guard page
+    pub fn bottom(&self) -> VirtAddr {
+        VirtAddr(STACKS_BASE + self.core as u64 * SLOT_SIZE + PAGE_SIZE)
+    }
+
+    /// Address just above the stack, where it starts growing down from
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr(self.bottom().0 + STACK_SIZE)
+    }
+
+    /// Switch to the stack and call `f` on it
+    /// There is no way back, the current stack is abandoned
+    ///
+    /// Safety: nothing may be running on the stack already
+    pub unsafe fn switch_to(&self, f: extern "C" fn() -> !) -> ! {
+        // The top is page aligned, so the stack is aligned as the call
+        // expects. A zero frame pointer ends backtraces here
+        core::arch::asm!(
+            "mov rsp, {top}",
+            "xor ebp, ebp",
+            "call {f}",
+            "ud2",
+            top = in(reg) self.top().0,
+            f = in(reg) f,
+            options(noreturn),
+        );
+    }
+}
+
+
+/// Allocate and map the stack of `core`, below its unmapped guard page
+pub fn alloc(core: u32) -> Result<Stack, MapError> {
+    if core >= MAX_CORES {
+        return Err(MapError::OutOfMemory);
+    }
+
+    let stack = Stack { core };
+    let phys = super::alloc_phys(STACK_SIZE, PAGE_SIZE).ok_or(MapError::OutOfMemory)?;
+    let flags = MapFlags { writable: true, ..MapFlags::default() };
+    if let Err(err) = virt::map(stack.bottom(), phys, STACK_SIZE, flags) {
+        if let Some(range) = super::Range::new(phys.0, STACK_SIZE) {
+            super::free_phys(range);
+        }
+        return Err(err);
+    }
+    Ok(stack)
+}
+
+
+/// Core whose stack overflowed if `addr`, the address of a page fault, is on
+/// a guard page
+pub fn overflow_core(addr: VirtAddr) -> Option<u32> {
+    let offset = addr.0.checked_sub(STACKS_BASE)?;
+    let core = offset / SLOT_SIZE;
+    (core < MAX_CORES as u64 && offset % SLOT_SIZE < PAGE_SIZE).then_some(core as u32)
+}
+
+
+/// Core whose stack `addr` is on, guard page included
+pub fn owner_core(addr: VirtAddr) -> Option<u32> {
+    let core = addr.0.checked_sub(STACKS_BASE)? / SLOT_SIZE;
+    (core < MAX_CORES as u64).then_some(core as u32)
+}
@@ -0,0 +1,159 @@
+//! Virtual memory mapping API
+//! `map()`, `unmap()` and `protect()` change the kernel address space in
+//! units of pages and take care of the TLB, so MMIO, the heap and later user
+//! space don't have to touch page table entries themselves
+//!
+//! Only the TLB of the calling processor is flushed directly, once other
+//! processors are running they get theirs flushed through the shootdown hook
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::paging::{self, AddressSpace, Flags, MapError, TableLock};
+use super::{PhysAddr, VirtAddr, PAGE_SIZE};
+use crate::sync::{InIrqContext, LockHeld};
+
+
+/// Memory type of a mapping
+/// Selected through the PWT/PCD bits, which index the PAT entries set up by
+/// `paging::init()`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CacheType {
+    #[default]
+    WriteBack,
+    #[allow(dead_code)]
+    WriteThrough,
+    WriteCombining,
+    Uncached,
+}
+
+
+/// What a mapping allows
+/// The default is read-only, non-executable, kernel-only and write-back
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MapFlags {
+    pub writable: bool,
+    pub executable: bool,
+    pub user: bool,
+    pub cache: CacheType,
+}
+
+impl MapFlags {
+    /// Page table entry flags for these permissions
+    fn to_flags(self) -> Flags {
+        let mut flags = Flags::PRESENT;
+        if self.writable {
+            flags = flags | Flags::WRITABLE;
+        }
+        if !self.executable {
+            flags = flags | Flags::NO_EXECUTE;
+        }
+        if self.user {
+            flags = flags | Flags::USER;
+        }
+        match self.cache {
+            CacheType::WriteBack => flags,
+            CacheType::WriteThrough => flags | Flags::WRITE_THROUGH,
+            CacheType::WriteCombining => flags | Flags::NO_CACHE,
+            CacheType::Uncached => flags | Flags::NO_CACHE | Flags::WRITE_THROUGH,
+        }
+    }
+}
+
+
+/// Ranges bigger than this are flushed from the TLB all at once instead of
+/// page by page
+const FLUSH_ALL_THRESHOLD: u64 = 64 * PAGE_SIZE;
+
+
+/// Function invalidating a range on the other processors, 0 if there is none
+static SHOOTDOWN_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Run `f` on the kernel address space with the page tables locked
+fn with_tables<R>(f: impl FnOnce(&AddressSpace, &LockHeld<TableLock>, &InIrqContext) -> Result<R, MapError>)
+        -> Result<R, MapError> {
+    // Before paging is set up we are still on the firmware tables
+    let space = paging::kernel_space().unwrap_or_else(AddressSpace::current);
+    paging::lock_tables(|lock, irq| f(&space, lock, irq))
+}
+
+
+/// Register the function which invalidates `size` bytes at a virtual
+/// address in the TLBs of the other processors
+#[allow(dead_code)]
+pub fn set_shootdown_hook(hook: fn(VirtAddr, u64)) {
+    SHOOTDOWN_HOOK.store(hook as usize, Ordering::SeqCst);
+}
+
+
+/// Invalidate `size` bytes at `virt` in the TLB of every processor
+#[allow(dead_code)]
+pub fn flush_tlb(virt: VirtAddr, size: u64) {
+    if size > FLUSH_ALL_THRESHOLD {
+        // Reloading CR3 drops every non-global translation
+        unsafe {
+            let cr3: u64;
+            core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+            core::arch::asm!("mov cr3, {}", in(reg) cr3, options(nostack, preserves_flags));
+        }
+    } else {
+        let end = virt.0.saturating_add(size);
+        let mut page = virt.page();
+        while page.0 < end {
+            unsafe {
+                core::arch::asm!("invlpg [{}]", in(reg) page.0, options(nostack, preserves_flags));
+            }
+            page = VirtAddr(page.0 + PAGE_SIZE);
+        }
+    }
+
+    let hook = SHOOTDOWN_HOOK.load(Ordering::SeqCst);
+    if hook != 0 {
+        let hook: fn(VirtAddr, u64) = unsafe { core::mem::transmute(hook) };
+        hook(virt, size);
+    }
+}
+
+
+/// Map `size` bytes at `virt` to the physical memory at `phys`
+/// Large pages are used where the addresses and size allow, mappings which
+/// are both writable and executable are refused
+/// Nothing is mapped if any page fails, e.g. because it is mapped already
+pub fn map(virt: VirtAddr, phys: PhysAddr, size: u64, flags: MapFlags) -> Result<(), MapError> {
+    if flags.writable && flags.executable {
+        return Err(MapError::WritableExecutable);
+    }
+    with_tables(|space, lock, irq| space.map_range(virt, phys, size, flags.to_flags(), lock, irq))
+}
+
+
+/// Remove the mapping of `size` bytes at `virt`
+/// Pages which are not mapped are skipped, the error is returned once the
+/// rest has been unmapped
+#[allow(dead_code)]
+pub fn unmap(virt: VirtAddr, size: u64) -> Result<(), MapError> {
+    let ret = with_tables(|space, lock, irq| space.unmap_range(virt, size, lock, irq));
+    flush_tlb(virt, size);
+    ret
+}
+
+
+/// Change the permissions of `size` bytes at `virt`, which must be mapped
+/// Large pages only partly in the range are split, permissions which are
+/// both writable and executable are refused
+/// Pages which are not mapped are skipped, the error is returned once the
+/// rest has been changed
+#[allow(dead_code)]
+pub fn protect(virt: VirtAddr, size: u64, flags: MapFlags) -> Result<(), MapError> {
+    if flags.writable && flags.executable {
+        return Err(MapError::WritableExecutable);
+    }
+    let ret = with_tables(|space, lock, irq| space.set_flags(virt, size, flags.to_flags(), lock, irq));
+    flush_tlb(virt, size);
+    ret
+}
+
+
+/// Physical address `virt` is mapped to in the kernel address space
+#[allow(dead_code)]
+pub fn translate(virt: VirtAddr) -> Option<PhysAddr> {
+    with_tables(|space, _, _| space.translate(virt).ok_or(MapError::NotMapped)).ok()
+}
@@ -1,26 +1,128 @@
+//! Kernel panic handling
+//! Prints everything we know about the panic and then does what the `panic=`
+//! command line option says:
+//!
+//! ```text
+//! panic=halt      halt forever (the default)
+//! panic=<secs>    reboot after <secs> seconds, 0 reboots right away
+//! panic=exit      return to the firmware, if boot services are still up
+//! ```
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::cpu::regs::Registers;
+use crate::efi::{self, reset::EFI_RESET_TYPE, EFI_STATUS};
+
+
+/// Number of bytes of the stack dumped, starting at RSP
+const STACK_DUMP_LEN: usize = 256;
+
+
+/// Set once we start handling a panic
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+
+/// Whether a panic is being handled, the code that panicked will never
+/// release what it holds
+pub fn panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
+}
+
+
+/// What to do once the panic has been reported
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Policy {
+    Halt,
+    Reboot(u64),
+    Exit,
+}
+
+impl Policy {
+    /// Read the policy from the command line, halting if unset or invalid
+    fn from_cmdline() -> Policy {
+        match crate::cmdline::get("panic") {
+            Some("exit") => Policy::Exit,
+            Some(secs) => secs.parse().map(Policy::Reboot).unwrap_or(Policy::Halt),
+            None => Policy::Halt,
+        }
+    }
+}
+
+
+/// Halt this processor forever
+fn halt() -> ! {
+    loop{
+        unsafe{
+            core::arch::asm!("cli; hlt");
+        }
+    }
+}
 
 // See: https://doc.rust-lang.org/std/panic/struct.PanicInfo.html#method.location
 #[panic_handler]
 fn panic(info: &PanicInfo) -> !{
-    eprint!("[!] KERNEL PANIC\n");
+    // Grab the registers before we clobber them any further
+    let regs = Registers::capture();
+
+    // Don't wait on a print lock which may never be released
+    crate::print::emergency();
+
+    // Panicking again means reporting the first panic broke, most likely in
+    // the console, so say what we can on the raw outputs and stop
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        crate::console::force_early();
+        eprintln!("[!] PANIC WHILE PANICKING");
+        if let Some(location) = info.location() {
+            eprintln!("[!] AT {}:{}", location.file(), location.line());
+        }
+        halt();
+    }
+
+    eprintln!("[!] KERNEL PANIC");
 
     if let Some(location) = info.location() {
-        eprint!("[!] PANIC OCCURED IN FILE '{}' AT LINE {}\n",
+        eprintln!("[!] PANIC OCCURED IN FILE '{}' AT LINE {}",
             location.file(),
             location.line(),
         );
     };
 
     if let Some(message) = info.message() {
-        eprint!("[!] PANIC MESSAGE: {}\n",
+        eprintln!("[!] PANIC MESSAGE: {}",
             message
         );
     };
 
-    loop{
-        unsafe{
-            core::arch::asm!("hlt");
-        }
+    eprintln!("[!] REGISTERS:\n{}", regs);
+
+    eprintln!("[!] BACKTRACE:");
+    crate::backtrace::print(regs.rbp, regs.rsp);
+
+    // Physical memory is identity mapped and the stack is ours, so the top
+    // of the stack is always readable
+    eprintln!("[!] STACK:");
+    unsafe {
+        crate::hexdump::hexdump_addr(regs.rsp, STACK_DUMP_LEN);
     }
+
+    // Keep a copy for the next boot in case nobody is watching the console
+    if crate::crashlog::save(info, &regs) {
+        eprintln!("[!] PANIC REPORT SAVED");
+    }
+
+    match Policy::from_cmdline() {
+        Policy::Halt => (),
+        Policy::Reboot(secs) => {
+            eprintln!("[!] REBOOTING IN {} SECONDS", secs);
+            crate::time::udelay(secs.saturating_mul(1_000_000));
+            efi::reset::reset_system(EFI_RESET_TYPE::Cold, EFI_STATUS::EFI_ABORTED);
+            crate::acpi::reboot();
+            eprintln!("[!] REBOOT FAILED");
+        },
+        Policy::Exit => {
+            let status = efi::exit(EFI_STATUS::EFI_ABORTED);
+            eprintln!("[!] EXIT TO FIRMWARE FAILED: {:#x}", status.0);
+        },
+    }
+
+    halt()
 }
@@ -0,0 +1,264 @@
+//! PCI configuration space access
+//! PCI Express devices have 4 KiB of configuration space, which is only
+//! reachable through the memory mapped ECAM windows described by the ACPI
+//! MCFG. The legacy 0xcf8/0xcfc port mechanism only reaches the first 256
+//! bytes of functions in segment 0, where the extended capabilities (AER,
+//! SR-IOV, ...) aren't, so it is only used when no ECAM window covers a bus
+//! See: https://wiki.osdev.org/PCI_Express
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::acpi::mcfg::{EcamRegion, MAX_REGIONS};
+use crate::cpu::port;
+use crate::mm::{self, VirtAddr};
+use crate::sync::SpinLock;
+
+
+/// Size of the configuration space of a function behind ECAM
+pub const CONFIG_SIZE: u16 = 4096;
+
+/// Size of the configuration space reachable through the legacy ports
+pub const LEGACY_CONFIG_SIZE: u16 = 256;
+
+/// Legacy configuration address port
+const CONFIG_ADDRESS: u16 = 0xcf8;
+
+/// Legacy configuration data port
+const CONFIG_DATA: u16 = 0xcfc;
+
+
+/// A PCI function
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Address {
+    pub segment: u16,
+    pub bus: u8,
+
+    // 0 to 31
+    pub device: u8,
+
+    // 0 to 7
+    pub function: u8,
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+
+/// The mapped ECAM windows
+/// Slots are filled in with `writer` held and never modified once `count`
+/// covers them, so readers don't need the lock
+struct EcamTable {
+    writer: SpinLock<()>,
+    count: AtomicUsize,
+    regions: UnsafeCell<[Option<(EcamRegion, VirtAddr)>; MAX_REGIONS]>,
+}
+
+unsafe impl Sync for EcamTable {}
+
+static ECAM: EcamTable = EcamTable {
+    writer: SpinLock::new(()),
+    count: AtomicUsize::new(0),
+    regions: UnsafeCell::new([None; MAX_REGIONS]),
+};
+
+
+/// Map the ECAM `regions` from the MCFG
+pub fn init(regions: &[EcamRegion]) {
+    let _writer = ECAM.writer.lock();
+    let slots = unsafe { &mut *ECAM.regions.get() };
+    let mut count = ECAM.count.load(Ordering::Acquire);
+    for region in regions {
+        if count == MAX_REGIONS {
+            warn!("Out of ECAM slots, ignoring segment {}", region.segment);
+            break;
+        }
+        match mm::map_mmio(region.start(), region.size()) {
+            Ok(virt) => {
+                debug!("ECAM for segment {} buses {}-{} at {:#x}", region.segment, region.start_bus,
+                    region.end_bus, region.start().0);
+                slots[count] = Some((*region, virt));
+                count += 1;
+                ECAM.count.store(count, Ordering::Release);
+            },
+            Err(err) => warn!("Could not map ECAM at {:#x}: {:?}", region.start().0, err),
+        }
+    }
+}
+
+
+/// Whether any ECAM window is mapped, i.e. the extended configuration space
+/// is reachable
+#[allow(dead_code)]
+pub fn has_ecam() -> bool {
+    ECAM.count.load(Ordering::Acquire) != 0
+}
+
+
+/// Held while the legacy address and data ports are in use, they only work
+/// as a pair
+static LEGACY: SpinLock<()> = SpinLock::new(());
+
+
+/// Select the legacy configuration `address` and run `f` to access the data
+/// port, with interrupts off and the ports to ourselves
+fn legacy<R>(address: u32, f: impl FnOnce() -> R) -> R {
+    let _legacy = LEGACY.lock();
+    unsafe {
+        port::outl(CONFIG_ADDRESS, address);
+    }
+    f()
+}
+
+
+/// Address of the configuration space of `addr` in its ECAM window
+/// Windows are mapped from their first bus on
+fn ecam_base(addr: Address) -> Option<VirtAddr> {
+    let count = ECAM.count.load(Ordering::Acquire);
+    let slots = unsafe { &*ECAM.regions.get() };
+    let (region, virt) = slots[..count].iter().flatten()
+        .find(|(region, _)| region.contains(addr.segment, addr.bus))?;
+
+    let offset = ((addr.bus - region.start_bus) as u64) << 20 |
+        (addr.device as u64 & 0x1f) << 15 |
+        (addr.function as u64 & 0x7) << 12;
+    Some(VirtAddr(virt.0 + offset))
+}
+
+
+/// Where the configuration register at `offset` of `addr` is
+enum Access {
+    Ecam(*mut u32),
+    Legacy(u32),
+}
+
+/// Find the dword holding the byte at `offset` of the configuration space
+/// of `addr`
+/// `None` if `offset` is beyond what is reachable for `addr`
+fn locate(addr: Address, offset: u16) -> Option<Access> {
+    if offset >= CONFIG_SIZE {
+        return None;
+    }
+    let dword = offset & !3;
+
+    if let Some(base) = ecam_base(addr) {
+        return Some(Access::Ecam((base.0 + dword as u64) as *mut u32));
+    }
+
+    if addr.segment != 0 || offset >= LEGACY_CONFIG_SIZE {
+        return None;
+    }
+    Some(Access::Legacy(
+        1 << 31 |
+        (addr.bus as u32) << 16 |
+        (addr.device as u32 & 0x1f) << 11 |
+        (addr.function as u32 & 0x7) << 8 |
+        dword as u32
+    ))
+}
+
+
+/// Read the aligned dword at `offset`, rounded down to a multiple of 4
+fn read_dword(addr: Address, offset: u16) -> Option<u32> {
+    match locate(addr, offset)? {
+        Access::Ecam(ptr) => Some(unsafe { core::ptr::read_volatile(ptr) }),
+        Access::Legacy(address) => Some(legacy(address, || unsafe { port::inl(CONFIG_DATA) })),
+    }
+}
+
+
+/// Write the aligned dword at `offset`, rounded down to a multiple of 4
+fn write_dword(addr: Address, offset: u16, val: u32) -> Option<()> {
+    match locate(addr, offset)? {
+        Access::Ecam(ptr) => unsafe { core::ptr::write_volatile(ptr, val) },
+        Access::Legacy(address) => legacy(address, || unsafe { port::outl(CONFIG_DATA, val) }),
+    }
+    Some(())
+}
+
+
+/// Read the 32-bit register at `offset`, which must be 4 byte aligned
+/// `None` if the register isn't reachable
+#[allow(dead_code)]
+pub fn read_u32(addr: Address, offset: u16) -> Option<u32> {
+    if !offset.is_multiple_of(4) {
+        return None;
+    }
+    read_dword(addr, offset)
+}
+
+
+/// Read the 16-bit register at `offset`, which must be 2 byte aligned
+#[allow(dead_code)]
+pub fn read_u16(addr: Address, offset: u16) -> Option<u16> {
+    if !offset.is_multiple_of(2) {
+        return None;
+    }
+    Some((read_dword(addr, offset)? >> ((offset & 2) * 8)) as u16)
+}
+
+
+/// Read the 8-bit register at `offset`
+#[allow(dead_code)]
+pub fn read_u8(addr: Address, offset: u16) -> Option<u8> {
+    Some((read_dword(addr, offset)? >> ((offset & 3) * 8)) as u8)
+}
+
+
+/// Write the 32-bit register at `offset`, which must be 4 byte aligned
+#[allow(dead_code)]
+pub fn write_u32(addr: Address, offset: u16, val: u32) -> Option<()> {
+    if !offset.is_multiple_of(4) {
+        return None;
+    }
+    write_dword(addr, offset, val)
+}
+
+
+/// Write the 16-bit register at `offset`, which must be 2 byte aligned
+/// Through the legacy ports the other half of the dword is read and written
+/// back, which clears any write-1-to-clear bits set in it
+#[allow(dead_code)]
+pub fn write_u16(addr: Address, offset: u16, val: u16) -> Option<()> {
+    if !offset.is_multiple_of(2) {
+        return None;
+    }
+    match locate(addr, offset)? {
+        Access::Ecam(ptr) => unsafe {
+            core::ptr::write_volatile(ptr.cast::<u8>().add(offset as usize & 3).cast::<u16>(), val);
+        },
+        Access::Legacy(address) => legacy(address, || unsafe {
+            let shift = (offset & 2) * 8;
+            let dword = port::inl(CONFIG_DATA) & !(0xffff << shift) | (val as u32) << shift;
+            port::outl(CONFIG_DATA, dword);
+        }),
+    }
+    Some(())
+}
+
+
+/// Write the 8-bit register at `offset`
+/// Through the legacy ports the rest of the dword is read and written back,
+/// as for `write_u16()`
+pub fn write_u8(addr: Address, offset: u16, val: u8) -> Option<()> {
+    match locate(addr, offset)? {
+        Access::Ecam(ptr) => unsafe {
+            core::ptr::write_volatile(ptr.cast::<u8>().add(offset as usize & 3), val);
+        },
+        Access::Legacy(address) => legacy(address, || unsafe {
+            let shift = (offset & 3) * 8;
+            let dword = port::inl(CONFIG_DATA) & !(0xff << shift) | (val as u32) << shift;
+            port::outl(CONFIG_DATA, dword);
+        }),
+    }
+    Some(())
+}
+
+
+/// Vendor ID of `addr`, `None` if there is no such function
+#[allow(dead_code)]
+pub fn vendor_id(addr: Address) -> Option<u16> {
+    read_u16(addr, 0).filter(|vendor| *vendor != 0xffff)
+}
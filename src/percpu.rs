@@ -0,0 +1,114 @@
+//! Per-core data
+//! Every core has a `Core` of its own in a static slot, and points its GS
+//! base at it. `core!()` then finds the calling core's with a single load
+//! from GS, which works anywhere, interrupt handlers included, once
+//! `init()` has run on the core
+//!
+//! User mode isn't there yet, once it is the kernel GS base has to be
+//! swapped in with `swapgs` on entry
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::cpu::{features, msr};
+
+
+/// Number of cores we have slots for
+const MAX_CORES: usize = 256;
+
+
+/// Event counters of a core
+pub struct Stats {
+    pub exceptions: AtomicU64,
+    pub page_faults: AtomicU64,
+}
+
+
+/// Data of a core
+#[repr(C)]
+pub struct Core {
+    // Address of this structure, must stay first, `current()` reads it
+    // through GS
+    this: u64,
+
+    // Index of the core, 0 is the boot processor
+    pub id: u32,
+
+    // Local APIC ID, what interrupts are addressed to
+    pub apic_id: u32,
+
+    pub stats: Stats,
+}
+
+impl Core {
+    const fn new() -> Self {
+        Core {
+            this: 0,
+            id: 0,
+            apic_id: 0,
+            stats: Stats {
+                exceptions: AtomicU64::new(0),
+                page_faults: AtomicU64::new(0),
+            },
+        }
+    }
+}
+
+
+/// Whether the boot processor has run `init()`, other cores do before they
+/// run anything else
+static READY: AtomicBool = AtomicBool::new(false);
+
+
+/// The slots, each core only ever writes its own, once
+struct Slots(UnsafeCell<[Core; MAX_CORES]>);
+
+unsafe impl Sync for Slots {}
+
+static SLOTS: Slots = Slots(UnsafeCell::new([const { Core::new() }; MAX_CORES]));
+
+
+/// Set up the `Core` of `core` and point the GS base of the calling
+/// processor at it
+/// Must come after `gdt::init()`, loading GS clears its base
+///
+/// Safety: must be called on `core` itself, once, before anything uses
+/// `core!()` on it
+pub unsafe fn init(core: u32) {
+    let slot = match (*SLOTS.0.get()).get_mut(core as usize) {
+        Some(slot) => slot,
+        None => panic!("No per-core slot for core {}", core),
+    };
+    slot.this = slot as *mut Core as u64;
+    slot.id = core;
+    slot.apic_id = features::cpuid(1, 0).ebx >> 24;
+
+    msr::wrmsr(msr::IA32_GS_BASE, slot.this);
+    READY.store(true, Ordering::Release);
+    debug!("Core {} has APIC ID {}", core, slot.apic_id);
+}
+
+
+/// The calling core's `Core`
+/// Only valid once `init()` has run on it, use `core!()`
+#[inline]
+pub fn current() -> &'static Core {
+    let this: u64;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) this, options(readonly, nostack, preserves_flags));
+        &*(this as *const Core)
+    }
+}
+
+
+/// Index of the calling core, `None` before `init()`, for code which may run
+/// that early
+pub fn id() -> Option<u32> {
+    READY.load(Ordering::Acquire).then(|| current().id)
+}
+
+
+/// The calling core's `Core`, e.g. `core!().id`
+macro_rules! core {
+    () => {
+        $crate::percpu::current()
+    };
+}
@@ -0,0 +1,104 @@
+//! Legacy 8259 PICs
+//! The two cascaded PICs are still there on most PCs, and reset to deliver
+//! IRQs 0-15 on vectors 8-15 and 0x70-0x77, the first of which are CPU
+//! exceptions: a stray timer tick would look like a double fault. The IO
+//! APICs take over all interrupts, so the PICs are moved out of the way and
+//! every line masked before anything enables interrupts
+//!
+//! A masked PIC can still raise a spurious IRQ 7 or 15, which then arrives
+//! on `SPURIOUS_MASTER` or `SPURIOUS_SLAVE`. Writes to the ports do nothing
+//! on machines without PICs
+//! See: https://wiki.osdev.org/8259_PIC
+use crate::cpu::port::{inb, outb};
+
+
+// Command and data ports of the master and the slave
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xa0;
+const SLAVE_DATA: u16 = 0xa1;
+
+/// Unused port, writing to it takes long enough for the PICs to catch up
+const DELAY_PORT: u16 = 0x80;
+
+/// ICW1: initialization, ICW4 follows
+const ICW1_INIT: u8 = 0x11;
+
+/// ICW4: 8086 mode
+const ICW4_8086: u8 = 0x01;
+
+/// Master ICW3: the slave is on IRQ 2
+const MASTER_CASCADE: u8 = 1 << 2;
+
+/// Slave ICW3: its cascade identity
+const SLAVE_CASCADE: u8 = 2;
+
+/// OCW3: read the in-service register next
+const READ_ISR: u8 = 0x0b;
+
+/// End of interrupt command
+const EOI: u8 = 0x20;
+
+/// Vector of IRQ 0, the PICs get 16 vectors from here
+pub const VECTOR_BASE: u8 = 0x20;
+
+/// Vectors spurious IRQs of the master and the slave arrive on
+pub const SPURIOUS_MASTER: u8 = VECTOR_BASE + 7;
+pub const SPURIOUS_SLAVE: u8 = VECTOR_BASE + 15;
+
+
+/// Give the PICs time to process the last write
+unsafe fn delay() {
+    outb(DELAY_PORT, 0);
+}
+
+
+/// Move the PIC vectors to `VECTOR_BASE` and mask every line
+///
+/// Safety: interrupts must be disabled
+pub unsafe fn init() {
+    outb(MASTER_COMMAND, ICW1_INIT);
+    delay();
+    outb(SLAVE_COMMAND, ICW1_INIT);
+    delay();
+    outb(MASTER_DATA, VECTOR_BASE);
+    delay();
+    outb(SLAVE_DATA, VECTOR_BASE + 8);
+    delay();
+    outb(MASTER_DATA, MASTER_CASCADE);
+    delay();
+    outb(SLAVE_DATA, SLAVE_CASCADE);
+    delay();
+    outb(MASTER_DATA, ICW4_8086);
+    delay();
+    outb(SLAVE_DATA, ICW4_8086);
+    delay();
+
+    outb(MASTER_DATA, 0xff);
+    outb(SLAVE_DATA, 0xff);
+    debug!("Legacy PICs remapped to vector {:#x} and masked", VECTOR_BASE);
+}
+
+
+/// Whether an interrupt on `vector` is a spurious one from a PIC, which must
+/// be ignored
+/// A spurious IRQ 15 still needs an EOI to the master, which took it for a
+/// real one from the slave
+#[allow(dead_code)]
+pub fn spurious(vector: u8) -> bool {
+    let (command, irq) = match vector {
+        SPURIOUS_MASTER => (MASTER_COMMAND, 7),
+        SPURIOUS_SLAVE => (SLAVE_COMMAND, 7),
+        _ => return false,
+    };
+    unsafe {
+        outb(command, READ_ISR);
+        if inb(command) & (1 << irq) != 0 {
+            return false;
+        }
+        if vector == SPURIOUS_SLAVE {
+            outb(MASTER_COMMAND, EOI);
+        }
+    }
+    true
+}
@@ -0,0 +1,340 @@
+//! Persistent memory (NVDIMM) support
+//! Persistent memory is byte addressable like RAM but keeps its contents over
+//! a reset, so it must never end up in the general purpose allocator. Regions
+//! come from `EfiPersistentMemory` entries in the memory map and from the SPA
+//! range structures of the ACPI NFIT, and each region is accessed in place
+//! (DAX style) as well as registered as a block device
+//!
+//! Stores only become durable once they have been flushed out of the caches,
+//! which is what `flush()` and `persist()` are for
+//! See the NVDIMM Firmware Interface Table chapter: https://uefi.org/sites/default/files/resources/ACPI_6_3_final_Jan30.pdf
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::cpu::features;
+use crate::dev::{self, BlockDevOps, DevError};
+use crate::efi::{MemoryMap, EFI_GUID, EFI_MEMORY_TYPE};
+use crate::mm::{phys_to_virt, PhysAddr, Range, PAGE_SIZE};
+use crate::sync::SpinLock;
+
+
+/// Maximum number of persistent memory regions we track
+const MAX_REGIONS: usize = 16;
+
+/// Block size the regions are exposed with as block devices
+#[allow(dead_code)]
+const BLOCK_SIZE: usize = 4096;
+
+/// Size of a cache line, the granularity of the flush instructions
+const CACHE_LINE: u64 = 64;
+
+/// Offset of the first structure in the NFIT, after the ACPI header and
+/// 4 reserved bytes
+const NFIT_STRUCTURES: usize = 40;
+
+/// NFIT structure type of a System Physical Address Range structure
+const NFIT_TYPE_SPA_RANGE: u16 = 0;
+
+/// SPA range flag: the proximity domain field is valid
+const SPA_PROXIMITY_VALID: u16 = 1 << 1;
+
+/// Address range type GUID of byte addressable persistent memory
+const SPA_PM_REGION_GUID: EFI_GUID = EFI_GUID::new(
+    0x66f0d379, 0xb4f3, 0x4074,
+    [0xac, 0x43, 0x0d, 0x33, 0x18, 0xb7, 0x8c, 0xdb]
+);
+
+
+/// Where a region was discovered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    MemoryMap,
+    Nfit,
+}
+
+
+/// A region of persistent memory
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    // Physical addresses of the region, identity mapped
+    pub range: Range,
+
+    // Proximity domain from the NFIT, if known
+    #[allow(dead_code)]
+    pub proximity_domain: Option<u32>,
+
+    // Where the region was discovered
+    #[allow(dead_code)]
+    pub source: Source,
+}
+
+impl Region {
+    /// Size of the region in bytes
+    pub fn size(&self) -> u64 {
+        self.range.size()
+    }
+
+    /// Pointer to the byte at `offset` if `len` bytes starting there are in
+    /// the region
+    fn ptr(&self, offset: u64, len: usize) -> Option<*mut u8> {
+        let end = offset.checked_add(len as u64)?;
+        if end > self.size() {
+            return None;
+        }
+        Some(phys_to_virt(PhysAddr(self.range.start + offset)).0 as *mut u8)
+    }
+
+    /// Copy `buf.len()` bytes starting at `offset` into `buf`
+    #[allow(dead_code)]
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), DevError> {
+        let src = self.ptr(offset, buf.len()).ok_or(DevError::InvalidArgument)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len());
+        }
+        Ok(())
+    }
+
+    /// Copy `buf` to `offset` and make it durable
+    #[allow(dead_code)]
+    pub fn write(&self, offset: u64, buf: &[u8]) -> Result<(), DevError> {
+        let dst = self.ptr(offset, buf.len()).ok_or(DevError::InvalidArgument)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len());
+        }
+        persist(dst as u64, buf.len());
+        Ok(())
+    }
+}
+
+impl BlockDevOps for Region {
+    fn name(&self) -> &str {"pmem"}
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.size() / BLOCK_SIZE as u64
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), DevError> {
+        if !buf.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(DevError::InvalidArgument);
+        }
+        let offset = lba.checked_mul(BLOCK_SIZE as u64).ok_or(DevError::InvalidArgument)?;
+        self.read(offset, buf)
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), DevError> {
+        if !buf.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(DevError::InvalidArgument);
+        }
+        let offset = lba.checked_mul(BLOCK_SIZE as u64).ok_or(DevError::InvalidArgument)?;
+        self.write(offset, buf)
+    }
+}
+
+
+/// Regions found so far
+/// Slots are filled in with `writer` held and never modified once `count`
+/// covers them, which is what allows handing out `'static` references to the
+/// device registry
+struct RegionTable {
+    writer: SpinLock<()>,
+    count: AtomicUsize,
+    regions: UnsafeCell<[Option<Region>; MAX_REGIONS]>,
+}
+
+unsafe impl Sync for RegionTable {}
+
+static REGIONS: RegionTable = RegionTable {
+    writer: SpinLock::new(()),
+    count: AtomicUsize::new(0),
+    regions: UnsafeCell::new([None; MAX_REGIONS]),
+};
+
+
+/// Add a region unless it overlaps one we know about already, the memory map
+/// and the NFIT usually describe the same memory
+/// Registers the region as a block device
+fn add(region: Region) -> bool {
+    let writer = REGIONS.writer.lock();
+    let count = REGIONS.count.load(Ordering::Acquire);
+    let regions = unsafe { &mut *REGIONS.regions.get() };
+    let known = regions[..count].iter().flatten().any(|known| known.range.overlaps(&region.range));
+
+    let added = if known || count == MAX_REGIONS {
+        None
+    } else {
+        regions[count] = Some(region);
+        REGIONS.count.store(count + 1, Ordering::Release);
+        regions[count].as_ref()
+    };
+
+    drop(writer);
+
+    let added = match added {
+        Some(added) => added,
+        None => {
+            if !known {
+                warn!("Out of region slots, ignoring {:#x}-{:#x}", region.range.start, region.range.end);
+            }
+            return false;
+        },
+    };
+
+    info!("{} MiB of persistent memory at {:#x}", added.size() >> 20, added.range.start);
+    if let Err(err) = dev::register_block(dev::major::PMEM, added) {
+        warn!("Could not register {:#x}: {:?}", added.range.start, err);
+    }
+    true
+}
+
+
+/// Pick up the persistent memory regions in the memory `map`
+pub fn init(map: &MemoryMap) {
+    for desc in map.iter() {
+        let typ: EFI_MEMORY_TYPE = desc.Type.into();
+        if typ != EFI_MEMORY_TYPE::EfiPersistentMemory {continue;}
+
+        if let Some(range) = Range::new(desc.PhysicalAddress, desc.NumberOfPages * PAGE_SIZE) {
+            add(Region { range, proximity_domain: None, source: Source::MemoryMap });
+        }
+    }
+}
+
+
+/// Pick up the persistent memory regions described by the NFIT `table`,
+/// including its ACPI header
+/// Returns the number of new regions
+pub fn parse_nfit(table: &[u8]) -> usize {
+    let u16_at = |off: usize| table.get(off..off + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let u32_at = |off: usize| table.get(off..off + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    let u64_at = |off: usize| table.get(off..off + 8).map(|b| {
+        u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])
+    });
+
+    let mut added = 0;
+    let mut off = NFIT_STRUCTURES;
+
+    while let (Some(typ), Some(len)) = (u16_at(off), u16_at(off + 2)) {
+        let len = len as usize;
+        if len < 4 || off + len > table.len() {
+            warn!("Malformed NFIT structure at offset {}", off);
+            break;
+        }
+
+        if typ == NFIT_TYPE_SPA_RANGE && len >= 56 {
+            let flags = u16_at(off + 6).unwrap_or(0);
+            let domain = u32_at(off + 8).unwrap_or(0);
+            let guid = EFI_GUID::new(
+                u32_at(off + 16).unwrap_or(0),
+                u16_at(off + 20).unwrap_or(0),
+                u16_at(off + 22).unwrap_or(0),
+                table[off + 24..off + 32].try_into().unwrap_or([0; 8]),
+            );
+            let base = u64_at(off + 32).unwrap_or(0);
+            let size = u64_at(off + 40).unwrap_or(0);
+
+            if guid == SPA_PM_REGION_GUID {
+                if let Some(range) = Range::new(base, size) {
+                    let proximity_domain = (flags & SPA_PROXIMITY_VALID != 0).then_some(domain);
+                    if add(Region { range, proximity_domain, source: Source::Nfit }) {
+                        added += 1;
+                    }
+                }
+            }
+        }
+
+        off += len;
+    }
+
+    added
+}
+
+
+/// Get the `index`th persistent memory region
+#[allow(dead_code)]
+pub fn region(index: usize) -> Option<&'static Region> {
+    if index >= REGIONS.count.load(Ordering::Acquire) {
+        return None;
+    }
+    unsafe { (*REGIONS.regions.get())[index].as_ref() }
+}
+
+
+/// Iterate over all persistent memory regions
+#[allow(dead_code)]
+pub fn regions() -> impl Iterator<Item = &'static Region> {
+    (0..).map_while(region)
+}
+
+
+/// Whether `addr` is persistent memory
+#[allow(dead_code)]
+pub fn contains(addr: u64) -> bool {
+    regions().any(|region| region.range.start <= addr && addr <= region.range.end)
+}
+
+
+/// Best cache line flush instruction the processor supports
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FlushInsn {
+    Clwb,
+    Clflushopt,
+    Clflush,
+}
+
+/// Pick the best of CLWB, CLFLUSHOPT and CLFLUSH, which is always there on
+/// x86_64
+fn flush_insn() -> FlushInsn {
+    if features::has_clwb() {
+        FlushInsn::Clwb
+    } else if features::has_clflushopt() {
+        FlushInsn::Clflushopt
+    } else {
+        FlushInsn::Clflush
+    }
+}
+
+
+/// Write the cache lines covering `len` bytes at `addr` back to memory
+/// CLWB keeps the lines cached, the other instructions evict them
+/// The write backs are only ordered by a following `fence()`
+#[allow(dead_code)]
+pub fn flush(addr: u64, len: usize) {
+    if len == 0 {
+        return;
+    }
+
+    let insn = flush_insn();
+    let end = addr.saturating_add(len as u64);
+    let mut line = addr & !(CACHE_LINE - 1);
+
+    while line < end {
+        unsafe {
+            match insn {
+                FlushInsn::Clwb => core::arch::asm!("clwb [{}]", in(reg) line, options(nostack, preserves_flags)),
+                FlushInsn::Clflushopt => core::arch::asm!("clflushopt [{}]", in(reg) line, options(nostack, preserves_flags)),
+                FlushInsn::Clflush => core::arch::asm!("clflush [{}]", in(reg) line, options(nostack, preserves_flags)),
+            }
+        }
+        line += CACHE_LINE;
+    }
+}
+
+
+/// Wait for all earlier stores and flushes to complete
+#[allow(dead_code)]
+pub fn fence() {
+    unsafe {
+        core::arch::asm!("sfence", options(nostack, preserves_flags));
+    }
+}
+
+
+/// Make `len` bytes at `addr` durable
+#[allow(dead_code)]
+pub fn persist(addr: u64, len: usize) {
+    flush(addr, len);
+    fence();
+}
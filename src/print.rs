@@ -1,24 +1,176 @@
 /// This code defines the `print!()` and `println()` functions so as to
 /// allow printing information using UEFI stdout
-use core::fmt::{Result, Write};
+///
+/// All printing goes through one lock so output from different processors
+/// doesn't interleave within a line. Interrupts are disabled while it is held
+/// so an interrupt handler which prints can't deadlock against the code it
+/// interrupted. Once we panic the lock is bypassed, see `emergency()`
+use core::fmt::{Arguments, Result, Write};
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::console::{Color, Sink};
+use crate::cpu::irq::IrqGuard;
+use crate::sync::{InIrqContext, LockHeld, SpinLock, SpinLockGuard};
+
+
+/// What the print lock guards, the output itself goes through `console`
+pub struct Output;
+
+/// The lock held while formatting and writing output
+pub type PrintLock = SpinLock<Output>;
+
+static PRINT_LOCK: PrintLock = SpinLock::new(Output);
+
+/// Set once we are panicking, after which printing ignores `PRINT_LOCK`
+static EMERGENCY: AtomicBool = AtomicBool::new(false);
+
+
+/// Proof that we print on the emergency path, where the print lock is bypassed
+/// Only `lock()` hands it out, once `emergency()` was called
+pub struct Emergency<'a> {
+    _not_send: PhantomData<&'a *const ()>,
+}
+
+
+/// What allows writing to the console: the print lock, or the emergency path
+pub enum Access<'a> {
+    Locked(LockHeld<'a, PrintLock>),
+    Emergency(Emergency<'a>),
+}
+
+
+/// Holds the print lock, with interrupts disabled, until dropped
+pub struct PrintGuard {
+    // The lock, `None` in emergency mode
+    guard: Option<SpinLockGuard<'static, Output>>,
+
+    // Keeps interrupts disabled in emergency mode as well, dropped after the
+    // lock is released
+    _irq: IrqGuard,
+}
+
+impl PrintGuard {
+    /// Proof that we may write to the console, the lock or emergency mode
+    pub fn access(&self) -> Access<'_> {
+        match &self.guard {
+            Some(guard) => Access::Locked(guard.held()),
+            None => Access::Emergency(Emergency { _not_send: PhantomData }),
+        }
+    }
+
+    /// Proof that interrupts are disabled for as long as the guard lives
+    pub fn irq_context(&self) -> InIrqContext<'_> {
+        InIrqContext::from_guard(&self._irq)
+    }
+}
+
+
+/// Take the print lock
+/// Spins until the lock is free or we enter emergency mode
+pub fn lock() -> PrintGuard {
+    let irq = IrqGuard::new();
+
+    loop {
+        if EMERGENCY.load(Ordering::Relaxed) {
+            return PrintGuard { guard: None, _irq: irq };
+        }
+
+        if let Some(guard) = PRINT_LOCK.try_lock() {
+            return PrintGuard { guard: Some(guard), _irq: irq };
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+
+/// Switch printing to the lock free emergency path
+/// Called when panicking: the lock may be held by the code which panicked, or
+/// by another processor which will never release it, and getting the panic
+/// message out matters more than keeping lines from interleaving
+pub fn emergency() {
+    EMERGENCY.store(true, Ordering::SeqCst);
+}
+
+
+/// Backend of `print!()`
+#[doc(hidden)]
+pub fn _print(args: Arguments) {
+    let guard = lock();
+    let _ = ScreenOutWriter(guard.access(), guard.irq_context()).write_fmt(args);
+}
+
+
+/// Backend of `eprint!()`
+#[doc(hidden)]
+pub fn _eprint(args: Arguments) {
+    let guard = lock();
+    let _ = ScreenErrWriter(guard.access(), guard.irq_context()).write_fmt(args);
+}
+
+
+/// Print in `color`, or the default color if `None`, to stderr if `err` is set
+/// The color is reset afterwards; the lock is held throughout so nothing else
+/// gets printed in our color
+pub fn print_colored(color: Option<Color>, err: bool, args: Arguments) {
+    let guard = lock();
+    let access = guard.access();
+    if color.is_some() {
+        crate::console::set_color(color, &access);
+    }
+
+    let _ = if err {
+        ScreenErrWriter(guard.access(), guard.irq_context()).write_fmt(args)
+    } else {
+        ScreenOutWriter(guard.access(), guard.irq_context()).write_fmt(args)
+    };
+
+    if color.is_some() {
+        crate::console::set_color(None, &access);
+    }
+}
+
+
+/// Backend of `kwrite!()`
+#[doc(hidden)]
+pub fn _kwrite<S: Sink + ?Sized>(sink: &S, args: Arguments) {
+    let _guard = lock();
+    let _ = SinkWriter(sink).write_fmt(args);
+}
+
 
 /// A dummy screen writing structure we can implement `Write` on
-pub struct ScreenOutWriter;
+/// Only exists while we may print, with interrupts disabled
+pub struct ScreenOutWriter<'a>(pub Access<'a>, pub InIrqContext<'a>);
 
-impl Write for ScreenOutWriter{
+impl<'a> Write for ScreenOutWriter<'a>{
     fn write_str(&mut self, string: &str) -> Result {
-        crate::efi::output_string(string);
+        crate::console::write_str(string, &self.0, &self.1);
         Ok(())
     }
 }
 
 
 /// A dummy screen writing structure we can implement `Write` on for stderr
-pub struct ScreenErrWriter;
+/// Only exists while we may print, with interrupts disabled
+pub struct ScreenErrWriter<'a>(pub Access<'a>, pub InIrqContext<'a>);
+
+impl<'a> Write for ScreenErrWriter<'a>{
+    fn write_str(&mut self, string: &str) -> Result {
+        crate::console::write_err(string, &self.0, &self.1);
+        Ok(())
+    }
+}
+
+
 
-impl Write for ScreenErrWriter{
+/// Adapter implementing `Write` on top of any console sink
+/// This is what lets `kwrite!()` target a specific sink
+pub struct SinkWriter<'a, S: Sink + ?Sized>(pub &'a S);
+
+impl<'a, S: Sink + ?Sized> Write for SinkWriter<'a, S>{
     fn write_str(&mut self, string: &str) -> Result {
-        crate::efi::stderr_string(string);
+        self.0.write_str(string);
         Ok(())
     }
 }
@@ -31,10 +183,7 @@ macro_rules! print {
     ($($arg:tt)*) => {
         // We use a hardcoded full path because we are using this in a macro
         // Hence it will be called from a lot of different paths
-    let _ = <$crate::print::ScreenOutWriter as core::fmt::Write>::write_fmt(
-            &mut $crate::print::ScreenOutWriter,
-            format_args!($($arg)*)
-        );
+        $crate::print::_print(format_args!($($arg)*))
     }
 }
 
@@ -45,11 +194,45 @@ macro_rules! eprint {
     ($($arg:tt)*) => {
         // We use a hardcoded full path because we are using this in a macro
         // Hence it will be called from a lot of different paths
-    let _ = <$crate::print::ScreenErrWriter as core::fmt::Write>::write_fmt(
-            &mut $crate::print::ScreenErrWriter,
-            format_args!($($arg)*)
-        );
+        $crate::print::_eprint(format_args!($($arg)*))
     }
 }
 
 
+
+
+/// Standard Rust `println!()`
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::print!("{}\n", format_args!($($arg)*))
+    };
+}
+
+
+/// Standard Rust `eprintln!()`
+#[macro_export]
+macro_rules! eprintln {
+    () => {
+        $crate::eprint!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::eprint!("{}\n", format_args!($($arg)*))
+    };
+}
+
+
+/// Write formatted output to a specific sink instead of the whole console
+/// The sink can be anything implementing `console::Sink`, e.g.
+/// `kwrite!(uart, "{}\n", x)`
+#[macro_export]
+macro_rules! kwrite {
+    ($sink:expr, $($arg:tt)*) => {
+        // We use a hardcoded full path because we are using this in a macro
+        // Hence it will be called from a lot of different paths
+        $crate::print::_kwrite(&$sink, format_args!($($arg)*))
+    }
+}
@@ -0,0 +1,195 @@
+//! Progress reporting for long running operations
+//! An operation creates a `Progress`, tells it how much work there is and
+//! advances it as work gets done. Interactive sinks (the screen) get a progress
+//! bar which is redrawn in place, log sinks (serial, ...) get a structured line
+//! every few seconds so the output stays readable when captured
+//!
+//! ```text
+//! wipe [##########....................]  33% 1365/4096 eta 12s
+//! progress name=wipe done=1365 total=4096 pct=33 eta_s=12 state=running
+//! ```
+use core::fmt;
+
+
+/// Minimum time between redraws of the progress bar in microseconds
+const DRAW_INTERVAL_US: u64 = 100_000;
+
+/// Minimum time between structured log lines in microseconds
+const LOG_INTERVAL_US: u64 = 5_000_000;
+
+/// Width of the progress bar in characters
+const BAR_WIDTH: u64 = 30;
+
+
+/// Handle to a running operation
+/// Progress is reported as units of work, e.g. bytes or blocks
+/// Dropping the handle finishes it
+pub struct Progress {
+    // Name shown in the output
+    name: &'static str,
+
+    // Units of work in total, 0 if not known (yet)
+    total: u64,
+
+    // Units of work done so far
+    done: u64,
+
+    // Uptime when the operation started
+    start_us: u64,
+
+    // Uptime of the last redraw of the bar
+    last_draw_us: Option<u64>,
+
+    // Uptime of the last structured log line
+    last_log_us: Option<u64>,
+
+    // Whether `finish()` has been called
+    finished: bool,
+}
+
+impl Progress {
+    /// Start reporting progress for the operation `name` which has `total`
+    /// units of work, pass 0 if the total is not known yet
+    #[allow(dead_code)]
+    pub fn new(name: &'static str, total: u64) -> Self {
+        let mut progress = Progress {
+            name,
+            total,
+            done: 0,
+            start_us: crate::time::uptime_us(),
+            last_draw_us: None,
+            last_log_us: None,
+            finished: false,
+        };
+        progress.report(false);
+        progress
+    }
+
+    /// Set the total units of work
+    #[allow(dead_code)]
+    pub fn set_total(&mut self, total: u64) {
+        self.total = total;
+        self.report(false);
+    }
+
+    /// Mark `units` more units of work as done
+    #[allow(dead_code)]
+    pub fn advance(&mut self, units: u64) {
+        self.set(self.done.saturating_add(units));
+    }
+
+    /// Set the units of work done so far
+    #[allow(dead_code)]
+    pub fn set(&mut self, done: u64) {
+        self.done = done;
+        self.report(false);
+    }
+
+    /// Units of work done so far
+    #[allow(dead_code)]
+    pub fn done(&self) -> u64 {
+        self.done
+    }
+
+    /// Percentage of the work done, `None` if the total is not known
+    #[allow(dead_code)]
+    pub fn percent(&self) -> Option<u64> {
+        if self.total == 0 {
+            return None;
+        }
+        Some(core::cmp::min(self.done, self.total) * 100 / self.total)
+    }
+
+    /// Estimated time until the operation finishes in microseconds, based on
+    /// the average rate so far
+    /// `None` if there is no total or nothing has been done yet
+    #[allow(dead_code)]
+    pub fn eta_us(&self) -> Option<u64> {
+        if self.total == 0 || self.done == 0 {
+            return None;
+        }
+
+        let elapsed = crate::time::uptime_us().saturating_sub(self.start_us);
+        let left = self.total.saturating_sub(self.done);
+        Some((elapsed as u128 * left as u128 / self.done as u128) as u64)
+    }
+
+    /// Report the operation as done
+    /// Draws the bar one last time and ends its line
+    #[allow(dead_code)]
+    pub fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        self.report(true);
+    }
+
+    /// Update the sinks which are due for an update, or all of them if `force`
+    fn report(&mut self, force: bool) {
+        let now = crate::time::uptime_us();
+        let draw = force || self.last_draw_us.is_none_or(|last| now - last >= DRAW_INTERVAL_US);
+        let log = force || self.last_log_us.is_none_or(|last| now - last >= LOG_INTERVAL_US);
+
+        if !draw && !log {
+            return;
+        }
+
+        let eta = Seconds(self.eta_us());
+        let end = if self.finished {"\n"} else {""};
+
+        crate::console::for_each(|sink| {
+            if sink.interactive() {
+                if draw {
+                    kwrite!(*sink, "\r{} [{}] {:>3}% {}/{} eta {}s{}",
+                        self.name, Bar(self.percent()), self.percent().unwrap_or(0),
+                        self.done, self.total, eta, end);
+                }
+            } else if log {
+                kwrite!(*sink, "progress name={} done={} total={} pct={} eta_s={} state={}\n",
+                    self.name, self.done, self.total, self.percent().unwrap_or(0), eta,
+                    if self.finished {"finished"} else {"running"});
+            }
+        });
+
+        if draw {
+            self.last_draw_us = Some(now);
+        }
+        if log {
+            self.last_log_us = Some(now);
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+
+/// Progress bar of `BAR_WIDTH` characters for a percentage
+struct Bar(Option<u64>);
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let filled = self.0.unwrap_or(0) * BAR_WIDTH / 100;
+        for ii in 0..BAR_WIDTH {
+            f.write_str(if ii < filled {"#"} else {"."})?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Whole seconds from a duration in microseconds, `?` if unknown
+struct Seconds(Option<u64>);
+
+impl fmt::Display for Seconds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(us) => write!(f, "{}", us / 1_000_000),
+            None => f.write_str("?"),
+        }
+    }
+}
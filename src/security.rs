@@ -0,0 +1,81 @@
+//! Platform security state reported by the firmware
+use core::fmt;
+use crate::efi::variable::{get_variable, EFI_GLOBAL_VARIABLE};
+
+
+/// Secure Boot mode of the platform
+/// See Chapter 30.3(Page 1816): https://uefi.org/sites/default/files/resources/UEFI%20Spec%202_6.pdf
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecureBootState {
+    // The firmware does not expose the Secure Boot variables
+    Unsupported,
+
+    // No Platform Key is enrolled, so nothing is enforced
+    Setup,
+
+    // A Platform Key is enrolled but image verification is turned off
+    Disabled,
+
+    // Image verification is enforced
+    Enforcing,
+
+    // Image verification is enforced and the platform is locked down
+    Deployed,
+}
+
+impl fmt::Display for SecureBootState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self {
+            SecureBootState::Unsupported => "unsupported",
+            SecureBootState::Setup => "setup mode",
+            SecureBootState::Disabled => "disabled",
+            SecureBootState::Enforcing => "enforcing",
+            SecureBootState::Deployed => "enforcing (deployed mode)",
+        };
+        f.write_str(state)
+    }
+}
+
+
+/// Read a single byte boolean global variable
+/// Returns `None` if the variable does not exist
+fn read_flag(name: &str) -> Option<bool> {
+    let mut data = [0u8; 1];
+    match get_variable(name, &EFI_GLOBAL_VARIABLE, &mut data) {
+        Ok(1) => Some(data[0] == 1),
+        _ => None,
+    }
+}
+
+
+/// Read the `SecureBoot`, `SetupMode` and `DeployedMode` variables and
+/// work out the Secure Boot state of the platform
+pub fn secure_boot_state() -> SecureBootState {
+    let secure_boot = match read_flag("SecureBoot") {
+        Some(val) => val,
+        None => return SecureBootState::Unsupported,
+    };
+
+    // `DeployedMode` only exists on UEFI 2.5+ firmware
+    let setup_mode = read_flag("SetupMode").unwrap_or(false);
+    let deployed_mode = read_flag("DeployedMode").unwrap_or(false);
+
+    match (secure_boot, setup_mode, deployed_mode) {
+        (_, true, _) => SecureBootState::Setup,
+        (false, _, _) => SecureBootState::Disabled,
+        (true, _, true) => SecureBootState::Deployed,
+        (true, _, false) => SecureBootState::Enforcing,
+    }
+}
+
+
+/// Whether we are allowed to load payloads which are not signed
+/// Unsigned payloads are refused whenever `SecureBoot` is set outside of
+/// setup mode, i.e. the firmware enforces it, otherwise we would be an easy
+/// way around it
+pub fn unsigned_payloads_allowed() -> bool {
+    !matches!(
+        secure_boot_state(),
+        SecureBootState::Enforcing | SecureBootState::Deployed
+    )
+}
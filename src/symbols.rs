@@ -0,0 +1,105 @@
+//! Kernel symbol table, used to put names on addresses in backtraces
+//!
+//! The table lives in its own `.ksyms` section and is empty when the kernel is
+//! linked. `tools/ksyms.py` fills it in afterwards with the (address, name) of
+//! every function in the image, sorted by address. Addresses are stored
+//! relative to the image base as UEFI loads us wherever it likes; the tool also
+//! records where the table itself is, which tells us our load address
+
+
+/// Maximum number of symbols in the table
+const MAX_SYMBOLS: usize = 4096;
+
+/// Space for symbol names, which are stored back to back without terminators
+const NAMES_LEN: usize = 160 * 1024;
+
+/// Identifies the table for `tools/ksyms.py`
+const MAGIC: [u8; 8] = *b"LZKSYMS\0";
+
+
+/// A single symbol
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Symbol {
+    // Address relative to the image base
+    rva: u32,
+
+    // Offset of the name in `names`
+    name: u32,
+
+    // Length of the name
+    len: u32,
+}
+
+/// Layout shared with `tools/ksyms.py`
+#[repr(C)]
+struct SymbolTable {
+    magic: [u8; 8],
+
+    // Address of this table relative to the image base, 0 if not filled in
+    table_rva: u32,
+
+    // Number of valid entries in `symbols`
+    count: u32,
+
+    // End of the code, addresses past it are not resolved
+    end_rva: u32,
+
+    symbols: [Symbol; MAX_SYMBOLS],
+    names: [u8; NAMES_LEN],
+}
+
+#[used]
+#[link_section = ".ksyms"]
+static SYMBOLS: SymbolTable = SymbolTable {
+    magic: MAGIC,
+    table_rva: 0,
+    count: 0,
+    end_rva: 0,
+    symbols: [Symbol { rva: 0, name: 0, len: 0 }; MAX_SYMBOLS],
+    names: [0; NAMES_LEN],
+};
+
+
+/// The symbol table as patched into the image
+fn table() -> &'static SymbolTable {
+    // The compiler only knows the empty table we were built with, hide it so
+    // what the tool wrote in is actually read
+    unsafe { &*core::hint::black_box(&SYMBOLS as *const SymbolTable) }
+}
+
+
+/// Whether the image has a symbol table
+pub fn available() -> bool {
+    let table = table();
+    table.table_rva != 0 && table.count != 0
+}
+
+
+/// Find the function containing `addr`
+/// Returns its name and the offset of `addr` into it
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    let table = table();
+    if !available() {
+        return None;
+    }
+
+    // Where the image got loaded. Code set up before the boot moved to the
+    // slid image, such as interrupt handlers, still runs at the identity
+    // mapped one, see `mm::slid()`
+    let base = (table as *const SymbolTable as u64).checked_sub(table.table_rva as u64)?;
+    let slide = crate::mm::kernel_slide();
+    let rva = [addr, addr.wrapping_add(slide), addr.wrapping_sub(slide)].into_iter()
+        .filter_map(|addr| addr.checked_sub(base))
+        .find(|rva| *rva < table.end_rva as u64)?;
+
+    // Last symbol at or below the address
+    let symbols = &table.symbols[..core::cmp::min(table.count as usize, MAX_SYMBOLS)];
+    let index = symbols.partition_point(|symbol| symbol.rva as u64 <= rva).checked_sub(1)?;
+    let symbol = &symbols[index];
+
+    let name = table.names.get(symbol.name as usize..(symbol.name + symbol.len) as usize)?;
+    let name = core::str::from_utf8(name).ok()?;
+
+    Some((name, rva - symbol.rva as u64))
+}
@@ -0,0 +1,457 @@
+//! Synchronization primitives and the markers that go with them
+//!
+//! The markers are zero sized proofs checked by the compiler. A function which
+//! must only run with a lock held takes a `&LockHeld<L>`, which only the owner
+//! of the lock can hand out, and a function which must not sleep or be
+//! interrupted takes an `&InIrqContext`. Neither can be sent to another
+//! processor, as what they prove only holds on the one they were made on
+//!
+//! `SpinLock` and `SpinRwLock` keep interrupts disabled while held, so an
+//! interrupt handler can't spin on a lock the code it interrupted holds. They
+//! remember which core holds them: if that core panics with the lock held the
+//! lock is poisoned and handed to the panic path instead of deadlocking it,
+//! and debug builds catch a core taking a lock it already holds
+//!
+//! `OnceCell` and `LazyLock` hold globals which are set up once at runtime,
+//! reading one before it is set gives `None` instead of a null pointer
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use crate::cpu::irq::IrqGuard;
+
+
+/// Proof that maskable interrupts are disabled on this processor for `'a`,
+/// either because we are in an interrupt handler or because an `IrqGuard` is
+/// alive. Code taking it must not sleep or wait for an interrupt
+pub struct InIrqContext<'a> {
+    _not_send: PhantomData<&'a *const ()>,
+}
+
+impl<'a> InIrqContext<'a> {
+    /// Proof for code which is known to run with interrupts disabled, such as
+    /// interrupt entry points
+    ///
+    /// Safety: interrupts must stay disabled on this processor for `'a`
+    #[allow(dead_code)]
+    pub unsafe fn assume() -> Self {
+        InIrqContext { _not_send: PhantomData }
+    }
+
+    /// Proof for as long as `guard` keeps interrupts disabled
+    pub fn from_guard(_guard: &'a IrqGuard) -> Self {
+        InIrqContext { _not_send: PhantomData }
+    }
+}
+
+
+/// Proof that the lock `L` is held for `'a`
+/// Locks hand it out from their guards
+pub struct LockHeld<'a, L: ?Sized> {
+    _lock: PhantomData<&'a L>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, L: ?Sized> LockHeld<'a, L> {
+    /// Proof that `lock` is held
+    ///
+    /// Safety: `lock` must be held by us for `'a`
+    pub unsafe fn new(_lock: &'a L) -> Self {
+        LockHeld { _lock: PhantomData, _not_send: PhantomData }
+    }
+}
+
+
+/// Owner value of a free lock, others are the core index plus one
+const NO_OWNER: u32 = 0;
+
+/// `SpinRwLock` state bit of a writer, the rest counts readers
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+
+/// Owner value of the calling core
+fn current_owner() -> u32 {
+    crate::percpu::id().map_or(1, |id| id + 1)
+}
+
+
+/// Whether a lock owned by `owner` will never be released: its owner is us
+/// and we are panicking, so it is stuck in code which will never finish
+fn abandoned(owner: u32) -> bool {
+    crate::panic_handler::panicking() && owner == current_owner()
+}
+
+
+/// Panic if a lock owned by `owner` is held by us, so spinning would never
+/// end. `taken_at` is where it was taken, only we write it then
+/// Debug builds only
+#[track_caller]
+#[allow(unused_variables)]
+fn check_deadlock(owner: u32, taken_at: &UnsafeCell<Option<&'static Location<'static>>>) {
+    #[cfg(debug_assertions)]
+    if owner == current_owner() && !crate::panic_handler::panicking() {
+        match unsafe { *taken_at.get() } {
+            Some(at) => panic!("Deadlock: lock already held by this core, taken at {}:{}", at.file(), at.line()),
+            None => panic!("Deadlock: lock already held by this core"),
+        }
+    }
+}
+
+
+/// Mutual exclusion lock which spins, with interrupts disabled while held
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    poisoned: AtomicBool,
+    owner: AtomicU32,
+
+    // Where the holder took it, debug builds only
+    taken_at: UnsafeCell<Option<&'static Location<'static>>>,
+
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            poisoned: AtomicBool::new(false),
+            owner: AtomicU32::new(NO_OWNER),
+            taken_at: UnsafeCell::new(None),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Take the lock, spinning until it is free
+    /// A lock abandoned by a panic on this core is poisoned and taken over
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let irq = IrqGuard::new();
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            let owner = self.owner.load(Ordering::Relaxed);
+            if abandoned(owner) {
+                self.poisoned.store(true, Ordering::Relaxed);
+                break;
+            }
+            check_deadlock(owner, &self.taken_at);
+            core::hint::spin_loop();
+        }
+        self.acquired();
+        SpinLockGuard { lock: self, _irq: irq }
+    }
+
+    /// Take the lock if it is free
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let irq = IrqGuard::new();
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        self.acquired();
+        Some(SpinLockGuard { lock: self, _irq: irq })
+    }
+
+    /// Whether a panic left the lock held, the value may be inconsistent
+    #[allow(dead_code)]
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    #[track_caller]
+    fn acquired(&self) {
+        self.owner.store(current_owner(), Ordering::Relaxed);
+        if cfg!(debug_assertions) {
+            unsafe { *self.taken_at.get() = Some(Location::caller()) };
+        }
+    }
+}
+
+
+/// Access to the value of a held `SpinLock`, releases it when dropped
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+
+    // Dropped after the lock is released
+    _irq: IrqGuard,
+}
+
+impl<'a, T> SpinLockGuard<'a, T> {
+    /// Proof that the lock is held, for functions taking a `LockHeld`
+    pub fn held(&self) -> LockHeld<'_, SpinLock<T>> {
+        unsafe { LockHeld::new(self.lock) }
+    }
+
+    /// Proof that interrupts are disabled for as long as the lock is held
+    pub fn irq_context(&self) -> InIrqContext<'_> {
+        InIrqContext::from_guard(&self._irq)
+    }
+
+    /// The value along with both proofs, for code which needs all three
+    pub fn split(&mut self) -> (&mut T, LockHeld<'_, SpinLock<T>>, InIrqContext<'_>) {
+        let lock = self.lock;
+        (unsafe { &mut *lock.value.get() }, unsafe { LockHeld::new(lock) }, InIrqContext::from_guard(&self._irq))
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.owner.store(NO_OWNER, Ordering::Relaxed);
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+
+/// Readers-writer lock which spins, with interrupts disabled while held
+/// Any number of readers or a single writer, readers coming in keep a writer
+/// waiting, so it suits state which is rarely written
+pub struct SpinRwLock<T> {
+    // `WRITER` and the number of readers
+    state: AtomicUsize,
+
+    poisoned: AtomicBool,
+
+    // Owner of the write lock
+    writer: AtomicU32,
+
+    // Where the writer took it, debug builds only
+    taken_at: UnsafeCell<Option<&'static Location<'static>>>,
+
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for SpinRwLock<T> {}
+unsafe impl<T: Send> Send for SpinRwLock<T> {}
+
+impl<T> SpinRwLock<T> {
+    #[allow(dead_code)]
+    pub const fn new(value: T) -> Self {
+        SpinRwLock {
+            state: AtomicUsize::new(0),
+            poisoned: AtomicBool::new(false),
+            writer: AtomicU32::new(NO_OWNER),
+            taken_at: UnsafeCell::new(None),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Whether the writer on this core abandoned the lock to a panic, after
+    /// which we take it over
+    fn take_over(&self) -> bool {
+        if abandoned(self.writer.load(Ordering::Relaxed)) {
+            self.poisoned.store(true, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+
+    /// Take the lock for reading, spinning while there is a writer
+    #[track_caller]
+    #[allow(dead_code)]
+    pub fn read(&self) -> SpinReadGuard<'_, T> {
+        let irq = IrqGuard::new();
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WRITER == 0 {
+                if self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                    return SpinReadGuard { lock: self, counted: true, _irq: irq };
+                }
+                continue;
+            }
+            if self.take_over() {
+                return SpinReadGuard { lock: self, counted: false, _irq: irq };
+            }
+            check_deadlock(self.writer.load(Ordering::Relaxed), &self.taken_at);
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Take the lock for writing, spinning until there are no readers or
+    /// writer
+    #[track_caller]
+    #[allow(dead_code)]
+    pub fn write(&self) -> SpinWriteGuard<'_, T> {
+        let irq = IrqGuard::new();
+        while self.state.compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            if self.take_over() {
+                break;
+            }
+            check_deadlock(self.writer.load(Ordering::Relaxed), &self.taken_at);
+            core::hint::spin_loop();
+        }
+        self.writer.store(current_owner(), Ordering::Relaxed);
+        if cfg!(debug_assertions) {
+            unsafe { *self.taken_at.get() = Some(Location::caller()) };
+        }
+        SpinWriteGuard { lock: self, _irq: irq }
+    }
+
+    /// Whether a panic left the lock held for writing, the value may be
+    /// inconsistent
+    #[allow(dead_code)]
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+}
+
+
+/// Shared access to the value of a `SpinRwLock`, releases it when dropped
+pub struct SpinReadGuard<'a, T> {
+    lock: &'a SpinRwLock<T>,
+
+    // Whether we are counted as a reader, readers of a poisoned lock aren't
+    counted: bool,
+
+    _irq: IrqGuard,
+}
+
+impl<'a, T> Deref for SpinReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinReadGuard<'a, T> {
+    fn drop(&mut self) {
+        if self.counted {
+            self.lock.state.fetch_sub(1, Ordering::Release);
+        }
+    }
+}
+
+
+/// Exclusive access to the value of a `SpinRwLock`, releases it when dropped
+pub struct SpinWriteGuard<'a, T> {
+    lock: &'a SpinRwLock<T>,
+    _irq: IrqGuard,
+}
+
+impl<'a, T> Deref for SpinWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.writer.store(NO_OWNER, Ordering::Relaxed);
+        self.lock.state.store(0, Ordering::Release);
+    }
+}
+
+
+// `OnceCell` states
+const EMPTY: u8 = 0;
+const BUSY: u8 = 1;
+const READY: u8 = 2;
+
+
+/// A value which is set once and only read after
+pub struct OnceCell<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+unsafe impl<T: Send> Send for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        OnceCell { state: AtomicU8::new(EMPTY), value: UnsafeCell::new(MaybeUninit::uninit()) }
+    }
+
+    /// The value, `None` until it is set
+    pub fn get(&self) -> Option<&T> {
+        match self.state.load(Ordering::Acquire) {
+            READY => Some(unsafe { (*self.value.get()).assume_init_ref() }),
+            _ => None,
+        }
+    }
+
+    /// Set the value, handing it back if the cell has or is getting one
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self.state.compare_exchange(EMPTY, BUSY, Ordering::Acquire, Ordering::Acquire).is_err() {
+            return Err(value);
+        }
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(READY, Ordering::Release);
+        Ok(())
+    }
+
+    /// The value, set to what `f` returns first if there is none
+    /// Other cores asking meanwhile wait for `f`, which must not ask itself
+    pub fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        if self.state.compare_exchange(EMPTY, BUSY, Ordering::Acquire, Ordering::Acquire).is_ok() {
+            unsafe { (*self.value.get()).write(f()) };
+            self.state.store(READY, Ordering::Release);
+        }
+        loop {
+            if let Some(value) = self.get() {
+                return value;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+
+/// A value made by `F` the first time it is used
+pub struct LazyLock<T, F = fn() -> T> {
+    cell: OnceCell<T>,
+    init: F,
+}
+
+impl<T, F: Fn() -> T> LazyLock<T, F> {
+    pub const fn new(init: F) -> Self {
+        LazyLock { cell: OnceCell::new(), init }
+    }
+
+    /// The value, made now if it wasn't yet
+    pub fn force(this: &Self) -> &T {
+        this.cell.get_or_init(&this.init)
+    }
+}
+
+impl<T, F: Fn() -> T> Deref for LazyLock<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        LazyLock::force(self)
+    }
+}
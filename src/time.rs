@@ -0,0 +1,89 @@
+//! Monotonic time since boot
+//! The clock source is the TSC, see `tsc` for how its frequency is found
+//! Until calibration has happened the uptime reads as zero
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub mod tsc;
+
+
+/// TSC frequency in Hz, 0 until calibrated
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// TSC value at calibration, which we treat as time zero
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+
+
+/// Calibrate the TSC and start counting uptime from now
+/// `boot_services` says whether the firmware can still be used to measure
+pub fn init(boot_services: bool) {
+    let hz = tsc::calibrate(boot_services);
+    if hz == 0 {return;}
+
+    TSC_BASE.store(tsc::rdtsc(), Ordering::SeqCst);
+    TSC_HZ.store(hz, Ordering::SeqCst);
+}
+
+
+/// Log the clock source, once logging is set up
+pub fn log_clock() {
+    match tsc_hz() {
+        Some(hz) => info!("TSC at {} MHz ({}){}", hz / 1_000_000, tsc::source(),
+            if crate::cpu::features::has_invariant_tsc() {""} else {", not invariant, uptime may drift"}),
+        None => warn!("TSC not calibrated, there are no timestamps"),
+    }
+}
+
+
+/// TSC frequency in Hz, `None` until calibrated
+pub fn tsc_hz() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+
+/// Nanoseconds since the clock was calibrated
+pub fn uptime_ns() -> u64 {
+    let hz = match tsc_hz() {
+        Some(hz) => hz,
+        None => return 0,
+    };
+
+    let ticks = tsc::rdtsc().saturating_sub(TSC_BASE.load(Ordering::Relaxed));
+    ((ticks as u128 * 1_000_000_000) / hz as u128) as u64
+}
+
+
+/// Microseconds since the clock was calibrated
+pub fn uptime_us() -> u64 {
+    uptime_ns() / 1_000
+}
+
+
+/// Busy wait for at least `us` microseconds
+/// Uses the TSC once calibrated and the firmware's `Stall()` before that
+pub fn udelay(us: u64) {
+    if tsc_hz().is_none() {
+        crate::efi::time::stall_us(us);
+        return;
+    }
+
+    let end = uptime_ns().saturating_add(us.saturating_mul(1_000));
+    while uptime_ns() < end {
+        core::hint::spin_loop();
+    }
+}
+
+
+/// Run `$body` and log how long it took under `$name` at debug level
+/// Evaluates to the value of `$body`
+macro_rules! bench {
+    ($name:expr, $body:expr) => {{
+        let start = $crate::time::uptime_ns();
+        let ret = $body;
+        let elapsed = $crate::time::uptime_ns() - start;
+        debug!("{} took {}.{:03} us", $name, elapsed / 1_000, elapsed % 1_000);
+        ret
+    }};
+}
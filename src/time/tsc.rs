@@ -0,0 +1,162 @@
+//! Time stamp counter
+//! Where the processor says how fast its TSC runs (CPUID leaf 0x15, or the
+//! base frequency in leaf 0x16) we take its word, otherwise the TSC is
+//! measured against the firmware's `Stall()` while boot services are
+//! available or against the PIT after
+//!
+//! Only an invariant TSC ticks at a constant rate through frequency and
+//! power state changes, without one the uptime drifts
+//! See: Intel SDM Vol. 3B, 18.7.3 Determining the Processor Base Frequency
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use crate::cpu::features;
+use crate::cpu::port::{inb, outb};
+
+
+/// How long we measure the TSC for during calibration in microseconds
+const CALIBRATION_US: u64 = 10_000;
+
+/// Frequency the PIT counts at in Hz
+const PIT_HZ: u64 = 1_193_182;
+
+/// `Source` the frequency came from, as its discriminant
+static SOURCE: AtomicU8 = AtomicU8::new(Source::None as u8);
+
+
+/// Where the TSC frequency came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Source {
+    // Not calibrated
+    None,
+
+    // CPUID leaf 0x15 or 0x16
+    Cpuid,
+
+    // Measured against the firmware's `Stall()`
+    Firmware,
+
+    // Measured against PIT channel 2
+    Pit,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Source::None => "uncalibrated",
+            Source::Cpuid => "CPUID",
+            Source::Firmware => "firmware",
+            Source::Pit => "PIT",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+
+/// Read the time stamp counter
+#[inline]
+pub fn rdtsc() -> u64 {
+    let lo: u32;
+    let hi: u32;
+    unsafe {
+        core::arch::asm!("rdtsc", out("eax") lo, out("edx") hi, options(nomem, nostack));
+    }
+    ((hi as u64) << 32) | lo as u64
+}
+
+
+/// TSC frequency as the processor reports it, `None` where it doesn't
+/// Leaf 0x15 gives the ratio of the TSC to the crystal clock and usually the
+/// crystal frequency, where it leaves that out the TSC runs at the base
+/// frequency from leaf 0x16
+fn frequency_cpuid() -> Option<u64> {
+    let max = features::max_leaf();
+    if max < 0x15 {
+        return None;
+    }
+
+    let leaf = features::cpuid(0x15, 0);
+    let (denominator, numerator, crystal) = (leaf.eax, leaf.ebx, leaf.ecx);
+    if denominator == 0 || numerator == 0 {
+        return None;
+    }
+    if crystal != 0 {
+        return Some(crystal as u64 * numerator as u64 / denominator as u64);
+    }
+
+    if max < 0x16 {
+        return None;
+    }
+    match features::cpuid(0x16, 0).eax & 0xffff {
+        0 => None,
+        mhz => Some(mhz as u64 * 1_000_000),
+    }
+}
+
+
+/// Measure the TSC frequency using the firmware's `Stall()`
+fn calibrate_firmware() -> u64 {
+    let start = rdtsc();
+    crate::efi::time::stall_us(CALIBRATION_US);
+    let end = rdtsc();
+
+    (end - start) * (1_000_000 / CALIBRATION_US)
+}
+
+
+/// Measure the TSC frequency using PIT channel 2
+/// See: https://wiki.osdev.org/Programmable_Interval_Timer
+fn calibrate_pit() -> u64 {
+    let ticks = PIT_HZ * CALIBRATION_US / 1_000_000;
+
+    unsafe {
+        // Gate channel 2 on and disconnect it from the speaker
+        let port61 = inb(0x61);
+        outb(0x61, (port61 & !0x02) | 0x01);
+
+        // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        outb(0x43, 0xb0);
+        outb(0x42, ticks as u8);
+        outb(0x42, (ticks >> 8) as u8);
+
+        let start = rdtsc();
+
+        // OUT2 goes high once the count reaches zero
+        while inb(0x61) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+
+        let end = rdtsc();
+
+        // Restore the gate
+        outb(0x61, port61);
+
+        (end - start) * (1_000_000 / CALIBRATION_US)
+    }
+}
+
+
+/// Find the TSC frequency in Hz, 0 if it can't be measured
+/// Measures with the firmware while boot services are up, the PIT otherwise
+pub fn calibrate(boot_services: bool) -> u64 {
+    let (hz, source) = match frequency_cpuid() {
+        Some(hz) => (hz, Source::Cpuid),
+        None if boot_services => (calibrate_firmware(), Source::Firmware),
+        None => (calibrate_pit(), Source::Pit),
+    };
+    if hz != 0 {
+        SOURCE.store(source as u8, Ordering::Relaxed);
+    }
+    hz
+}
+
+
+/// Where the TSC frequency came from
+pub fn source() -> Source {
+    match SOURCE.load(Ordering::Relaxed) {
+        1 => Source::Cpuid,
+        2 => Source::Firmware,
+        3 => Source::Pit,
+        _ => Source::None,
+    }
+}
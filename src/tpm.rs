@@ -0,0 +1,34 @@
+//! Measured boot support
+//! Anything we hand control to, along with the command line we pass it, is
+//! measured into the TPM first so that the measured boot chain covers us
+//! We follow the PCR assignment used by GRUB: command lines go into PCR 8 and
+//! loaded images go into PCR 9
+//! See: https://www.gnu.org/software/grub/manual/grub/html_node/Measured-Boot.html
+use crate::efi::{tcg2, EFI_STATUS};
+
+
+/// PCR extended with command lines
+pub const PCR_CMDLINE: u32 = 8;
+
+/// PCR extended with loaded payloads
+pub const PCR_PAYLOAD: u32 = 9;
+
+/// Event type for code and data loaded by the boot loader
+/// See: https://trustedcomputinggroup.org/resource/pc-client-specific-platform-firmware-profile-specification/
+const EV_IPL: u32 = 0x0000000d;
+
+
+/// Measure a payload image before jumping to it
+/// `name` is logged along with the measurement to identify the payload
+#[allow(dead_code)]
+pub fn measure_payload(image: &[u8], name: &str) -> Result<(), EFI_STATUS> {
+    tcg2::hash_log_extend_event(PCR_PAYLOAD, EV_IPL, image, name.as_bytes())
+}
+
+
+/// Measure the command line handed to a payload
+/// The command line itself is logged as the event data
+#[allow(dead_code)]
+pub fn measure_cmdline(cmdline: &str) -> Result<(), EFI_STATUS> {
+    tcg2::hash_log_extend_event(PCR_CMDLINE, EV_IPL, cmdline.as_bytes(), cmdline.as_bytes())
+}
@@ -0,0 +1,81 @@
+//! Driver for a 16550 compatible UART
+//! Used for console output once the firmware's serial protocol is gone
+//! See: https://wiki.osdev.org/Serial_Ports
+use crate::cpu::port::{inb, outb};
+
+/// IO port base of the first serial port
+pub const COM1: u16 = 0x3f8;
+
+/// Register offsets from the port base
+const DATA: u16 = 0;           // Transmit/receive buffer, divisor low byte when DLAB is set
+const INT_ENABLE: u16 = 1;     // Interrupt enable, divisor high byte when DLAB is set
+const FIFO_CTRL: u16 = 2;      // FIFO control
+const LINE_CTRL: u16 = 3;      // Line control, bit 7 is DLAB
+const MODEM_CTRL: u16 = 4;     // Modem control
+const LINE_STATUS: u16 = 5;    // Line status
+
+/// Line status bit set when the transmit holding register is empty
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// Give up waiting on the transmitter after this many polls so a missing or
+/// wedged UART can't hang the console
+const TX_TIMEOUT: usize = 100_000;
+
+
+/// A 16550 UART at a fixed IO port base
+pub struct Uart {
+    base: u16,
+}
+
+impl Uart {
+    /// Create a handle to the UART at `base`
+    /// Nothing is programmed until `init()` is called
+    pub const fn new(base: u16) -> Self {
+        Uart { base }
+    }
+
+    /// Program the UART for 115200 baud, 8 data bits, no parity, one stop bit
+    pub fn init(&self) {
+        unsafe {
+            // Disable interrupts, we only poll
+            outb(self.base + INT_ENABLE, 0x00);
+
+            // Set the divisor to 1 (115200 baud)
+            outb(self.base + LINE_CTRL, 0x80);
+            outb(self.base + DATA, 0x01);
+            outb(self.base + INT_ENABLE, 0x00);
+
+            // 8N1 and clear DLAB
+            outb(self.base + LINE_CTRL, 0x03);
+
+            // Enable and clear the FIFOs with a 14 byte threshold
+            outb(self.base + FIFO_CTRL, 0xc7);
+
+            // Assert DTR and RTS
+            outb(self.base + MODEM_CTRL, 0x03);
+        }
+    }
+
+    /// Write a single byte, waiting for the transmitter to be ready
+    pub fn write_byte(&self, byte: u8) {
+        unsafe {
+            for _ in 0..TX_TIMEOUT {
+                if inb(self.base + LINE_STATUS) & LSR_THR_EMPTY != 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            outb(self.base + DATA, byte);
+        }
+    }
+
+    /// Write a string, translating `\n` into `\r\n`
+    pub fn write_str(&self, string: &str) {
+        for byte in string.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+    }
+}
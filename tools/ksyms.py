@@ -0,0 +1,106 @@
+#!/usr/bin/env python3
+"""Fill the kernel symbol table into a linked image
+
+Usage: ksyms.py <lazarus.efi>
+
+The kernel reserves an empty table in its `.ksyms` section (see
+src/symbols.rs). This lists the functions in the image with `llvm-nm` (or
+whatever $NM points at), sorts them by address and writes them into that
+section in place, so backtraces can print function names.
+"""
+import os
+import re
+import struct
+import subprocess
+import sys
+
+# Must match src/symbols.rs
+MAGIC = b"LZKSYMS\0"
+MAX_SYMBOLS = 4096
+NAMES_LEN = 160 * 1024
+HEADER = struct.Struct("<8sIII")
+SYMBOL = struct.Struct("<III")
+
+# Hash suffix of legacy mangled Rust symbols
+HASH = re.compile(r"::h[0-9a-f]{16}$")
+
+
+def sections(image):
+    """Yield (name, virtual address, virtual size, file offset) of every PE section"""
+    pe = struct.unpack_from("<I", image, 0x3c)[0]
+    if image[pe:pe + 4] != b"PE\0\0":
+        sys.exit("not a PE image")
+
+    count, = struct.unpack_from("<H", image, pe + 6)
+    opt_size, = struct.unpack_from("<H", image, pe + 20)
+    table = pe + 24 + opt_size
+
+    for ii in range(count):
+        name, vsize, vaddr, _, raw = struct.unpack_from("<8sIIII", image, table + ii * 40)
+        yield name.rstrip(b"\0").decode(), vaddr, vsize, raw
+
+
+def image_base(image):
+    pe = struct.unpack_from("<I", image, 0x3c)[0]
+    return struct.unpack_from("<Q", image, pe + 24 + 24)[0]
+
+
+def functions(path, base):
+    """List (rva, name) of the code symbols in the image"""
+    nm = os.environ.get("NM", "llvm-nm")
+    out = subprocess.run([nm, "--defined-only", "--demangle", path],
+                         check=True, capture_output=True, text=True).stdout
+
+    symbols = {}
+    for line in out.splitlines():
+        parts = line.split(" ", 2)
+        if len(parts) != 3 or parts[1] not in "tT":
+            continue
+        rva = int(parts[0], 16) - base
+        symbols.setdefault(rva, HASH.sub("", parts[2]))
+
+    return sorted(symbols.items())
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(__doc__)
+    path = sys.argv[1]
+
+    with open(path, "rb") as file:
+        image = bytearray(file.read())
+
+    base = image_base(image)
+    found = {name: (vaddr, vsize, raw) for name, vaddr, vsize, raw in sections(image)}
+    if ".ksyms" not in found or ".text" not in found:
+        sys.exit("image has no .ksyms or .text section")
+
+    table_rva, _, offset = found[".ksyms"]
+    text_rva, text_size, _ = found[".text"]
+    if image[offset:offset + len(MAGIC)] != MAGIC:
+        sys.exit(".ksyms does not start with the symbol table")
+
+    entries = []
+    names = bytearray()
+    for rva, name in functions(path, base):
+        encoded = name.encode()
+        if len(entries) == MAX_SYMBOLS or len(names) + len(encoded) > NAMES_LEN:
+            print("ksyms: table full, dropping symbols past %#x" % rva, file=sys.stderr)
+            break
+        entries.append(SYMBOL.pack(rva, len(names), len(encoded)))
+        names += encoded
+
+    HEADER.pack_into(image, offset, MAGIC, table_rva, len(entries), text_rva + text_size)
+    pos = offset + HEADER.size
+    image[pos:pos + len(entries) * SYMBOL.size] = b"".join(entries)
+    pos = offset + HEADER.size + MAX_SYMBOLS * SYMBOL.size
+    image[pos:pos + len(names)] = names
+
+    with open(path, "wb") as file:
+        file.write(image)
+
+    print("ksyms: %d symbols" % len(entries))
+
+
+if __name__ == "__main__":
+    main()